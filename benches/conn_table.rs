@@ -0,0 +1,91 @@
+//! Benchmarks of the table of open TCP connections, sharded as portfwd uses it against a single
+//! shard, which is what one table behind one lock would be.
+//!
+//! The table is compiled from its source, since portfwd has no library target.
+
+use std::{net::SocketAddr, sync::Arc, thread, time::Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+#[allow(dead_code)]
+#[path = "../src/conn_table.rs"]
+mod conn_table;
+#[allow(dead_code)]
+#[path = "../src/timer_wheel.rs"]
+mod timer_wheel;
+
+/// The one type of the metrics that the table uses.
+mod metrics {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[derive(Debug, Default)]
+    pub struct Counter(AtomicU64);
+
+    impl Counter {
+        pub fn get(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+}
+
+use conn_table::{ConnInfo, ConnTable, LastActive};
+
+/// The number of threads that add and remove connections at once.
+const THREADS: usize = 8;
+
+fn info() -> ConnInfo {
+    let started = Instant::now();
+    let addr: SocketAddr = ([127, 0, 0, 1], 8080).into();
+    ConnInfo {
+        peer_addr: addr,
+        backend: addr,
+        started,
+        bytes: Arc::default(),
+        last_active: Arc::new(LastActive::new(started)),
+        kill: smol::channel::bounded(1).0,
+    }
+}
+
+/// Adds and removes connections from all threads at once.
+fn churn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("churn");
+    for shards in [1, THREADS] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(shards),
+            &shards,
+            |b, &shards| {
+                let table = ConnTable::new(shards);
+                b.iter_custom(|iters| {
+                    let started = Instant::now();
+                    thread::scope(|scope| {
+                        for _ in 0..THREADS {
+                            scope.spawn(|| {
+                                for _ in 0..iters {
+                                    drop(table.insert(info()));
+                                }
+                            });
+                        }
+                    });
+                    started.elapsed()
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Sums up a table of open connections, as the stats endpoint does.
+fn stats(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stats");
+    for shards in [1, THREADS] {
+        let table = ConnTable::new(shards);
+        let _entries: Vec<_> = (0..10_000).map(|_| table.insert(info())).collect();
+        group.bench_with_input(BenchmarkId::from_parameter(shards), &table, |b, table| {
+            b.iter(|| table.stats());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, churn, stats);
+criterion_main!(benches);
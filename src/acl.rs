@@ -0,0 +1,161 @@
+//! Access control by the time of day, for `--allow-hours`.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily window of time in UTC, given as `<HH:MM>-<HH:MM>`, which wraps around midnight if it
+/// ends before it starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeWindow {
+    /// Minutes after midnight.
+    start: u32,
+    end: u32,
+}
+
+impl TimeWindow {
+    /// Whether a minute of the day is in the window, which includes its start but not its end.
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    fn overlaps(&self, other: &TimeWindow) -> bool {
+        self.contains(other.start) || other.contains(self.start)
+    }
+}
+
+/// Parses a time of day `<HH:MM>` into minutes after midnight.
+fn parse_time(s: &str) -> Result<u32, String> {
+    let (hours, minutes) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected <HH:MM>, got: {s}"))?;
+    match (hours.parse::<u32>(), minutes.parse::<u32>()) {
+        (Ok(h), Ok(m)) if h < 24 && m < 60 && minutes.len() == 2 => Ok(h * 60 + m),
+        _ => Err(format!("invalid time of day: {s}")),
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected <HH:MM>-<HH:MM>, got: {s}"))?;
+        let window = TimeWindow {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        };
+        if window.start == window.end {
+            return Err(format!("window is empty: {s}"));
+        }
+        Ok(window)
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// The windows of time in which clients are served.
+#[derive(Debug)]
+pub struct Schedule {
+    windows: Vec<TimeWindow>,
+}
+
+impl Schedule {
+    /// Creates a schedule of windows, which must not overlap.
+    pub fn new(windows: Vec<TimeWindow>) -> Result<Self, String> {
+        for (i, window) in windows.iter().enumerate() {
+            if let Some(other) = windows[i + 1..].iter().find(|w| w.overlaps(window)) {
+                return Err(format!("windows {window} and {other} overlap"));
+            }
+        }
+        Ok(Self { windows })
+    }
+
+    /// Whether clients are served at the current time.
+    pub fn is_open(&self) -> bool {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let minute = (secs / 60 % u64::from(MINUTES_PER_DAY)) as u32;
+        self.windows.iter().any(|window| window.contains(minute))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(s: &str) -> TimeWindow {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert_eq!(
+            window("09:00-17:30"),
+            TimeWindow {
+                start: 540,
+                end: 1050
+            }
+        );
+        assert_eq!(window("9:05-00:00").to_string(), "09:05-00:00");
+        for invalid in [
+            "09:00",
+            "24:00-01:00",
+            "09:60-10:00",
+            "09:5-10:00",
+            "10:00-10:00",
+        ] {
+            assert!(invalid.parse::<TimeWindow>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn includes_the_start_but_not_the_end() {
+        let day = window("09:00-17:00");
+        assert!(!day.contains(8 * 60 + 59));
+        assert!(day.contains(9 * 60));
+        assert!(day.contains(16 * 60 + 59));
+        assert!(!day.contains(17 * 60));
+    }
+
+    #[test]
+    fn wraps_around_midnight() {
+        let night = window("22:00-06:00");
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(0));
+        assert!(night.contains(5 * 60 + 59));
+        assert!(!night.contains(6 * 60));
+        assert!(!night.contains(12 * 60));
+        assert!(!night.contains(21 * 60 + 59));
+    }
+
+    #[test]
+    fn rejects_overlapping_windows() {
+        assert!(Schedule::new(vec![window("09:00-12:00"), window("13:00-17:00")]).is_ok());
+        assert!(Schedule::new(vec![window("09:00-12:00"), window("12:00-13:00")]).is_ok());
+        assert!(Schedule::new(vec![window("09:00-12:00"), window("11:00-13:00")]).is_err());
+        assert!(Schedule::new(vec![window("22:00-06:00"), window("05:00-07:00")]).is_err());
+        assert!(Schedule::new(vec![window("10:00-11:00"), window("09:00-17:00")]).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! Scheduling of executor threads: pinning them to CPUs, so that their caches stay warm, and
+//! giving them real-time priority.
+
+use std::io;
+
+/// Pins the calling thread to a single CPU.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    // SAFETY: `cpu_set_t` is a plain bit mask, for which all zeroes is the empty set.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("CPU {cpu} is out of range"),
+        ));
+    }
+    // SAFETY: `cpu` was checked to be within the set, and pid 0 is the calling thread.
+    unsafe {
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "CPU affinity is unsupported on this platform",
+    ))
+}
+
+/// Runs the calling thread with the `SCHED_FIFO` real-time policy at a priority from 1 to 99.
+#[cfg(unix)]
+pub fn set_realtime_priority(priority: u8) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority.into(),
+    };
+    // SAFETY: `param` is a valid parameter for the calling thread.
+    match unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) } {
+        0 => Ok(()),
+        libc::EPERM => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "real-time priority requires the CAP_SYS_NICE capability",
+        )),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn set_realtime_priority(_priority: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "real-time priority is unsupported on this platform",
+    ))
+}
+
+/// Finds the CPUs of the NUMA node that the network interface with an address is attached to.
+#[cfg(target_os = "linux")]
+pub fn numa_cpus(ip: std::net::IpAddr) -> io::Result<Vec<usize>> {
+    let interface = interface_of(ip)?;
+    let node = std::fs::read_to_string(format!("/sys/class/net/{interface}/device/numa_node"))
+        .ok()
+        .and_then(|node| node.trim().parse::<i32>().ok())
+        .filter(|&node| node >= 0)
+        .ok_or_else(|| io::Error::other(format!("{interface} is not attached to a NUMA node")))?;
+    let cpus = std::fs::read_to_string(format!("/sys/devices/system/node/node{node}/cpulist"))?;
+    parse_cpu_list(cpus.trim())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid CPU list"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn numa_cpus(_ip: std::net::IpAddr) -> io::Result<Vec<usize>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "NUMA topology is unsupported on this platform",
+    ))
+}
+
+/// Finds the name of the network interface that has an address.
+#[cfg(target_os = "linux")]
+fn interface_of(ip: std::net::IpAddr) -> io::Result<String> {
+    use std::{
+        ffi::CStr,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        ptr,
+    };
+
+    let mut addrs = ptr::null_mut();
+    // SAFETY: `getifaddrs` stores a list in `addrs`, which is freed below.
+    if unsafe { libc::getifaddrs(&mut addrs) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut found = None;
+    let mut cursor = addrs;
+    // SAFETY: the list is well-formed until it is freed, and each address is read according to
+    // its family.
+    unsafe {
+        while let Some(ifa) = cursor.as_ref() {
+            cursor = ifa.ifa_next;
+            let Some(addr) = ifa.ifa_addr.as_ref() else {
+                continue;
+            };
+            let this: IpAddr = match addr.sa_family as i32 {
+                libc::AF_INET => {
+                    let addr = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into()
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr).into()
+                }
+                _ => continue,
+            };
+            if this == ip {
+                found = Some(CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned());
+                break;
+            }
+        }
+        libc::freeifaddrs(addrs);
+    }
+    found.ok_or_else(|| io::Error::other(format!("no network interface has the address {ip}")))
+}
+
+/// Parses a list of CPUs in the format of sysfs, e.g. `0-3,8-11`.
+#[cfg(target_os = "linux")]
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => cpus.extend(start.parse::<usize>().ok()?..=end.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
@@ -0,0 +1,99 @@
+//! Username and password authentication of proxy clients.
+//!
+//! Passwords are only given to portfwd as bcrypt hashes, which `portfwd hash-password` prints.
+
+use std::{fmt, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use smol::Timer;
+
+/// How long to wait before answering a failed attempt, to slow down brute-force attacks.
+const FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+/// The credentials clients must present, given on the command line as `<USER>:<BCRYPT-HASH>`.
+#[derive(Clone)]
+pub struct Credentials {
+    user: String,
+    hash: Arc<str>,
+}
+
+impl FromStr for Credentials {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, hash) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <USER>:<BCRYPT-HASH>, got: {s}"))?;
+        if !hash.starts_with("$2") {
+            return Err(format!(
+                "not a bcrypt hash, use `portfwd hash-password` to create one: {hash}"
+            ));
+        }
+        Ok(Credentials {
+            user: user.to_string(),
+            hash: hash.into(),
+        })
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Credentials {
+    /// Checks the credentials presented by a client, delaying the answer if they are wrong.
+    ///
+    /// The password is verified even if the user is wrong, so that the time taken does not tell
+    /// valid users apart.
+    pub async fn check(&self, user: &[u8], password: &[u8], peer_addr: SocketAddr) -> bool {
+        let hash = self.hash.clone();
+        let password = password.to_vec();
+        let verified =
+            smol::unblock(move || bcrypt::verify(password, &hash).unwrap_or(false)).await;
+
+        let ok = verified && user == self.user.as_bytes();
+        if !ok {
+            tracing::warn!(
+                "Failed authentication from {} as {:?}",
+                peer_addr,
+                String::from_utf8_lossy(user)
+            );
+            Timer::after(FAILURE_DELAY).await;
+        }
+        ok
+    }
+}
+
+/// Hashes a password for `--auth`.
+pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
+    bcrypt::hash(password, bcrypt::DEFAULT_COST)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_credentials() {
+        let credentials: Credentials = "alice:$2b$04$abc:def".parse().unwrap();
+        assert_eq!(credentials.user, "alice");
+        assert_eq!(&*credentials.hash, "$2b$04$abc:def");
+        assert!(!format!("{credentials:?}").contains("$2b"));
+        assert!("alice".parse::<Credentials>().is_err());
+        assert!("alice:secret".parse::<Credentials>().is_err());
+    }
+
+    #[test]
+    fn checks_the_user_and_password() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let credentials: Credentials = format!("alice:{hash}").parse().unwrap();
+        let peer_addr = "127.0.0.1:1234".parse().unwrap();
+        smol::block_on(async {
+            assert!(credentials.check(b"alice", b"secret", peer_addr).await);
+            assert!(!credentials.check(b"bob", b"secret", peer_addr).await);
+        });
+    }
+}
@@ -0,0 +1,231 @@
+//! The backends that clients are forwarded to, and how one is picked for each new client.
+//!
+//! Backends are given as `<ADDR>[@<WEIGHT>]`, and are picked at random in proportion to their
+//! weights, which the control socket can change while clients come in.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use crate::{builtin::Builtin, session::Sessions};
+
+/// A backend and its share of new clients, given on the command line as `<ADDR>[@<WEIGHT>]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backend {
+    pub addr: SocketAddr,
+    /// Relative weight of the backend, where 0 drains it of new clients.
+    pub weight: u32,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, weight) = match s.rsplit_once('@') {
+            Some((addr, weight)) => {
+                let weight = weight.parse().map_err(|e| format!("{e}: {weight}"))?;
+                (addr, weight)
+            }
+            None => (s, 1),
+        };
+        Ok(Backend {
+            addr: addr.parse().map_err(|e| format!("{e}: {addr}"))?,
+            weight,
+        })
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.addr, self.weight)
+    }
+}
+
+/// A target given to `--forward`: either a backend, or a built-in target by name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Forward {
+    Backend(Backend),
+    Builtin(Builtin),
+}
+
+impl FromStr for Forward {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(builtin) => Ok(Forward::Builtin(builtin)),
+            Err(_) => s.parse().map(Forward::Backend),
+        }
+    }
+}
+
+/// The pool of destinations that clients are forwarded to.
+///
+/// The weights of the backends can be changed while the pool is in use.
+#[derive(Debug)]
+pub struct Backends {
+    backends: RwLock<Vec<Backend>>,
+    /// Sticky assignments of clients to backends, if enabled.
+    sessions: Option<Arc<Sessions>>,
+}
+
+impl Backends {
+    /// Creates a pool from a list of backends, which is empty for SOCKS proxies.
+    pub fn new(backends: Vec<Backend>, sessions: Option<Arc<Sessions>>) -> Self {
+        Self {
+            backends: RwLock::new(backends),
+            sessions,
+        }
+    }
+
+    /// The first backend, whose port is the default port to listen on.
+    pub fn first(&self) -> Option<SocketAddr> {
+        self.backends.read().unwrap().first().map(|b| b.addr)
+    }
+
+    /// Changes the weight of a backend, returning `false` if it is not part of the pool.
+    ///
+    /// Setting the weight to 0 drains the backend: clients that are already connected to it
+    /// stay, but no new clients are sent there.
+    pub fn set_weight(&self, addr: SocketAddr, weight: u32) -> bool {
+        let mut backends = self.backends.write().unwrap();
+        match backends.iter_mut().find(|b| b.addr == addr) {
+            Some(backend) => {
+                backend.weight = weight;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Replaces the address of a backend, keeping its weight, returning `false` if the old
+    /// address is not part of the pool or the new one already is.
+    ///
+    /// New clients go to the new address at once, including the sticky clients of the old one.
+    pub fn replace(&self, old: SocketAddr, new: SocketAddr) -> bool {
+        let mut backends = self.backends.write().unwrap();
+        if backends.iter().any(|b| b.addr == new) {
+            return false;
+        }
+        match backends.iter_mut().find(|b| b.addr == old) {
+            Some(backend) => {
+                backend.addr = new;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the pool has backends, but all of them are drained.
+    pub fn all_drained(&self) -> bool {
+        let backends = self.backends.read().unwrap();
+        !backends.is_empty() && backends.iter().all(|b| b.weight == 0)
+    }
+
+    /// Picks the backend for a client, or `None` if all backends are drained.
+    ///
+    /// Backends are picked at random in proportion to their weights. With sticky sessions, a
+    /// client that was seen before goes to the same backend again, as long as that backend is
+    /// still part of the pool and not drained.
+    pub fn select(&self, client: IpAddr) -> Option<SocketAddr> {
+        let backends = self.backends.read().unwrap();
+        if let Some(sessions) = &self.sessions {
+            let active =
+                |addr: &SocketAddr| backends.iter().any(|b| b.addr == *addr && b.weight > 0);
+            if let Some(addr) = sessions.get(client).filter(active) {
+                return Some(addr);
+            }
+        }
+
+        let addr = pick(&backends)?;
+
+        if let Some(sessions) = &self.sessions {
+            sessions.insert(client, addr);
+        }
+        Some(addr)
+    }
+}
+
+/// Picks a backend at random in proportion to its weight, by walking the cumulative weights up
+/// to a random point below their total.
+fn pick(backends: &[Backend]) -> Option<SocketAddr> {
+    let total: u64 = backends.iter().map(|b| b.weight as u64).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let mut point = fastrand::u64(..total);
+    for backend in backends {
+        if point < backend.weight as u64 {
+            return Some(backend.addr);
+        }
+        point -= backend.weight as u64;
+    }
+    unreachable!("the point is below the total weight")
+}
+
+impl fmt::Display for Backends {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, backend) in self.backends.read().unwrap().iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{backend}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(s: &str) -> Backend {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_backends() {
+        let addr: SocketAddr = "10.0.0.1:80".parse().unwrap();
+        assert_eq!(backend("10.0.0.1:80"), Backend { addr, weight: 1 });
+        assert_eq!(backend("10.0.0.1:80@5"), Backend { addr, weight: 5 });
+        assert_eq!(backend("10.0.0.1:80@0").weight, 0);
+        assert_eq!(backend("[::1]:53@2").addr, "[::1]:53".parse().unwrap());
+        assert_eq!(backend("10.0.0.1:80@5").to_string(), "10.0.0.1:80@5");
+        assert!("10.0.0.1".parse::<Backend>().is_err());
+        assert!("10.0.0.1:80@".parse::<Backend>().is_err());
+        assert!("10.0.0.1:80@-1".parse::<Backend>().is_err());
+        assert!("example.com:80".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn picks_in_proportion_to_the_weights() {
+        fastrand::seed(7);
+        let backends = [
+            backend("10.0.0.1:80@1"),
+            backend("10.0.0.2:80@3"),
+            backend("10.0.0.3:80@0"),
+        ];
+        let mut picked = [0; 3];
+        for _ in 0..4000 {
+            let addr = pick(&backends).unwrap();
+            picked[backends.iter().position(|b| b.addr == addr).unwrap()] += 1;
+        }
+        assert!((900..1100).contains(&picked[0]), "{picked:?}");
+        assert!((2900..3100).contains(&picked[1]), "{picked:?}");
+        assert_eq!(picked[2], 0);
+    }
+
+    #[test]
+    fn picks_nothing_when_all_are_drained() {
+        assert_eq!(pick(&[]), None);
+        let backends = [backend("10.0.0.1:80@0"), backend("10.0.0.2:80@0")];
+        assert_eq!(pick(&backends), None);
+        let backends = Backends::new(backends.to_vec(), None);
+        assert!(backends.all_drained());
+        assert_eq!(backends.select([127, 0, 0, 1].into()), None);
+    }
+}
@@ -0,0 +1,153 @@
+//! The `bench` subcommand, which measures how fast portfwd forwards on this machine.
+//!
+//! portfwd starts as usual, forwarding TCP from a free port of the loopback address to an echo
+//! server of its own. Clients then keep a number of connections busy sending requests and reading
+//! them back, until the time is up. The clients and the echo server run on the executor threads
+//! next to the forwarder, so they take a share of the CPU time, much like a real client and
+//! backend on the same machine would.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+
+use smol::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    Async, Timer,
+};
+
+use crate::{cli::BenchArgs, task::spawn_named};
+
+/// How long the forwarder may take to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Binds the echo server on a free port of the loopback address.
+pub fn bind_echo() -> io::Result<Async<TcpListener>> {
+    Async::<TcpListener>::bind((Ipv4Addr::LOCALHOST, 0))
+}
+
+/// Finds a free port of the loopback address for the forwarder to listen on.
+pub fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port())
+}
+
+/// Sends back everything that clients send, forever.
+pub async fn echo(listener: Async<TcpListener>) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        stream.get_ref().set_nodelay(true)?;
+        spawn_named("bench-echo", async move {
+            let _ = io::copy(&stream, &mut &stream).await;
+        })
+        .detach();
+    }
+}
+
+/// What the clients measured.
+#[derive(Default)]
+struct Stats {
+    connections: u64,
+    bytes: u64,
+    /// The round-trip time of each request.
+    latencies: Vec<Duration>,
+}
+
+/// Runs the benchmark against the forwarder listening on `addr`, and prints the results.
+pub async fn run(args: &BenchArgs, addr: SocketAddr) -> io::Result<()> {
+    wait_listening(addr).await?;
+    println!(
+        "Benchmarking {} connections of {} requests of {} bytes for {}s...",
+        args.concurrency, args.requests, args.size, args.duration
+    );
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.duration);
+    let clients: Vec<_> = (0..args.concurrency)
+        .map(|i| {
+            let (size, requests) = (args.size as usize, args.requests);
+            spawn_named(
+                format!("bench-client-{i}"),
+                client(addr, size, requests, deadline),
+            )
+        })
+        .collect();
+    let mut stats = Stats::default();
+    for client in clients {
+        let client = client.await?;
+        stats.connections += client.connections;
+        stats.bytes += client.bytes;
+        stats.latencies.extend(client.latencies);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    stats.latencies.sort_unstable();
+    let percentile = |p: f64| {
+        let i = ((stats.latencies.len() - 1) as f64 * p).round() as usize;
+        stats.latencies[i].as_secs_f64() * 1000.0
+    };
+    println!(
+        "Connections: {} ({:.1}/s)",
+        stats.connections,
+        stats.connections as f64 / elapsed
+    );
+    println!(
+        "Throughput:  {:.1} MB/s ({} bytes in both directions)",
+        stats.bytes as f64 / elapsed / 1e6,
+        stats.bytes
+    );
+    if stats.latencies.is_empty() {
+        println!("Latency:     no request completed");
+    } else {
+        println!(
+            "Latency:     P50 {:.3} ms, P95 {:.3} ms, P99 {:.3} ms over {} requests",
+            percentile(0.5),
+            percentile(0.95),
+            percentile(0.99),
+            stats.latencies.len()
+        );
+    }
+    Ok(())
+}
+
+/// Waits for the forwarder to accept connections.
+async fn wait_listening(addr: SocketAddr) -> io::Result<()> {
+    let start = Instant::now();
+    loop {
+        match Async::<TcpStream>::connect(addr).await {
+            Ok(_) => return Ok(()),
+            Err(err) if start.elapsed() >= STARTUP_TIMEOUT => return Err(err),
+            Err(_) => Timer::after(Duration::from_millis(10)).await,
+        };
+    }
+}
+
+/// Sends requests over one connection after another until the deadline, each connection
+/// carrying up to `requests` of them.
+async fn client(
+    addr: SocketAddr,
+    size: usize,
+    requests: u64,
+    deadline: Instant,
+) -> io::Result<Stats> {
+    let request = vec![0x5a; size];
+    let mut response = vec![0; size];
+    let mut stats = Stats::default();
+    while Instant::now() < deadline {
+        let mut stream = Async::<TcpStream>::connect(addr).await?;
+        stream.get_ref().set_nodelay(true)?;
+        stats.connections += 1;
+        for _ in 0..requests {
+            let start = Instant::now();
+            if start >= deadline {
+                break;
+            }
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut response).await?;
+            stats.latencies.push(start.elapsed());
+            stats.bytes += 2 * size as u64;
+        }
+    }
+    Ok(stats)
+}
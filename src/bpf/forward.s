@@ -0,0 +1,236 @@
+# The XDP program of `--xdp`, which forwards the IPv4 UDP datagrams of the clients in FLOWS to
+# their backends from the interface driver. FLOWS is keyed by the address and port of the client
+# and the port it sent to, all in network order, and holds the local address the datagrams must
+# be for, or 0 for any, and the address and port of the backend. Each datagram is sent on from
+# the port of the listener and its local address, or the address the kernel would pick for the
+# backend, as the listener would, with its TTL decreased, and to the next hop that the FIB of the
+# kernel gives for the backend. Everything else, including the datagrams that it gives no next
+# hop for, is passed to the kernel.
+#
+# Built into forward.bpf.o, which is checked in, with:
+#
+#     llvm-mc -triple bpfel -filetype=obj -o src/bpf/forward.bpf.o src/bpf/forward.s
+
+	.section	xdp,"ax",@progbits
+	.globl	forward
+	.type	forward,@function
+forward:
+	r6 = r1
+	# IPv4 without options, then UDP.
+	r2 = *(u32 *)(r6 + 0)
+	r3 = *(u32 *)(r6 + 4)
+	r4 = r2
+	r4 += 42
+	if r4 > r3 goto .Lpass
+	r5 = *(u16 *)(r2 + 12)
+	if r5 != 0x0008 goto .Lpass
+	r5 = *(u8 *)(r2 + 14)
+	if r5 != 0x45 goto .Lpass
+	# Fragments are left to the kernel to reassemble.
+	r5 = *(u16 *)(r2 + 20)
+	r5 &= 0xff3f
+	if r5 != 0 goto .Lpass
+	r5 = *(u8 *)(r2 + 23)
+	if r5 != 17 goto .Lpass
+	r5 = *(u8 *)(r2 + 22)
+	if r5 <= 1 goto .Lpass
+
+	# The flow of the client, keyed by its address and port and the port it sent to.
+	r5 = *(u32 *)(r2 + 26)
+	*(u32 *)(r10 - 8) = r5
+	r5 = *(u16 *)(r2 + 34)
+	*(u16 *)(r10 - 4) = r5
+	r5 = *(u16 *)(r2 + 36)
+	*(u16 *)(r10 - 2) = r5
+	r2 = r10
+	r2 += -8
+	r1 = FLOWS ll
+	call 1
+	if r0 == 0 goto .Lpass
+	r7 = r0
+	r2 = *(u32 *)(r6 + 0)
+	r3 = *(u32 *)(r6 + 4)
+	r4 = r2
+	r4 += 42
+	if r4 > r3 goto .Lpass
+	# Only datagrams for the local address of the listener, if it has one, which they are then sent
+	# on from. Otherwise they are sent from the address that the kernel picks for the backend, with
+	# BPF_FIB_LOOKUP_SRC.
+	r8 = *(u32 *)(r2 + 30)
+	r9 = *(u32 *)(r7 + 0)
+	if r9 == 0 goto .Lroute
+	if r9 != r8 goto .Lpass
+
+.Lroute:
+	# The next hop to the backend, in a struct bpf_fib_lookup of 64 bytes.
+	r1 = 0
+	*(u64 *)(r10 - 72) = r1
+	*(u64 *)(r10 - 64) = r1
+	*(u64 *)(r10 - 56) = r1
+	*(u64 *)(r10 - 48) = r1
+	*(u64 *)(r10 - 40) = r1
+	*(u64 *)(r10 - 32) = r1
+	*(u64 *)(r10 - 24) = r1
+	*(u64 *)(r10 - 16) = r1
+	r1 = 2
+	*(u8 *)(r10 - 72) = r1
+	r1 = 17
+	*(u8 *)(r10 - 71) = r1
+	r1 = *(u16 *)(r2 + 36)
+	*(u16 *)(r10 - 70) = r1
+	r1 = *(u16 *)(r7 + 8)
+	*(u16 *)(r10 - 68) = r1
+	r1 = *(u16 *)(r2 + 16)
+	r1 = be16 r1
+	*(u16 *)(r10 - 66) = r1
+	r1 = *(u32 *)(r6 + 12)
+	*(u32 *)(r10 - 64) = r1
+	r1 = *(u8 *)(r2 + 15)
+	*(u8 *)(r10 - 60) = r1
+	*(u32 *)(r10 - 56) = r9
+	r1 = *(u32 *)(r7 + 4)
+	*(u32 *)(r10 - 40) = r1
+	r1 = r6
+	r2 = r10
+	r2 += -72
+	r3 = 64
+	r4 = 0
+	if r9 != 0 goto .Llookup
+	r4 = 16
+.Llookup:
+	call 69
+	if r0 != 0 goto .Lpass
+	r2 = *(u32 *)(r6 + 0)
+	r3 = *(u32 *)(r6 + 4)
+	r4 = r2
+	r4 += 42
+	if r4 > r3 goto .Lpass
+
+	# The UDP checksum, if there is one, updated as RFC 1624 does for the addresses and the source
+	# port that change. The destination port of the client becomes the source port.
+	r9 = *(u16 *)(r2 + 40)
+	if r9 == 0 goto .Lrewrite
+	r9 ^= 0xffff
+	r1 = *(u16 *)(r2 + 26)
+	r1 ^= 0xffff
+	r9 += r1
+	r1 = *(u16 *)(r2 + 28)
+	r1 ^= 0xffff
+	r9 += r1
+	r1 = *(u16 *)(r2 + 30)
+	r1 ^= 0xffff
+	r9 += r1
+	r1 = *(u16 *)(r2 + 32)
+	r1 ^= 0xffff
+	r9 += r1
+	r1 = *(u16 *)(r2 + 34)
+	r1 ^= 0xffff
+	r9 += r1
+	r1 = *(u16 *)(r10 - 56)
+	r9 += r1
+	r1 = *(u16 *)(r10 - 54)
+	r9 += r1
+	r1 = *(u16 *)(r7 + 4)
+	r9 += r1
+	r1 = *(u16 *)(r7 + 6)
+	r9 += r1
+	r1 = *(u16 *)(r7 + 8)
+	r9 += r1
+	r1 = r9
+	r1 >>= 16
+	r9 &= 0xffff
+	r9 += r1
+	r1 = r9
+	r1 >>= 16
+	r9 &= 0xffff
+	r9 += r1
+	r9 ^= 0xffff
+	if r9 != 0 goto .Lchecksum
+	r9 = 0xffff
+.Lchecksum:
+	*(u16 *)(r2 + 40) = r9
+
+.Lrewrite:
+	r1 = *(u32 *)(r10 - 56)
+	*(u32 *)(r2 + 26) = r1
+	r1 = *(u32 *)(r7 + 4)
+	*(u32 *)(r2 + 30) = r1
+	r1 = *(u16 *)(r2 + 36)
+	*(u16 *)(r2 + 34) = r1
+	r1 = *(u16 *)(r7 + 8)
+	*(u16 *)(r2 + 36) = r1
+	r1 = *(u8 *)(r2 + 22)
+	r1 -= 1
+	*(u8 *)(r2 + 22) = r1
+
+	# The IPv4 checksum, summed again over the 10 words of the header.
+	r1 = 0
+	*(u16 *)(r2 + 24) = r1
+	r9 = *(u16 *)(r2 + 14)
+	r1 = *(u16 *)(r2 + 16)
+	r9 += r1
+	r1 = *(u16 *)(r2 + 18)
+	r9 += r1
+	r1 = *(u16 *)(r2 + 20)
+	r9 += r1
+	r1 = *(u16 *)(r2 + 22)
+	r9 += r1
+	r1 = *(u16 *)(r2 + 26)
+	r9 += r1
+	r1 = *(u16 *)(r2 + 28)
+	r9 += r1
+	r1 = *(u16 *)(r2 + 30)
+	r9 += r1
+	r1 = *(u16 *)(r2 + 32)
+	r9 += r1
+	r1 = r9
+	r1 >>= 16
+	r9 &= 0xffff
+	r9 += r1
+	r1 = r9
+	r1 >>= 16
+	r9 &= 0xffff
+	r9 += r1
+	r9 ^= 0xffff
+	*(u16 *)(r2 + 24) = r9
+
+	# The MAC addresses of the next hop, and out of the interface it is on.
+	r1 = *(u16 *)(r10 - 14)
+	*(u16 *)(r2 + 0) = r1
+	r1 = *(u32 *)(r10 - 12)
+	*(u32 *)(r2 + 2) = r1
+	r1 = *(u32 *)(r10 - 20)
+	*(u32 *)(r2 + 6) = r1
+	r1 = *(u16 *)(r10 - 16)
+	*(u16 *)(r2 + 10) = r1
+	r1 = *(u32 *)(r10 - 64)
+	r2 = 0
+	call 23
+	exit
+
+.Lpass:
+	r0 = 2
+	exit
+	.size	forward, .-forward
+
+	# A BPF_MAP_TYPE_LRU_HASH of 65536 flows, with keys of 8 bytes and values of 12.
+	.section	maps,"aw",@progbits
+	.globl	FLOWS
+	.type	FLOWS,@object
+	.p2align	2
+FLOWS:
+	.long	9
+	.long	8
+	.long	12
+	.long	65536
+	.long	0
+	.long	0
+	.long	0
+	.size	FLOWS, 28
+
+	.section	license,"aw",@progbits
+	.globl	_license
+	.type	_license,@object
+_license:
+	.asciz	"Dual MIT/GPL"
+	.size	_license, 13
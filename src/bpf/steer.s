@@ -0,0 +1,105 @@
+# The TC ingress program of `--ebpf-steer`, which assigns the SYNs of new TCP clients to the
+# listener of their port in SOCKETS, keyed by the port shifted left by one, plus one for IPv6.
+# Other packets are left to the lookup of the kernel, which finds the connections that the SYNs
+# started.
+#
+# Built into steer.bpf.o, which is checked in, with:
+#
+#     llvm-mc -triple bpfel -filetype=obj -o src/bpf/steer.bpf.o src/bpf/steer.s
+
+	.section	classifier,"ax",@progbits
+	.globl	steer
+	.type	steer,@function
+steer:
+	r6 = r1
+	# The packet, from its Ethernet header.
+	r2 = *(u32 *)(r6 + 76)
+	r3 = *(u32 *)(r6 + 80)
+	r4 = r2
+	r4 += 14
+	if r4 > r3 goto .Lpass
+	r5 = *(u16 *)(r2 + 12)
+	if r5 == 0x0008 goto .Lipv4
+	if r5 == 0xdd86 goto .Lipv6
+	goto .Lpass
+
+.Lipv4:
+	r5 = r4
+	r5 += 20
+	if r5 > r3 goto .Lpass
+	r7 = *(u8 *)(r4 + 9)
+	if r7 != 6 goto .Lpass
+	# Fragments after the first have no TCP header.
+	r7 = *(u16 *)(r4 + 6)
+	r7 &= 0xff1f
+	if r7 != 0 goto .Lpass
+	r7 = *(u8 *)(r4 + 0)
+	r7 &= 15
+	r7 <<= 2
+	if r7 < 20 goto .Lpass
+	r4 += r7
+	r8 = 0
+	goto .Ltcp
+
+.Lipv6:
+	r5 = r4
+	r5 += 40
+	if r5 > r3 goto .Lpass
+	# Only TCP right after the IPv6 header, without extension headers.
+	r7 = *(u8 *)(r4 + 6)
+	if r7 != 6 goto .Lpass
+	r4 += 40
+	r8 = 1
+
+.Ltcp:
+	r5 = r4
+	r5 += 20
+	if r5 > r3 goto .Lpass
+	# SYN without ACK.
+	r7 = *(u8 *)(r4 + 13)
+	r7 &= 0x12
+	if r7 != 2 goto .Lpass
+	r7 = *(u16 *)(r4 + 2)
+	r7 = be16 r7
+	r7 <<= 1
+	r7 |= r8
+	*(u32 *)(r10 - 4) = r7
+	r2 = r10
+	r2 += -4
+	r1 = SOCKETS ll
+	call 1
+	if r0 == 0 goto .Lpass
+	r7 = r0
+	r1 = r6
+	r2 = r7
+	r3 = 0
+	call 124
+	r1 = r7
+	call 86
+
+.Lpass:
+	r0 = 0
+	exit
+	.size	steer, .-steer
+
+	# A BPF_MAP_TYPE_SOCKHASH of 64 listeners, with keys of 4 bytes.
+	.section	maps,"aw",@progbits
+	.globl	SOCKETS
+	.type	SOCKETS,@object
+	.p2align	2
+SOCKETS:
+	.long	18
+	.long	4
+	.long	4
+	.long	64
+	.long	0
+	.long	0
+	.long	0
+	.size	SOCKETS, 28
+
+	.section	license,"aw",@progbits
+	.globl	_license
+	.type	_license,@object
+_license:
+	.asciz	"Dual MIT/GPL"
+	.size	_license, 13
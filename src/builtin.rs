@@ -0,0 +1,113 @@
+//! Built-in targets that serve clients without any backend, for testing clients and portfwd
+//! itself.
+
+use std::{fmt, net::SocketAddr, str::FromStr, time::Instant};
+
+use smol::future;
+
+use crate::{
+    config::Config,
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    metrics::{Counter, CountingReader},
+    timer_wheel::{self, ActivityReader, Idle},
+    transport::BoxStream,
+};
+
+/// A target given to `--forward` by name instead of an address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Builtin {
+    /// Reads everything clients send, but never answers.
+    Blackhole,
+    /// Sends everything back to the client that sent it.
+    Echo,
+    /// Reads everything clients send as fast as possible and counts it as discarded.
+    Null,
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Builtin::Blackhole => "blackhole",
+            Builtin::Echo => "echo",
+            Builtin::Null => "null",
+        })
+    }
+}
+
+impl FromStr for Builtin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blackhole" => Ok(Builtin::Blackhole),
+            "echo" => Ok(Builtin::Echo),
+            "null" => Ok(Builtin::Null),
+            _ => Err(format!("unknown built-in target: {s}")),
+        }
+    }
+}
+
+/// Serves a TCP client until it closes the connection or its idle timeout fires.
+pub async fn serve_tcp(
+    builtin: Builtin,
+    stream: BoxStream,
+    peer_addr: SocketAddr,
+    idle: Option<Idle>,
+    config: &Config,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let bytes = Counter::default();
+    let (reader, mut writer) = io::split(stream);
+    let mut reader = CountingReader::new(ActivityReader::new(reader, idle.as_ref()), &bytes);
+
+    let serve = async {
+        match builtin {
+            Builtin::Blackhole => {
+                let mut buf = vec![0; 8192];
+                loop {
+                    let n = reader.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    tracing::debug!("Received {} bytes from {}", n, peer_addr);
+                }
+            }
+            Builtin::Echo => {
+                io::adaptive_copy(&mut reader, &mut writer).await?;
+                writer.close().await?;
+            }
+            Builtin::Null => {
+                let n = io::adaptive_copy(&mut reader, io::sink()).await?;
+                config.metrics.record_discarded(n);
+            }
+        }
+        tracing::info!("Client closed connection: {}", peer_addr);
+        Ok(())
+    };
+
+    let result = future::or(serve, timer_wheel::timeout(idle.as_ref())).await;
+    config
+        .metrics
+        .record_connection(start.elapsed(), bytes.get());
+    result
+}
+
+/// Serves a UDP datagram, returning the reply to send back, if any.
+pub fn serve_udp<'a>(
+    builtin: Builtin,
+    datagram: &'a [u8],
+    peer_addr: SocketAddr,
+    config: &Config,
+) -> Option<&'a [u8]> {
+    match builtin {
+        Builtin::Blackhole => {
+            tracing::debug!("Received {} bytes from {}", datagram.len(), peer_addr);
+            None
+        }
+        Builtin::Echo => Some(datagram),
+        Builtin::Null => {
+            config.metrics.record_discarded(datagram.len() as u64);
+            None
+        }
+    }
+}
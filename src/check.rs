@@ -0,0 +1,106 @@
+//! The `check` subcommand, which validates the options without forwarding anything.
+//!
+//! The options are parsed and validated the same way as for a run, which exits with an error on
+//! the first invalid one, and the files they name are opened. Then the listeners are bound and
+//! closed again, to find ports that are taken or addresses that aren't local, and the host names
+//! of proxies, Redis and the webhook are resolved. Each check prints a line, and portfwd exits with an
+//! error if any failed.
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    num::NonZeroU16,
+};
+
+use smol::io;
+
+use crate::{
+    cli::Cli, config::Config, netns, port_conflict, resolve, socket, transport::Transport,
+};
+
+/// A host name that portfwd connects to, and the option that gave it.
+pub struct Host {
+    option: &'static str,
+    host: String,
+    port: u16,
+}
+
+/// The host names given to the options.
+pub fn hosts(cli: &Cli) -> Vec<Host> {
+    let mut hosts = Vec::new();
+    if let Some(proxy) = &cli.socks5_proxy {
+        hosts.push(Host {
+            option: "--socks5-proxy",
+            host: proxy.host().to_string(),
+            port: proxy.port(),
+        });
+    }
+    if let Some(proxy) = &cli.http_proxy {
+        hosts.push(Host {
+            option: "--proxy",
+            host: proxy.host().to_string(),
+            port: proxy.port(),
+        });
+    }
+    if let Some(redis) = &cli.redis_limit_backend {
+        hosts.push(Host {
+            option: "--redis-limit-backend",
+            host: redis.host().to_string(),
+            port: redis.port(),
+        });
+    }
+    if let Some(webhook) = &cli.webhook {
+        hosts.push(Host {
+            option: "--webhook",
+            host: webhook.host().to_string(),
+            port: webhook.port(),
+        });
+    }
+    hosts
+}
+
+/// Runs the checks, returning whether all of them passed.
+pub async fn run(
+    config: &Config,
+    tcp: bool,
+    udp: bool,
+    metrics_port: Option<NonZeroU16>,
+    hosts: &[Host],
+) -> bool {
+    let mut passed = true;
+    let mut report = |what: String, result: io::Result<()>| match result {
+        Ok(()) => println!("ok: {what}"),
+        Err(err) => {
+            println!("error: {what}: {err}");
+            passed = false;
+        }
+    };
+
+    for &ip in &config.bind {
+        let addr = SocketAddr::new(ip, config.port);
+        if tcp {
+            let listener = config.transport.listen(addr).await;
+            let listener = listener.map_err(|err| port_conflict::explain("tcp", addr, err));
+            report(format!("listen on {addr}/tcp"), listener.map(drop));
+        }
+        if udp {
+            let socket =
+                netns::within(|| socket::udp_socket(addr, config.only_v6, config.outbound.ttl))
+                    .map_err(|err| port_conflict::explain("udp", addr, err));
+            report(format!("listen on {addr}/udp"), socket.map(drop));
+        }
+        if let Some(port) = metrics_port {
+            let addr = SocketAddr::new(ip, port.into());
+            let listener = TcpListener::bind(addr);
+            report(format!("serve metrics on {addr}"), listener.map(drop));
+        }
+    }
+
+    for host in hosts {
+        let resolved = resolve::resolve(&host.host, host.port).await;
+        report(
+            format!("resolve {}:{} of {}", host.host, host.port, host.option),
+            resolved.map(drop),
+        );
+    }
+    passed
+}
@@ -1,30 +1,612 @@
-use std::{net::SocketAddr, num::NonZeroU16};
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU16,
+    path::PathBuf,
+};
 
-use clap::{Args, Parser};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    acl::TimeWindow,
+    auth::Credentials,
+    backend::Forward,
+    detect::Route,
+    distributed_limit::RedisUrl,
+    io,
+    knock::Sequence,
+    nat64::Nat64Prefix,
+    protocols::http::HostRewrite,
+    routing::{Cidr, Fallback, Rule},
+    socket::{self, PortRange},
+    transport::{http_proxy::ProxyUrl, socks5_client::ProxyAddr, Side},
+    webhook::WebhookUrl,
+    wireguard::Key,
+};
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// The port to listen on, defaults to the same as the forward port.
     #[clap(short, long)]
     pub port: Option<NonZeroU16>,
 
-    /// The address and port to forward to.
-    #[clap(short, long)]
-    pub forward: SocketAddr,
+    /// The address to listen on, repeat to listen on several addresses.
+    #[clap(
+        long,
+        visible_alias = "bind",
+        value_name = "ADDR",
+        default_value = "127.0.0.1"
+    )]
+    pub bind_address: Vec<IpAddr>,
+
+    /// Listen on all IPv4 and all IPv6 addresses, with separate listeners for each.
+    #[clap(long, conflicts_with = "bind_address")]
+    pub dual_stack: bool,
+
+    /// Listen in the network namespace of this file, e.g. /proc/<PID>/ns/net or /run/netns/<NAME>, while connecting to the backends from the current one.
+    #[clap(long, value_name = "PATH")]
+    pub netns: Option<PathBuf>,
+
+    /// The address and port to forward to, repeat to balance clients by `<ADDR>@<WEIGHT>`, or blackhole, echo or null.
+    #[clap(
+        short,
+        long,
+        value_name = "FORWARD",
+        required_unless_present_any = ["socks", "http_connect", "rule", "nat64_prefix", "version_check"]
+    )]
+    pub forward: Vec<Forward>,
+
+    /// Send repeat clients to the same backend, saving the assignments to this file.
+    #[clap(long, value_name = "PATH")]
+    pub session_file: Option<PathBuf>,
+
+    /// Save the open TCP connections and the UDP sessions of `--udp-randomize-src-port` to this file every few seconds and on exit, reporting the connections and taking over the sessions after a restart.
+    #[clap(long, value_name = "PATH")]
+    pub state_file: Option<PathBuf>,
 
     #[command(flatten)]
     pub features: Features,
 
+    /// Detect the protocol of each client and forward it to the matching `--route`.
+    #[clap(long, visible_alias = "multiplex")]
+    pub auto_detect: bool,
+
+    /// Backend for a detected protocol (http, ssh, tls or dns), e.g. ssh=10.0.0.1:22.
+    #[clap(long, value_name = "PROTO=BACKEND", requires = "auto_detect")]
+    pub route: Vec<Route>,
+
+    /// Backend for detected TLS clients, the same as `--route tls=ADDR`.
+    #[clap(long, value_name = "ADDR", requires = "auto_detect")]
+    pub tls_backend: Option<SocketAddr>,
+
+    /// Backend for detected SSH clients, the same as `--route ssh=ADDR`.
+    #[clap(long, value_name = "ADDR", requires = "auto_detect")]
+    pub ssh_backend: Option<SocketAddr>,
+
+    /// Backend for detected HTTP clients, the same as `--route http=ADDR`.
+    #[clap(long, value_name = "ADDR", requires = "auto_detect")]
+    pub http_backend: Option<SocketAddr>,
+
+    /// Forward clients from a block of addresses to a destination, by priority, e.g. `10,10.0.0.0/8=10.0.0.1:80`.
+    #[clap(long, value_name = "[PRIORITY,]CIDR=ADDR", conflicts_with = "proxy")]
+    pub rule: Vec<Rule>,
+
+    /// Forward the connections of clients from this block of addresses ahead of the others, e.g. `10.0.0.0/8`.
+    #[clap(long, value_name = "CIDR")]
+    pub priority_cidr: Vec<Cidr>,
+
+    /// What to do with clients that match no `--rule`: forward, reject or drop.
+    #[clap(long, value_name = "ACTION", default_value_t = Fallback::Forward, requires = "rule")]
+    pub fallback: Fallback,
+
+    /// Route each client by the `route` function of a Lua script, reloaded on SIGHUP, in builds with the `lua` feature.
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["proxy", "rule"])]
+    pub lua_script: Option<PathBuf>,
+
+    /// Forward TCP clients that connect to an IPv6 address in this prefix to the IPv4 address embedded in it, e.g. `64:ff9b::/96`.
+    #[clap(long, value_name = "PREFIX")]
+    pub nat64_prefix: Option<Nat64Prefix>,
+
+    /// Only serve clients in this daily window of time in UTC, e.g. `08:00-18:00`, repeat for several windows.
+    #[clap(long, value_name = "HH:MM-HH:MM")]
+    pub allow_hours: Vec<TimeWindow>,
+
+    /// Only forward TCP clients that connected to these ports in order within 10 seconds before, e.g. `7000:8000:9000`.
+    #[clap(long, value_name = "P1:P2:...")]
+    pub port_knock: Option<Sequence>,
+
+    /// How long clients are forwarded after knocking with `--port-knock`.
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        requires = "port_knock"
+    )]
+    pub port_knock_allow: u64,
+
+    /// Accept at most this many TCP clients per second, leaving bursts in the kernel's backlog.
+    #[clap(long, value_name = "CONNS_PER_SEC", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_accept_rate: Option<u32>,
+
+    /// Close new TCP clients while this many TCP connections are open on their listener.
+    #[clap(long, value_name = "CONNS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_connections: Option<u32>,
+
+    /// Count the TCP connections of all portfwd instances in this Redis, given as redis://[:PASSWORD@]HOST[:PORT][/DB], turning clients away once --max-connections are open in all.
+    #[clap(long, value_name = "URL", requires = "max_connections")]
+    pub redis_limit_backend: Option<RedisUrl>,
+
+    /// The Redis key that --redis-limit-backend counts the connections in.
+    #[clap(
+        long,
+        value_name = "KEY",
+        default_value = "portfwd:connections",
+        requires = "redis_limit_backend"
+    )]
+    pub redis_limit_key: String,
+
+    /// Raise the soft limit on open file descriptors to the hard limit at startup.
+    #[clap(long)]
+    pub auto_raise_fd_limit: bool,
+
+    /// Forward the requests of HTTP/1.1 clients over persistent connections shared by all clients.
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "socks",
+            "http_connect",
+            "auto_detect",
+            "coalesce_ms",
+            "plugin",
+            "wasm_filter",
+            "http_log",
+            "hexdump",
+            "mqtt_aware",
+            "redis_aware",
+            "idle_timeout",
+            "per_conn_mem_limit",
+            "response_buffer",
+            "webhook",
+            "event_log",
+            "sqlite_stats",
+            "netflow",
+        ]
+    )]
+    pub http_keepalive: bool,
+
+    /// Keep the backend connection of a closed TCP client this long for the next client from its address.
+    #[clap(
+        long,
+        value_name = "MILLISECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = ["socks", "http_connect"]
+    )]
+    pub coalesce_ms: Option<u64>,
+
+    /// Open at most this many TCP connections to the backends per second, across all clients.
+    #[clap(long, value_name = "CONNS_PER_SEC", value_parser = clap::value_parser!(u32).range(1..))]
+    pub backend_rate_limit: Option<u32>,
+
+    /// How many clients may wait for `--backend-rate-limit` before new ones are refused.
+    #[clap(
+        long,
+        value_name = "CLIENTS",
+        default_value_t = 128,
+        requires = "backend_rate_limit"
+    )]
+    pub backend_queue_depth: usize,
+
+    /// Close TCP connections that queue more than this many bytes for a slow side, at least 262144.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(io::MAX_BUF as u64..)
+    )]
+    pub per_conn_mem_limit: Option<usize>,
+
+    /// Collect up to this many bytes of what a backend sends before passing it on, so small responses go out in one write.
+    #[clap(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    pub response_buffer: Option<u64>,
+
+    /// Close TCP connections that have not transferred any data for this many seconds.
+    #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub idle_timeout: Option<u64>,
+
+    /// How long to wait for TCP connections to close after the listeners are drained.
+    #[clap(long, value_name = "SECONDS", default_value_t = 30)]
+    pub drain_timeout: u64,
+
+    /// Drain the listeners on SIGTERM, exiting once the TCP connections closed or `--drain-timeout` passed, instead of exiting at once.
+    #[clap(long)]
+    pub drain_on_sigterm: bool,
+
+    /// Set SO_LINGER on TCP sockets; 0 resets connections on close instead of closing them.
+    #[clap(long, value_name = "SECONDS")]
+    pub linger: Option<u64>,
+
+    /// How many TCP clients may wait to be accepted by each listener, capped by net.core.somaxconn on Linux.
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = socket::BACKLOG,
+        value_parser = clap::value_parser!(u32).range(1..=i32::MAX as i64)
+    )]
+    pub backlog: u32,
+
+    /// Raise net.core.somaxconn and net.ipv4.tcp_max_syn_backlog to the --backlog if below, restoring them on exit, which needs CAP_NET_ADMIN.
+    #[clap(long)]
+    pub auto_sysctl: bool,
+
+    /// Bind TCP listeners without SO_REUSEADDR, so portfwd can't start while connections to the port linger in TIME_WAIT (SO_REUSEPORT is never set).
+    #[clap(long)]
+    pub no_reuse_addr: bool,
+
+    /// Assign the new TCP clients that arrive on this interface for a listening port to its listener with an eBPF program, whichever local address they are for, in builds with the `ebpf` feature.
+    #[clap(long, value_name = "IFACE")]
+    pub ebpf_steer: Option<String>,
+
+    /// Connect to the backends from this local address, e.g. the address of one interface.
+    #[clap(long, value_name = "IP")]
+    pub source_addr: Option<IpAddr>,
+
+    /// Connect to the backends from a free port in this range, e.g. `40000-40999`, if there is one.
+    #[clap(long, value_name = "START-END")]
+    pub source_port_range: Option<PortRange>,
+
+    /// Send outgoing TCP and UDP packets with this IP time-to-live, or IPv6 hop limit.
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=255))]
+    pub ttl: Option<u32>,
+
+    /// Forward each UDP datagram from a new socket on a random port, relaying the replies to it.
+    #[clap(long)]
+    pub udp_randomize_src_port: bool,
+
+    /// Send a keepalive to each UDP backend that no datagram went to for this many seconds, keeping NAT mappings alive.
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "udp_randomize_src_port"
+    )]
+    pub udp_keepalive_interval: Option<u64>,
+
+    /// What the keepalives of `--udp-keepalive-interval` hold, empty by default.
+    #[clap(
+        long,
+        value_name = "TEXT",
+        default_value = "",
+        requires = "udp_keepalive_interval"
+    )]
+    pub udp_keepalive_payload: String,
+
+    /// Send ICMP errors to UDP clients whose datagrams can't reach the backend, which needs raw sockets.
+    #[clap(long, requires = "udp_randomize_src_port")]
+    pub icmp_errors: bool,
+
+    /// Drop UDP datagrams from clients larger than this many bytes, e.g. 512 for plain DNS.
+    #[clap(long, value_name = "BYTES")]
+    pub udp_max_size: Option<usize>,
+
+    /// Drop UDP replies relayed with `--udp-randomize-src-port` larger than this many bytes.
+    #[clap(long, value_name = "BYTES", requires = "udp_randomize_src_port")]
+    pub udp_max_response_size: Option<usize>,
+
+    /// Remove the EDNS Client Subnet option from forwarded DNS queries, and from the replies relayed with `--udp-randomize-src-port`.
+    #[clap(long)]
+    pub dns_strip_ecs: bool,
+
+    /// Validate the DNS replies relayed with `--udp-randomize-src-port` with DNSSEC, replacing those that fail with SERVFAIL, in builds with the `dnssec` feature.
+    #[clap(long, requires = "udp_randomize_src_port")]
+    pub dns_validate: bool,
+
+    /// Tag UDP datagrams forwarded to IPv6 backends with this flow label, from 1 to 1048575.
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=0xfffff))]
+    pub ipv6_flow_label: Option<u32>,
+
+    /// Forward the next UDP datagrams of each IPv4 client that arrive on this interface with an XDP program once one went through portfwd, in builds with the `xdp` feature.
+    #[clap(
+        long,
+        value_name = "IFACE",
+        conflicts_with_all = [
+            "netns", "auto_detect", "lua_script", "allow_hours", "source_addr", "source_port_range",
+            "ttl", "udp_randomize_src_port", "udp_keepalive_interval", "udp_max_size",
+            "dns_strip_ecs", "pad_to", "hexdump",
+        ]
+    )]
+    pub xdp: Option<String>,
+
+    /// Reconnect to the backend when it resets a TCP connection, replaying everything sent to it if that fits in the --reconnect-buffer, or with `--http-keepalive`, resend requests whose response it drops midway.
+    #[clap(long)]
+    pub reconnect_on_error: bool,
+
+    /// How many bytes sent to the backend can be replayed with `--reconnect-on-error`, or how large resent HTTP requests may be.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value_t = 65536,
+        requires = "reconnect_on_error"
+    )]
+    pub reconnect_buffer: usize,
+
+    /// Connect to the backends over TLS, verifying their certificates for the backend IP.
+    #[clap(long, group = "tls_mode")]
+    pub tls: bool,
+
+    /// Connect over TLS only to the backends on the --tls-ports.
+    #[clap(long, group = "tls_mode")]
+    pub auto_tls: bool,
+
+    /// Backend ports that --auto-tls connects to over TLS.
+    #[clap(
+        long,
+        value_name = "PORTS",
+        value_delimiter = ',',
+        default_value = "443",
+        requires = "auto_tls"
+    )]
+    pub tls_ports: Vec<u16>,
+
+    /// Verify backend certificates for this name and send it as SNI, instead of the backend IP.
+    #[clap(long, value_name = "NAME", requires = "tls_mode")]
+    pub tls_sni: Option<String>,
+
+    /// Connect to the backends through this SOCKS5 proxy.
+    #[clap(long, value_name = "HOST:PORT")]
+    pub socks5_proxy: Option<ProxyAddr>,
+
+    /// Log in to the SOCKS5 proxy with this username.
+    #[clap(long, value_name = "USER", requires_all = ["socks5_proxy", "socks5_pass"])]
+    pub socks5_user: Option<String>,
+
+    /// Log in to the SOCKS5 proxy with this password.
+    #[clap(long, value_name = "PASS", requires = "socks5_user")]
+    pub socks5_pass: Option<String>,
+
+    /// Connect to the backends in the --wg-allowed-ips through a WireGuard tunnel with this private key in base64, in builds with the `wireguard` feature.
+    #[clap(
+        long,
+        value_name = "KEY",
+        requires_all = ["wg_peer_pubkey", "wg_peer_endpoint", "wg_allowed_ips", "wg_address"],
+        conflicts_with_all = ["udp_randomize_src_port", "udp_keepalive_interval", "xdp"]
+    )]
+    pub wg_private_key: Option<Key>,
+
+    /// The public key of the WireGuard peer, in base64.
+    #[clap(long, value_name = "KEY", requires = "wg_private_key")]
+    pub wg_peer_pubkey: Option<Key>,
+
+    /// The address and port that the WireGuard peer receives the tunnel on.
+    #[clap(long, value_name = "ADDR", requires = "wg_private_key")]
+    pub wg_peer_endpoint: Option<SocketAddr>,
+
+    /// The blocks of addresses behind the WireGuard peer, e.g. `10.8.0.0/24`, repeat or separate them with commas for several.
+    #[clap(
+        long,
+        value_name = "CIDR",
+        value_delimiter = ',',
+        requires = "wg_private_key"
+    )]
+    pub wg_allowed_ips: Vec<Cidr>,
+
+    /// The address of portfwd in the WireGuard tunnel, which the connections through it come from.
+    #[clap(long, value_name = "IP", requires = "wg_private_key")]
+    pub wg_address: Option<IpAddr>,
+
+    /// Obfuscate the connections on the --obfuscate-side with this passphrase, shared with the portfwd on the other end.
+    #[clap(long, value_name = "KEY")]
+    pub obfuscate: Option<String>,
+
+    /// Which connections --obfuscate applies to: those to the backends, or those from the clients.
+    #[clap(long, value_name = "SIDE", default_value_t = Side::Backend, requires = "obfuscate")]
+    pub obfuscate_side: Side,
+
+    /// Pad the traffic on the --pad-side to frames or datagrams of exactly this size, stripped by the portfwd on the other end.
+    #[clap(long, value_name = "BYTES", value_parser = clap::value_parser!(u16).range(3..))]
+    pub pad_to: Option<u16>,
+
+    /// Which traffic --pad-to applies to: that to the backends, or that from the clients.
+    #[clap(long, value_name = "SIDE", default_value_t = Side::Backend, requires = "pad_to")]
+    pub pad_side: Side,
+
+    /// Connect to the backends through this HTTP CONNECT proxy, given as http://[USER:PASS@]HOST[:PORT] or https://.
+    #[clap(long = "proxy", value_name = "URL")]
+    pub http_proxy: Option<ProxyUrl>,
+
+    /// Resolve host names on this DNS server, repeat to query several at once, falling back to the system resolver.
+    #[clap(long, value_name = "IP")]
+    pub dns_server: Vec<IpAddr>,
+
+    /// Act as a SOCKS4a/SOCKS5 proxy that forwards each client where it asks to, on port 1080 by default.
+    #[clap(long, group = "proxy", conflicts_with_all = ["udp", "auto_detect"])]
+    pub socks: bool,
+
+    /// Act as an HTTP CONNECT proxy that forwards each client where it asks to, on port 8080 by default.
+    #[clap(long, group = "proxy", conflicts_with_all = ["udp", "auto_detect"])]
+    pub http_connect: bool,
+
+    /// Require proxy clients to log in with this user and bcrypt hash from `portfwd hash-password`.
+    #[clap(long, value_name = "USER:HASH", requires = "proxy")]
+    pub auth: Option<Credentials>,
+
+    /// Dump the first bytes of each TCP connection and UDP datagram in hex at trace level (-vv).
+    #[clap(long)]
+    pub hexdump: bool,
+
+    /// How many bytes to dump with `--hexdump`.
+    #[clap(long, value_name = "N", default_value_t = 256, requires = "hexdump")]
+    pub hexdump_bytes: usize,
+
+    /// Log the method, path and status of HTTP/1.x requests.
+    #[clap(long)]
+    pub http_log: bool,
+
+    /// Rewrite the `Host` header of the first HTTP/1.x request of each connection, or of all with `--http-keepalive`, e.g. `example.com=backend.internal`.
+    #[clap(long, value_name = "ORIGINAL=REPLACEMENT")]
+    pub rewrite_host: Vec<HostRewrite>,
+
+    /// Add `X-Forwarded-For` and `X-Real-IP` headers with the client address to HTTP/1.x requests like `--rewrite-host`.
+    #[clap(long)]
+    pub inject_xff: bool,
+
+    /// Add an `X-Portfwd-Request-Id` header with a new UUID to HTTP/1.x requests like `--rewrite-host`, and log it with the connection id.
+    #[clap(long)]
+    pub inject_request_id: bool,
+
+    /// Log the client ids and topics of MQTT connections.
+    #[clap(long)]
+    pub mqtt_aware: bool,
+
+    /// Log the names of Redis commands at debug level.
+    #[clap(long)]
+    pub redis_aware: bool,
+
+    /// Only inspect this fraction of TCP connections and UDP datagrams with --hexdump, --http-log, --mqtt-aware and --redis-aware, from 0.0 to 1.0.
+    #[clap(long, value_name = "RATE", value_parser = parse_rate)]
+    pub sample_rate: Option<f64>,
+
+    /// Serve Prometheus metrics at `/metrics` on this port of the bind addresses.
+    #[clap(long, value_name = "PORT")]
+    pub metrics_port: Option<NonZeroU16>,
+
+    /// Log the TCP connections and bytes since the start and in the last interval every this many seconds.
+    #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub stats_interval: Option<u64>,
+
+    /// Answer Kubernetes liveness probes on this port of the bind addresses, which succeed while portfwd runs.
+    #[clap(long, value_name = "PORT")]
+    pub liveness_port: Option<NonZeroU16>,
+
+    /// Answer Kubernetes readiness probes on this port of the bind addresses, which fail while draining, while all backends are drained, or for a while after a backend connection failed.
+    #[clap(long, value_name = "PORT")]
+    pub readiness_port: Option<NonZeroU16>,
+
+    /// How long a failed backend connection fails the readiness probes for.
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        requires = "readiness_port"
+    )]
+    pub readiness_window: u64,
+
+    /// Accept JSON control commands on this Unix socket.
+    #[clap(long, value_name = "PATH")]
+    pub control_socket: Option<PathBuf>,
+
+    /// Post a JSON event to this http:// or https:// URL when a TCP connection opens, closes or fails.
+    #[clap(long, value_name = "URL")]
+    pub webhook: Option<WebhookUrl>,
+
+    /// Append a JSON line to this file when a TCP connection opens, closes or fails.
+    #[clap(long, value_name = "FILE")]
+    pub event_log: Option<PathBuf>,
+
+    /// Insert a row into the connections table of this SQLite database when a TCP connection closes, creating it if needed, in builds with the `sqlite` feature.
+    #[clap(long, value_name = "PATH")]
+    pub sqlite_stats: Option<PathBuf>,
+
+    /// Export a NetFlow v9 record of each TCP connection to this UDP collector when it closes.
+    #[clap(long, value_name = "COLLECTOR:PORT")]
+    pub netflow: Option<SocketAddr>,
+
+    /// Load a shared library plugin with hooks that see and may transform the data of each TCP connection.
+    #[clap(long, value_name = "PATH")]
+    pub plugin: Option<PathBuf>,
+
+    /// Pass the data of each TCP connection through a WebAssembly module that may transform it, in builds with the `wasm` feature.
+    #[clap(long, value_name = "PATH")]
+    pub wasm_filter: Option<PathBuf>,
+
     /// Number of threads to use, defaults to the number of logical CPUs.
     #[clap(short = 'T', long)]
     pub threads: Option<usize>,
 
+    /// Pin the executor threads to these CPUs, e.g. 0,1,2,3, cycling through them if there are fewer.
+    #[clap(long, value_name = "LIST", value_delimiter = ',')]
+    pub cpu_affinity: Vec<usize>,
+
+    /// Pin the executor threads to the CPUs of the NUMA node of the network interface of the bind address.
+    #[clap(long, conflicts_with = "cpu_affinity")]
+    pub numa_aware: bool,
+
+    /// Run the executor threads with the SCHED_FIFO real-time policy at this priority, needs CAP_SYS_NICE.
+    #[clap(long, value_name = "1-99", value_parser = clap::value_parser!(u8).range(1..=99))]
+    pub rt_priority: Option<u8>,
+
+    /// Name the executor threads `<PREFIX>-0`, `<PREFIX>-1`, etc. for profilers.
+    #[clap(long, value_name = "PREFIX", default_value = "portfwd-executor")]
+    pub thread_name_prefix: String,
+
+    /// Print which optional kernel features are supported, and exit.
+    #[clap(long)]
+    pub version_check: bool,
+
     /// Verbose output (-v, -vv, etc.)
     #[clap(short, action = clap::ArgAction::Count)]
     pub verbose: u8,
 }
 
+#[derive(Subcommand)]
+pub enum Command {
+    /// Print the bcrypt hash of a password for `--auth`.
+    HashPassword {
+        /// The password to hash, read from standard input if not given.
+        password: Option<String>,
+    },
+    /// Measure the throughput and latency of forwarding TCP connections to an internal echo server.
+    Bench(BenchArgs),
+    /// Validate the options, bind the listeners and resolve host names without forwarding, exiting with an error if any check fails.
+    Check,
+    /// Run portfwd with the options before `watch`, restarting it whenever its binary or another file changes.
+    Watch {
+        /// The file to watch instead of the portfwd binary.
+        #[clap(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// How long to run the benchmark for.
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub duration: u64,
+
+    /// Number of client connections open at once.
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub concurrency: u64,
+
+    /// Bytes of each request, which the echo server sends back.
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value_t = 1024,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub size: u64,
+
+    /// Number of requests on each connection before it is closed and another is opened.
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 100,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub requests: u64,
+}
+
 #[derive(Args)]
 #[group(required = false, multiple = false)]
 pub struct Features {
@@ -36,3 +618,12 @@ pub struct Features {
     #[clap(short, long)]
     pub udp: bool,
 }
+
+/// Parses a fraction from 0.0 to 1.0.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate = s.parse::<f64>().map_err(|err| err.to_string())?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(format!("expected a rate from 0.0 to 1.0, got: {s}"));
+    }
+    Ok(rate)
+}
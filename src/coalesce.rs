@@ -0,0 +1,96 @@
+//! Reuse of backend connections by short-lived clients from the same address, for
+//! `--coalesce-ms`.
+//!
+//! When a client closes its connection while the backend keeps its side open, the backend
+//! connection is parked for a short window instead of being closed. The next client from the
+//! same address within that window is forwarded over it, instead of over a new connection.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use smol::{io::AsyncWriteExt, Timer};
+
+use crate::{task::spawn_named, transport::BoxStream};
+
+/// Backend connections left open by the last client of each address.
+pub struct Coalescer {
+    window: Duration,
+    parked: Mutex<HashMap<IpAddr, (Parked, Instant)>>,
+}
+
+/// A backend connection that no client uses.
+struct Parked {
+    backend: SocketAddr,
+    stream: BoxStream,
+}
+
+impl Coalescer {
+    /// Creates a coalescer that keeps backend connections for `window` after their client closed.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            parked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long backend connections are kept, which is also how long they must be quiet before
+    /// their client is let go.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Keeps a backend connection for the next client from an address, closing it once the
+    /// window passes without one.
+    pub fn park(self: &Arc<Self>, ip: IpAddr, backend: SocketAddr, stream: BoxStream) {
+        let parked_at = Instant::now();
+        let replaced = self
+            .parked
+            .lock()
+            .unwrap()
+            .insert(ip, (Parked { backend, stream }, parked_at));
+        if let Some((replaced, _)) = replaced {
+            tracing::debug!(
+                "Closing older backend connection of {} to {}",
+                ip,
+                replaced.backend
+            );
+        }
+
+        let coalesce = self.clone();
+        spawn_named(format!("coalesce-{ip}"), async move {
+            Timer::after(coalesce.window).await;
+            let expired = {
+                let mut parked = coalesce.parked.lock().unwrap();
+                match parked.get(&ip) {
+                    Some(&(_, at)) if at == parked_at => parked.remove(&ip),
+                    _ => None,
+                }
+            };
+            if let Some((mut parked, _)) = expired {
+                tracing::debug!("Closing unused backend connection to {}", parked.backend);
+                let _ = parked.stream.close().await;
+            }
+        })
+        .detach();
+    }
+
+    /// Takes the backend connection parked for an address, with the address of its backend.
+    pub fn take(&self, ip: IpAddr) -> Option<(SocketAddr, BoxStream)> {
+        let (parked, at) = self.parked.lock().unwrap().remove(&ip)?;
+        (at.elapsed() < self.window).then_some((parked.backend, parked.stream))
+    }
+}
+
+impl fmt::Debug for Coalescer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Coalescer")
+            .field("window", &self.window)
+            .field("parked", &self.parked.lock().unwrap().len())
+            .finish()
+    }
+}
@@ -0,0 +1,174 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use crate::{
+    acl::Schedule,
+    auth::Credentials,
+    backend::Backends,
+    builtin::Builtin,
+    coalesce::Coalescer,
+    conn_table::{ConnTable, UdpSessions},
+    detect::Route,
+    distributed_limit::DistributedLimit,
+    dnssec::Validator,
+    drain::Drain,
+    event_log::EventLog,
+    icmp::IcmpSender,
+    keepalive::HttpPool,
+    knock::Knocker,
+    lua::LuaScript,
+    metrics::Metrics,
+    nat64::Nat64Prefix,
+    netflow::Exporter,
+    padding::Padding,
+    plugin::Plugin,
+    pool::BufferPool,
+    probes::Readiness,
+    protocols::http::HostRewrite,
+    proxy,
+    rate_limit::SharedRateLimit,
+    routing::{Cidr, Routing},
+    socket::Outbound,
+    sqlite_stats::SqliteStats,
+    transport::ChainedTransport,
+    wasm::WasmFilter,
+    webhook::Webhook,
+    wireguard::Tunnel,
+    xdp::Offload,
+};
+
+/// Settings shared by the TCP and UDP servers, resolved from the command line.
+#[derive(Debug)]
+pub struct Config {
+    /// The addresses to listen on.
+    pub bind: Vec<IpAddr>,
+    /// Whether IPv6 listeners only accept IPv6 clients.
+    pub only_v6: bool,
+    /// The port to listen on.
+    pub port: u16,
+    /// Options of the sockets that UDP datagrams are forwarded from.
+    pub outbound: Outbound,
+    /// Flow label of UDP datagrams forwarded to IPv6 backends.
+    pub flow_label: Option<u32>,
+    /// The XDP program that forwards the datagrams of known UDP clients, if it is attached.
+    pub offload: Option<Arc<Offload>>,
+    /// The WireGuard tunnel to the backends behind its peer, if one is given.
+    pub tunnel: Option<Arc<Tunnel>>,
+    /// Whether UDP datagrams are forwarded from a socket of their own, on a random port.
+    pub udp_randomize_src_port: bool,
+    /// Keepalives sent to idle UDP backends, if enabled.
+    pub udp_keepalive: Option<UdpKeepalive>,
+    /// Raw sockets to tell UDP clients about unreachable backends, if enabled.
+    pub icmp: Option<IcmpSender>,
+    /// Largest UDP datagram forwarded from a client, in bytes.
+    pub udp_max_size: Option<usize>,
+    /// Largest UDP reply relayed from a backend, in bytes.
+    pub udp_max_response_size: Option<usize>,
+    /// Whether the EDNS Client Subnet option is removed from DNS datagrams.
+    pub dns_strip_ecs: bool,
+    /// The validator of DNS replies, if they are validated with DNSSEC.
+    pub dns_validator: Option<Validator>,
+    /// The backends to forward to.
+    pub backends: Backends,
+    /// Protocol-specific backends, if protocol detection is enabled.
+    pub routes: Option<Vec<Route>>,
+    /// Rules that route clients by their address, if any were given.
+    pub routing: Option<Routing>,
+    /// The script that routes clients, if one was given.
+    pub lua_script: Option<Arc<LuaScript>>,
+    /// The IPv6 prefix that clients connect to the IPv4 addresses embedded in, if enabled.
+    pub nat64: Option<Nat64Prefix>,
+    /// The ports that TCP clients must knock on before they are forwarded, if enabled.
+    pub knocker: Option<Arc<Knocker>>,
+    /// Clients whose connections are forwarded ahead of the others.
+    pub priority_cidrs: Vec<Cidr>,
+    /// The hours of the day in which clients are served, if limited.
+    pub schedule: Option<Schedule>,
+    /// Where connection events are posted, if anywhere.
+    pub webhook: Option<Arc<Webhook>>,
+    /// Where connection events are logged, if anywhere.
+    pub event_log: Option<Arc<EventLog>>,
+    /// Where closed connections are recorded, if anywhere.
+    pub sqlite_stats: Option<Arc<SqliteStats>>,
+    /// Where flow records are exported, if anywhere.
+    pub netflow: Option<Arc<Exporter>>,
+    /// The failures that fail the readiness probes, if they are answered.
+    pub readiness: Option<Readiness>,
+    /// The hooks that TCP connections go through, if any.
+    pub plugin: Option<Plugin>,
+    /// The WebAssembly filter that TCP connections go through, if any.
+    pub wasm_filter: Option<WasmFilter>,
+    /// The size that the traffic on one side is padded to, if it is.
+    pub padding: Option<Padding>,
+    /// Maximum number of TCP clients accepted per second by each listener.
+    pub max_accept_rate: Option<u32>,
+    /// Maximum number of TCP connections open at once on each listener.
+    pub max_connections: Option<u32>,
+    /// The count of the TCP connections of all instances, if it is limited.
+    pub distributed_limit: Option<DistributedLimit>,
+    /// Limit of the connections opened to the backends per second, shared by all clients.
+    pub backend_rate_limit: Option<SharedRateLimit>,
+    /// Maximum number of bytes buffered for a single TCP connection.
+    pub per_conn_mem_limit: Option<usize>,
+    /// How many bytes of a backend response are collected before they are passed on, if any.
+    pub response_buffer: Option<usize>,
+    /// How long TCP connections may go without transferring data.
+    pub idle_timeout: Option<Duration>,
+    /// How long to wait for TCP connections to close once the listeners are drained.
+    pub drain_timeout: Duration,
+    /// Whether the listeners stopped accepting new clients.
+    pub drain: Drain,
+    /// How many bytes sent to a backend can be replayed when it resets a connection, and
+    /// the largest HTTP request kept to resend, if reconnecting is enabled.
+    pub reconnect_buffer: Option<usize>,
+    /// Backend connections kept for the next client from the same address, if coalescing is
+    /// enabled.
+    pub coalesce: Option<Arc<Coalescer>>,
+    /// Idle connections to HTTP backends, if requests are forwarded over persistent connections.
+    pub http_pool: Option<HttpPool>,
+    /// The transport that TCP clients are accepted and forwarded with.
+    pub transport: ChainedTransport,
+    /// The built-in target that serves clients instead of the backends.
+    pub builtin: Option<Builtin>,
+    /// The kind of proxy to act as, if TCP clients choose their own destinations.
+    pub proxy: Option<proxy::Mode>,
+    /// Credentials that proxy clients must log in with.
+    pub auth: Option<Credentials>,
+    /// Measurements of the forwarded connections.
+    pub metrics: Metrics,
+    /// The open TCP connections.
+    pub connections: ConnTable,
+    /// The UDP sessions that relay replies on sockets of their own.
+    pub udp_sessions: UdpSessions,
+    /// Buffers for received UDP datagrams.
+    pub buffers: BufferPool,
+    /// How many bytes to dump at the start of each TCP direction and UDP datagram, if any.
+    pub hexdump: Option<usize>,
+    /// Whether to log HTTP/1.x requests and responses.
+    pub http_log: bool,
+    /// Replacements of the `Host` header of HTTP/1.x requests.
+    pub host_rewrites: Vec<HostRewrite>,
+    /// Whether HTTP/1.x requests get `X-Forwarded-For` and `X-Real-IP` headers with the client.
+    pub inject_xff: bool,
+    /// Whether HTTP/1.x requests get an `X-Portfwd-Request-Id` header with a new request id.
+    pub inject_request_id: bool,
+    /// Whether to log MQTT client ids and topics.
+    pub mqtt_aware: bool,
+    /// Whether to log the names of Redis commands.
+    pub redis_aware: bool,
+    /// The fraction of connections and datagrams that are inspected, if not all of them.
+    pub sample_rate: Option<f64>,
+}
+
+impl Config {
+    /// Draws whether a connection or datagram is inspected.
+    pub fn sample(&self) -> bool {
+        self.sample_rate.is_none_or(|rate| fastrand::f64() < rate)
+    }
+}
+
+/// Datagrams sent to UDP backends that no datagram went to for a while.
+#[derive(Clone, Debug)]
+pub struct UdpKeepalive {
+    pub interval: Duration,
+    pub payload: Vec<u8>,
+}
@@ -0,0 +1,392 @@
+//! The table of open TCP connections, sharded so that connections opened on different executor
+//! threads rarely contend for the same lock, and of the UDP sessions that relay replies.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use smol::{channel::Sender, Timer};
+
+use crate::{metrics::Counter, timer_wheel::Activity};
+
+/// How often the state file is written.
+const STATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An open connection.
+#[derive(Debug)]
+pub struct ConnInfo {
+    pub peer_addr: SocketAddr,
+    pub backend: SocketAddr,
+    pub started: Instant,
+    /// Bytes transferred in both directions so far.
+    pub bytes: Arc<Counter>,
+    pub last_active: Arc<LastActive>,
+    /// Closed to kill the connection.
+    pub kill: Sender<()>,
+}
+
+/// When a connection last transferred data.
+#[derive(Debug)]
+pub struct LastActive {
+    started: Instant,
+    /// Milliseconds from the start of the connection to its last activity.
+    millis: AtomicU64,
+}
+
+impl LastActive {
+    pub fn new(started: Instant) -> Self {
+        Self {
+            started,
+            millis: AtomicU64::new(0),
+        }
+    }
+
+    /// How long the connection has gone without transferring data.
+    pub fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.millis.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+}
+
+impl Activity for LastActive {
+    fn touch(&self) {
+        let millis = self.started.elapsed().as_millis() as u64;
+        self.millis.store(millis, Ordering::Relaxed);
+    }
+}
+
+/// Totals over all open connections.
+#[derive(Debug, Default)]
+pub struct TableStats {
+    pub active: u64,
+    pub bytes: u64,
+}
+
+/// Open connections, in shards picked by connection id.
+#[derive(Debug)]
+pub struct ConnTable {
+    next_id: AtomicU64,
+    shards: Vec<Mutex<HashMap<u64, ConnInfo>>>,
+}
+
+impl ConnTable {
+    /// Creates a table with a shard for each executor thread.
+    pub fn new(shards: usize) -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            shards: (0..shards.max(1)).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn shard(&self, id: u64) -> &Mutex<HashMap<u64, ConnInfo>> {
+        &self.shards[(id % self.shards.len() as u64) as usize]
+    }
+
+    /// Adds a connection, which stays in the table until the returned entry is dropped.
+    pub fn insert(&self, info: ConnInfo) -> ConnEntry<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.shard(id).lock().unwrap().insert(id, info);
+        ConnEntry { table: self, id }
+    }
+
+    /// Visits every open connection with its id, locking one shard at a time.
+    pub fn for_each(&self, mut f: impl FnMut(u64, &ConnInfo)) {
+        for shard in &self.shards {
+            for (&id, info) in shard.lock().unwrap().iter() {
+                f(id, info);
+            }
+        }
+    }
+
+    /// Kills a connection, returning `false` if there is no open connection with the id.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.shard(id).lock().unwrap().get(&id) {
+            Some(info) => info.kill.close(),
+            None => false,
+        }
+    }
+
+    /// Kills all open connections, returning how many there were.
+    pub fn kill_all(&self) -> u64 {
+        let mut killed = 0;
+        self.for_each(|_, info| {
+            if info.kill.close() {
+                killed += 1;
+            }
+        });
+        killed
+    }
+
+    /// The number of open connections to a backend.
+    pub fn open_to(&self, backend: SocketAddr) -> u64 {
+        let mut open = 0;
+        self.for_each(|_, info| {
+            if info.backend == backend {
+                open += 1;
+            }
+        });
+        open
+    }
+
+    /// Kills all open connections to a backend, returning how many there were.
+    pub fn kill_backend(&self, backend: SocketAddr) -> u64 {
+        let mut killed = 0;
+        self.for_each(|_, info| {
+            if info.backend == backend && info.kill.close() {
+                killed += 1;
+            }
+        });
+        killed
+    }
+
+    /// The number of connections added so far, open or closed.
+    pub fn added(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed)
+    }
+
+    /// Sums up the open connections.
+    pub fn stats(&self) -> TableStats {
+        let mut stats = TableStats::default();
+        self.for_each(|_, info| {
+            stats.active += 1;
+            stats.bytes += info.bytes.get();
+        });
+        stats
+    }
+
+    /// Describes the open connections as JSON, with start times in seconds since the epoch.
+    fn snapshot(&self, now: SystemTime) -> Value {
+        let mut conns = Vec::new();
+        self.for_each(|id, info| {
+            let started = (now - info.started.elapsed())
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |started| started.as_secs());
+            conns.push(json!({
+                "id": id,
+                "peer_addr": info.peer_addr.to_string(),
+                "backend": info.backend.to_string(),
+                "started": started,
+                "bytes": info.bytes.get(),
+            }));
+        });
+        Value::Array(conns)
+    }
+}
+
+/// A UDP client whose replies come to a socket of its own, as with `--udp-randomize-src-port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpSession {
+    pub client: SocketAddr,
+    /// The address of the listener that the client sent to.
+    pub listener: SocketAddr,
+    pub backend: SocketAddr,
+    /// The local address of the socket that the replies come to.
+    pub relay: SocketAddr,
+}
+
+impl UdpSession {
+    fn to_json(&self) -> Value {
+        json!({
+            "client": self.client.to_string(),
+            "listener": self.listener.to_string(),
+            "backend": self.backend.to_string(),
+            "relay": self.relay.to_string(),
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let addr = |key| value.get(key)?.as_str()?.parse().ok();
+        Some(Self {
+            client: addr("client")?,
+            listener: addr("listener")?,
+            backend: addr("backend")?,
+            relay: addr("relay")?,
+        })
+    }
+}
+
+/// The UDP sessions that are relaying replies.
+#[derive(Debug, Default)]
+pub struct UdpSessions {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, UdpSession>>,
+}
+
+impl UdpSessions {
+    /// Adds a session, which stays in the table until the returned entry is dropped.
+    pub fn insert(&self, session: UdpSession) -> UdpEntry<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sessions.lock().unwrap().insert(id, session);
+        UdpEntry { sessions: self, id }
+    }
+
+    fn snapshot(&self) -> Value {
+        let sessions = self.sessions.lock().unwrap();
+        Value::Array(sessions.values().map(UdpSession::to_json).collect())
+    }
+}
+
+/// A UDP session in the table, removed from it when dropped.
+pub struct UdpEntry<'a> {
+    sessions: &'a UdpSessions,
+    id: u64,
+}
+
+impl Drop for UdpEntry<'_> {
+    fn drop(&mut self) {
+        self.sessions.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// What a previous run saved to its state file.
+#[derive(Debug, Default)]
+pub struct State {
+    /// The TCP connections that were open, which can only be reported.
+    pub tcp: Vec<Value>,
+    /// The UDP sessions that were relaying replies, if they were saved recently enough to take
+    /// over.
+    pub udp: Vec<UdpSession>,
+}
+
+/// Describes the open connections and UDP sessions as JSON, with the time they were saved in
+/// milliseconds since the epoch.
+fn snapshot(connections: &ConnTable, udp: &UdpSessions) -> Value {
+    let now = SystemTime::now();
+    let saved = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |saved| saved.as_millis());
+    json!({
+        "saved": saved as u64,
+        "tcp": connections.snapshot(now),
+        "udp": udp.snapshot(),
+    })
+}
+
+/// Writes the open connections and UDP sessions to a state file every few seconds, the same way
+/// as the session file.
+pub async fn persist(connections: &ConnTable, udp: &UdpSessions, path: &Path) -> io::Result<()> {
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+
+    loop {
+        Timer::after(STATE_INTERVAL).await;
+        let json = serde_json::to_vec(&snapshot(connections, udp))?;
+        smol::fs::write(&tmp, json).await?;
+        smol::fs::rename(&tmp, path).await?;
+        tracing::trace!("Saved connections to {}", path.display());
+    }
+}
+
+/// Writes the open connections and UDP sessions to a state file once, when the process exits.
+pub fn save(connections: &ConnTable, udp: &UdpSessions, path: &Path) -> io::Result<()> {
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    std::fs::write(&tmp, serde_json::to_vec(&snapshot(connections, udp))?)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Reads what a previous run saved to a state file, if it exists.
+///
+/// TCP connections can't be taken over by a new process, so these are only reported. UDP
+/// sessions are left out if they were saved more than `udp_lifetime` ago, since they would have
+/// ended by now.
+pub fn load_state(path: &Path, udp_lifetime: Duration) -> io::Result<State> {
+    let json = match std::fs::read(path) {
+        Ok(json) => json,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(State::default()),
+        Err(err) => return Err(err),
+    };
+    let value: Value = serde_json::from_slice(&json)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let tcp = match value.get("tcp") {
+        Some(Value::Array(tcp)) => tcp.clone(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the state has no TCP connections",
+            ))
+        }
+    };
+    let saved = value.get("saved").and_then(Value::as_u64).unwrap_or(0);
+    let saved = UNIX_EPOCH + Duration::from_millis(saved);
+    let recent = SystemTime::now()
+        .duration_since(saved)
+        .is_ok_and(|age| age < udp_lifetime);
+    let udp = match value.get("udp") {
+        Some(Value::Array(udp)) if recent => udp.iter().filter_map(UdpSession::from_json).collect(),
+        _ => Vec::new(),
+    };
+    Ok(State { tcp, udp })
+}
+
+/// A connection in the table, removed from it when dropped.
+pub struct ConnEntry<'a> {
+    table: &'a ConnTable,
+    id: u64,
+}
+
+impl ConnEntry<'_> {
+    /// The id of the connection, unique for the lifetime of the process.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for ConnEntry<'_> {
+    fn drop(&mut self) {
+        self.table.shard(self.id).lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> UdpSession {
+        UdpSession {
+            client: "192.0.2.1:5353".parse().unwrap(),
+            listener: "127.0.0.1:53".parse().unwrap(),
+            backend: "198.51.100.7:53".parse().unwrap(),
+            relay: "0.0.0.0:40000".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn saves_and_restores_udp_sessions() {
+        let path = std::env::temp_dir().join(format!("portfwd-test-state-{}", std::process::id()));
+        let (connections, udp) = (ConnTable::new(2), UdpSessions::default());
+        let entry = udp.insert(session());
+        save(&connections, &udp, &path).unwrap();
+
+        let state = load_state(&path, Duration::from_secs(5)).unwrap();
+        assert!(state.tcp.is_empty());
+        assert_eq!(state.udp, [session()]);
+        // Sessions saved longer ago than they last are left out.
+        let state = load_state(&path, Duration::ZERO).unwrap();
+        assert!(state.udp.is_empty());
+
+        // Sessions that ended are no longer saved.
+        drop(entry);
+        save(&connections, &udp, &path).unwrap();
+        let state = load_state(&path, Duration::from_secs(5)).unwrap();
+        assert!(state.udp.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn starts_empty_without_a_state_file() {
+        let path = std::env::temp_dir().join("portfwd-test-state-missing");
+        let state = load_state(&path, Duration::from_secs(5)).unwrap();
+        assert!(state.tcp.is_empty() && state.udp.is_empty());
+    }
+}
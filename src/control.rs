@@ -0,0 +1,211 @@
+//! A Unix socket for controlling a running portfwd.
+//!
+//! Each line sent to the socket is a JSON command such as
+//! `{"cmd":"set_weight","backend":"10.0.0.1:80","weight":0}`, `{"cmd":"list_connections"}` or
+//! `{"cmd":"list_idle","threshold_secs":60}`, and is answered by a single line holding
+//! `{"ok":true}` with the fields of the response, or `{"ok":false,"error":"..."}`.
+//!
+//! `{"cmd":"kill_connection","conn_id":42}` resets a connection from `list_connections`, and
+//! `{"cmd":"kill_all","port":8080}` resets all connections on a port.
+//!
+//! `{"cmd":"upgrade_backend","old":"10.0.0.1:80","new":"10.0.0.2:80"}` replaces a backend in the
+//! pool, keeping its weight, and answers with the number of open connections to the old one.
+//! New clients go to the new backend at once, while the connections to the old one are left to
+//! finish, and killed after `--drain-timeout`.
+//!
+//! `{"cmd":"drain_rule","port":8080}` stops the listeners on the port from accepting new clients,
+//! and answers with the number of open connections, which are left to finish, and killed after
+//! `--drain-timeout`. portfwd keeps running with the control socket until it is stopped, e.g. by
+//! SIGTERM with `--drain-on-sigterm`.
+
+use std::{io, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use serde_json::{json, Value};
+
+use crate::{config::Config, drain, task::spawn_named};
+
+/// Accepts control connections on a Unix socket and serves their commands.
+#[cfg(unix)]
+pub async fn serve(path: PathBuf, config: Arc<Config>) -> io::Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+    use smol::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        stream::StreamExt,
+        Async,
+    };
+
+    // Replace the socket left behind by a previous run, but never any other kind of file.
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+
+    let listener = Async::<UnixListener>::bind(&path)?;
+    tracing::info!("Control socket listening on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let config = config.clone();
+        spawn_named("control-client", async move {
+            let mut lines = BufReader::new(&stream).lines();
+            let mut writer = &stream;
+            while let Some(line) = lines.next().await {
+                let response = match handle(&config, &line?) {
+                    Ok(mut response) => {
+                        response["ok"] = true.into();
+                        response
+                    }
+                    Err(error) => json!({ "ok": false, "error": error }),
+                };
+                writer.write_all(format!("{response}\n").as_bytes()).await?;
+            }
+            Ok(()) as io::Result<()>
+        })
+        .detach();
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_path: PathBuf, _config: Arc<Config>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "control sockets are only supported on Unix",
+    ))
+}
+
+/// Runs a single command, returning the fields of its response.
+fn handle(config: &Arc<Config>, line: &str) -> Result<Value, String> {
+    let request: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let cmd = request["cmd"].as_str().ok_or("missing \"cmd\"")?;
+    tracing::debug!("Control command: {}", line);
+
+    match cmd {
+        "set_weight" => {
+            let backend = request["backend"]
+                .as_str()
+                .ok_or("missing \"backend\"")?
+                .parse()
+                .map_err(|e| format!("invalid backend: {e}"))?;
+            let weight = request["weight"]
+                .as_u64()
+                .and_then(|w| u32::try_from(w).ok())
+                .ok_or("missing or invalid \"weight\"")?;
+            if !config.backends.set_weight(backend, weight) {
+                return Err(format!("unknown backend: {backend}"));
+            }
+            tracing::info!("Set weight of {} to {}", backend, weight);
+            Ok(json!({}))
+        }
+        "upgrade_backend" => {
+            let addr = |field: &str| {
+                request[field]
+                    .as_str()
+                    .ok_or(format!("missing \"{field}\""))?
+                    .parse::<SocketAddr>()
+                    .map_err(|e| format!("invalid {field} backend: {e}"))
+            };
+            let (old, new) = (addr("old")?, addr("new")?);
+            if !config.backends.replace(old, new) {
+                return Err(format!(
+                    "cannot replace {old} with {new}: unknown backend or already in the pool"
+                ));
+            }
+            let open = config.connections.open_to(old);
+            tracing::info!(
+                "Replaced backend {} with {}, draining {} connections",
+                old,
+                new,
+                open
+            );
+
+            // Kill the connections to the old backend that are still open after the timeout.
+            let config = config.clone();
+            spawn_named("upgrade-backend", async move {
+                let connections = &config.connections;
+                let open =
+                    drain::wait_until_closed(config.drain_timeout, || connections.open_to(old))
+                        .await;
+                if open > 0 {
+                    let killed = connections.kill_backend(old);
+                    tracing::warn!(
+                        "Killed {} connections to replaced backend {} after the drain timeout",
+                        killed,
+                        old
+                    );
+                } else {
+                    tracing::info!("Drained replaced backend {}", old);
+                }
+            })
+            .detach();
+            Ok(json!({ "connections": open }))
+        }
+        "list_connections" => {
+            let mut connections = Vec::new();
+            config.connections.for_each(|id, info| {
+                connections.push(json!({
+                    "id": id,
+                    "client": info.peer_addr.to_string(),
+                    "backend": info.backend.to_string(),
+                    "seconds": info.started.elapsed().as_secs(),
+                    "bytes": info.bytes.get(),
+                }));
+            });
+            Ok(json!({ "connections": connections }))
+        }
+        "list_idle" => {
+            let threshold = request["threshold_secs"]
+                .as_u64()
+                .ok_or("missing or invalid \"threshold_secs\"")?;
+            let mut connections = Vec::new();
+            config.connections.for_each(|id, info| {
+                let idle = info.last_active.idle().as_secs();
+                if idle >= threshold {
+                    connections.push(json!({
+                        "id": id,
+                        "client": info.peer_addr.to_string(),
+                        "backend": info.backend.to_string(),
+                        "idle_seconds": idle,
+                        "bytes": info.bytes.get(),
+                    }));
+                }
+            });
+            Ok(json!({ "connections": connections }))
+        }
+        "drain_rule" => {
+            let port = request["port"]
+                .as_u64()
+                .ok_or("missing or invalid \"port\"")?;
+            if port != u64::from(config.port) {
+                return Err(format!("no listener on port {port}"));
+            }
+            if config.drain.start() {
+                tracing::info!("Draining the listeners on port {}", port);
+            }
+            Ok(json!({ "connections": config.connections.stats().active }))
+        }
+        "kill_connection" => {
+            let id = request["conn_id"]
+                .as_u64()
+                .ok_or("missing or invalid \"conn_id\"")?;
+            if !config.connections.kill(id) {
+                return Err(format!("unknown connection: {id}"));
+            }
+            tracing::info!("Killed connection {}", id);
+            Ok(json!({}))
+        }
+        "kill_all" => {
+            let port = request["port"]
+                .as_u64()
+                .ok_or("missing or invalid \"port\"")?;
+            if port != u64::from(config.port) {
+                return Err(format!("no listener on port {port}"));
+            }
+            let killed = config.connections.kill_all();
+            tracing::info!("Killed {} connections on port {}", killed, port);
+            Ok(json!({ "killed": killed }))
+        }
+        _ => Err(format!("unknown command: {cmd}")),
+    }
+}
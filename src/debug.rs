@@ -0,0 +1,101 @@
+//! Hex dumps of forwarded data, for debugging protocols with `--hexdump`.
+
+use std::{fmt::Write, net::SocketAddr};
+
+use crate::protocols::{Direction, Inspector};
+
+/// Formats bytes like `hexdump -C`: an offset, 16 bytes in hex and the same bytes in ASCII on
+/// each line, with unprintable bytes shown as dots.
+pub fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", i * 16);
+        for j in 0..16 {
+            if j % 8 == 0 {
+                out.push(' ');
+            }
+            match line.get(j) {
+                Some(byte) => {
+                    let _ = write!(out, "{byte:02x} ");
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str(" |");
+        for &byte in line {
+            out.push(if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            });
+        }
+        out.push_str("|\n");
+    }
+    out
+}
+
+/// Dumps the first bytes of one direction of a TCP connection.
+pub struct HexdumpInspector {
+    direction: Direction,
+    peer_addr: SocketAddr,
+    /// How many more bytes to dump.
+    remaining: usize,
+}
+
+impl HexdumpInspector {
+    pub fn new(direction: Direction, peer_addr: SocketAddr, limit: usize) -> Self {
+        Self {
+            direction,
+            peer_addr,
+            remaining: limit,
+        }
+    }
+}
+
+impl Inspector for HexdumpInspector {
+    fn inspect(&mut self, data: &[u8]) -> bool {
+        let data = &data[..data.len().min(self.remaining)];
+        tracing::trace!(
+            "{} bytes {} of {}:\n{}",
+            data.len(),
+            self.direction,
+            self.peer_addr,
+            hexdump(data)
+        );
+        self.remaining -= data.len();
+        self.remaining > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dumps_full_and_partial_lines() {
+        assert_eq!(
+            hexdump(b"GET / HTTP/1.1\r\nHost: a\r\n"),
+            concat!(
+                "00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|\n",
+                "00000010  48 6f 73 74 3a 20 61 0d  0a                       |Host: a..|\n",
+            )
+        );
+        assert_eq!(hexdump(b""), "");
+    }
+
+    #[test]
+    fn shows_unprintable_bytes_as_dots() {
+        assert_eq!(
+            hexdump(&[0x00, 0x7f, 0x80, 0xff, b' ', b'A']),
+            "00000000  00 7f 80 ff 20 41                                 |.... A|\n"
+        );
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let peer_addr = "127.0.0.1:1234".parse().unwrap();
+        let mut inspector = HexdumpInspector::new(Direction::ClientToServer, peer_addr, 20);
+        assert!(inspector.inspect(&[0; 16]));
+        assert!(!inspector.inspect(&[0; 16]));
+    }
+}
@@ -0,0 +1,305 @@
+//! Detection of the application protocol of a client from its first bytes, for
+//! `--auto-detect`, and the routes that send each protocol to a backend of its own.
+
+use std::{
+    fmt,
+    net::{SocketAddr, TcpStream},
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use smol::io::{self, AsyncRead, AsyncWrite};
+
+use crate::transport::Stream;
+
+/// Application protocols that can be recognized from the first bytes of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Http,
+    Ssh,
+    Tls,
+    Dns,
+    Unknown,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Http => "http",
+            Protocol::Ssh => "ssh",
+            Protocol::Tls => "tls",
+            Protocol::Dns => "dns",
+            Protocol::Unknown => "unknown",
+        })
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "http" => Ok(Protocol::Http),
+            "ssh" => Ok(Protocol::Ssh),
+            "tls" => Ok(Protocol::Tls),
+            "dns" => Ok(Protocol::Dns),
+            _ => Err(format!("unknown protocol: {s}")),
+        }
+    }
+}
+
+/// HTTP/1.x request methods, each followed by the space that ends the method token.
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"HEAD ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+/// Guesses the application protocol from the first bytes a client sent over a TCP stream.
+///
+/// This only looks at simple byte patterns and never parses the message, so it is cheap enough
+/// to be run on every connection.
+pub fn detect_protocol(buf: &[u8]) -> Protocol {
+    // DNS over TCP prefixes each message with its length, which a query fills with at least
+    // its header and a question.
+    let dns = match buf {
+        [hi, lo, msg @ ..] => {
+            let len = u16::from_be_bytes([*hi, *lo]) as usize;
+            len >= MIN_QUERY && len >= msg.len() && is_dns_query(msg)
+        }
+        _ => false,
+    };
+    detect(buf, dns)
+}
+
+/// Guesses the application protocol of a UDP datagram, like [`detect_protocol`].
+pub fn detect_datagram(buf: &[u8]) -> Protocol {
+    detect(buf, buf.len() >= MIN_QUERY && is_dns_query(buf))
+}
+
+/// The smallest DNS query: a header, and a question for the root name.
+const MIN_QUERY: usize = 12 + 5;
+
+fn detect(buf: &[u8], dns: bool) -> Protocol {
+    // TLS handshake record: content type 0x16, followed by the major version 3.
+    if buf.len() >= 3 && buf[0] == 0x16 && buf[1] == 0x03 && buf[2] <= 0x04 {
+        return Protocol::Tls;
+    }
+
+    // SSH identification string.
+    if buf.starts_with(b"SSH-") {
+        return Protocol::Ssh;
+    }
+
+    // HTTP/1.x request line, or the HTTP/2 connection preface.
+    if HTTP_METHODS.iter().any(|m| buf.starts_with(m)) || buf.starts_with(b"PRI * HTTP/2") {
+        return Protocol::Http;
+    }
+
+    if dns {
+        return Protocol::Dns;
+    }
+    Protocol::Unknown
+}
+
+/// Whether a message starts like a DNS query: a standard query that is neither authoritative
+/// nor truncated and has no response code, with one question, no answers or authority records,
+/// at most the OPT record in its additional section, and a question name that starts with a
+/// label or is the root.
+fn is_dns_query(msg: &[u8]) -> bool {
+    if msg.len() < 13 {
+        return false;
+    }
+    let u16_at = |i: usize| u16::from_be_bytes([msg[i], msg[i + 1]]);
+    // Only the recursion desired, authentic data and checking disabled bits may be set.
+    u16_at(2) & !0x0130 == 0
+        && u16_at(4) == 1
+        && u16_at(6) == 0
+        && u16_at(8) == 0
+        && u16_at(10) <= 1
+        && msg[12] <= 63
+}
+
+/// A protocol-specific backend, given on the command line as `<PROTO>=<BACKEND>`.
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub protocol: Protocol,
+    pub backend: SocketAddr,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, backend) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <PROTO>=<BACKEND>, got: {s}"))?;
+        Ok(Route {
+            protocol: protocol.parse()?,
+            backend: backend.parse().map_err(|e| format!("{e}: {backend}"))?,
+        })
+    }
+}
+
+/// Finds the backend for a detected protocol, if one was routed.
+pub fn route(routes: &[Route], protocol: Protocol) -> Option<SocketAddr> {
+    routes
+        .iter()
+        .find(|r| r.protocol == protocol)
+        .map(|r| r.backend)
+}
+
+/// A stream whose first bytes were already read for detection, and are read again from here.
+pub struct PeekedStream<S> {
+    peeked: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> PeekedStream<S> {
+    pub fn new(peeked: Vec<u8>, inner: S) -> Self {
+        Self {
+            peeked,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PeekedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.pos < this.peeked.len() {
+            let n = buf.len().min(this.peeked.len() - this.pos);
+            buf[..n].copy_from_slice(&this.peeked[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(n));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PeekedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<S: Stream> Stream for PeekedStream<S> {
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        self.inner.tcp_socket()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A query for the A records of `example.com`, without EDNS.
+    const QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+        \x07example\x03com\x00\x00\x01\x00\x01";
+
+    fn tcp(msg: &[u8]) -> Vec<u8> {
+        let mut framed = (msg.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(msg);
+        framed
+    }
+
+    #[test]
+    fn detects_stream_protocols() {
+        assert_eq!(detect_protocol(b"GET / HTTP/1.1\r\n"), Protocol::Http);
+        assert_eq!(detect_protocol(b"PRI * HTTP/2.0\r\n"), Protocol::Http);
+        assert_eq!(detect_protocol(b"SSH-2.0-OpenSSH_9.6\r\n"), Protocol::Ssh);
+        assert_eq!(detect_protocol(b"\x16\x03\x01\x02\x00\x01"), Protocol::Tls);
+        assert_eq!(detect_protocol(b""), Protocol::Unknown);
+        assert_eq!(detect_protocol(b"GETX"), Protocol::Unknown);
+    }
+
+    #[test]
+    fn detects_dns_over_tcp_after_its_length() {
+        assert_eq!(detect_protocol(&tcp(QUERY)), Protocol::Dns);
+        // Only the first bytes of a stream are peeked at.
+        assert_eq!(detect_protocol(&tcp(QUERY)[..16]), Protocol::Dns);
+        // A datagram has no length before its header.
+        assert_eq!(detect_protocol(QUERY), Protocol::Unknown);
+    }
+
+    #[test]
+    fn rejects_dns_over_tcp_with_a_wrong_length() {
+        let mut framed = tcp(QUERY);
+        framed[1] = 4;
+        assert_eq!(detect_protocol(&framed), Protocol::Unknown);
+    }
+
+    #[test]
+    fn detects_dns_datagrams() {
+        assert_eq!(detect_datagram(QUERY), Protocol::Dns);
+        assert_eq!(detect_datagram(&tcp(QUERY)), Protocol::Unknown);
+    }
+
+    #[test]
+    fn rejects_binary_that_is_not_a_query() {
+        // A response.
+        let mut response = QUERY.to_vec();
+        response[2] |= 0x80;
+        assert_eq!(detect_datagram(&response), Protocol::Unknown);
+        // Several questions.
+        let mut questions = QUERY.to_vec();
+        questions[5] = 2;
+        assert_eq!(detect_datagram(&questions), Protocol::Unknown);
+        // Authority records.
+        let mut authority = QUERY.to_vec();
+        authority[9] = 1;
+        assert_eq!(detect_datagram(&authority), Protocol::Unknown);
+        // Zeros, which have no question.
+        assert_eq!(detect_datagram(&[0; 32]), Protocol::Unknown);
+        // Too short for a question.
+        assert_eq!(detect_datagram(&QUERY[..14]), Protocol::Unknown);
+    }
+
+    #[test]
+    fn parses_routes() {
+        let route: Route = "DNS=127.0.0.1:53".parse().unwrap();
+        assert_eq!(route.protocol, Protocol::Dns);
+        assert_eq!(route.backend, "127.0.0.1:53".parse().unwrap());
+        assert!("ftp=127.0.0.1:21".parse::<Route>().is_err());
+        assert!("http".parse::<Route>().is_err());
+        assert!("http=localhost".parse::<Route>().is_err());
+    }
+
+    #[test]
+    fn finds_the_route_of_a_protocol() {
+        let routes: Vec<Route> = ["ssh=127.0.0.1:22", "http=127.0.0.1:80"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        assert_eq!(
+            route(&routes, Protocol::Http),
+            Some("127.0.0.1:80".parse().unwrap())
+        );
+        assert_eq!(route(&routes, Protocol::Tls), None);
+    }
+}
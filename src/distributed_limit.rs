@@ -0,0 +1,326 @@
+//! A limit on the TCP connections open across several portfwd instances, counted in Redis by
+//! `--redis-limit-backend`.
+//!
+//! Each instance increments a shared key with `INCR` before forwarding a client, and decrements
+//! it with `DECR` once the connection is closed. A client that takes the count over
+//! `--max-connections` is turned away, even if its own listener has room. The counts of an
+//! instance that dies with connections open stay in the key until it is reset.
+//!
+//! Redis is reached over a single connection per instance, which is reconnected after any
+//! error. Builds with the `redis` feature connect with redis-rs, and others with a minimal
+//! client that speaks just enough RESP for these commands. While Redis can't be reached,
+//! clients are let through.
+
+#[cfg(not(feature = "redis"))]
+use std::net::TcpStream;
+use std::{fmt, str::FromStr, time::Duration};
+
+use smol::{future, io, lock::Mutex, Timer};
+#[cfg(not(feature = "redis"))]
+use smol::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    Async,
+};
+
+use crate::resolve;
+
+/// How long Redis may take to connect or to answer a command.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The Redis server given to `--redis-limit-backend`, as `redis://[:PASSWORD@]HOST[:PORT][/DB]`.
+#[derive(Clone, Debug)]
+pub struct RedisUrl {
+    password: Option<String>,
+    host: String,
+    port: u16,
+    db: Option<u32>,
+}
+
+impl FromStr for RedisUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("redis://")
+            .ok_or_else(|| format!("expected a redis:// URL, got: {s}"))?;
+        let (authority, db) = match rest.split_once('/') {
+            Some((authority, "")) => (authority, None),
+            Some((authority, db)) => (
+                authority,
+                Some(db.parse().map_err(|e| format!("{e}: {db}"))?),
+            ),
+            None => (rest, None),
+        };
+        let (password, addr) = match authority.rsplit_once('@') {
+            // A user name before the colon is ignored, as Redis before 6 has none.
+            Some((userinfo, addr)) => {
+                let password = userinfo.split_once(':').map_or(userinfo, |(_, p)| p);
+                (Some(password.to_string()), addr)
+            }
+            None => (None, authority),
+        };
+        let (host, port) = match addr.rsplit_once(':') {
+            // The colons of an IPv6 address are not a port.
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|e| format!("{e}: {port}"))?)
+            }
+            _ => (addr, 6379),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("missing host: {s}"));
+        }
+        Ok(RedisUrl {
+            password,
+            host: host.to_string(),
+            port,
+            db,
+        })
+    }
+}
+
+impl fmt::Display for RedisUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never show the password, e.g. in the logs.
+        write!(f, "redis://{}:{}", self.host, self.port)?;
+        if let Some(db) = self.db {
+            write!(f, "/{db}")?;
+        }
+        Ok(())
+    }
+}
+
+impl RedisUrl {
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// A connection to Redis.
+#[cfg(feature = "redis")]
+type Conn = redis::aio::MultiplexedConnection;
+
+/// A connection to Redis.
+#[cfg(not(feature = "redis"))]
+type Conn = BufReader<Async<TcpStream>>;
+
+/// The connection count shared by all instances.
+#[derive(Debug)]
+pub struct DistributedLimit {
+    url: RedisUrl,
+    key: String,
+    max: u64,
+    /// The connection to Redis, if it is open.
+    conn: Mutex<Option<Conn>>,
+}
+
+impl DistributedLimit {
+    pub fn new(url: RedisUrl, key: String, max: u32) -> Self {
+        Self {
+            url,
+            key,
+            max: max.into(),
+            conn: Mutex::new(None),
+        }
+    }
+
+    /// Counts a new connection, returning `false`, and uncounting it again, if it takes the
+    /// count over the limit.
+    pub async fn acquire(&self) -> io::Result<bool> {
+        let count = self.command(&["INCR", &self.key]).await?;
+        if count > self.max as i64 {
+            self.release().await?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Uncounts a closed connection.
+    pub async fn release(&self) -> io::Result<()> {
+        self.command(&["DECR", &self.key]).await.map(drop)
+    }
+
+    /// Runs a command that answers with an integer, connecting first if needed, and dropping
+    /// the connection if anything fails.
+    async fn command(&self, args: &[&str]) -> io::Result<i64> {
+        let mut conn = self.conn.lock().await;
+        let timeout = async {
+            Timer::after(TIMEOUT).await;
+            Err(io::Error::new(io::ErrorKind::TimedOut, "Redis timed out"))
+        };
+        let result = future::or(
+            async {
+                if conn.is_none() {
+                    *conn = Some(self.connect().await?);
+                }
+                let conn = conn.as_mut().expect("connected above");
+                request(conn, args).await
+            },
+            timeout,
+        )
+        .await;
+        if result.is_err() {
+            *conn = None;
+        }
+        result
+    }
+
+    /// Connects to Redis, logging in and selecting the database if the URL says so.
+    #[cfg(feature = "redis")]
+    async fn connect(&self) -> io::Result<Conn> {
+        use redis::{ConnectionAddr, IntoConnectionInfo, RedisConnectionInfo};
+
+        let addr = resolve::resolve(&self.url.host, self.url.port).await?;
+        let mut settings = RedisConnectionInfo::default();
+        if let Some(password) = &self.url.password {
+            settings = settings.set_password(password);
+        }
+        if let Some(db) = self.url.db {
+            settings = settings.set_db(db.into());
+        }
+        let info = ConnectionAddr::Tcp(addr.ip().to_string(), addr.port())
+            .into_connection_info()
+            .map_err(redis_error)?
+            .set_redis_settings(settings);
+        let conn = redis::Client::open(info)
+            .map_err(redis_error)?
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(redis_error)?;
+        tracing::debug!("Connected to Redis at {}", self.url);
+        Ok(conn)
+    }
+
+    /// Connects to Redis, logging in and selecting the database if the URL says so.
+    #[cfg(not(feature = "redis"))]
+    async fn connect(&self) -> io::Result<Conn> {
+        let addr = resolve::resolve(&self.url.host, self.url.port).await?;
+        let mut conn = BufReader::new(Async::<TcpStream>::connect(addr).await?);
+        if let Some(password) = &self.url.password {
+            request(&mut conn, &["AUTH", password]).await?;
+        }
+        if let Some(db) = self.url.db {
+            request(&mut conn, &["SELECT", &db.to_string()]).await?;
+        }
+        tracing::debug!("Connected to Redis at {}", self.url);
+        Ok(conn)
+    }
+}
+
+/// Sends a command, and reads its answer, which is an integer, or `0` for simple strings such
+/// as `OK`.
+#[cfg(feature = "redis")]
+async fn request(conn: &mut Conn, args: &[&str]) -> io::Result<i64> {
+    let (name, args) = args.split_first().expect("a command has a name");
+    let answer = redis::cmd(name).arg(args).query_async(conn).await;
+    match answer.map_err(redis_error)? {
+        redis::Value::Int(n) => Ok(n),
+        redis::Value::Okay | redis::Value::SimpleString(_) => Ok(0),
+        answer => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected answer from Redis: {answer:?}"),
+        )),
+    }
+}
+
+#[cfg(feature = "redis")]
+fn redis_error(err: redis::RedisError) -> io::Error {
+    io::Error::other(format!("Redis: {err}"))
+}
+
+/// Sends a command, and reads its answer, which is an integer, or `0` for simple strings such
+/// as `OK`.
+#[cfg(not(feature = "redis"))]
+async fn request(conn: &mut Conn, args: &[&str]) -> io::Result<i64> {
+    conn.get_mut().write_all(&encode_command(args)).await?;
+    let mut line = String::new();
+    if conn.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    parse_answer(&line)
+}
+
+/// Encodes a command as an array of bulk strings.
+#[cfg(not(feature = "redis"))]
+fn encode_command(args: &[&str]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command.push_str(&format!("${}\r\n{arg}\r\n", arg.len()));
+    }
+    command.into_bytes()
+}
+
+/// Parses the line of an answer that is an integer, a simple string or an error.
+#[cfg(not(feature = "redis"))]
+fn parse_answer(line: &str) -> io::Result<i64> {
+    let line = line.trim_end();
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected answer from Redis: {line}"),
+        )
+    };
+    match line.split_at_checked(1).ok_or_else(invalid)? {
+        (":", n) => n.parse().map_err(|_| invalid()),
+        ("+", _) => Ok(0),
+        ("-", err) => Err(io::Error::other(format!("Redis: {err}"))),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_urls() {
+        let url: RedisUrl = "redis://user:secret@[::1]:6380/2".parse().unwrap();
+        assert_eq!(url.password.as_deref(), Some("secret"));
+        assert_eq!((url.host(), url.port(), url.db), ("::1", 6380, Some(2)));
+        assert_eq!(url.to_string(), "redis://::1:6380/2");
+
+        let url: RedisUrl = "redis://redis/".parse().unwrap();
+        assert_eq!(url.password, None);
+        assert_eq!((url.host(), url.port(), url.db), ("redis", 6379, None));
+
+        for url in [
+            "http://redis",
+            "redis://",
+            "redis://redis:x",
+            "redis://redis/x",
+        ] {
+            assert!(url.parse::<RedisUrl>().is_err(), "{url}");
+        }
+    }
+
+    #[cfg(not(feature = "redis"))]
+    #[test]
+    fn encodes_commands_as_bulk_strings() {
+        assert_eq!(
+            encode_command(&["INCR", "portfwd:connections"]),
+            b"*2\r\n$4\r\nINCR\r\n$19\r\nportfwd:connections\r\n"
+        );
+        assert_eq!(
+            encode_command(&["AUTH", ""]),
+            b"*2\r\n$4\r\nAUTH\r\n$0\r\n\r\n"
+        );
+    }
+
+    #[cfg(not(feature = "redis"))]
+    #[test]
+    fn parses_answers() {
+        assert_eq!(parse_answer(":42\r\n").unwrap(), 42);
+        assert_eq!(parse_answer(":-1\r\n").unwrap(), -1);
+        assert_eq!(parse_answer("+OK\r\n").unwrap(), 0);
+        let err = parse_answer("-ERR value is not an integer\r\n").unwrap_err();
+        assert_eq!(err.to_string(), "Redis: ERR value is not an integer");
+        for line in ["", "\r\n", ":x\r\n", "$2\r\n", "*1\r\n"] {
+            let err = parse_answer(line).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{line:?}");
+        }
+    }
+}
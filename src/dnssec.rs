@@ -0,0 +1,208 @@
+//! DNSSEC validation of the DNS replies relayed from UDP backends, for `--dns-validate` in builds
+//! with the `dnssec` feature.
+//!
+//! The question of each reply is asked again of the backend that sent it by a validating
+//! resolver, which follows the signatures of the answer up to the trust anchor of the root zone.
+//! A reply fails validation if the signatures of its answer don't check out, or if the answer was
+//! signed and the reply has records that aren't part of it. Replies to questions that the
+//! resolver couldn't ask, e.g. as the backend didn't answer it in time, are relayed as they are,
+//! as are the replies for zones that aren't signed.
+//!
+//! The resolvers run on a thread of their own, and cache what they validated for its TTL, so
+//! only the first reply for each name waits for the lookups of the keys.
+
+use std::fmt;
+#[cfg(feature = "dnssec")]
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+
+use smol::io;
+
+/// The validating resolvers of the backends replies are relayed from.
+#[cfg(feature = "dnssec")]
+pub struct Validator {
+    runtime: tokio::runtime::Handle,
+    resolvers: Mutex<HashMap<SocketAddr, hickory_resolver::TokioResolver>>,
+}
+
+/// A validator, which can't be created without the `dnssec` feature.
+#[cfg(not(feature = "dnssec"))]
+pub struct Validator {
+    never: std::convert::Infallible,
+}
+
+/// A reply that failed validation.
+#[derive(Debug)]
+pub struct Bogus {
+    /// The domain that the reply answered a question about.
+    pub domain: String,
+    /// The SERVFAIL reply to relay instead.
+    pub servfail: Vec<u8>,
+}
+
+#[cfg(feature = "dnssec")]
+impl Validator {
+    /// Starts the thread that the resolvers run on.
+    pub fn new() -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+        std::thread::Builder::new()
+            .name("dnssec".to_string())
+            .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+        Ok(Self {
+            runtime: handle,
+            resolvers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Validates a reply from a backend, returning what to relay instead if it failed.
+    pub async fn validate(&self, backend: SocketAddr, reply: &[u8]) -> Option<Bogus> {
+        use hickory_resolver::{
+            net::{DnsError, NetError},
+            proto::{dnssec::Proof, op::Message, rr::RecordType},
+        };
+
+        // Datagrams that aren't DNS replies aren't for us to judge.
+        let message = Message::from_vec(reply).ok()?;
+        let query = message.queries.first()?.clone();
+        let resolver = match self.resolver(backend) {
+            Ok(resolver) => resolver,
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to create a DNSSEC resolver for {}: {}",
+                    backend,
+                    err
+                );
+                return None;
+            }
+        };
+        let (name, record_type) = (query.name().clone(), query.query_type());
+        let lookup = self
+            .runtime
+            .spawn(async move { resolver.lookup(name, record_type).await })
+            .await
+            .ok()?;
+        let valid = match lookup {
+            Ok(lookup) => {
+                // Only a signed answer tells which records the reply may have.
+                let answers = lookup.answers();
+                !answers.iter().any(|record| record.proof == Proof::Secure)
+                    || message
+                        .answers
+                        .iter()
+                        .filter(|record| record.record_type() != RecordType::RRSIG)
+                        .all(|record| {
+                            answers.iter().any(|answer| {
+                                answer.name == record.name
+                                    && answer.record_type() == record.record_type()
+                                    && answer.data == record.data
+                            })
+                        })
+            }
+            Err(NetError::Dns(DnsError::DnssecBogus)) => false,
+            Err(err) => {
+                tracing::debug!("Could not validate the reply for {}: {}", query.name(), err);
+                true
+            }
+        };
+        if valid {
+            return None;
+        }
+        Some(Bogus {
+            domain: query.name().to_string(),
+            servfail: servfail(&message)?,
+        })
+    }
+
+    /// The resolver for a backend, created on its first reply.
+    fn resolver(&self, backend: SocketAddr) -> io::Result<hickory_resolver::TokioResolver> {
+        use hickory_resolver::{
+            config::{ConnectionConfig, NameServerConfig, ResolveHosts, ResolverConfig},
+            net::runtime::TokioRuntimeProvider,
+            Resolver,
+        };
+
+        let mut resolvers = self.resolvers.lock().unwrap();
+        if let Some(resolver) = resolvers.get(&backend) {
+            return Ok(resolver.clone());
+        }
+        let connections = [ConnectionConfig::udp(), ConnectionConfig::tcp()]
+            .map(|mut connection| {
+                connection.port = backend.port();
+                connection
+            })
+            .into();
+        let config = ResolverConfig::from_name_servers(vec![NameServerConfig::new(
+            backend.ip(),
+            true,
+            connections,
+        )]);
+        // The resolver starts its tasks on the runtime it is built in.
+        let _runtime = self.runtime.enter();
+        let mut builder = Resolver::builder_with_config(config, TokioRuntimeProvider::default());
+        builder.options_mut().validate = true;
+        builder.options_mut().use_hosts_file = ResolveHosts::Never;
+        let resolver = builder.build().map_err(io::Error::other)?;
+        resolvers.insert(backend, resolver.clone());
+        Ok(resolver)
+    }
+}
+
+/// The SERVFAIL reply to the question of a message, with its ID and flags.
+#[cfg(feature = "dnssec")]
+fn servfail(message: &hickory_resolver::proto::op::Message) -> Option<Vec<u8>> {
+    use hickory_resolver::proto::op::{Message, ResponseCode};
+
+    let mut servfail = Message::error_msg(
+        message.metadata.id,
+        message.metadata.op_code,
+        ResponseCode::ServFail,
+    );
+    servfail.metadata.recursion_desired = message.metadata.recursion_desired;
+    servfail.metadata.recursion_available = message.metadata.recursion_available;
+    servfail.queries = message.queries.clone();
+    servfail.to_vec().ok()
+}
+
+#[cfg(not(feature = "dnssec"))]
+impl Validator {
+    pub fn new() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "DNSSEC validation needs portfwd to be built with the `dnssec` feature",
+        ))
+    }
+
+    pub async fn validate(&self, _backend: std::net::SocketAddr, _reply: &[u8]) -> Option<Bogus> {
+        match self.never {}
+    }
+}
+
+impl fmt::Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Validator").finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "dnssec"))]
+mod tests {
+    use hickory_resolver::proto::op::{Message, ResponseCode};
+
+    use super::*;
+
+    #[test]
+    fn servfail_keeps_the_id_and_question() {
+        // A reply for example.com with RD and RA set and the address 1.2.3.4.
+        let mut reply = b"\x00\x07\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00".to_vec();
+        reply.extend(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        reply.extend(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x1e\x00\x04\x01\x02\x03\x04");
+        let reply = Message::from_vec(&reply).unwrap();
+        let servfail = Message::from_vec(&servfail(&reply).unwrap()).unwrap();
+        assert_eq!(servfail.metadata.id, 7);
+        assert_eq!(servfail.metadata.response_code, ResponseCode::ServFail);
+        assert!(servfail.metadata.recursion_desired);
+        assert_eq!(servfail.queries, reply.queries);
+        assert!(servfail.answers.is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+//! Draining of the listeners, which stop accepting new clients while the open connections are
+//! left to finish.
+
+use std::time::{Duration, Instant};
+
+use smol::{
+    channel::{bounded, Receiver, Sender},
+    io, Timer,
+};
+
+use crate::conn_table::ConnTable;
+
+/// How often the connection table is checked while waiting for it to empty.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether the listeners are being drained, and whether the process exits once they are.
+#[derive(Debug)]
+pub struct Drain {
+    /// Closed to start draining, which wakes up every waiting listener at once.
+    start: Sender<()>,
+    started: Receiver<()>,
+    /// Closed to let the process exit once drained, rather than keep running.
+    exit: Sender<()>,
+    exiting: Receiver<()>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        let (start, started) = bounded(1);
+        let (exit, exiting) = bounded(1);
+        Self {
+            start,
+            started,
+            exit,
+            exiting,
+        }
+    }
+}
+
+impl Drain {
+    /// Starts draining, returning whether it had not been started before.
+    pub fn start(&self) -> bool {
+        self.start.close()
+    }
+
+    /// Whether draining has started.
+    pub fn is_started(&self) -> bool {
+        self.start.is_closed()
+    }
+
+    /// Waits until draining starts.
+    pub async fn started(&self) {
+        let _ = self.started.recv().await;
+    }
+
+    /// Lets the process exit once the listeners are drained.
+    pub fn exit(&self) {
+        self.exit.close();
+    }
+
+    /// Waits until the process may exit.
+    pub async fn exiting(&self) {
+        let _ = self.exiting.recv().await;
+    }
+}
+
+/// Starts draining whenever the process receives SIGTERM, forever.
+#[cfg(unix)]
+pub async fn on_sigterm(drain: &Drain) -> io::Result<()> {
+    use std::os::unix::net::UnixStream;
+
+    use smol::{io::AsyncReadExt, Async};
+
+    // The signal handler writes a byte to the pipe for each signal.
+    let (mut reader, writer) = Async::<UnixStream>::pair()?;
+    signal_hook::low_level::pipe::register(signal_hook::consts::SIGTERM, writer.into_inner()?)?;
+    let mut byte = [0; 1];
+    loop {
+        reader.read_exact(&mut byte).await?;
+        drain.exit();
+        if drain.start() {
+            tracing::info!("Draining the listeners on SIGTERM");
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn on_sigterm(_drain: &Drain) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "signals are only supported on Unix",
+    ))
+}
+
+/// Waits until all connections in a table are closed or a timeout passes, returning the number
+/// of connections that are still open.
+pub async fn wait_closed(connections: &ConnTable, timeout: Duration) -> u64 {
+    wait_until_closed(timeout, || connections.stats().active).await
+}
+
+/// Waits until the count of open connections drops to 0 or a timeout passes, returning the last
+/// count.
+pub async fn wait_until_closed(timeout: Duration, open: impl Fn() -> u64) -> u64 {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let active = open();
+        if active == 0 || Instant::now() >= deadline {
+            return active;
+        }
+        Timer::after(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_running_until_told_to_exit() {
+        let drain = Drain::default();
+        assert!(drain.start());
+        assert!(!drain.start());
+        smol::block_on(async {
+            drain.started().await;
+            let exited = async {
+                drain.exiting().await;
+                true
+            };
+            let running = async {
+                Timer::after(Duration::from_millis(50)).await;
+                false
+            };
+            assert!(!smol::future::or(exited, running).await);
+            drain.exit();
+            drain.exiting().await;
+        });
+    }
+}
@@ -0,0 +1,89 @@
+//! Errors of the servers, which say which step of forwarding failed.
+
+use std::{fmt, io, net::SocketAddr};
+
+use crate::protocols::Direction;
+
+/// An error of a server or of one of its connections.
+#[derive(Debug)]
+pub enum Error {
+    /// A listener could not be bound to its address.
+    BindFailed { addr: SocketAddr, source: io::Error },
+    /// A listener failed to accept a client or receive a datagram.
+    AcceptFailed { addr: SocketAddr, source: io::Error },
+    /// The connection to a backend could not be established.
+    BackendConnectFailed { addr: SocketAddr, source: io::Error },
+    /// Copying the data of a connection failed in one direction.
+    ForwardFailed {
+        conn_id: u64,
+        direction: Direction,
+        source: io::Error,
+    },
+    /// All backends are drained, so there is nowhere to forward a client to.
+    NoBackend,
+    /// Any other I/O error, e.g. in a proxy handshake.
+    Io(io::Error),
+}
+
+impl Error {
+    /// The kind of the underlying I/O error.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::BindFailed { source, .. }
+            | Error::AcceptFailed { source, .. }
+            | Error::BackendConnectFailed { source, .. }
+            | Error::ForwardFailed { source, .. }
+            | Error::Io(source) => source.kind(),
+            Error::NoBackend => io::ErrorKind::NotFound,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BindFailed { addr, source } => write!(f, "failed to bind {addr}: {source}"),
+            Error::AcceptFailed { addr, source } => {
+                write!(f, "failed to accept on {addr}: {source}")
+            }
+            Error::BackendConnectFailed { addr, source } => {
+                write!(f, "failed to connect to backend {addr}: {source}")
+            }
+            Error::ForwardFailed {
+                conn_id,
+                direction,
+                source,
+            } => write!(f, "connection {conn_id} failed {direction}: {source}"),
+            Error::NoBackend => f.write_str("all backends are drained"),
+            Error::Io(source) => source.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::BindFailed { source, .. }
+            | Error::AcceptFailed { source, .. }
+            | Error::BackendConnectFailed { source, .. }
+            | Error::ForwardFailed { source, .. }
+            | Error::Io(source) => Some(source),
+            Error::NoBackend => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! A JSON Lines log of connection events, for `--event-log`.
+//!
+//! Like the events of the webhook, log lines are queued without waiting and appended by a
+//! background task, so a slow disk never holds up forwarding. Lines that don't fit in the queue
+//! are dropped.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::json;
+use smol::{
+    channel::{bounded, Receiver, Sender},
+    io::{self, AsyncWriteExt},
+    Unblock,
+};
+
+use crate::{
+    metrics,
+    webhook::{Event, EventKind},
+};
+
+/// How many lines may wait to be written.
+const QUEUE_SIZE: usize = 4096;
+
+/// The queue of lines to append to a file.
+pub struct EventLog {
+    path: PathBuf,
+    file: File,
+    queued: Sender<Vec<u8>>,
+    queue: Receiver<Vec<u8>>,
+}
+
+impl EventLog {
+    /// Opens a file to append to, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (queued, queue) = bounded(QUEUE_SIZE);
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            queued,
+            queue,
+        })
+    }
+
+    /// Queues an event to be logged, dropping it if the queue is full.
+    pub fn log(&self, event: &Event) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let event_type = match event.kind {
+            EventKind::Connect => "open",
+            EventKind::Disconnect => "close",
+            EventKind::Error => "error",
+        };
+        let mut line = json!({
+            "event_type": event_type,
+            "timestamp_unix_ms": timestamp,
+            "conn_id": event.conn_id,
+            "proto": event.proto,
+            "src_addr": event.peer_addr.to_string(),
+            "dst_addr": event.dest_addr.to_string(),
+            "src_family": metrics::family(event.peer_addr.ip()),
+            "dst_family": metrics::family(event.dest_addr.ip()),
+            "bytes_in": event.bytes_in,
+            "bytes_out": event.bytes_out,
+            "duration_ms": event.duration.as_millis() as u64,
+        });
+        if let Some(error) = &event.error {
+            line["error"] = error.as_str().into();
+        }
+        let mut line = line.to_string().into_bytes();
+        line.push(b'\n');
+        if self.queued.try_send(line).is_err() {
+            tracing::debug!(
+                "Dropped {} event of connection {}: event log queue is full",
+                event.kind,
+                event.conn_id
+            );
+        }
+    }
+
+    /// Appends the queued lines in order, forever.
+    pub async fn write(&self) -> io::Result<()> {
+        let mut file = Unblock::new(self.file.try_clone()?);
+        while let Ok(line) = self.queue.recv().await {
+            file.write_all(&line).await?;
+            // Write out what is queued before waiting for more, so that the log is up to date
+            // whenever portfwd is idle.
+            if self.queue.is_empty() {
+                file.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventLog")
+            .field("path", &self.path)
+            .field("queued", &self.queue.len())
+            .finish()
+    }
+}
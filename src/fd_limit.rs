@@ -0,0 +1,89 @@
+//! The limit on open file descriptors, checked at startup against the connections that portfwd
+//! may have to hold open, so it doesn't fail with "too many open files" later.
+
+/// How many descriptors beyond those in use are too few to go on safely.
+#[cfg(unix)]
+const MIN_HEADROOM: u64 = 64;
+
+/// Checks the soft limit on open file descriptors, first raising it to the hard limit if
+/// `auto_raise` is set, and warns if it is below the descriptors `needed`, if known, or about to
+/// run out.
+#[cfg(unix)]
+pub fn check(needed: Option<u64>, auto_raise: bool) {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid `rlimit` to write to.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == -1 {
+        tracing::warn!(
+            "Failed to get the file descriptor limit: {}",
+            std::io::Error::last_os_error()
+        );
+        return;
+    }
+    let target = if auto_raise {
+        limit.rlim_max
+    } else {
+        limit.rlim_cur
+    };
+    tracing::debug!(
+        "File descriptor limit: {}, hard limit: {}, target: {}",
+        limit.rlim_cur,
+        limit.rlim_max,
+        target
+    );
+
+    if target > limit.rlim_cur {
+        let raised = libc::rlimit {
+            rlim_cur: target,
+            rlim_max: limit.rlim_max,
+        };
+        // SAFETY: `raised` is a valid `rlimit`, which the call only reads.
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == -1 {
+            tracing::warn!(
+                "Failed to raise the file descriptor limit from {} to {}: {}",
+                limit.rlim_cur,
+                target,
+                std::io::Error::last_os_error()
+            );
+        } else {
+            tracing::debug!(
+                "Raised the file descriptor limit from {} to {}",
+                limit.rlim_cur,
+                target
+            );
+            limit = raised;
+        }
+    }
+
+    let soft = limit.rlim_cur;
+    if let Some(needed) = needed.filter(|&needed| soft < needed) {
+        tracing::warn!(
+            "The file descriptor limit of {} is below the {} that the connections may need, raise it with `ulimit -n` or --auto-raise-fd-limit",
+            soft,
+            needed
+        );
+    }
+    if let Some(used) = in_use() {
+        if soft < used + MIN_HEADROOM {
+            tracing::warn!(
+                "Only {} of the {} file descriptors allowed are free",
+                soft.saturating_sub(used),
+                soft
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn check(_needed: Option<u64>, _auto_raise: bool) {}
+
+/// The number of file descriptors open in this process, if the platform lists them.
+#[cfg(unix)]
+fn in_use() -> Option<u64> {
+    // Listing the directory opens one more, which is counted too.
+    std::fs::read_dir("/dev/fd")
+        .ok()
+        .map(|entries| entries.count() as u64)
+}
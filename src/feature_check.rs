@@ -0,0 +1,377 @@
+//! Probes for optional kernel features, so that missing support is reported up front.
+
+use std::{fmt, io};
+
+use clap::{error::ErrorKind, CommandFactory};
+
+use crate::cli::Cli;
+
+/// A kernel feature that some options of portfwd depend on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    ReusePort,
+    IpTransparent,
+    TcpFastOpen,
+    Splice,
+    Recvmmsg,
+    IpTtl,
+    RecvErr,
+    FlowLabel,
+    Setns,
+    CpuAffinity,
+    SchedFifo,
+}
+
+impl Feature {
+    /// All the features that can be probed.
+    pub const ALL: [Feature; 11] = [
+        Feature::ReusePort,
+        Feature::IpTransparent,
+        Feature::TcpFastOpen,
+        Feature::Splice,
+        Feature::Recvmmsg,
+        Feature::IpTtl,
+        Feature::RecvErr,
+        Feature::FlowLabel,
+        Feature::Setns,
+        Feature::CpuAffinity,
+        Feature::SchedFifo,
+    ];
+
+    /// Checks whether the feature is available on this system.
+    pub fn probe(self) -> Support {
+        let result = match self {
+            Feature::ReusePort => probe_reuse_port(),
+            Feature::IpTransparent => probe_ip_transparent(),
+            Feature::TcpFastOpen => probe_tcp_fastopen(),
+            Feature::Splice => probe_splice(),
+            Feature::Recvmmsg => probe_recvmmsg(),
+            Feature::IpTtl => probe_ip_ttl(),
+            Feature::RecvErr => probe_recv_err(),
+            Feature::FlowLabel => probe_flow_label(),
+            Feature::Setns => probe_setns(),
+            Feature::CpuAffinity => probe_cpu_affinity(),
+            Feature::SchedFifo => probe_sched_fifo(),
+        };
+        match result {
+            Ok(()) => Support::Supported,
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => Support::NotPermitted,
+            Err(err) => Support::Unsupported(err),
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Feature::ReusePort => "SO_REUSEPORT",
+            Feature::IpTransparent => "IP_TRANSPARENT",
+            Feature::TcpFastOpen => "TCP_FASTOPEN",
+            Feature::Splice => "splice(2)",
+            Feature::Recvmmsg => "recvmmsg(2)",
+            Feature::IpTtl => "IP_TTL",
+            Feature::RecvErr => "IP_RECVERR",
+            Feature::FlowLabel => "IPV6_FLOWLABEL_MGR",
+            Feature::Setns => "setns(2)",
+            Feature::CpuAffinity => "sched_setaffinity(2)",
+            Feature::SchedFifo => "SCHED_FIFO",
+        })
+    }
+}
+
+/// Whether a feature is available.
+#[derive(Debug)]
+pub enum Support {
+    Supported,
+    /// The feature exists, but the process lacks the privileges to use it.
+    NotPermitted,
+    Unsupported(io::Error),
+}
+
+impl fmt::Display for Support {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Support::Supported => f.write_str("supported"),
+            Support::NotPermitted => f.write_str("supported, but not permitted"),
+            Support::Unsupported(err) => write!(f, "not supported ({err})"),
+        }
+    }
+}
+
+/// Prints a matrix of all features and whether they are supported.
+pub fn print_matrix() {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    for feature in Feature::ALL {
+        println!("{:<22}{}", feature.to_string(), feature.probe());
+    }
+}
+
+/// Probes the features that the options depend on, exiting with an error that names the option
+/// of the first one that is missing.
+pub fn check_requested(cli: &Cli) {
+    let requested = [
+        ("--ttl", cli.ttl.is_some(), Feature::IpTtl),
+        ("--icmp-errors", cli.icmp_errors, Feature::RecvErr),
+        (
+            "--ipv6-flow-label",
+            cli.ipv6_flow_label.is_some(),
+            Feature::FlowLabel,
+        ),
+        ("--netns", cli.netns.is_some(), Feature::Setns),
+        (
+            "--cpu-affinity",
+            !cli.cpu_affinity.is_empty(),
+            Feature::CpuAffinity,
+        ),
+        ("--numa-aware", cli.numa_aware, Feature::CpuAffinity),
+        (
+            "--rt-priority",
+            cli.rt_priority.is_some(),
+            Feature::SchedFifo,
+        ),
+    ];
+    for (flag, _, feature) in requested.into_iter().filter(|(_, set, _)| *set) {
+        match feature.probe() {
+            Support::Supported => tracing::debug!("{} is supported for {}", feature, flag),
+            support => Cli::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("{flag} needs {feature}, which is {support}"),
+                )
+                .exit(),
+        }
+    }
+}
+
+/// Sets an integer socket option on a fresh socket, and reports whether the kernel accepted it.
+#[cfg(unix)]
+fn probe_sockopt(ty: socket2::Type, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let socket = socket2::Socket::new(socket2::Domain::IPV4, ty, None)?;
+    let value: libc::c_int = 1;
+    // SAFETY: the option value points to a live `c_int` of the given size.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "not available on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn probe_reuse_port() -> io::Result<()> {
+    probe_sockopt(socket2::Type::STREAM, libc::SOL_SOCKET, libc::SO_REUSEPORT)
+}
+
+#[cfg(not(unix))]
+fn probe_reuse_port() -> io::Result<()> {
+    unsupported()
+}
+
+#[cfg(target_os = "linux")]
+fn probe_ip_transparent() -> io::Result<()> {
+    probe_sockopt(socket2::Type::STREAM, libc::SOL_IP, libc::IP_TRANSPARENT)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_ip_transparent() -> io::Result<()> {
+    unsupported()
+}
+
+#[cfg(target_os = "linux")]
+fn probe_tcp_fastopen() -> io::Result<()> {
+    probe_sockopt(socket2::Type::STREAM, libc::IPPROTO_TCP, libc::TCP_FASTOPEN)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_tcp_fastopen() -> io::Result<()> {
+    unsupported()
+}
+
+/// Splices a single byte from one pipe into another.
+#[cfg(target_os = "linux")]
+fn probe_splice() -> io::Result<()> {
+    use std::{
+        fs::File,
+        io::Write,
+        os::unix::io::{AsRawFd, FromRawFd},
+    };
+
+    fn pipe() -> io::Result<(File, File)> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors written by `pipe`.
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: both descriptors were just created and are owned by nobody else.
+        Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+    }
+
+    let (src_read, mut src_write) = pipe()?;
+    let (_dst_read, dst_write) = pipe()?;
+    src_write.write_all(&[0])?;
+    // SAFETY: both descriptors are valid pipes, and null offsets are allowed for pipes.
+    let ret = unsafe {
+        libc::splice(
+            src_read.as_raw_fd(),
+            std::ptr::null_mut(),
+            dst_write.as_raw_fd(),
+            std::ptr::null_mut(),
+            1,
+            libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_splice() -> io::Result<()> {
+    unsupported()
+}
+
+/// Calls `recvmmsg` on an empty socket, which fails with `EAGAIN` if the call is supported.
+#[cfg(target_os = "linux")]
+fn probe_recvmmsg() -> io::Result<()> {
+    use std::{net::UdpSocket, os::unix::io::AsRawFd};
+
+    let socket = UdpSocket::bind(("127.0.0.1", 0))?;
+    let mut buf = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // SAFETY: `mmsghdr` is a plain C struct for which all zeroes is a valid value.
+    let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+    msg.msg_hdr.msg_iov = &mut iov;
+    msg.msg_hdr.msg_iovlen = 1;
+    // SAFETY: `msg` points to a single message header with a valid buffer.
+    let ret = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            &mut msg,
+            1,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+    match ret {
+        -1 => match io::Error::last_os_error() {
+            err if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            err => Err(err),
+        },
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_recvmmsg() -> io::Result<()> {
+    unsupported()
+}
+
+#[cfg(unix)]
+fn probe_ip_ttl() -> io::Result<()> {
+    probe_sockopt(socket2::Type::STREAM, libc::IPPROTO_IP, libc::IP_TTL)
+}
+
+#[cfg(not(unix))]
+fn probe_ip_ttl() -> io::Result<()> {
+    unsupported()
+}
+
+#[cfg(target_os = "linux")]
+fn probe_recv_err() -> io::Result<()> {
+    probe_sockopt(socket2::Type::DGRAM, libc::IPPROTO_IP, libc::IP_RECVERR)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_recv_err() -> io::Result<()> {
+    unsupported()
+}
+
+/// Leases a flow label for the loopback address on a fresh socket, which releases it on close.
+#[cfg(target_os = "linux")]
+fn probe_flow_label() -> io::Result<()> {
+    use std::net::{Ipv6Addr, UdpSocket};
+
+    let socket = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0))?;
+    crate::socket::lease_flow_label(&socket, Ipv6Addr::LOCALHOST, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_flow_label() -> io::Result<()> {
+    unsupported()
+}
+
+/// Enters the network namespace that the calling thread is in already.
+#[cfg(target_os = "linux")]
+fn probe_setns() -> io::Result<()> {
+    use std::{fs::File, os::unix::io::AsRawFd};
+
+    let namespace = File::open("/proc/thread-self/ns/net")?;
+    // SAFETY: the descriptor is open for as long as the file is.
+    match unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_setns() -> io::Result<()> {
+    unsupported()
+}
+
+/// Pins a thread of its own to the first CPU it may run on, so that the other threads keep theirs.
+#[cfg(target_os = "linux")]
+fn probe_cpu_affinity() -> io::Result<()> {
+    // SAFETY: `cpu_set_t` is a plain bit mask, for which all zeroes is the empty set.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: `set` is a live CPU set of the given size.
+    if unsafe { libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: every CPU below `CPU_SETSIZE` is within the set.
+    let cpu = (0..libc::CPU_SETSIZE as usize)
+        .find(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .unwrap_or_default();
+    std::thread::spawn(move || crate::affinity::pin_current_thread(cpu))
+        .join()
+        .map_err(|_| io::Error::other("the probe panicked"))?
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_cpu_affinity() -> io::Result<()> {
+    unsupported()
+}
+
+/// Runs a thread of its own at the lowest real-time priority, so that the other threads keep
+/// theirs.
+#[cfg(unix)]
+fn probe_sched_fifo() -> io::Result<()> {
+    std::thread::spawn(|| crate::affinity::set_realtime_priority(1))
+        .join()
+        .map_err(|_| io::Error::other("the probe panicked"))?
+}
+
+#[cfg(not(unix))]
+fn probe_sched_fifo() -> io::Result<()> {
+    unsupported()
+}
@@ -0,0 +1,234 @@
+//! The server side of HTTP CONNECT tunnels, for the `--http-connect` proxy.
+//!
+//! When `--auth` is given, clients must send a `Proxy-Authorization: Basic` header, and are
+//! asked for one with a 407 response otherwise.
+
+use std::net::SocketAddr;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use smol::io::{self, AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    auth::Credentials, detect::PeekedStream, protocols::http::find, resolve, transport::BoxStream,
+};
+
+/// Maximum size of a request head.
+const MAX_HEAD: usize = 8192;
+
+/// Reads the CONNECT request of a client, returning the address to connect to and the client
+/// stream with any bytes sent after the request put back.
+pub async fn request(
+    mut stream: BoxStream,
+    auth: Option<&Credentials>,
+    peer_addr: SocketAddr,
+) -> io::Result<(SocketAddr, BoxStream)> {
+    // Read up to the blank line that ends the head.
+    let mut head = Vec::new();
+    let end = loop {
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let from = head.len().saturating_sub(3);
+        head.extend_from_slice(&buf[..n]);
+        let end = find(&head[from..], b"\r\n\r\n").map(|end| from + end + 4);
+        if end.unwrap_or(head.len()) > MAX_HEAD {
+            respond(&mut stream, "431 Request Header Fields Too Large").await?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP CONNECT request too large",
+            ));
+        }
+        if let Some(end) = end {
+            break end;
+        }
+    };
+    let rest = head.split_off(end);
+
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+    let mut start = lines.next().unwrap_or_default().split(' ');
+    let (method, target) = (start.next().unwrap_or_default(), start.next());
+    let header = |name: &str| {
+        lines
+            .clone()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    };
+
+    if method != "CONNECT" {
+        respond(&mut stream, "405 Method Not Allowed").await?;
+        return Err(io::Error::other(format!(
+            "unsupported HTTP proxy method {method}"
+        )));
+    }
+
+    if let Some(credentials) = auth {
+        let authorized = match header("Proxy-Authorization").and_then(basic_credentials) {
+            Some((user, password)) => credentials.check(&user, &password, peer_addr).await,
+            None => false,
+        };
+        if !authorized {
+            respond(
+                &mut stream,
+                "407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"portfwd\"",
+            )
+            .await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "HTTP CONNECT authentication failed",
+            ));
+        }
+    }
+
+    let Some((host, port)) = target
+        .and_then(|target| target.rsplit_once(':'))
+        .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+    else {
+        respond(&mut stream, "400 Bad Request").await?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid HTTP CONNECT target",
+        ));
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addr = match host.parse() {
+        Ok(ip) => SocketAddr::new(ip, port),
+        Err(_) => match resolve::resolve(host, port).await {
+            Ok(addr) => addr,
+            Err(err) => {
+                reply(&mut stream, false).await?;
+                return Err(err);
+            }
+        },
+    };
+
+    Ok((addr, Box::new(PeekedStream::new(rest, stream))))
+}
+
+/// Tells the client whether its tunnel was established.
+pub async fn reply(stream: &mut BoxStream, succeeded: bool) -> io::Result<()> {
+    match succeeded {
+        true => respond(stream, "200 Connection Established").await,
+        false => respond(stream, "502 Bad Gateway").await,
+    }
+}
+
+/// Sends a response without a body, given its status and any headers.
+///
+/// Failed requests are answered right before the connection is closed, which ends their body.
+async fn respond(stream: &mut BoxStream, status: &str) -> io::Result<()> {
+    let response = format!("HTTP/1.1 {status}\r\n\r\n");
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Decodes the user and password of a `Basic` authorization header.
+fn basic_credentials(value: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let colon = decoded.iter().position(|&b| b == b':')?;
+    Some((decoded[..colon].to_vec(), decoded[colon + 1..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ScriptedStream;
+
+    /// Reads a request, returning the address it asked for with the rest of the stream, and
+    /// what was sent back.
+    fn run(
+        script: &[u8],
+        auth: Option<&Credentials>,
+    ) -> (io::Result<(SocketAddr, Vec<u8>)>, String) {
+        let (stream, written) = ScriptedStream::boxed(script);
+        let result = smol::block_on(async {
+            let (addr, mut stream) =
+                request(stream, auth, "127.0.0.1:1234".parse().unwrap()).await?;
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await?;
+            Ok((addr, rest))
+        });
+        let written = String::from_utf8(written.lock().unwrap().clone()).unwrap();
+        (result, written)
+    }
+
+    #[test]
+    fn reads_connect_requests() {
+        let (result, written) = run(
+            b"CONNECT 10.0.0.1:443 HTTP/1.1\r\nHost: 10.0.0.1:443\r\n\r\n\x16\x03\x01",
+            None,
+        );
+        let (addr, rest) = result.unwrap();
+        assert_eq!(addr, "10.0.0.1:443".parse().unwrap());
+        // The bytes after the head are put back for the backend.
+        assert_eq!(rest, b"\x16\x03\x01");
+        assert!(written.is_empty());
+
+        let (result, _) = run(b"CONNECT [2001:db8::1]:8443 HTTP/1.1\r\n\r\n", None);
+        assert_eq!(result.unwrap().0, "[2001:db8::1]:8443".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let (result, written) = run(b"GET http://example.com/ HTTP/1.1\r\n\r\n", None);
+        assert!(result.is_err());
+        assert_eq!(written, "HTTP/1.1 405 Method Not Allowed\r\n\r\n");
+
+        for target in ["10.0.0.1", "10.0.0.1:https", ""] {
+            let request = format!("CONNECT {target} HTTP/1.1\r\n\r\n");
+            let (result, written) = run(request.as_bytes(), None);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert_eq!(written, "HTTP/1.1 400 Bad Request\r\n\r\n");
+        }
+
+        let (result, _) = run(b"CONNECT 10.0.0.1:443 HTTP/1.1\r\n", None);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let huge = format!(
+            "CONNECT 10.0.0.1:443 HTTP/1.1\r\nX: {}\r\n\r\n",
+            "a".repeat(MAX_HEAD)
+        );
+        let (result, written) = run(huge.as_bytes(), None);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(written.starts_with("HTTP/1.1 431 "));
+    }
+
+    #[test]
+    fn asks_for_credentials() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let auth: Credentials = format!("alice:{hash}").parse().unwrap();
+        let request = format!(
+            "CONNECT 10.0.0.1:443 HTTP/1.1\r\nproxy-authorization: basic {}\r\n\r\n",
+            STANDARD.encode("alice:secret")
+        );
+        let (result, _) = run(request.as_bytes(), Some(&auth));
+        assert_eq!(result.unwrap().0, "10.0.0.1:443".parse().unwrap());
+
+        let (result, written) = run(b"CONNECT 10.0.0.1:443 HTTP/1.1\r\n\r\n", Some(&auth));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(written.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"));
+        assert!(written.contains("Proxy-Authenticate: Basic"));
+    }
+
+    #[test]
+    fn decodes_basic_credentials() {
+        let encoded = STANDARD.encode("alice:pass:word");
+        assert_eq!(
+            basic_credentials(&format!("Basic {encoded}")),
+            Some((b"alice".to_vec(), b"pass:word".to_vec()))
+        );
+        assert_eq!(basic_credentials(&format!("Bearer {encoded}")), None);
+        assert_eq!(basic_credentials("Basic !!!"), None);
+        assert_eq!(
+            basic_credentials(&format!("Basic {}", STANDARD.encode("alice"))),
+            None
+        );
+        assert_eq!(basic_credentials("Basic"), None);
+    }
+}
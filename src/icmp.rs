@@ -0,0 +1,174 @@
+//! ICMP errors sent back to UDP clients when their datagrams can't reach a backend, for
+//! `--icmp-errors`.
+//!
+//! The error that a backend caused is reported to the socket that forwarded the datagram, and
+//! passed on to the client as if the client's own datagram to portfwd had failed: the ICMP
+//! message quotes a rebuilt IP and UDP header of that datagram, so that the client's stack can
+//! match it to its socket. Sending ICMP messages takes raw sockets, which need `CAP_NET_RAW`.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+
+use smol::{io, Async};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Why a datagram could not be delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unreachable {
+    Host,
+    Port,
+}
+
+impl Unreachable {
+    /// The cause of an error of a UDP socket, if it came from an ICMP error.
+    pub fn from_error(err: &io::Error) -> Option<Self> {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => Some(Unreachable::Port),
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+                Some(Unreachable::Host)
+            }
+            _ => None,
+        }
+    }
+
+    /// The type and code of the ICMP or ICMPv6 message.
+    fn type_code(self, v6: bool) -> (u8, u8) {
+        match (self, v6) {
+            (Unreachable::Host, false) => (3, 1),
+            (Unreachable::Port, false) => (3, 3),
+            (Unreachable::Host, true) => (1, 3),
+            (Unreachable::Port, true) => (1, 4),
+        }
+    }
+}
+
+impl fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Unreachable::Host => "host unreachable",
+            Unreachable::Port => "port unreachable",
+        })
+    }
+}
+
+/// Raw sockets to send ICMP and ICMPv6 errors from.
+pub struct IcmpSender {
+    v4: Option<Async<Socket>>,
+    v6: Option<Async<Socket>>,
+}
+
+impl IcmpSender {
+    /// Opens the raw sockets, failing if neither could be opened.
+    pub fn new() -> io::Result<Self> {
+        let open = |domain, protocol| {
+            let socket = Socket::new(domain, Type::RAW, Some(protocol))?;
+            Async::new(socket)
+        };
+        let v4 = open(Domain::IPV4, Protocol::ICMPV4);
+        let v6 = open(Domain::IPV6, Protocol::ICMPV6);
+        match (v4, v6) {
+            (Err(err), Err(_)) => Err(err),
+            (v4, v6) => Ok(Self {
+                v4: v4.ok(),
+                v6: v6.ok(),
+            }),
+        }
+    }
+
+    /// Tells a client that its datagram of `len` bytes to `local_addr` could not be delivered.
+    pub async fn send(
+        &self,
+        cause: Unreachable,
+        client: SocketAddr,
+        local_addr: SocketAddr,
+        len: usize,
+    ) -> io::Result<()> {
+        // Quote the datagram with the addresses the client used, so that it matches its socket,
+        // which can't be done if the address it sent to is unknown.
+        if local_addr.ip().is_unspecified() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the listener is bound to an unspecified address",
+            ));
+        }
+        let client = SocketAddr::new(client.ip().to_canonical(), client.port());
+        let local_addr = SocketAddr::new(local_addr.ip().to_canonical(), local_addr.port());
+        let udp_len = 8 + len as u16;
+        let mut udp = Vec::with_capacity(8);
+        udp.extend_from_slice(&client.port().to_be_bytes());
+        udp.extend_from_slice(&local_addr.port().to_be_bytes());
+        udp.extend_from_slice(&udp_len.to_be_bytes());
+        udp.extend_from_slice(&[0, 0]);
+
+        let (socket, quote) = match (client.ip(), local_addr.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let mut header = vec![0x45, 0];
+                header.extend_from_slice(&(20 + udp_len).to_be_bytes());
+                header.extend_from_slice(&[0, 0, 0, 0, 64, 17, 0, 0]);
+                header.extend_from_slice(&src.octets());
+                header.extend_from_slice(&dst.octets());
+                let checksum = checksum(&header);
+                header[10..12].copy_from_slice(&checksum.to_be_bytes());
+                (&self.v4, header)
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                let mut header = vec![0x60, 0, 0, 0];
+                header.extend_from_slice(&udp_len.to_be_bytes());
+                header.extend_from_slice(&[17, 64]);
+                header.extend_from_slice(&src.octets());
+                header.extend_from_slice(&dst.octets());
+                (&self.v6, header)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "client and listener addresses are of different families",
+                ))
+            }
+        };
+        let Some(socket) = socket else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "no raw socket for the address family of the client",
+            ));
+        };
+
+        // The kernel fills in the checksum of ICMPv6 messages on its own.
+        let (ty, code) = cause.type_code(client.is_ipv6());
+        let mut message = vec![ty, code, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&quote);
+        message.extend_from_slice(&udp);
+        if client.is_ipv4() {
+            let checksum = checksum(&message);
+            message[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+        let dest = SocketAddr::new(client.ip(), 0).into();
+        socket
+            .write_with(|socket| socket.send_to(&message, &dest))
+            .await?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for IcmpSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IcmpSender")
+            .field("v4", &self.v4.is_some())
+            .field("v6", &self.v6.is_some())
+            .finish()
+    }
+}
+
+/// The Internet checksum of RFC 1071.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|pair| u32::from(pair[0]) << 8 | u32::from(*pair.get(1).unwrap_or(&0)))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,356 @@
+//! The I/O primitives of smol, with a copy loop that sizes its buffer by throughput, and a
+//! reader that collects small responses.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+pub use smol::io::*;
+use smol::{future, Timer};
+
+use crate::meter::Meter;
+
+/// Initial and smallest size of a copy buffer.
+const MIN_BUF: usize = 8 * 1024;
+
+/// Largest size of a copy buffer.
+pub const MAX_BUF: usize = 256 * 1024;
+
+/// Length of the windows over which throughput is measured.
+const WINDOW: Duration = Duration::from_millis(100);
+
+/// Weight of the latest window in the moving average of the throughput.
+const ALPHA: f64 = 0.3;
+
+/// How long a connection may go without data before its buffer shrinks back to the minimum.
+const IDLE: Duration = Duration::from_secs(1);
+
+/// Copies all bytes from a reader to a writer, like [`copy`], returning how many were copied.
+///
+/// The buffer starts at 8 KiB. It doubles, up to 256 KiB, while the throughput fills it more
+/// than 8 times per window of 100 ms, and halves while it is not filled once per window. After a
+/// second without data it drops back to 8 KiB, so that idle connections hold little memory.
+pub async fn adaptive_copy<R, W>(reader: R, writer: W) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    metered_copy(reader, writer, None).await
+}
+
+/// Copies like [`adaptive_copy`], but with a meter it keeps reading while a write is pending,
+/// queuing what it read in the meter, which fails the copy once the queue exceeds its limit.
+pub async fn metered_copy<R, W>(
+    mut reader: R,
+    mut writer: W,
+    mut meter: Option<Meter>,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; MIN_BUF];
+    let mut total = 0;
+    let mut idle = Timer::never();
+
+    // Bytes per second, as an exponential moving average over the windows.
+    let mut rate: Option<f64> = None;
+    let mut window_start = Instant::now();
+    let mut window_bytes = 0;
+
+    loop {
+        // The queued bytes are written while the next ones are read.
+        let drain = async {
+            if let Some(meter) = &mut meter {
+                while !meter.queued().is_empty() {
+                    match writer.write(meter.queued()).await {
+                        Ok(0) => return Some(Err(ErrorKind::WriteZero.into())),
+                        Ok(n) => meter.consume(n),
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
+            }
+            future::pending().await
+        };
+
+        // Large buffers are released when no data arrives for a while.
+        let read = async {
+            if buf.len() <= MIN_BUF {
+                return Some(reader.read(&mut buf).await);
+            }
+            idle.set_after(IDLE);
+            let read = async { Some(reader.read(&mut buf).await) };
+            let timeout = async {
+                (&mut idle).await;
+                None
+            };
+            future::or(read, timeout).await
+        };
+
+        let read = future::or(drain, read).await;
+        let n = match read {
+            Some(n) => n?,
+            None => {
+                tracing::trace!(
+                    "Resizing copy buffer of idle connection to {} bytes",
+                    MIN_BUF
+                );
+                buf = vec![0; MIN_BUF];
+                rate = None;
+                if let Some(meter) = &mut meter {
+                    meter.shrink();
+                }
+                continue;
+            }
+        };
+        if n == 0 {
+            if let Some(meter) = &mut meter {
+                while !meter.queued().is_empty() {
+                    let n = meter.queued().len();
+                    writer.write_all(meter.queued()).await?;
+                    meter.consume(n);
+                }
+            }
+            writer.flush().await?;
+            return Ok(total);
+        }
+        match &mut meter {
+            Some(meter) => meter.push(&buf[..n])?,
+            None => writer.write_all(&buf[..n]).await?,
+        }
+        total += n as u64;
+        window_bytes += n;
+
+        // Resize the buffer at the end of each window.
+        let elapsed = window_start.elapsed();
+        if elapsed >= WINDOW {
+            let sample = window_bytes as f64 / elapsed.as_secs_f64();
+            let average = rate.map_or(sample, |rate| ALPHA * sample + (1.0 - ALPHA) * rate);
+            rate = Some(average);
+            window_start = Instant::now();
+            window_bytes = 0;
+
+            let per_window = average * WINDOW.as_secs_f64();
+            let len = if per_window > (buf.len() * 8) as f64 && buf.len() < MAX_BUF {
+                buf.len() * 2
+            } else if per_window < buf.len() as f64 && buf.len() > MIN_BUF {
+                buf.len() / 2
+            } else {
+                continue;
+            };
+            tracing::trace!(
+                "Resizing copy buffer to {} bytes at {:.0} B/s",
+                len,
+                average
+            );
+            buf = vec![0; len];
+        }
+    }
+}
+
+/// A reader that collects what is available from its inner reader, up to a limit, before
+/// handing it out, so that a small response is passed on in one write instead of many.
+///
+/// It only waits for more bytes while the inner reader has them ready; once it would block, the
+/// collected bytes are handed out. Larger responses are streamed in chunks of the limit. An
+/// error of the inner reader is returned once the bytes collected before it are handed out.
+pub struct BufferedReader<R> {
+    inner: R,
+    /// Nothing is collected without a buffer.
+    buf: Option<Box<[u8]>>,
+    pos: usize,
+    filled: usize,
+    eof: bool,
+    /// The error that ended the last collection, returned after its bytes.
+    error: Option<Error>,
+}
+
+impl<R> BufferedReader<R> {
+    /// Wraps a reader, collecting up to `limit` bytes, or passing reads through if there is none.
+    pub fn new(inner: R, limit: Option<usize>) -> Self {
+        Self {
+            inner,
+            buf: limit.map(|limit| vec![0; limit].into_boxed_slice()),
+            pos: 0,
+            filled: 0,
+            eof: false,
+            error: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for BufferedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        let this = &mut *self;
+        let Some(buf) = &mut this.buf else {
+            return Pin::new(&mut this.inner).poll_read(cx, out);
+        };
+
+        // Collect bytes once the last ones were handed out, until the buffer is full, or the
+        // inner reader has no more right now.
+        if this.pos == this.filled {
+            if let Some(err) = this.error.take() {
+                return Poll::Ready(Err(err));
+            }
+            this.pos = 0;
+            this.filled = 0;
+            while this.filled < buf.len() && !this.eof {
+                match Pin::new(&mut this.inner).poll_read(cx, &mut buf[this.filled..]) {
+                    Poll::Ready(Ok(0)) => this.eof = true,
+                    Poll::Ready(Ok(n)) => this.filled += n,
+                    Poll::Ready(Err(err)) if this.filled == 0 => return Poll::Ready(Err(err)),
+                    Poll::Ready(Err(err)) => {
+                        this.error = Some(err);
+                        break;
+                    }
+                    Poll::Pending if this.filled == 0 => return Poll::Pending,
+                    Poll::Pending => break,
+                }
+            }
+        }
+
+        let n = (this.filled - this.pos).min(out.len());
+        out[..n].copy_from_slice(&buf[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// What a [`Steps`] reader does on each read.
+    enum Step {
+        Data(&'static [u8]),
+        /// Would block, but is ready again right away.
+        Pending,
+        Fail,
+    }
+
+    /// A reader that takes its steps in order, and then ends.
+    struct Steps(VecDeque<Step>);
+
+    impl Steps {
+        fn new(steps: impl IntoIterator<Item = Step>) -> Self {
+            Self(steps.into_iter().collect())
+        }
+    }
+
+    impl AsyncRead for Steps {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize>> {
+            match self.0.pop_front() {
+                Some(Step::Data(data)) => {
+                    let n = data.len().min(buf.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    if n < data.len() {
+                        self.0.push_front(Step::Data(&data[n..]));
+                    }
+                    Poll::Ready(Ok(n))
+                }
+                Some(Step::Pending) => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                Some(Step::Fail) => Poll::Ready(Err(ErrorKind::ConnectionReset.into())),
+                None => Poll::Ready(Ok(0)),
+            }
+        }
+    }
+
+    /// Reads once from a reader.
+    fn read_once(reader: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+        smol::block_on(async {
+            let mut buf = [0; 64];
+            let n = reader.read(&mut buf).await?;
+            Ok(buf[..n].to_vec())
+        })
+    }
+
+    #[test]
+    fn collects_what_is_ready() {
+        use Step::*;
+        let steps = Steps::new([Data(b"ab"), Data(b"cd"), Pending, Data(b"ef")]);
+        let mut reader = BufferedReader::new(steps, Some(16));
+        assert_eq!(read_once(&mut reader).unwrap(), b"abcd");
+        assert_eq!(read_once(&mut reader).unwrap(), b"ef");
+        assert_eq!(read_once(&mut reader).unwrap(), b"");
+    }
+
+    #[test]
+    fn streams_in_chunks_of_the_limit() {
+        let steps = Steps::new([Step::Data(b"abcdefg")]);
+        let mut reader = BufferedReader::new(steps, Some(3));
+        assert_eq!(read_once(&mut reader).unwrap(), b"abc");
+        assert_eq!(read_once(&mut reader).unwrap(), b"def");
+        assert_eq!(read_once(&mut reader).unwrap(), b"g");
+        assert_eq!(read_once(&mut reader).unwrap(), b"");
+    }
+
+    #[test]
+    fn hands_out_the_bytes_before_an_error() {
+        use Step::*;
+        let steps = Steps::new([Data(b"ab"), Data(b"cd"), Fail]);
+        let mut reader = BufferedReader::new(steps, Some(16));
+        assert_eq!(read_once(&mut reader).unwrap(), b"abcd");
+        let err = read_once(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn passes_reads_through_without_a_limit() {
+        use Step::*;
+        let steps = Steps::new([Data(b"ab"), Data(b"cd")]);
+        let mut reader = BufferedReader::new(steps, None);
+        assert_eq!(read_once(&mut reader).unwrap(), b"ab");
+        assert_eq!(read_once(&mut reader).unwrap(), b"cd");
+    }
+
+    #[test]
+    fn copies_everything() {
+        let data: Vec<u8> = (0..3 * MAX_BUF + 5).map(|i| i as u8).collect();
+        smol::block_on(async {
+            let mut out = Vec::new();
+            let copied = adaptive_copy(Cursor::new(&data), &mut out).await.unwrap();
+            assert_eq!(copied, data.len() as u64);
+            assert_eq!(out, data);
+
+            let mut out = Vec::new();
+            let copied = metered_copy(Cursor::new(&data), &mut out, Some(Meter::new(MAX_BUF)))
+                .await
+                .unwrap();
+            assert_eq!(copied, data.len() as u64);
+            assert_eq!(out, data);
+
+            let copied = adaptive_copy(Cursor::new(b""), &mut Vec::new())
+                .await
+                .unwrap();
+            assert_eq!(copied, 0);
+        });
+    }
+
+    #[test]
+    fn fails_with_the_reader() {
+        use Step::*;
+        smol::block_on(async {
+            let mut out = Vec::new();
+            let err = adaptive_copy(Steps::new([Data(b"ab"), Fail]), &mut out)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+            assert_eq!(out, b"ab");
+        });
+    }
+}
@@ -0,0 +1,386 @@
+//! Persistent connections to HTTP/1.1 backends, shared by the requests of all clients, for
+//! `--http-keepalive`.
+//!
+//! Each request of a client is sent over an idle connection to its backend if there is one,
+//! and the connection is put back once the response has been read, unless either side asked to
+//! close it.
+//!
+//! With `--reconnect-on-error`, requests that may be repeated are kept, up to
+//! `--reconnect-buffer` bytes. If the backend drops the connection before the end of the
+//! response to one, it is sent again over a new connection, and the client is passed the rest of
+//! the response from the new one, as long as both have the same strong `ETag`. The client
+//! connection fails instead if they differ, or if the response has none, as a weak `ETag` or a
+//! `Last-Modified` date doesn't tell that the bytes are the same.
+
+use std::{collections::HashMap, fmt, net::SocketAddr, sync::Mutex};
+
+use smol::{
+    future,
+    io::{self, AsyncReadExt, AsyncWriteExt, BufReader},
+};
+
+use crate::{
+    config::Config,
+    metrics::Counter,
+    protocols::{
+        http,
+        http11::{self, BodyLength, Head, Progress},
+    },
+    reconnect,
+    transport::BoxStream,
+};
+
+/// Maximum number of idle connections kept to each backend.
+const MAX_IDLE: usize = 32;
+
+type Conn = BufReader<BoxStream>;
+
+/// Idle connections to the backends.
+#[derive(Default)]
+pub struct HttpPool {
+    idle: Mutex<HashMap<SocketAddr, Vec<Conn>>>,
+}
+
+impl HttpPool {
+    /// Takes an idle connection to a backend that is still open, or connects a new one.
+    ///
+    /// Returns whether the connection was reused.
+    async fn get(&self, backend: SocketAddr, config: &Config) -> io::Result<(Conn, bool)> {
+        loop {
+            let conn = self
+                .idle
+                .lock()
+                .unwrap()
+                .get_mut(&backend)
+                .and_then(Vec::pop);
+            let Some(mut conn) = conn else {
+                break;
+            };
+            // Bytes after the last response would be taken for the start of the next one.
+            if !conn.buffer().is_empty() {
+                tracing::debug!(
+                    "Dropping idle connection to {} with bytes after the last response",
+                    backend
+                );
+                continue;
+            }
+            // The backend may have closed the connection while it was idle, which shows once
+            // it can be read without waiting.
+            let mut byte = [0];
+            if future::poll_once(conn.get_mut().read(&mut byte))
+                .await
+                .is_none()
+            {
+                return Ok((conn, true));
+            }
+            tracing::debug!("Dropping idle connection to {} that was closed", backend);
+        }
+        let stream = crate::connect_backend(backend, config).await?;
+        Ok((BufReader::new(stream), false))
+    }
+
+    /// Puts a connection back, unless there are enough idle connections to its backend.
+    fn put(&self, backend: SocketAddr, conn: Conn) {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.entry(backend).or_default();
+        if conns.len() < MAX_IDLE {
+            conns.push(conn);
+        }
+    }
+}
+
+impl fmt::Debug for HttpPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let idle = self.idle.lock().unwrap();
+        f.debug_map()
+            .entries(idle.iter().map(|(backend, conns)| (backend, conns.len())))
+            .finish()
+    }
+}
+
+/// Forwards the requests of a client to a backend one after another, over pooled connections,
+/// adding the bytes transferred to a counter.
+pub async fn serve(
+    pool: &HttpPool,
+    client: BoxStream,
+    peer_addr: SocketAddr,
+    conn_id: u64,
+    backend: SocketAddr,
+    config: &Config,
+    bytes: &Counter,
+) -> io::Result<()> {
+    let mut client = BufReader::new(client);
+    loop {
+        // Read the next request, until the client closes the connection.
+        let Some(request) = http11::read_head(&mut client).await? else {
+            tracing::info!("Client closed connection: {}", peer_addr);
+            return Ok(());
+        };
+        let request_body = request.request_body()?;
+
+        // Send the request over a pooled connection.
+        let (mut conn, reused) = pool.get(backend, config).await?;
+        tracing::debug!(
+            "Forwarding {} from {} over {} connection to {}",
+            request.start_line(),
+            peer_addr,
+            if reused { "a pooled" } else { "a new" },
+            backend
+        );
+        let client_ip = config.inject_xff.then_some(peer_addr.ip());
+        let request_id = config.inject_request_id.then(http::request_id);
+        if let Some(id) = &request_id {
+            tracing::debug!("Request {} of connection {}", id, conn_id);
+        }
+        let rewritten = http::rewrite_head(
+            &request.raw,
+            &config.host_rewrites,
+            client_ip,
+            request_id.as_deref(),
+        );
+        if rewritten.is_some() {
+            tracing::debug!("Rewrote the request head of {}", peer_addr);
+        }
+        let head = rewritten.as_deref().unwrap_or(&request.raw);
+
+        // Keep the request to send it again if the backend drops the response, if it may be
+        // repeated and fits in the buffer.
+        let replayable = config.reconnect_buffer.is_some_and(|capacity| {
+            let len = match request_body {
+                BodyLength::Empty => Some(0),
+                BodyLength::Fixed(len) => Some(len),
+                _ => None,
+            };
+            request.is_idempotent()
+                && len.is_some_and(|len| head.len() as u64 + len <= capacity as u64)
+        });
+        let (body, replay) = if replayable {
+            let mut replay = head.to_vec();
+            http11::copy_body(&mut client, &mut replay, request_body).await?;
+            conn.get_mut().write_all(&replay).await?;
+            ((replay.len() - head.len()) as u64, Some(replay))
+        } else {
+            conn.get_mut().write_all(head).await?;
+            let body = http11::copy_body(&mut client, conn.get_mut(), request_body).await?;
+            (body, None)
+        };
+        conn.get_mut().flush().await?;
+        bytes.add(request.raw.len() as u64 + body);
+
+        // Pass on the response, sending the request again over a new connection each time the
+        // backend drops it, if it was kept.
+        let mut response = None;
+        let mut retries = 0;
+        loop {
+            let result = pass_response(
+                &mut conn,
+                client.get_mut(),
+                request.method(),
+                &mut response,
+                bytes,
+            )
+            .await;
+            match (result, &replay) {
+                (Ok(()), _) => break,
+                (Err(Failure::Dropped(err)), Some(replay)) if retries < reconnect::MAX_ATTEMPTS => {
+                    retries += 1;
+                    tracing::warn!(
+                        "Backend {} dropped the response to {} ({}), sending it again",
+                        backend,
+                        request.start_line(),
+                        err
+                    );
+                    conn = BufReader::new(crate::connect_backend(backend, config).await?);
+                    conn.get_mut().write_all(replay).await?;
+                    conn.get_mut().flush().await?;
+                }
+                (Err(Failure::Dropped(err) | Failure::Other(err)), _) => return Err(err),
+            }
+        }
+        let Response {
+            head: response,
+            body: response_body,
+            ..
+        } = response.expect("the response was passed on");
+        // The connection switches to another protocol, and is no longer HTTP.
+        if response.status() == Some(101) {
+            return tunnel(client, conn, bytes).await;
+        }
+        client.get_mut().flush().await?;
+
+        // Keep the backend connection for the next request, unless it has to be closed.
+        let closes = request.closes() || response.closes();
+        if !closes && response_body != BodyLength::UntilClose {
+            pool.put(backend, conn);
+        }
+        if closes || response_body == BodyLength::UntilClose {
+            client.get_mut().close().await?;
+            return Ok(());
+        }
+    }
+}
+
+/// The final response to a request, as passed on to the client.
+struct Response {
+    head: Head,
+    body: BodyLength,
+    progress: Progress,
+}
+
+impl Response {
+    /// Whether another response to the same request is the same one, as far as its head tells,
+    /// so that the rest of its body can be passed on in place of this one. Only a strong `ETag`
+    /// tells that the bytes are the same, so responses without one never are.
+    fn same_as(&self, head: &Head, body: BodyLength) -> bool {
+        head.status() == self.head.status()
+            && strong_etag(head).is_some()
+            && strong_etag(head) == strong_etag(&self.head)
+            && matches!(body, BodyLength::Fixed(_) | BodyLength::Chunked)
+            && !matches!((self.body, body), (BodyLength::Fixed(a), BodyLength::Fixed(b)) if a != b)
+    }
+}
+
+/// The `ETag` of a response, unless it is a weak one.
+fn strong_etag(head: &Head) -> Option<&str> {
+    head.header("ETag").filter(|tag| tag.starts_with('"'))
+}
+
+/// Why passing on a response failed.
+enum Failure {
+    /// The backend dropped the connection, or closed it before the end of the response.
+    Dropped(io::Error),
+    Other(io::Error),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Other(err)
+    }
+}
+
+/// Passes on the response to a request, after any interim responses, or if the start of
+/// `passed` already was, the rest of it from this copy of it.
+async fn pass_response(
+    conn: &mut Conn,
+    client: &mut BoxStream,
+    method: &str,
+    passed: &mut Option<Response>,
+    bytes: &Counter,
+) -> Result<(), Failure> {
+    let head = loop {
+        let head = match http11::read_head(conn).await {
+            Ok(Some(head)) => head,
+            Ok(None) => return Err(Failure::Dropped(io::ErrorKind::UnexpectedEof.into())),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => return Err(err.into()),
+            Err(err) => return Err(Failure::Dropped(err)),
+        };
+        match head.status() {
+            Some(101) => break head,
+            // Interim responses were passed on with the start of the response.
+            Some(100..=199) if passed.is_some() => {}
+            Some(100..=199) => {
+                client.write_all(&head.raw).await?;
+                bytes.add(head.raw.len() as u64);
+            }
+            _ => break head,
+        }
+    };
+    let body = head.response_body(method)?;
+
+    let resumed = passed.is_some();
+    let response = match passed {
+        Some(response) if response.same_as(&head, body) => response,
+        Some(_) => {
+            return Err(
+                io::Error::other("the backend answered the repeated request differently").into(),
+            )
+        }
+        None => {
+            client.write_all(&head.raw).await?;
+            bytes.add(head.raw.len() as u64);
+            passed.insert(Response {
+                head,
+                body,
+                progress: Progress::default(),
+            })
+        }
+    };
+    if response.head.status() == Some(101) {
+        return Ok(());
+    }
+    let result = if resumed {
+        http11::resume_body(conn, client, body, response.body, &mut response.progress).await
+    } else {
+        http11::copy_body_tracked(conn, client, body, &mut response.progress).await
+    };
+    match result {
+        Ok(n) => {
+            bytes.add(n);
+            Ok(())
+        }
+        Err(err) if response.progress.dropped => Err(Failure::Dropped(err)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Copies bytes in both directions until either side closes, once a connection upgraded.
+async fn tunnel(client: BufReader<BoxStream>, conn: Conn, bytes: &Counter) -> io::Result<()> {
+    let (client_reader, mut client_writer) = io::split(client);
+    let (conn_reader, mut conn_writer) = io::split(conn);
+    let (up, down) = future::try_zip(
+        async {
+            let n = io::copy(client_reader, &mut conn_writer).await?;
+            conn_writer.close().await?;
+            Ok(n) as io::Result<u64>
+        },
+        async {
+            let n = io::copy(conn_reader, &mut client_writer).await?;
+            client_writer.close().await?;
+            Ok(n) as io::Result<u64>
+        },
+    )
+    .await?;
+    bytes.add(up + down);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(headers: &str) -> Head {
+        Head {
+            raw: format!("HTTP/1.1 200 OK\r\n{headers}Content-Length: 5\r\n\r\n").into_bytes(),
+        }
+    }
+
+    fn response(headers: &str) -> Response {
+        Response {
+            head: head(headers),
+            body: BodyLength::Fixed(5),
+            progress: Progress::default(),
+        }
+    }
+
+    #[test]
+    fn resumes_only_responses_with_the_same_strong_etag() {
+        let tagged = response("ETag: \"a\"\r\n");
+        assert!(tagged.same_as(&head("ETag: \"a\"\r\n"), BodyLength::Fixed(5)));
+        assert!(!tagged.same_as(&head("ETag: \"b\"\r\n"), BodyLength::Fixed(5)));
+        assert!(!tagged.same_as(&head(""), BodyLength::Fixed(5)));
+        assert!(!tagged.same_as(&head("ETag: \"a\"\r\n"), BodyLength::Fixed(6)));
+    }
+
+    #[test]
+    fn never_resumes_responses_with_a_weak_etag() {
+        let weak = "ETag: W/\"a\"\r\n";
+        assert!(!response(weak).same_as(&head(weak), BodyLength::Fixed(5)));
+    }
+
+    #[test]
+    fn never_resumes_responses_with_only_a_date() {
+        let date = "Last-Modified: Tue, 13 Oct 2026 00:00:00 GMT\r\n";
+        assert!(!response(date).same_as(&head(date), BodyLength::Fixed(5)));
+        assert!(!response("").same_as(&head(""), BodyLength::Fixed(5)));
+    }
+}
@@ -0,0 +1,154 @@
+//! Port knocking, for `--port-knock`.
+//!
+//! Clients are only forwarded once they connected to a sequence of ports in order, within a
+//! short window. Each address that completes the sequence is allowed for a while; a knock on the
+//! wrong port starts the sequence over.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr, TcpListener},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use smol::Async;
+
+/// How long a client has to complete the sequence, from its first knock.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// Ports to knock on in order, given as `<P1>:<P2>:...`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sequence(Vec<u16>);
+
+impl Sequence {
+    /// The ports, in order.
+    pub fn ports(&self) -> &[u16] {
+        &self.0
+    }
+}
+
+impl FromStr for Sequence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ports = s
+            .split(':')
+            .map(|port| port.parse().map_err(|e| format!("{e}: {port}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Sequence(ports))
+    }
+}
+
+impl fmt::Display for Sequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ports: Vec<_> = self.0.iter().map(u16::to_string).collect();
+        f.write_str(&ports.join(":"))
+    }
+}
+
+/// How far an address got in the sequence.
+struct Progress {
+    /// Index of the next port to knock on.
+    next: usize,
+    started: Instant,
+}
+
+/// The knocks of each address, and the addresses that completed the sequence.
+pub struct Knocker {
+    sequence: Sequence,
+    allow_for: Duration,
+    progress: Mutex<HashMap<IpAddr, Progress>>,
+    /// When each allowed address stops being allowed.
+    allowed: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Knocker {
+    /// Creates a knocker that allows addresses for `allow_for` once they knocked on `sequence`.
+    pub fn new(sequence: Sequence, allow_for: Duration) -> Self {
+        Self {
+            sequence,
+            allow_for,
+            progress: Mutex::new(HashMap::new()),
+            allowed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The ports to knock on.
+    pub fn sequence(&self) -> &Sequence {
+        &self.sequence
+    }
+
+    /// Records a knock of an address on a port, allowing the address if that completed the
+    /// sequence.
+    fn knock(&self, ip: IpAddr, port: u16) {
+        let ports = self.sequence.ports();
+        let mut progress = self.progress.lock().unwrap();
+        progress.retain(|_, p| p.started.elapsed() < WINDOW);
+        let current = progress.get(&ip).map_or(0, |p| p.next);
+        let next = if ports[current] == port {
+            current + 1
+        } else if ports[0] == port {
+            // A wrong knock may still be the first of a new sequence.
+            1
+        } else {
+            tracing::debug!("Wrong knock from {} on port {}", ip, port);
+            progress.remove(&ip);
+            return;
+        };
+        if next == ports.len() {
+            progress.remove(&ip);
+            tracing::info!("Allowing {} after knocking", ip);
+            let now = Instant::now();
+            let mut allowed = self.allowed.lock().unwrap();
+            allowed.retain(|_, &mut until| until > now);
+            allowed.insert(ip, now + self.allow_for);
+            return;
+        }
+        tracing::debug!("Knock {} of {} from {}", next, ports.len(), ip);
+        let started = match progress.get(&ip) {
+            Some(p) if next > 1 => p.started,
+            _ => Instant::now(),
+        };
+        progress.insert(ip, Progress { next, started });
+    }
+
+    /// Whether an address completed the sequence recently enough.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let mut allowed = self.allowed.lock().unwrap();
+        match allowed.get(&ip) {
+            Some(&until) if until > Instant::now() => true,
+            Some(_) => {
+                allowed.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Listens for knocks on one of the ports of the sequence, closing each connection right
+    /// after it was accepted.
+    pub async fn listen(self: Arc<Self>, addr: SocketAddr) -> std::io::Result<()> {
+        let listener = Async::<TcpListener>::bind(addr)?;
+        tracing::debug!(
+            "Listening for knocks on {}",
+            listener.get_ref().local_addr()?
+        );
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            drop(stream);
+            self.knock(peer_addr.ip().to_canonical(), addr.port());
+        }
+    }
+}
+
+impl fmt::Debug for Knocker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Knocker")
+            .field("sequence", &self.sequence)
+            .field("allow_for", &self.allow_for)
+            .field("allowed", &self.allowed.lock().unwrap().len())
+            .finish()
+    }
+}
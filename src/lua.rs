@@ -0,0 +1,283 @@
+//! Routing scripts written in Lua, for `--lua-script` in builds with the `lua` feature.
+//!
+//! A script defines a global function `route(src_ip, src_port, proto)`, which is called with the
+//! address of each new TCP client and of each UDP datagram, and `"tcp"` or `"udp"`. It returns
+//! where the client goes:
+//!
+//! - a backend address such as `"10.0.0.2:80"`, to forward the client there;
+//! - `"reject"`, to reset the TCP connection or discard the datagram;
+//! - `"drop"`, to close the TCP connection without a word or discard the datagram;
+//! - `nil`, to forward the client as without the script.
+//!
+//! Scripts run without the `io`, `os`, `package` and `debug` libraries, or the functions that load
+//! other chunks, so they can't reach the files or anything else of the host, and `print` goes to
+//! the debug log. Each call may only run for a limited number of instructions, as it holds up the
+//! executor thread it runs on, and a call that fails, runs out of them or returns anything else
+//! rejects the client.
+//!
+//! The script is compiled once at startup, and again on SIGHUP, when a script that fails to
+//! compile leaves the last one in place.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use smol::io;
+
+use crate::routing::Decision;
+#[cfg(feature = "lua")]
+use crate::routing::Fallback;
+
+/// The routing script, which is replaced when it is compiled again.
+#[cfg(feature = "lua")]
+pub struct LuaScript {
+    path: PathBuf,
+    compiled: std::sync::Mutex<Compiled>,
+}
+
+/// A routing script, which can't be loaded without the `lua` feature.
+#[cfg(not(feature = "lua"))]
+pub struct LuaScript {
+    path: PathBuf,
+    never: std::convert::Infallible,
+}
+
+/// A compiled script, which defines the `route` function.
+#[cfg(feature = "lua")]
+struct Compiled {
+    lua: mlua::Lua,
+    /// The instructions run by the current call, in thousands.
+    ran: std::sync::Arc<std::sync::atomic::AtomicU32>,
+}
+
+/// How many thousand instructions a single call of `route` may run.
+#[cfg(feature = "lua")]
+const BUDGET: u32 = 10_000;
+
+#[cfg(feature = "lua")]
+impl LuaScript {
+    /// Compiles a script. Errors name the script.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            compiled: std::sync::Mutex::new(compile(path)?),
+        })
+    }
+
+    /// Compiles the script again, keeping the last one if it fails to.
+    pub fn reload(&self) {
+        match compile(&self.path) {
+            Ok(compiled) => {
+                *self.compiled.lock().unwrap() = compiled;
+                tracing::info!("Reloaded {}", self.path.display());
+            }
+            Err(err) => tracing::warn!("Kept the last Lua script: {}", err),
+        }
+    }
+
+    /// Routes a client by calling the `route` function of the script.
+    pub fn route(&self, peer_addr: SocketAddr, proto: &str) -> Decision {
+        use std::sync::atomic::Ordering;
+
+        // Calls take turns, as they share the state and its count of instructions.
+        let compiled = self.compiled.lock().unwrap();
+        compiled.ran.store(0, Ordering::Relaxed);
+        let ip = peer_addr.ip().to_canonical().to_string();
+        let routed = compiled
+            .lua
+            .globals()
+            .raw_get::<mlua::Function>("route")
+            .and_then(|route| route.call::<Option<String>>((ip, peer_addr.port(), proto)))
+            .map_err(|err| err.to_string())
+            .and_then(|routed| match routed.as_deref() {
+                None => Ok(Decision::Default),
+                Some("reject") => Ok(Decision::Refuse(Fallback::Reject)),
+                Some("drop") => Ok(Decision::Refuse(Fallback::Drop)),
+                Some(forward) => forward
+                    .parse()
+                    .map(Decision::Forward)
+                    .map_err(|err| format!("{err}: {forward}")),
+            });
+        routed.unwrap_or_else(|err| {
+            tracing::warn!("Rejected {}: {}: {}", peer_addr, self.path.display(), err);
+            Decision::Refuse(Fallback::Reject)
+        })
+    }
+
+    /// Compiles the script again on each SIGHUP.
+    #[cfg(unix)]
+    pub fn reload_on_hangup(self: &std::sync::Arc<Self>) -> io::Result<()> {
+        use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+        let mut signals = Signals::new([SIGHUP])?;
+        let script = std::sync::Arc::downgrade(self);
+        std::thread::Builder::new()
+            .name("lua-reload".to_string())
+            .spawn(move || {
+                for _ in signals.forever() {
+                    match script.upgrade() {
+                        Some(script) => script.reload(),
+                        None => return,
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn reload_on_hangup(self: &std::sync::Arc<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compiles a script in a state of its own, without the libraries that reach the host.
+#[cfg(feature = "lua")]
+fn compile(path: &Path) -> io::Result<Compiled> {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use mlua::{HookTriggers, Lua, LuaOptions, StdLib, VmState};
+
+    let failed = |err: mlua::Error| io::Error::other(format!("{}: {err}", path.display()));
+    let source = std::fs::read(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+    let libs = StdLib::COROUTINE | StdLib::TABLE | StdLib::STRING | StdLib::UTF8 | StdLib::MATH;
+    let lua = Lua::new_with(libs, LuaOptions::default()).map_err(failed)?;
+    let globals = lua.globals();
+    for name in ["dofile", "loadfile", "load", "require"] {
+        globals.raw_set(name, mlua::Nil).map_err(failed)?;
+    }
+    let print = lua
+        .create_function(|_, args: mlua::MultiValue| {
+            let args: Vec<_> = args
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect::<Result<_, _>>()?;
+            tracing::debug!("{}", args.join("\t"));
+            Ok(())
+        })
+        .map_err(failed)?;
+    globals.raw_set("print", print).map_err(failed)?;
+
+    let ran = Arc::new(AtomicU32::new(0));
+    let counter = ran.clone();
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(1000),
+        move |_, _| {
+            if counter.fetch_add(1, Ordering::Relaxed) >= BUDGET {
+                return Err(mlua::Error::runtime("ran out of instructions"));
+            }
+            Ok(VmState::Continue)
+        },
+    )
+    .map_err(failed)?;
+    lua.load(source)
+        .set_name(format!("@{}", path.display()))
+        .exec()
+        .map_err(failed)?;
+    if globals
+        .raw_get::<Option<mlua::Function>>("route")
+        .map_err(failed)?
+        .is_none()
+    {
+        return Err(failed(mlua::Error::runtime(
+            "no `route` function is defined",
+        )));
+    }
+    drop(globals);
+    Ok(Compiled { lua, ran })
+}
+
+#[cfg(not(feature = "lua"))]
+impl LuaScript {
+    pub fn load(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Lua scripts need portfwd to be built with the `lua` feature",
+        ))
+    }
+
+    pub fn route(&self, _peer_addr: SocketAddr, _proto: &str) -> Decision {
+        match self.never {}
+    }
+
+    pub fn reload_on_hangup(self: &std::sync::Arc<Self>) -> io::Result<()> {
+        match self.never {}
+    }
+}
+
+impl fmt::Debug for LuaScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LuaScript")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "lua"))]
+mod tests {
+    use super::*;
+
+    fn script(name: &str, source: &str) -> LuaScript {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, source).unwrap();
+        LuaScript::load(&path).unwrap()
+    }
+
+    #[test]
+    fn routes_by_what_the_script_returns() {
+        let script = script(
+            "portfwd-test-route.lua",
+            r#"
+            function route(ip, port, proto)
+                if proto == "udp" then return nil end
+                if port == 1 then return "reject" end
+                if port == 2 then return "drop" end
+                if port == 3 then return "nowhere" end
+                return ip .. ":80"
+            end
+            "#,
+        );
+        let peer = |port| SocketAddr::from(([10, 0, 0, 1], port));
+        assert_eq!(
+            script.route(peer(4), "tcp"),
+            Decision::Forward("10.0.0.1:80".parse().unwrap())
+        );
+        assert_eq!(script.route(peer(4), "udp"), Decision::Default);
+        assert_eq!(
+            script.route(peer(1), "tcp"),
+            Decision::Refuse(Fallback::Reject)
+        );
+        assert_eq!(
+            script.route(peer(2), "tcp"),
+            Decision::Refuse(Fallback::Drop)
+        );
+        assert_eq!(
+            script.route(peer(3), "tcp"),
+            Decision::Refuse(Fallback::Reject)
+        );
+    }
+
+    #[test]
+    fn sandboxes_the_script() {
+        let script = script(
+            "portfwd-test-sandbox.lua",
+            r#"
+            function route(ip, port, proto)
+                if port == 1 then while true do end end
+                return io or os or dofile or load or require
+            end
+            "#,
+        );
+        let peer = |port| SocketAddr::from(([10, 0, 0, 1], port));
+        assert_eq!(script.route(peer(2), "tcp"), Decision::Default);
+        assert_eq!(
+            script.route(peer(1), "tcp"),
+            Decision::Refuse(Fallback::Reject)
+        );
+    }
+}
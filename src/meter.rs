@@ -0,0 +1,87 @@
+//! The limit on the bytes that one direction of a TCP connection holds for a slow destination,
+//! for `--per-conn-mem-limit`.
+
+use std::{collections::VecDeque, error::Error, fmt};
+
+use smol::io;
+
+/// The bytes of one direction of a connection that were read from one side while a write to the
+/// other was still pending, failing the connection once they exceed a limit.
+#[derive(Debug)]
+pub struct Meter {
+    queue: VecDeque<u8>,
+    limit: usize,
+}
+
+impl Meter {
+    /// Creates a meter for one direction of a new connection.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            limit,
+        }
+    }
+
+    /// Queues bytes behind those waiting for the destination, failing if they would exceed the
+    /// limit.
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.queue.len() + data.len() > self.limit {
+            return Err(io::Error::other(LimitExceeded(self.limit)));
+        }
+        self.queue.extend(data);
+        Ok(())
+    }
+
+    /// The first of the queued bytes, which are empty only if there are none.
+    pub fn queued(&self) -> &[u8] {
+        self.queue.as_slices().0
+    }
+
+    /// Removes bytes that were written from the front of the queue.
+    pub fn consume(&mut self, n: usize) {
+        self.queue.drain(..n);
+    }
+
+    /// Releases the memory of the queue, once it is empty.
+    pub fn shrink(&mut self) {
+        if self.queue.is_empty() {
+            self.queue = VecDeque::new();
+        }
+    }
+}
+
+/// The error of a connection that queued more bytes than its limit.
+#[derive(Debug)]
+struct LimitExceeded(usize);
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "per-connection memory limit of {} bytes exceeded",
+            self.0
+        )
+    }
+}
+
+impl Error for LimitExceeded {}
+
+/// Whether a copy failed because its meter exceeded the limit.
+pub fn exceeded(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<LimitExceeded>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fails_once_the_queue_exceeds_the_limit() {
+        let mut meter = Meter::new(8);
+        meter.push(b"abcde").unwrap();
+        assert!(exceeded(&meter.push(b"fghi").unwrap_err()));
+        meter.consume(2);
+        meter.push(b"fghi").unwrap();
+        assert_eq!(meter.queue.iter().copied().collect::<Vec<_>>(), b"cdefghi");
+    }
+}
@@ -0,0 +1,524 @@
+//! Prometheus metrics of forwarded connections, served over HTTP by `--metrics-port`, and
+//! statistics logged every `--stats-interval`.
+
+use std::{
+    fmt::Write,
+    net::{IpAddr, SocketAddr, TcpListener},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use smol::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    Async, Timer,
+};
+
+use crate::{config::Config, conn_table::TableStats, protocols::http::find, task::spawn_named};
+
+/// Upper bounds of the buckets of connection durations, in seconds.
+const DURATION_BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// Upper bounds of the buckets of bytes transferred per connection.
+const BYTES_BUCKETS: [f64; 10] = [
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0,
+];
+
+/// Kinds of errors that failed TCP connections are counted by, with their label values.
+const ERROR_KINDS: [(io::ErrorKind, &str); 5] = [
+    (io::ErrorKind::ConnectionRefused, "connection_refused"),
+    (io::ErrorKind::TimedOut, "timed_out"),
+    (io::ErrorKind::ConnectionReset, "reset_by_peer"),
+    (io::ErrorKind::BrokenPipe, "broken_pipe"),
+    (io::ErrorKind::UnexpectedEof, "unexpected_eof"),
+];
+
+/// Protocols that connections are counted by address family for.
+const PROTOS: [&str; 2] = ["tcp", "udp"];
+
+/// Label values of the address families.
+const FAMILIES: [&str; 2] = ["v4", "v6"];
+
+/// The index in [`FAMILIES`] of the family of an address, IPv4 for IPv4-mapped IPv6 addresses.
+fn family_index(ip: IpAddr) -> usize {
+    usize::from(ip.to_canonical().is_ipv6())
+}
+
+/// The label value of the address family of an address.
+pub fn family(ip: IpAddr) -> &'static str {
+    FAMILIES[family_index(ip)]
+}
+
+/// The label value of a kind of error, `other` for the kinds not in [`ERROR_KINDS`].
+pub fn error_label(kind: io::ErrorKind) -> &'static str {
+    ERROR_KINDS
+        .iter()
+        .find(|&&(k, _)| k == kind)
+        .map_or("other", |&(_, label)| label)
+}
+
+/// A value that only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Writes the counter in the Prometheus exposition format.
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {}", self.get());
+    }
+}
+
+/// Writes a value that can go up and down in the Prometheus exposition format.
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} gauge");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// Counts of observations in buckets of increasing upper bounds.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Number of observations in each bucket, not including the ones before it.
+    buckets: Vec<Counter>,
+    count: Counter,
+    sum: Mutex<f64>,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: bounds.iter().map(|_| Counter::default()).collect(),
+            count: Counter::default(),
+            sum: Mutex::new(0.0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.buckets[i].add(1);
+        }
+        self.count.add(1);
+        *self.sum.lock().unwrap() += value;
+    }
+
+    /// Writes the histogram in the Prometheus exposition format, with cumulative buckets.
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.get();
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count.get();
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {}", *self.sum.lock().unwrap());
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// All metrics of a running portfwd.
+#[derive(Debug)]
+pub struct Metrics {
+    connections: Counter,
+    bytes: Counter,
+    discarded: Counter,
+    /// Failed connections by the kinds in [`ERROR_KINDS`], followed by all other kinds.
+    errors: [Counter; ERROR_KINDS.len() + 1],
+    /// Connections by protocol, family of the client and family of the backend, in the order of
+    /// [`PROTOS`] and [`FAMILIES`].
+    families: [[[Counter; 2]; 2]; 2],
+    connection_duration: Histogram,
+    connection_bytes: Histogram,
+    /// Connections to the listening port that were half-open at the last poll.
+    #[cfg(target_os = "linux")]
+    accept_queue: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            connections: Counter::default(),
+            bytes: Counter::default(),
+            discarded: Counter::default(),
+            errors: Default::default(),
+            families: Default::default(),
+            connection_duration: Histogram::new(&DURATION_BUCKETS),
+            connection_bytes: Histogram::new(&BYTES_BUCKETS),
+            #[cfg(target_os = "linux")]
+            accept_queue: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    /// Records a TCP connection once it is closed.
+    pub fn record_connection(&self, duration: Duration, bytes: u64) {
+        self.connections.add(1);
+        self.bytes.add(bytes);
+        self.connection_duration.observe(duration.as_secs_f64());
+        self.connection_bytes.observe(bytes as f64);
+    }
+
+    /// Records a TCP connection once it is closed, or a UDP datagram once it is forwarded, by the
+    /// address families of the client and the backend.
+    pub fn record_families(&self, proto: &str, client: IpAddr, backend: IpAddr) {
+        let proto = PROTOS.iter().position(|&p| p == proto).unwrap_or(0);
+        self.families[proto][family_index(client)][family_index(backend)].add(1);
+    }
+
+    /// Records bytes that were read by the null target and thrown away.
+    pub fn record_discarded(&self, bytes: u64) {
+        self.discarded.add(bytes);
+    }
+
+    /// Records how many connections to the listening port are waiting to be accepted.
+    #[cfg(target_os = "linux")]
+    pub fn record_accept_queue(&self, depth: u64) {
+        self.accept_queue.store(depth, Ordering::Relaxed);
+    }
+
+    /// Records a TCP connection that failed with an error of some kind.
+    pub fn record_error(&self, kind: io::ErrorKind) {
+        let i = ERROR_KINDS
+            .iter()
+            .position(|&(k, _)| k == kind)
+            .unwrap_or(ERROR_KINDS.len());
+        self.errors[i].add(1);
+    }
+
+    /// Writes all metrics in the Prometheus exposition format, along with the totals of the
+    /// connections that are still open.
+    pub fn render(&self, open: &TableStats) -> String {
+        let mut out = String::new();
+        gauge(
+            &mut out,
+            "portfwd_active_connections",
+            "Number of open TCP connections.",
+            open.active,
+        );
+        gauge(
+            &mut out,
+            "portfwd_active_bytes",
+            "Bytes transferred so far in both directions of open TCP connections.",
+            open.bytes,
+        );
+        if let Some(rss) = rss_bytes() {
+            gauge(
+                &mut out,
+                "portfwd_memory_rss_bytes",
+                "Resident set size of the process.",
+                rss,
+            );
+        }
+        if let Some(allocated) = heap_allocated_bytes() {
+            gauge(
+                &mut out,
+                "portfwd_heap_allocated_bytes",
+                "Bytes allocated on the heap, as counted by jemalloc.",
+                allocated,
+            );
+        }
+        #[cfg(target_os = "linux")]
+        gauge(
+            &mut out,
+            "portfwd_accept_queue_depth",
+            "Approximate number of connections waiting in the kernel accept queue, as of the last stats interval.",
+            self.accept_queue.load(Ordering::Relaxed),
+        );
+        self.connections.render(
+            &mut out,
+            "portfwd_connections_total",
+            "Number of closed TCP connections.",
+        );
+        self.bytes.render(
+            &mut out,
+            "portfwd_bytes_total",
+            "Bytes transferred in both directions of closed TCP connections.",
+        );
+        self.discarded.render(
+            &mut out,
+            "portfwd_discarded_bytes_total",
+            "Bytes received and thrown away by the null target.",
+        );
+        let name = "portfwd_connections_by_family_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Number of closed TCP connections and forwarded UDP datagrams, by address family of the client and of the backend."
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (proto, by_client) in PROTOS.iter().zip(&self.families) {
+            for (family, by_backend) in FAMILIES.iter().zip(by_client) {
+                for (backend_family, counter) in FAMILIES.iter().zip(by_backend) {
+                    let _ = writeln!(
+                        out,
+                        "{name}{{proto=\"{proto}\",family=\"{family}\",backend_family=\"{backend_family}\"}} {}",
+                        counter.get()
+                    );
+                }
+            }
+        }
+        let name = "portfwd_connection_errors_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Number of TCP connections that failed, by kind of error."
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let labels = ERROR_KINDS.iter().map(|&(_, label)| label).chain(["other"]);
+        for (label, counter) in labels.zip(&self.errors) {
+            let _ = writeln!(out, "{name}{{kind=\"{label}\"}} {}", counter.get());
+        }
+        self.connection_duration.render(
+            &mut out,
+            "portfwd_connection_duration_seconds",
+            "Duration of TCP connections.",
+        );
+        self.connection_bytes.render(
+            &mut out,
+            "portfwd_connection_bytes",
+            "Bytes transferred in both directions of a TCP connection.",
+        );
+        out
+    }
+}
+
+/// A reader that adds the number of bytes it reads to a counter.
+pub struct CountingReader<'a, R> {
+    inner: R,
+    counter: &'a Counter,
+}
+
+impl<'a, R> CountingReader<'a, R> {
+    pub fn new(inner: R, counter: &'a Counter) -> Self {
+        Self { inner, counter }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.counter.add(n as u64);
+        }
+        poll
+    }
+}
+
+/// Totals of the TCP traffic at some moment, open connections included.
+#[derive(Clone, Copy, Debug)]
+struct Totals {
+    connections: u64,
+    bytes: u64,
+}
+
+impl Totals {
+    fn now(config: &Config) -> Self {
+        let open = config.connections.stats();
+        Self {
+            connections: config.connections.added(),
+            bytes: config.metrics.bytes.get() + open.bytes,
+        }
+    }
+}
+
+/// Logs the TCP traffic since the start and in the last interval, every interval.
+pub async fn report(config: Arc<Config>, interval: Duration) {
+    let mut last = Totals::now(&config);
+    loop {
+        Timer::after(interval).await;
+        let totals = Totals::now(&config);
+        let rss = rss_bytes().map_or_else(String::new, |rss| format!(", {rss} bytes resident"));
+        let allocated = heap_allocated_bytes().map_or_else(String::new, |allocated| {
+            format!(", {allocated} bytes allocated")
+        });
+        tracing::info!(
+            "Stats: {} connections ({} in the last {}s), {} bytes ({} in the last {}s), {} open{}{}",
+            totals.connections,
+            totals.connections - last.connections,
+            interval.as_secs(),
+            totals.bytes,
+            // The total may briefly go back while a connection is moved from open to closed.
+            totals.bytes.saturating_sub(last.bytes),
+            interval.as_secs(),
+            config.connections.stats().active,
+            rss,
+            allocated
+        );
+        last = totals;
+
+        #[cfg(target_os = "linux")]
+        match accept_queue_depth(config.port).await {
+            Ok(depth) => config.metrics.record_accept_queue(depth),
+            Err(err) => tracing::debug!("Failed to read the accept queue depth: {}", err),
+        }
+    }
+}
+
+/// The resident set size of the process, from the `VmRSS` line of `/proc/self/status`.
+#[cfg(target_os = "linux")]
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kib: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// The resident set size of the process, as sysinfo reads it.
+#[cfg(not(target_os = "linux"))]
+fn rss_bytes() -> Option<u64> {
+    use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System};
+
+    let pid = sysinfo::get_current_pid().ok()?;
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[pid]),
+        false,
+        ProcessRefreshKind::nothing().with_memory(),
+    );
+    system.process(pid).map(|process| process.memory())
+}
+
+/// The bytes allocated on the heap, from the statistics of jemalloc, which are only brought up
+/// to date by advancing its epoch.
+#[cfg(feature = "jemalloc")]
+fn heap_allocated_bytes() -> Option<u64> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    epoch::advance().ok()?;
+    stats::allocated::read()
+        .ok()
+        .map(|allocated| allocated as u64)
+}
+
+#[cfg(not(feature = "jemalloc"))]
+fn heap_allocated_bytes() -> Option<u64> {
+    None
+}
+
+/// Approximates the number of connections waiting in the kernel accept queue of a port, by
+/// counting the sockets of the port in the `SYN_RECV` state in `/proc/net/tcp` and
+/// `/proc/net/tcp6`.
+#[cfg(target_os = "linux")]
+async fn accept_queue_depth(port: u16) -> io::Result<u64> {
+    /// The state of half-open sockets in the `st` column.
+    const SYN_RECV: &str = "03";
+
+    let port = format!(":{port:04X}");
+    let mut depth = 0;
+    for path in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let table = match smol::fs::read_to_string(path).await {
+            Ok(table) => table,
+            // Without IPv6 there is no table for it.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        // Each line after the header starts with `sl local_address rem_address st`.
+        for line in table.lines().skip(1) {
+            let mut fields = line.split_whitespace().skip(1);
+            let (Some(local), Some(_), Some(state)) = (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if state == SYN_RECV && local.ends_with(&port) {
+                depth += 1;
+            }
+        }
+    }
+    Ok(depth)
+}
+
+/// Serves the metrics at `/metrics` to HTTP clients.
+pub async fn serve(addr: SocketAddr, config: Arc<Config>) -> io::Result<()> {
+    let listener = Async::<TcpListener>::bind(addr)?;
+    tracing::info!("Serving metrics on {}", listener.get_ref().local_addr()?);
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let config = config.clone();
+        spawn_named("metrics-client", async move {
+            // Read the request head, of which only the path matters.
+            let mut head = Vec::new();
+            let mut buf = [0; 1024];
+            while find(&head, b"\r\n\r\n").is_none() && head.len() < 8192 {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok(());
+                }
+                head.extend_from_slice(&buf[..n]);
+            }
+
+            let response = if head.starts_with(b"GET /metrics ") {
+                let body = config.metrics.render(&config.connections.stats());
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into()
+            };
+            stream.write_all(response.as_bytes()).await?;
+            Ok(()) as io::Result<()>
+        })
+        .detach();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cumulative_buckets() {
+        let histogram = Histogram::new(&[0.1, 1.0, 10.0]);
+        for value in [0.05, 0.1, 0.5, 5.0, 50.0] {
+            histogram.observe(value);
+        }
+        let mut out = String::new();
+        histogram.render(&mut out, "test_seconds", "A test.");
+        assert_eq!(
+            out,
+            concat!(
+                "# HELP test_seconds A test.\n",
+                "# TYPE test_seconds histogram\n",
+                "test_seconds_bucket{le=\"0.1\"} 2\n",
+                "test_seconds_bucket{le=\"1\"} 3\n",
+                "test_seconds_bucket{le=\"10\"} 4\n",
+                "test_seconds_bucket{le=\"+Inf\"} 5\n",
+                "test_seconds_sum 55.65\n",
+                "test_seconds_count 5\n",
+            )
+        );
+    }
+
+    #[test]
+    fn renders_empty_histograms() {
+        let histogram = Histogram::new(&[1.0]);
+        let mut out = String::new();
+        histogram.render(&mut out, "empty", "Nothing.");
+        assert!(out.contains("empty_bucket{le=\"1\"} 0\nempty_bucket{le=\"+Inf\"} 0\n"));
+        assert!(out.ends_with("empty_sum 0\nempty_count 0\n"));
+    }
+}
@@ -0,0 +1,147 @@
+//! Translation of IPv6 addresses that embed an IPv4 address, for `--nat64-prefix`.
+//!
+//! The IPv4 address is placed in the address as described by RFC 6052: right after the prefix,
+//! skipping bits 64 to 71, which are always zero.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+/// Lengths of prefixes that an IPv4 address can be embedded after.
+const PREFIX_LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+/// An IPv6 prefix that IPv4 addresses are embedded in, given as `<IPV6>/<PREFIX>`, e.g. the
+/// well-known `64:ff9b::/96`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Nat64Prefix {
+    addr: Ipv6Addr,
+    len: u8,
+}
+
+impl Nat64Prefix {
+    /// The IPv4 address embedded in an address, if it is in the prefix.
+    pub fn extract(&self, ip: IpAddr) -> Option<Ipv4Addr> {
+        let IpAddr::V6(ip) = ip else {
+            return None;
+        };
+        let mask = u128::MAX << (128 - u32::from(self.len));
+        if u128::from(ip) & mask != u128::from(self.addr) & mask {
+            return None;
+        }
+        let octets = ip.octets();
+        let bytes: Vec<u8> = octets[..8].iter().chain(&octets[9..]).copied().collect();
+        let start = if self.len <= 64 {
+            self.len / 8
+        } else {
+            self.len / 8 - 1
+        };
+        let embedded: [u8; 4] = bytes[usize::from(start)..][..4].try_into().unwrap();
+        Some(embedded.into())
+    }
+
+    /// Where to forward a client that connected to an address, if the address is in the prefix:
+    /// the embedded IPv4 address, on the same port.
+    pub fn translate(&self, local_addr: SocketAddr) -> Option<SocketAddr> {
+        let ip = self.extract(local_addr.ip())?;
+        Some(SocketAddr::new(ip.into(), local_addr.port()))
+    }
+}
+
+impl FromStr for Nat64Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected <IPV6>/<PREFIX>, got: {s}"))?;
+        let addr = addr.parse().map_err(|e| format!("{e}: {addr}"))?;
+        match len.parse() {
+            Ok(len) if PREFIX_LENGTHS.contains(&len) => Ok(Nat64Prefix { addr, len }),
+            _ => Err(format!(
+                "prefix length must be 32, 40, 48, 56, 64 or 96, got: {len}"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Nat64Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(s: &str) -> Nat64Prefix {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// The addresses that embed 192.0.2.33 in the examples of RFC 6052, section 2.4.
+    const EXAMPLES: [(&str, &str); 6] = [
+        ("2001:db8::/32", "2001:db8:c000:221::"),
+        ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+        ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+        ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+        ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+        ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+    ];
+
+    #[test]
+    fn extracts_the_examples_of_rfc_6052() {
+        for (block, addr) in EXAMPLES {
+            assert_eq!(
+                prefix(block).extract(ip(addr)),
+                Some(Ipv4Addr::new(192, 0, 2, 33)),
+                "{addr} in {block}"
+            );
+        }
+        assert_eq!(
+            prefix("64:ff9b::/96").extract(ip("64:ff9b::8.8.8.8")),
+            Some(Ipv4Addr::new(8, 8, 8, 8))
+        );
+    }
+
+    #[test]
+    fn skips_the_u_octet() {
+        // Bits 64 to 71 are skipped, wherever the IPv4 address spans them.
+        for (block, addr) in [
+            ("2001:db8::/40", "2001:db8:c0:2:ff21::"),
+            ("2001:db8::/48", "2001:db8:0:c000:ff02:2100::"),
+            ("2001:db8::/56", "2001:db8:0:c0:ff00:221::"),
+            ("2001:db8::/64", "2001:db8::ffc0:2:2100:0"),
+        ] {
+            assert_eq!(
+                prefix(block).extract(ip(addr)),
+                Some(Ipv4Addr::new(192, 0, 2, 33)),
+                "{addr} in {block}"
+            );
+        }
+    }
+
+    #[test]
+    fn translates_only_addresses_in_the_prefix() {
+        let prefix = prefix("64:ff9b::/96");
+        assert_eq!(prefix.extract(ip("64:ff9c::8.8.8.8")), None);
+        assert_eq!(prefix.extract(ip("8.8.8.8")), None);
+        assert_eq!(
+            prefix.translate("[64:ff9b::8.8.8.8]:443".parse().unwrap()),
+            Some("8.8.8.8:443".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_prefixes() {
+        assert_eq!(prefix("64:ff9b::/96").to_string(), "64:ff9b::/96");
+        for invalid in ["64:ff9b::", "64:ff9b::/95", "64:ff9b::/128", "10.0.0.0/32"] {
+            assert!(invalid.parse::<Nat64Prefix>().is_err(), "{invalid}");
+        }
+    }
+}
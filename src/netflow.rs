@@ -0,0 +1,334 @@
+//! Export of flow records in the NetFlow v9 format of RFC 3954, for `--netflow`.
+//!
+//! A record is made for each TCP connection when it closes, from the client to the backend, and
+//! queued without waiting. A background task batches the queued records into export packets of
+//! up to [`MAX_PACKET`] bytes, and sends them to the collector at most a second after the first
+//! record of a batch was queued. Each packet carries its templates, so that a collector that
+//! starts late or loses packets can always decode it.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use smol::{
+    channel::{bounded, Receiver, Sender},
+    future, io, Async, Timer,
+};
+
+/// Largest export packet, which stays below the usual MTU.
+const MAX_PACKET: usize = 1400;
+
+/// How long the first record of a batch may wait for more.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many records may wait to be exported.
+const QUEUE_SIZE: usize = 4096;
+
+/// Bytes of payload assumed for each packet, to estimate the packets of a flow.
+const SEGMENT_SIZE: u64 = 1460;
+
+const HEADER_LEN: usize = 20;
+const TEMPLATE_V4: u16 = 256;
+const TEMPLATE_V6: u16 = 257;
+
+/// The fields of the records for each address family, as (type, length).
+const FIELDS_V4: [(u16, u16); 9] = [
+    (8, 4),  // IPV4_SRC_ADDR
+    (12, 4), // IPV4_DST_ADDR
+    (7, 2),  // L4_SRC_PORT
+    (11, 2), // L4_DST_PORT
+    (4, 1),  // PROTOCOL
+    (1, 8),  // IN_BYTES
+    (2, 8),  // IN_PKTS
+    (22, 4), // FIRST_SWITCHED
+    (21, 4), // LAST_SWITCHED
+];
+const FIELDS_V6: [(u16, u16); 9] = [
+    (27, 16), // IPV6_SRC_ADDR
+    (28, 16), // IPV6_DST_ADDR
+    (7, 2),   // L4_SRC_PORT
+    (11, 2),  // L4_DST_PORT
+    (4, 1),   // PROTOCOL
+    (1, 8),   // IN_BYTES
+    (2, 8),   // IN_PKTS
+    (22, 4),  // FIRST_SWITCHED
+    (21, 4),  // LAST_SWITCHED
+];
+const RECORD_V4: usize = 37;
+const RECORD_V6: usize = 61;
+
+/// Length of the template flowset with both templates.
+const TEMPLATES_LEN: usize = 4 + 2 * (4 + 4 * 9);
+
+/// A flow of a single connection.
+#[derive(Clone, Copy, Debug)]
+pub struct Flow {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// The IP protocol number, 6 for TCP.
+    pub protocol: u8,
+    pub start: Instant,
+    pub end: Instant,
+    pub bytes: u64,
+}
+
+impl Flow {
+    /// The addresses of the flow, as IPv4 if both are, and as IPv6 otherwise.
+    fn addrs(&self) -> (IpAddr, IpAddr) {
+        match (self.src.ip().to_canonical(), self.dst.ip().to_canonical()) {
+            (src @ IpAddr::V4(_), dst @ IpAddr::V4(_)) => (src, dst),
+            (src, dst) => (IpAddr::V6(to_v6(src)), IpAddr::V6(to_v6(dst))),
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// The queue of flows to export to a collector.
+pub struct Exporter {
+    collector: SocketAddr,
+    /// The start of the exporter, which the times in the records count from.
+    boot: Instant,
+    queued: Sender<Flow>,
+    queue: Receiver<Flow>,
+}
+
+impl Exporter {
+    pub fn new(collector: SocketAddr) -> Self {
+        let (queued, queue) = bounded(QUEUE_SIZE);
+        Self {
+            collector,
+            boot: Instant::now(),
+            queued,
+            queue,
+        }
+    }
+
+    /// Queues a flow to be exported, dropping it if the queue is full.
+    pub fn record(&self, flow: Flow) {
+        if self.queued.try_send(flow).is_err() {
+            tracing::debug!("Dropped flow of {}: NetFlow queue is full", flow.src);
+        }
+    }
+
+    /// Exports the queued flows in batches, forever.
+    pub async fn export(&self) -> io::Result<()> {
+        let bind = if self.collector.is_ipv4() {
+            SocketAddr::from(([0, 0, 0, 0], 0))
+        } else {
+            SocketAddr::from(([0; 16], 0))
+        };
+        let socket = Async::<UdpSocket>::bind(bind)?;
+        let mut sequence = 0;
+        let mut pending = None;
+
+        loop {
+            // Wait for the first flow of a batch, then for more until the batch is full or has
+            // waited long enough.
+            let Some(first) = pending.take().or(self.queue.recv().await.ok()) else {
+                return Ok(());
+            };
+            let deadline = Instant::now() + FLUSH_INTERVAL;
+            let mut batch = vec![first];
+            loop {
+                let recv = async { self.queue.recv().await.ok() };
+                let flush = async {
+                    Timer::at(deadline).await;
+                    None
+                };
+                let Some(flow) = future::or(recv, flush).await else {
+                    break;
+                };
+                batch.push(flow);
+                if packet_len(&batch) > MAX_PACKET {
+                    pending = batch.pop();
+                    break;
+                }
+            }
+
+            let packet = self.encode(&batch, sequence);
+            sequence = sequence.wrapping_add(1);
+            match socket.send_to(&packet, self.collector).await {
+                Ok(_) => tracing::trace!("Exported {} flows to {}", batch.len(), self.collector),
+                Err(err) => {
+                    tracing::debug!("Failed to export flows to {}: {}", self.collector, err)
+                }
+            }
+        }
+    }
+
+    /// Milliseconds from the start of the exporter to an instant.
+    fn uptime(&self, at: Instant) -> u32 {
+        at.saturating_duration_since(self.boot).as_millis() as u32
+    }
+
+    /// Encodes an export packet with the templates and a data flowset for each address family.
+    fn encode(&self, flows: &[Flow], sequence: u32) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(packet_len(flows));
+        let unix_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as u32);
+        let count = 2 + flows.len() as u16;
+        packet.extend_from_slice(&9u16.to_be_bytes());
+        packet.extend_from_slice(&count.to_be_bytes());
+        packet.extend_from_slice(&self.uptime(Instant::now()).to_be_bytes());
+        packet.extend_from_slice(&unix_secs.to_be_bytes());
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(&0u32.to_be_bytes()); // source id
+
+        // The template flowset.
+        packet.extend_from_slice(&0u16.to_be_bytes());
+        packet.extend_from_slice(&(TEMPLATES_LEN as u16).to_be_bytes());
+        for (id, fields) in [(TEMPLATE_V4, FIELDS_V4), (TEMPLATE_V6, FIELDS_V6)] {
+            packet.extend_from_slice(&id.to_be_bytes());
+            packet.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+            for (ty, len) in fields {
+                packet.extend_from_slice(&ty.to_be_bytes());
+                packet.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+
+        // A data flowset for each template that has flows.
+        for v6 in [false, true] {
+            let flows: Vec<_> = flows
+                .iter()
+                .filter(|flow| flow.addrs().0.is_ipv6() == v6)
+                .collect();
+            if flows.is_empty() {
+                continue;
+            }
+            let start = packet.len();
+            let id = if v6 { TEMPLATE_V6 } else { TEMPLATE_V4 };
+            packet.extend_from_slice(&id.to_be_bytes());
+            packet.extend_from_slice(&[0, 0]);
+            for flow in flows {
+                match flow.addrs() {
+                    (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                        packet.extend_from_slice(&src.octets());
+                        packet.extend_from_slice(&dst.octets());
+                    }
+                    (src, dst) => {
+                        packet.extend_from_slice(&to_v6(src).octets());
+                        packet.extend_from_slice(&to_v6(dst).octets());
+                    }
+                }
+                packet.extend_from_slice(&flow.src.port().to_be_bytes());
+                packet.extend_from_slice(&flow.dst.port().to_be_bytes());
+                packet.push(flow.protocol);
+                packet.extend_from_slice(&flow.bytes.to_be_bytes());
+                packet.extend_from_slice(&flow.bytes.div_ceil(SEGMENT_SIZE).to_be_bytes());
+                packet.extend_from_slice(&self.uptime(flow.start).to_be_bytes());
+                packet.extend_from_slice(&self.uptime(flow.end).to_be_bytes());
+            }
+            // Flowsets are padded to a multiple of 4 bytes.
+            packet.resize(start + (packet.len() - start).next_multiple_of(4), 0);
+            let len = (packet.len() - start) as u16;
+            packet[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+        }
+        packet
+    }
+}
+
+/// The length of the export packet of some flows.
+fn packet_len(flows: &[Flow]) -> usize {
+    let v6 = flows.iter().filter(|flow| flow.addrs().0.is_ipv6()).count();
+    let v4 = flows.len() - v6;
+    let flowset = |count: usize, record: usize| match count {
+        0 => 0,
+        _ => (4 + count * record).next_multiple_of(4),
+    };
+    HEADER_LEN + TEMPLATES_LEN + flowset(v4, RECORD_V4) + flowset(v6, RECORD_V6)
+}
+
+impl fmt::Debug for Exporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Exporter")
+            .field("collector", &self.collector)
+            .field("queued", &self.queue.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes written as hex, with any whitespace between them.
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn flow(exporter: &Exporter, src: &str, dst: &str, bytes: u64) -> Flow {
+        Flow {
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            protocol: 6,
+            start: exporter.boot + Duration::from_millis(1500),
+            end: exporter.boot + Duration::from_millis(4000),
+            bytes,
+        }
+    }
+
+    #[test]
+    fn encodes_templates_and_records() {
+        let exporter = Exporter::new("127.0.0.1:2055".parse().unwrap());
+        let flows = [
+            flow(&exporter, "10.0.0.1:12345", "10.0.0.2:80", 3000),
+            flow(&exporter, "10.0.0.1:12345", "[2001:db8::2]:443", 1460),
+        ];
+        let packet = exporter.encode(&flows, 7);
+        assert_eq!(packet.len(), packet_len(&flows));
+
+        // The version and count, then the uptime and the time of export, which change.
+        assert_eq!(packet[..4], hex("0009 0004"));
+        assert_eq!(packet[12..HEADER_LEN], hex("00000007 00000000"));
+        let expected = hex("
+            0000 0054
+            0100 0009
+              0008 0004 000c 0004 0007 0002 000b 0002 0004 0001
+              0001 0008 0002 0008 0016 0004 0015 0004
+            0101 0009
+              001b 0010 001c 0010 0007 0002 000b 0002 0004 0001
+              0001 0008 0002 0008 0016 0004 0015 0004
+            0100 002c
+              0a000001 0a000002 3039 0050 06
+              0000000000000bb8 0000000000000003 000005dc 00000fa0
+              000000
+            0101 0044
+              00000000000000000000ffff0a000001 20010db8000000000000000000000002
+              3039 01bb 06
+              00000000000005b4 0000000000000001 000005dc 00000fa0
+              000000
+            ");
+        assert_eq!(packet[HEADER_LEN..], expected);
+    }
+
+    #[test]
+    fn leaves_out_empty_flowsets() {
+        let exporter = Exporter::new("127.0.0.1:2055".parse().unwrap());
+        let flows = [flow(&exporter, "[::1]:1", "[::1]:2", 0)];
+        let packet = exporter.encode(&flows, 0);
+        assert_eq!(packet[2..4], hex("0003"));
+        assert_eq!(packet.len(), HEADER_LEN + TEMPLATES_LEN + 68);
+        assert_eq!(packet[HEADER_LEN + TEMPLATES_LEN..][..4], hex("0101 0044"));
+    }
+
+    #[test]
+    fn batches_up_to_the_largest_packet() {
+        let exporter = Exporter::new("127.0.0.1:2055".parse().unwrap());
+        let flows = vec![flow(&exporter, "10.0.0.1:1", "10.0.0.2:2", 1); 35];
+        assert!(packet_len(&flows[..34]) <= MAX_PACKET);
+        assert!(packet_len(&flows) > MAX_PACKET);
+    }
+}
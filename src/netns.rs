@@ -0,0 +1,84 @@
+//! Listening in another network namespace, for `--netns` on Linux.
+//!
+//! Only the listeners are bound in the namespace: the connections to the backends, and every
+//! other socket, are made in the namespace that portfwd was started in, so that clients of one
+//! namespace reach backends in the other. A listener keeps the namespace it was bound in, but a
+//! thread that enters a namespace stays in it, so the thread that binds a listener enters the
+//! namespace only while it does, and goes back to the one it came from at once.
+
+use std::{fs::File, io, path::Path, sync::OnceLock};
+
+/// The namespace that listeners are bound in, set by [`use_namespace`].
+static NETNS: OnceLock<Netns> = OnceLock::new();
+
+#[derive(Debug)]
+struct Netns {
+    /// The namespace that listeners are bound in.
+    listen: File,
+    /// The namespace that portfwd was started in.
+    home: File,
+}
+
+/// Binds the listeners in the network namespace of a file, such as `/proc/<pid>/ns/net` or
+/// `/run/netns/<name>`, checking first that it can be entered.
+#[cfg(target_os = "linux")]
+pub fn use_namespace(path: &Path) -> io::Result<()> {
+    let netns = Netns {
+        listen: File::open(path)?,
+        home: File::open("/proc/thread-self/ns/net")?,
+    };
+    netns.within(|| Ok(()))?;
+    NETNS
+        .set(netns)
+        .expect("the network namespace is only set once");
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn use_namespace(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "network namespaces are only supported on Linux",
+    ))
+}
+
+/// Binds a listener, or creates any other socket, in the namespace that listeners are bound in,
+/// if there is one.
+pub fn within<T>(bind: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    match NETNS.get() {
+        Some(netns) => netns.within(bind),
+        None => bind(),
+    }
+}
+
+/// Whether listeners are bound in a namespace of their own.
+pub fn is_set() -> bool {
+    NETNS.get().is_some()
+}
+
+impl Netns {
+    /// Runs `bind` on the current thread in the listening namespace, and goes back after it.
+    #[cfg(target_os = "linux")]
+    fn within<T>(&self, bind: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        use std::os::unix::io::AsRawFd;
+
+        fn enter(namespace: &File) -> io::Result<()> {
+            // SAFETY: the descriptor is open for as long as the file is.
+            match unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) } {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        }
+
+        enter(&self.listen)?;
+        let result = bind();
+        // A thread left in the listening namespace would connect to the backends from there.
+        enter(&self.home).expect("going back to the network namespace portfwd started in");
+        result
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn within<T>(&self, bind: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        bind()
+    }
+}
@@ -0,0 +1,406 @@
+//! Obfuscation of the streams between two portfwd instances, for `--obfuscate`.
+//!
+//! Each direction of a stream starts with a random salt, from which a key of its own is derived
+//! from the shared passphrase with HKDF-SHA256. The data follows in frames sealed with
+//! ChaCha20-Poly1305, each padded with a random amount of noise, so that neither the bytes nor
+//! the sizes of the writes show through. Both the length header and the body of a frame are
+//! sealed, so the stream looks random from its first byte.
+//!
+//! This is meant to get past filters that look into the traffic, not to keep it secret: the
+//! passphrase isn't stretched, so a weak one can be guessed from a recorded stream.
+
+use std::{
+    fmt,
+    net::{SocketAddr, TcpStream},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    hkdf::{Salt, HKDF_SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use smol::io::{self, AsyncRead, AsyncWrite};
+
+use crate::transport::{BoxFuture, BoxStream, Side, Stream, Transport, TransportListener};
+
+/// Length of the salt that starts each direction.
+const SALT_LEN: usize = 32;
+
+/// Length of the authentication tag of each sealed part.
+const TAG_LEN: usize = 16;
+
+/// Length of a sealed frame header: the lengths of the data and of the padding, and a tag.
+const HEADER_LEN: usize = 4 + TAG_LEN;
+
+/// Most bytes of data in a frame.
+const MAX_DATA: usize = 16 * 1024;
+
+/// Most bytes of padding in a frame.
+const MAX_PAD: usize = 255;
+
+/// The key of one direction of a stream, with the count of the parts it sealed or opened.
+struct Key {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Key {
+    fn derive(passphrase: &[u8], salt: &[u8]) -> Self {
+        let prk = Salt::new(HKDF_SHA256, salt).extract(passphrase);
+        let okm = prk
+            .expand(&[b"portfwd obfuscate"], &CHACHA20_POLY1305)
+            .expect("the key length is valid for HKDF-SHA256");
+        Self {
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+            counter: 0,
+        }
+    }
+
+    /// The nonce of the next part, which is never reused under the same key.
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = [0; NONCE_LEN];
+        nonce[NONCE_LEN - 8..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Seals the bytes of `out` from `start` on, appending the tag.
+    fn seal(&mut self, out: &mut Vec<u8>, start: usize) {
+        let nonce = self.nonce();
+        let mut part = out.split_off(start);
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut part)
+            .expect("frames are far below the size limit of ChaCha20-Poly1305");
+        out.extend_from_slice(&part);
+    }
+
+    /// Opens a sealed part in place, returning its plaintext.
+    fn open<'a>(&mut self, part: &'a mut [u8]) -> io::Result<&'a mut [u8]> {
+        let nonce = self.nonce();
+        self.key
+            .open_in_place(nonce, Aad::empty(), part)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "obfuscated stream failed to decrypt, is the key the same on both ends?",
+                )
+            })
+    }
+}
+
+/// A stream obfuscated with a passphrase shared with its peer.
+pub struct ObfuscatedStream<S> {
+    inner: S,
+    passphrase: Arc<[u8]>,
+    /// The key of the incoming direction, once its salt arrived.
+    read_key: Option<Key>,
+    /// Sealed bytes received and not decoded yet.
+    sealed: Vec<u8>,
+    /// The lengths of the data and padding of the frame being received, once its header arrived.
+    frame: Option<(usize, usize)>,
+    /// Decoded data not read yet, from `plain_pos` on.
+    plain: Vec<u8>,
+    plain_pos: usize,
+    /// The key of the outgoing direction, once its salt was queued.
+    write_key: Option<Key>,
+    /// Sealed bytes not written yet, from `out_pos` on.
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+impl<S> ObfuscatedStream<S> {
+    pub fn new(inner: S, passphrase: Arc<[u8]>) -> Self {
+        Self {
+            inner,
+            passphrase,
+            read_key: None,
+            sealed: Vec::new(),
+            frame: None,
+            plain: Vec::new(),
+            plain_pos: 0,
+            write_key: None,
+            out: Vec::new(),
+            out_pos: 0,
+        }
+    }
+
+    /// Decodes the next part of the incoming direction, returning whether there was enough of
+    /// it.
+    fn decode(&mut self) -> io::Result<bool> {
+        let Some(key) = &mut self.read_key else {
+            if self.sealed.len() < SALT_LEN {
+                return Ok(false);
+            }
+            self.read_key = Some(Key::derive(&self.passphrase, &self.sealed[..SALT_LEN]));
+            self.sealed.drain(..SALT_LEN);
+            return Ok(true);
+        };
+        match self.frame {
+            None if self.sealed.len() >= HEADER_LEN => {
+                let header = key.open(&mut self.sealed[..HEADER_LEN])?;
+                let data = u16::from_be_bytes([header[0], header[1]]) as usize;
+                let pad = u16::from_be_bytes([header[2], header[3]]) as usize;
+                if data > MAX_DATA || pad > MAX_PAD {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid obfuscated frame",
+                    ));
+                }
+                self.frame = Some((data, pad));
+                self.sealed.drain(..HEADER_LEN);
+                Ok(true)
+            }
+            Some((data, pad)) if self.sealed.len() >= data + pad + TAG_LEN => {
+                let len = data + pad + TAG_LEN;
+                let body = key.open(&mut self.sealed[..len])?;
+                self.plain.clear();
+                self.plain.extend_from_slice(&body[..data]);
+                self.plain_pos = 0;
+                self.frame = None;
+                self.sealed.drain(..len);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Queues a frame of data, after the salt of the outgoing direction if it is the first.
+    fn encode(&mut self, data: &[u8]) {
+        let key = self.write_key.get_or_insert_with(|| {
+            let mut salt = [0; SALT_LEN];
+            SystemRandom::new()
+                .fill(&mut salt)
+                .expect("the system random number generator works");
+            self.out.extend_from_slice(&salt);
+            Key::derive(&self.passphrase, &salt)
+        });
+        let pad = fastrand::usize(..=MAX_PAD);
+        let start = self.out.len();
+        self.out
+            .extend_from_slice(&(data.len() as u16).to_be_bytes());
+        self.out.extend_from_slice(&(pad as u16).to_be_bytes());
+        key.seal(&mut self.out, start);
+        let start = self.out.len();
+        self.out.extend_from_slice(data);
+        self.out.resize(self.out.len() + pad, 0);
+        key.seal(&mut self.out, start);
+    }
+}
+
+impl<S: AsyncWrite + Unpin> ObfuscatedStream<S> {
+    /// Writes out the queued sealed bytes.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.out_pos < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.out_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out_pos += n;
+        }
+        self.out.clear();
+        self.out_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ObfuscatedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.len().min(this.plain.len() - this.plain_pos);
+                buf[..n].copy_from_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if this.decode()? {
+                continue;
+            }
+            let mut chunk = [0; 8192];
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if n == 0 {
+                // The peer may only close the stream between frames.
+                return if this.sealed.is_empty() && this.frame.is_none() {
+                    Poll::Ready(Ok(0))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.sealed.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ObfuscatedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_DATA);
+        this.encode(&buf[..n]);
+        // The frame is taken either way, and the rest of it is written out on the next call.
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+impl<S: Stream> Stream for ObfuscatedStream<S> {
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        self.inner.tcp_socket()
+    }
+}
+
+/// Obfuscates the streams of an inner transport on one side: those it opens to the backends,
+/// or those it accepts from the clients.
+pub struct ObfuscateTransport {
+    inner: Arc<dyn Transport>,
+    passphrase: Arc<[u8]>,
+    side: Side,
+}
+
+impl ObfuscateTransport {
+    pub fn new(inner: Arc<dyn Transport>, passphrase: &str, side: Side) -> Self {
+        Self {
+            inner,
+            passphrase: passphrase.as_bytes().into(),
+            side,
+        }
+    }
+}
+
+impl fmt::Debug for ObfuscateTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObfuscateTransport")
+            .field("inner", &self.inner)
+            .field("side", &self.side)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Transport for ObfuscateTransport {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let stream = self.inner.connect(addr).await?;
+            Ok(match self.side {
+                Side::Backend => Box::new(ObfuscatedStream::new(stream, self.passphrase.clone())),
+                Side::Client => stream,
+            })
+        })
+    }
+
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn TransportListener>>> {
+        Box::pin(async move {
+            let listener = self.inner.listen(addr).await?;
+            Ok(match self.side {
+                Side::Client => Box::new(ObfuscatedListener {
+                    inner: listener,
+                    passphrase: self.passphrase.clone(),
+                }),
+                Side::Backend => listener,
+            })
+        })
+    }
+}
+
+struct ObfuscatedListener {
+    inner: Box<dyn TransportListener>,
+    passphrase: Arc<[u8]>,
+}
+
+impl TransportListener for ObfuscatedListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(BoxStream, SocketAddr)>> {
+        Box::pin(async move {
+            let (stream, peer_addr) = self.inner.accept().await?;
+            let stream = ObfuscatedStream::new(stream, self.passphrase.clone());
+            Ok((Box::new(stream) as BoxStream, peer_addr))
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::io::{AsyncReadExt, AsyncWriteExt, Cursor};
+
+    use super::*;
+
+    /// Obfuscates data with a passphrase into the bytes sent on the wire.
+    fn seal(passphrase: &str, data: &[u8]) -> Vec<u8> {
+        smol::block_on(async {
+            let mut stream = ObfuscatedStream::new(Vec::new(), passphrase.as_bytes().into());
+            stream.write_all(data).await.unwrap();
+            stream.flush().await.unwrap();
+            stream.inner
+        })
+    }
+
+    /// Reads back obfuscated bytes with a passphrase.
+    fn open(passphrase: &str, sealed: Vec<u8>) -> io::Result<Vec<u8>> {
+        smol::block_on(async {
+            let mut stream =
+                ObfuscatedStream::new(Cursor::new(sealed), passphrase.as_bytes().into());
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await?;
+            Ok(data)
+        })
+    }
+
+    #[test]
+    fn round_trips() {
+        let data: Vec<u8> = (0..3 * MAX_DATA + 100).map(|i| (i % 251) as u8).collect();
+        let sealed = seal("secret", &data);
+        assert!(sealed.len() > data.len() + SALT_LEN);
+        assert!(!sealed.windows(64).any(|window| window == &data[..64]));
+        assert_eq!(open("secret", sealed).unwrap(), data);
+        // Each direction starts with a salt of its own.
+        assert_ne!(
+            seal("secret", b"hello")[..SALT_LEN],
+            seal("secret", b"hello")[..SALT_LEN]
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_streams() {
+        let sealed = seal("secret", b"hello world");
+        for at in [SALT_LEN, SALT_LEN + HEADER_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            let err = open("secret", tampered).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "at {at}");
+        }
+        let err = open("other", sealed.clone()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = open("secret", sealed[..sealed.len() - 1].to_vec()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
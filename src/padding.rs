@@ -0,0 +1,302 @@
+//! Padding of the traffic between two portfwd instances to a fixed size, for `--pad-to`.
+//!
+//! TCP streams are cut into frames of exactly the given size, and each UDP datagram is sent as
+//! one. A frame starts with the length of its payload in two bytes, and is filled up with
+//! random bytes after it, which the portfwd on the other end strips. This hides the sizes of
+//! the writes from traffic analysis, but not their timing.
+
+use std::{
+    net::{SocketAddr, TcpStream},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use smol::io::{self, AsyncRead, AsyncWrite};
+
+use crate::transport::{BoxFuture, BoxStream, Side, Stream, Transport, TransportListener};
+
+/// Length of the header of a frame, which holds the length of its payload.
+const HEADER_LEN: usize = 2;
+
+/// The size that frames are padded to, and which side of the connections they are sent on.
+#[derive(Clone, Copy, Debug)]
+pub struct Padding {
+    size: usize,
+    side: Side,
+}
+
+impl Padding {
+    /// Pads to `size` bytes, which must leave room for at least a byte of payload.
+    pub fn new(size: usize, side: Side) -> Self {
+        assert!(size > HEADER_LEN, "frames have room for a payload");
+        Self { size, side }
+    }
+
+    /// Whether the traffic on a side is padded.
+    pub fn applies_to(&self, side: Side) -> bool {
+        self.side == side
+    }
+
+    /// The most bytes of payload in a frame.
+    pub fn capacity(&self) -> usize {
+        self.size - HEADER_LEN
+    }
+
+    /// Builds a frame of a payload that fits in it.
+    pub fn pad(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() > self.capacity() {
+            return None;
+        }
+        let mut frame = Vec::with_capacity(self.size);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame.extend((frame.len()..self.size).map(|_| fastrand::u8(..)));
+        Some(frame)
+    }
+
+    /// The payload of a frame, unless it isn't one.
+    pub fn unpad<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        if frame.len() != self.size {
+            return None;
+        }
+        let len = u16::from_be_bytes([frame[0], frame[1]]) as usize;
+        frame.get(HEADER_LEN..HEADER_LEN + len)
+    }
+}
+
+/// A stream sent in padded frames.
+pub struct PaddedStream<S> {
+    inner: S,
+    padding: Padding,
+    /// The frame being received, until it is complete.
+    frame: Vec<u8>,
+    /// The payload of the last frame not read yet, from `plain_pos` on.
+    plain: Vec<u8>,
+    plain_pos: usize,
+    /// The frame not written yet, from `out_pos` on.
+    out: Vec<u8>,
+    out_pos: usize,
+}
+
+impl<S> PaddedStream<S> {
+    pub fn new(inner: S, padding: Padding) -> Self {
+        Self {
+            inner,
+            padding,
+            frame: Vec::with_capacity(padding.size),
+            plain: Vec::new(),
+            plain_pos: 0,
+            out: Vec::new(),
+            out_pos: 0,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> PaddedStream<S> {
+    /// Writes out the rest of the last frame.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.out_pos < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.out_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out_pos += n;
+        }
+        self.out.clear();
+        self.out_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PaddedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.len().min(this.plain.len() - this.plain_pos);
+                buf[..n].copy_from_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if this.frame.len() == this.padding.size {
+                let payload = this.padding.unpad(&this.frame).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid padded frame")
+                })?;
+                this.plain.clear();
+                this.plain.extend_from_slice(payload);
+                this.plain_pos = 0;
+                this.frame.clear();
+                continue;
+            }
+            let mut chunk = [0; 8192];
+            let want = (this.padding.size - this.frame.len()).min(chunk.len());
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk[..want]))?;
+            if n == 0 {
+                // The peer may only close the stream between frames.
+                return if this.frame.is_empty() {
+                    Poll::Ready(Ok(0))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.frame.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PaddedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(this.padding.capacity());
+        this.out = this
+            .padding
+            .pad(&buf[..n])
+            .expect("the payload is cut to the capacity of a frame");
+        // The frame is taken either way, and the rest of it is written out on the next call.
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+impl<S: Stream> Stream for PaddedStream<S> {
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        self.inner.tcp_socket()
+    }
+}
+
+/// Pads the streams of an inner transport on one side: those it opens to the backends, or those
+/// it accepts from the clients.
+#[derive(Debug)]
+pub struct PadTransport {
+    inner: Arc<dyn Transport>,
+    padding: Padding,
+}
+
+impl PadTransport {
+    pub fn new(inner: Arc<dyn Transport>, padding: Padding) -> Self {
+        Self { inner, padding }
+    }
+}
+
+impl Transport for PadTransport {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let stream = self.inner.connect(addr).await?;
+            Ok(match self.padding.side {
+                Side::Backend => Box::new(PaddedStream::new(stream, self.padding)),
+                Side::Client => stream,
+            })
+        })
+    }
+
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn TransportListener>>> {
+        Box::pin(async move {
+            let listener = self.inner.listen(addr).await?;
+            Ok(match self.padding.side {
+                Side::Client => Box::new(PaddedListener {
+                    inner: listener,
+                    padding: self.padding,
+                }),
+                Side::Backend => listener,
+            })
+        })
+    }
+}
+
+struct PaddedListener {
+    inner: Box<dyn TransportListener>,
+    padding: Padding,
+}
+
+impl TransportListener for PaddedListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(BoxStream, SocketAddr)>> {
+        Box::pin(async move {
+            let (stream, peer_addr) = self.inner.accept().await?;
+            let stream = PaddedStream::new(stream, self.padding);
+            Ok((Box::new(stream) as BoxStream, peer_addr))
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::io::{AsyncReadExt, AsyncWriteExt, Cursor};
+
+    use super::*;
+
+    #[test]
+    fn pads_and_unpads_datagrams() {
+        let padding = Padding::new(16, Side::Backend);
+        for payload in [&b""[..], b"hello", &[7; 14]] {
+            let frame = padding.pad(payload).unwrap();
+            assert_eq!(frame.len(), 16);
+            assert_eq!(&frame[..2], (payload.len() as u16).to_be_bytes());
+            assert_eq!(padding.unpad(&frame), Some(payload));
+        }
+        assert_eq!(padding.pad(&[7; 15]), None);
+    }
+
+    #[test]
+    fn rejects_invalid_frames() {
+        let padding = Padding::new(16, Side::Backend);
+        let frame = padding.pad(b"hello").unwrap();
+        assert_eq!(padding.unpad(&frame[..15]), None);
+        assert_eq!(padding.unpad(&[frame.clone(), vec![0]].concat()), None);
+        let mut frame = frame;
+        frame[..2].copy_from_slice(&15u16.to_be_bytes());
+        assert_eq!(padding.unpad(&frame), None);
+    }
+
+    #[test]
+    fn round_trips_streams() {
+        smol::block_on(async {
+            let padding = Padding::new(64, Side::Backend);
+            let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+            let mut stream = PaddedStream::new(Vec::new(), padding);
+            stream.write_all(&data).await.unwrap();
+            stream.flush().await.unwrap();
+            let sent = stream.inner;
+            assert_eq!(sent.len(), 1000_usize.div_ceil(62) * 64);
+
+            let mut stream = PaddedStream::new(Cursor::new(sent.clone()), padding);
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, data);
+
+            let mut stream = PaddedStream::new(Cursor::new(&sent[..100]), padding);
+            let err = stream.read_to_end(&mut Vec::new()).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        });
+    }
+}
@@ -0,0 +1,258 @@
+//! Plugins loaded from shared libraries, for `--plugin`.
+//!
+//! A plugin is a shared library that exports a C function `portfwd_plugin_init`, which returns a
+//! pointer to a [`PortfwdPlugin`] table of hooks that lives as long as the library, or null if
+//! the plugin failed to start. In C:
+//!
+//! ```c
+//! typedef struct {
+//!     const uint8_t *buf;
+//!     size_t len;
+//! } PortfwdBuf;
+//!
+//! typedef struct {
+//!     uint32_t abi_version; /* PORTFWD_PLUGIN_ABI_VERSION, 1 */
+//!     void (*on_connect)(uint64_t conn_id, const char *src, const char *dst);
+//!     PortfwdBuf (*on_data)(uint64_t conn_id, uint8_t dir, const uint8_t *buf, size_t len);
+//!     void (*on_close)(uint64_t conn_id);
+//! } PortfwdPlugin;
+//!
+//! const PortfwdPlugin *portfwd_plugin_init(void);
+//! ```
+//!
+//! Any hook may be null. They are called for each TCP connection once it is connected to its
+//! backend:
+//!
+//! - `on_connect` with the addresses of the client and the backend, as `IP:PORT` strings that
+//!   are only valid during the call;
+//! - `on_data` with each chunk read from the client (`dir` 0) or from the backend (`dir` 1),
+//!   before it is passed on. It returns the bytes to pass on instead, which portfwd copies
+//!   before calling a hook of the connection again, so the plugin may reuse the buffer then.
+//!   A null `buf` passes the chunk on unchanged, and an empty one drops it;
+//! - `on_close` once the connection is closed.
+//!
+//! Hooks are called from the executor threads, on several connections at once, so they must be
+//! thread-safe, and they hold up the connection they are called on until they return. The
+//! library is never unloaded.
+
+use std::{
+    ffi::{c_char, CString},
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use smol::io::{self, AsyncRead};
+
+use crate::protocols::Direction;
+
+/// The version of the table of hooks that portfwd understands.
+pub const ABI_VERSION: u32 = 1;
+
+/// Bytes returned by a hook.
+#[repr(C)]
+pub struct PortfwdBuf {
+    pub buf: *const u8,
+    pub len: usize,
+}
+
+/// The table of hooks of a plugin.
+#[repr(C)]
+pub struct PortfwdPlugin {
+    pub abi_version: u32,
+    pub on_connect:
+        Option<unsafe extern "C" fn(conn_id: u64, src: *const c_char, dst: *const c_char)>,
+    pub on_data: Option<
+        unsafe extern "C" fn(conn_id: u64, dir: u8, buf: *const u8, len: usize) -> PortfwdBuf,
+    >,
+    pub on_close: Option<unsafe extern "C" fn(conn_id: u64)>,
+}
+
+/// A loaded plugin.
+pub struct Plugin {
+    path: PathBuf,
+    hooks: &'static PortfwdPlugin,
+}
+
+// SAFETY: the table of hooks is never written to once the plugin returned it, and the hooks are
+// required to be thread-safe.
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    /// Loads a plugin from a shared library, and starts it. Errors name the library.
+    #[cfg(unix)]
+    pub fn load(path: &Path) -> io::Result<Self> {
+        use std::{ffi::c_void, os::unix::ffi::OsStrExt};
+
+        let filename = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        // SAFETY: `filename` is a valid C string. Loading a library runs its initializers, which
+        // the user trusts by asking for it.
+        let handle = unsafe { libc::dlopen(filename.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(dl_error());
+        }
+        // SAFETY: `handle` is a loaded library, and the name is a valid C string.
+        let init = unsafe { libc::dlsym(handle, c"portfwd_plugin_init".as_ptr()) };
+        if init.is_null() {
+            return Err(dl_error());
+        }
+        // SAFETY: plugins export `portfwd_plugin_init` with this signature.
+        let init: unsafe extern "C" fn() -> *const PortfwdPlugin =
+            unsafe { std::mem::transmute::<*mut c_void, _>(init) };
+        // SAFETY: the table returned lives as long as the library, which is never unloaded.
+        let hooks = match unsafe { init().as_ref() } {
+            Some(hooks) => hooks,
+            None => {
+                return Err(io::Error::other(format!(
+                    "{}: plugin failed to start",
+                    path.display()
+                )))
+            }
+        };
+        if hooks.abi_version != ABI_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{}: plugin ABI version {} is unsupported, expected {}",
+                    path.display(),
+                    hooks.abi_version,
+                    ABI_VERSION
+                ),
+            ));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            hooks,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn load(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "plugins are only supported on Unix",
+        ))
+    }
+
+    /// Tells the plugin that a connection is connected to its backend.
+    pub fn connect(&self, conn_id: u64, src: SocketAddr, dst: SocketAddr) {
+        if let Some(on_connect) = self.hooks.on_connect {
+            let src = CString::new(src.to_string()).expect("addresses have no NUL bytes");
+            let dst = CString::new(dst.to_string()).expect("addresses have no NUL bytes");
+            // SAFETY: both strings are valid C strings for the duration of the call.
+            unsafe { on_connect(conn_id, src.as_ptr(), dst.as_ptr()) };
+        }
+    }
+
+    /// Tells the plugin that a connection is closed.
+    pub fn close(&self, conn_id: u64) {
+        if let Some(on_close) = self.hooks.on_close {
+            // SAFETY: the hook takes no pointers.
+            unsafe { on_close(conn_id) };
+        }
+    }
+
+    /// Passes a chunk of a connection through the plugin, returning the bytes to pass on
+    /// instead, if it changed them.
+    fn data(&self, conn_id: u64, direction: Direction, data: &[u8]) -> Option<Vec<u8>> {
+        let on_data = self.hooks.on_data?;
+        let dir = match direction {
+            Direction::ClientToServer => 0,
+            Direction::ServerToClient => 1,
+        };
+        // SAFETY: `data` is valid for the duration of the call, and the bytes returned stay
+        // valid until the next hook of the connection, before which they are copied.
+        unsafe {
+            let out = on_data(conn_id, dir, data.as_ptr(), data.len());
+            if out.buf.is_null() || (out.buf == data.as_ptr() && out.len == data.len()) {
+                return None;
+            }
+            Some(std::slice::from_raw_parts(out.buf, out.len).to_vec())
+        }
+    }
+}
+
+/// The last error of the dynamic linker.
+#[cfg(unix)]
+fn dl_error() -> io::Error {
+    use std::ffi::CStr;
+
+    // SAFETY: `dlerror` returns null or a valid C string, which is copied before any other call.
+    let message = unsafe {
+        let err = libc::dlerror();
+        if err.is_null() {
+            "unknown dynamic linker error".to_string()
+        } else {
+            CStr::from_ptr(err).to_string_lossy().into_owned()
+        }
+    };
+    io::Error::other(message)
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plugin").field("path", &self.path).finish()
+    }
+}
+
+/// A reader that passes every chunk it reads through the `on_data` hook of a plugin, if there is
+/// one.
+pub struct PluginReader<'a, R> {
+    inner: R,
+    plugin: Option<&'a Plugin>,
+    conn_id: u64,
+    direction: Direction,
+    /// The replacement of the last chunk not read yet, from `pos` on.
+    replaced: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, R> PluginReader<'a, R> {
+    pub fn new(inner: R, plugin: Option<&'a Plugin>, conn_id: u64, direction: Direction) -> Self {
+        Self {
+            inner,
+            plugin,
+            conn_id,
+            direction,
+            replaced: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for PluginReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(plugin) = this.plugin else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        loop {
+            if this.pos < this.replaced.len() {
+                let n = buf.len().min(this.replaced.len() - this.pos);
+                buf[..n].copy_from_slice(&this.replaced[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            if n == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            match plugin.data(this.conn_id, this.direction, &buf[..n]) {
+                Some(replaced) => {
+                    // An empty replacement drops the chunk, and the next one is read.
+                    this.replaced = replaced;
+                    this.pos = 0;
+                }
+                None => return Poll::Ready(Ok(n)),
+            }
+        }
+    }
+}
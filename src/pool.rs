@@ -0,0 +1,86 @@
+//! A pool of pre-allocated buffers, so that busy servers don't allocate one for every datagram.
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+/// Size of each buffer, which fits any UDP datagram.
+pub const BUF_SIZE: usize = 65536;
+
+type Buf = Box<[u8; BUF_SIZE]>;
+
+/// Buffers that are not in use, up to a fixed number of them.
+pub struct BufferPool {
+    free: Mutex<Vec<Buf>>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool that keeps at most `capacity` free buffers, allocating them up front.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            free: Mutex::new((0..capacity).map(|_| alloc()).collect()),
+            capacity,
+        }
+    }
+
+    /// Takes a free buffer, or allocates a new one if there are none left.
+    pub fn get(&self) -> PooledBuffer<'_> {
+        let buf = self.free.lock().unwrap().pop().unwrap_or_else(alloc);
+        PooledBuffer {
+            pool: self,
+            buf: Some(buf),
+        }
+    }
+
+    fn put(&self, buf: Buf) {
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.capacity {
+            free.push(buf);
+        }
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("free", &self.free.lock().unwrap().len())
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+/// Allocates a zeroed buffer directly on the heap.
+fn alloc() -> Buf {
+    vec![0; BUF_SIZE].into_boxed_slice().try_into().unwrap()
+}
+
+/// A buffer taken from a pool, given back to it when dropped.
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buf: Option<Buf>,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf.as_ref().unwrap()[..]
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf.as_mut().unwrap()[..]
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.put(buf);
+        }
+    }
+}
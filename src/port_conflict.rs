@@ -0,0 +1,92 @@
+//! Explanations of listeners that fail to bind because their port is taken, naming the process
+//! that holds it where the platform tells.
+
+use std::{io, net::SocketAddr};
+
+/// A process that has a socket open.
+#[derive(Debug)]
+struct Owner {
+    pid: u32,
+    name: String,
+}
+
+/// Explains a failure to bind a listener of `proto` ("tcp" or "udp") to `addr`, if its port is
+/// in use, keeping the kind of the error.
+pub fn explain(proto: &str, addr: SocketAddr, err: io::Error) -> io::Error {
+    if err.kind() != io::ErrorKind::AddrInUse {
+        return err;
+    }
+    let port = addr.port();
+    let by = match owner(proto, port) {
+        Some(owner) => format!("process {} ({})", owner.pid, owner.name),
+        None => "another process".to_string(),
+    };
+    io::Error::new(
+        err.kind(),
+        format!("port {port}/{proto} is already in use by {by}, choose another port with --port"),
+    )
+}
+
+/// Finds the process that listens on a port, by looking up the inodes of the sockets bound to
+/// the port in `/proc/net`, and then the process with one of them among its open files in
+/// `/proc/<pid>/fd`. Processes of other users are only found with the privileges to see them.
+#[cfg(target_os = "linux")]
+fn owner(proto: &str, port: u16) -> Option<Owner> {
+    use std::fs;
+
+    /// The state of listening TCP sockets in the `st` column.
+    const LISTEN: &str = "0A";
+
+    let suffix = format!(":{port:04X}");
+    let mut inodes = Vec::new();
+    for path in [format!("/proc/net/{proto}"), format!("/proc/net/{proto}6")] {
+        let Ok(table) = fs::read_to_string(path) else {
+            continue;
+        };
+        // Each line after the header holds `sl local_address rem_address st ... uid timeout
+        // inode`.
+        for line in table.lines().skip(1) {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (Some(local), Some(state), Some(inode)) =
+                (fields.get(1), fields.get(3), fields.get(9))
+            else {
+                continue;
+            };
+            if local.ends_with(&suffix) && (proto != "tcp" || *state == LISTEN) {
+                inodes.push(format!("socket:[{inode}]"));
+            }
+        }
+    }
+    if inodes.is_empty() {
+        return None;
+    }
+
+    for entry in fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|pid| pid.parse().ok()) else {
+            continue;
+        };
+        let Ok(fds) = fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        let holds = fds.flatten().any(|fd| {
+            fs::read_link(fd.path()).is_ok_and(|target| {
+                inodes
+                    .iter()
+                    .any(|inode| target.as_os_str() == inode.as_str())
+            })
+        });
+        if holds {
+            let name = fs::read_to_string(entry.path().join("comm")).map_or_else(
+                |_| "unknown".to_string(),
+                |name| name.trim_end().to_string(),
+            );
+            return Some(Owner { pid, name });
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn owner(_proto: &str, _port: u16) -> Option<Owner> {
+    None
+}
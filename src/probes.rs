@@ -0,0 +1,122 @@
+//! Kubernetes liveness and readiness probes, served over HTTP by `--liveness-port` and
+//! `--readiness-port`.
+//!
+//! Any request gets a minimal HTTP/1.0 response, whatever its path: the liveness probe always
+//! succeeds while portfwd runs, and the readiness probe fails with the reason while portfwd
+//! shouldn't get new clients.
+
+use std::{
+    fmt,
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use smol::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    Async,
+};
+
+use crate::{config::Config, protocols::http::find, task::spawn_named};
+
+/// Which probe a port answers.
+#[derive(Clone, Copy, Debug)]
+pub enum Probe {
+    Liveness,
+    Readiness,
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Probe::Liveness => "liveness",
+            Probe::Readiness => "readiness",
+        })
+    }
+}
+
+/// The failures that make portfwd unready for a while.
+#[derive(Debug)]
+pub struct Readiness {
+    /// How long a failure makes portfwd unready.
+    window: Duration,
+    last_failure: Mutex<Option<Instant>>,
+}
+
+impl Readiness {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_failure: Mutex::new(None),
+        }
+    }
+
+    /// Records a failed connection to a backend.
+    pub fn record_failure(&self) {
+        *self.last_failure.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Checks whether portfwd is ready for new clients, returning why not if it isn't.
+    fn check(&self, config: &Config) -> Result<(), String> {
+        if config.drain.is_started() {
+            return Err("the listeners are draining".into());
+        }
+        if config.backends.all_drained() {
+            return Err("all backends are drained".into());
+        }
+        let since = self.last_failure.lock().unwrap().map(|at| at.elapsed());
+        match since {
+            Some(since) if since < self.window => Err(format!(
+                "a backend connection failed {}s ago",
+                since.as_secs()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Answers a probe on a port, forever.
+pub async fn serve(addr: SocketAddr, config: Arc<Config>, probe: Probe) -> io::Result<()> {
+    let listener = Async::<TcpListener>::bind(addr)?;
+    tracing::info!(
+        "Serving {} probes on {}",
+        probe,
+        listener.get_ref().local_addr()?
+    );
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let config = config.clone();
+        spawn_named(format!("{probe}-probe"), async move {
+            // Read the request head, which doesn't matter.
+            let mut head = Vec::new();
+            let mut buf = [0; 1024];
+            while find(&head, b"\r\n\r\n").is_none() && head.len() < 8192 {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok(());
+                }
+                head.extend_from_slice(&buf[..n]);
+            }
+
+            let result = match (probe, &config.readiness) {
+                (Probe::Readiness, Some(readiness)) => readiness.check(&config),
+                _ => Ok(()),
+            };
+            let (status, body) = match result {
+                Ok(()) => ("200 OK", "ok\n".to_string()),
+                Err(reason) => {
+                    tracing::debug!("Failed a readiness probe: {}", reason);
+                    ("503 Service Unavailable", format!("{reason}\n"))
+                }
+            };
+            let response = format!(
+                "HTTP/1.0 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await?;
+            Ok(()) as io::Result<()>
+        })
+        .detach();
+    }
+}
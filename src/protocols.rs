@@ -0,0 +1,96 @@
+//! Lightweight inspection of application protocols in forwarded TCP streams and UDP datagrams.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use smol::io::{self, AsyncRead};
+
+use crate::{config::Config, debug::HexdumpInspector};
+
+pub mod dns;
+pub mod http;
+pub mod http11;
+pub mod mqtt;
+pub mod redis;
+
+/// The direction in which bytes flow through a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::ClientToServer => "client to server",
+            Direction::ServerToClient => "server to client",
+        })
+    }
+}
+
+/// Looks at the bytes flowing in one direction of a connection, without changing them.
+pub trait Inspector: Send {
+    /// Inspects the next chunk of the stream, returning `false` once no more bytes are needed.
+    fn inspect(&mut self, data: &[u8]) -> bool;
+}
+
+/// A reader that shows every chunk it reads to a set of inspectors.
+pub struct InspectReader<R> {
+    inner: R,
+    inspectors: Vec<Box<dyn Inspector>>,
+}
+
+impl<R> InspectReader<R> {
+    pub fn new(inner: R, inspectors: Vec<Box<dyn Inspector>>) -> Self {
+        Self { inner, inspectors }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for InspectReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if n > 0 {
+                this.inspectors.retain_mut(|i| i.inspect(&buf[..n]));
+            }
+        }
+        poll
+    }
+}
+
+/// Creates the inspectors enabled by the configuration for one direction of a connection, none
+/// unless the connection is `sampled`.
+pub fn inspectors(
+    config: &Config,
+    direction: Direction,
+    peer_addr: SocketAddr,
+    sampled: bool,
+) -> Vec<Box<dyn Inspector>> {
+    let mut inspectors: Vec<Box<dyn Inspector>> = Vec::new();
+    if !sampled {
+        return inspectors;
+    }
+    if let Some(limit) = config.hexdump {
+        inspectors.push(Box::new(HexdumpInspector::new(direction, peer_addr, limit)));
+    }
+    if config.http_log {
+        inspectors.push(Box::new(http::HttpInspector::new(direction, peer_addr)));
+    }
+    if config.mqtt_aware {
+        inspectors.push(Box::new(mqtt::MqttInspector::new(direction, peer_addr)));
+    }
+    if config.redis_aware && direction == Direction::ClientToServer {
+        inspectors.push(Box::new(redis::RedisInspector::new(peer_addr)));
+    }
+    inspectors
+}
@@ -0,0 +1,163 @@
+//! Removal of the EDNS Client Subnet option from DNS messages, for `--dns-strip-ecs`.
+//!
+//! Only as much of a message is parsed as it takes to find its OPT record: the questions and
+//! the records before the additional section are skipped over, and the options of the OPT
+//! record are rewritten without the client subnet. Anything that doesn't parse as DNS is left
+//! alone.
+
+/// The type of the OPT pseudo-record that holds the EDNS options.
+const OPT: u16 = 41;
+
+/// The code of the EDNS Client Subnet option.
+const CLIENT_SUBNET: u16 = 8;
+
+/// Skips a possibly compressed name at a position of a message, returning the position after it.
+pub fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // A pointer to the rest of the name elsewhere in the message ends it here.
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn u16_at(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+/// Removes the EDNS Client Subnet options from a DNS query or response, returning the message
+/// without them, or `None` if it has none or isn't DNS.
+pub fn strip_ecs(msg: &[u8]) -> Option<Vec<u8>> {
+    let questions = u16_at(msg, 4)?;
+    let records = [u16_at(msg, 6)?, u16_at(msg, 8)?];
+    let additional = u16_at(msg, 10)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+    for _ in 0..records.iter().sum::<u16>() {
+        pos = skip_name(msg, pos)? + 8;
+        pos += 2 + u16_at(msg, pos)? as usize;
+    }
+    for _ in 0..additional {
+        pos = skip_name(msg, pos)?;
+        let rtype = u16_at(msg, pos)?;
+        let len_pos = pos + 8;
+        let len = u16_at(msg, len_pos)? as usize;
+        let data = msg.get(len_pos + 2..len_pos + 2 + len)?;
+        if rtype != OPT {
+            pos = len_pos + 2 + len;
+            continue;
+        }
+
+        // Keep every option but the client subnet.
+        let mut options = Vec::with_capacity(len);
+        let mut stripped = false;
+        let mut i = 0;
+        while i < data.len() {
+            let code = u16_at(data, i)?;
+            let end = i + 4 + u16_at(data, i + 2)? as usize;
+            let option = data.get(i..end)?;
+            if code == CLIENT_SUBNET {
+                stripped = true;
+            } else {
+                options.extend_from_slice(option);
+            }
+            i = end;
+        }
+        if !stripped {
+            return None;
+        }
+
+        let mut out = Vec::with_capacity(msg.len());
+        out.extend_from_slice(&msg[..len_pos]);
+        out.extend_from_slice(&(options.len() as u16).to_be_bytes());
+        out.extend_from_slice(&options);
+        out.extend_from_slice(&msg[len_pos + 2 + len..]);
+        return Some(out);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header with one question and `additional` additional records, and the question for the
+    /// A records of `example.com`.
+    fn query(answers: u8, additional: u8) -> Vec<u8> {
+        let mut msg = vec![
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, answers, 0, 0, 0, additional,
+        ];
+        msg.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        msg
+    }
+
+    /// An OPT record holding these options, each given as its code and data.
+    fn opt(options: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (code, option) in options {
+            data.extend_from_slice(&code.to_be_bytes());
+            data.extend_from_slice(&(option.len() as u16).to_be_bytes());
+            data.extend_from_slice(option);
+        }
+        let mut record = vec![0, 0, 41, 0x10, 0, 0, 0, 0, 0];
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(&data);
+        record
+    }
+
+    const SUBNET: &[u8] = &[0, 1, 24, 0, 192, 0, 2];
+    const COOKIE: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+
+    #[test]
+    fn strips_the_client_subnet() {
+        let mut msg = query(0, 1);
+        msg.extend_from_slice(&opt(&[(CLIENT_SUBNET, SUBNET), (10, COOKIE)]));
+        let mut expected = query(0, 1);
+        expected.extend_from_slice(&opt(&[(10, COOKIE)]));
+        assert_eq!(strip_ecs(&msg), Some(expected));
+    }
+
+    #[test]
+    fn strips_the_client_subnet_after_answers() {
+        let mut msg = query(1, 1);
+        // An A record for the name of the question, by a pointer to it.
+        msg.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        let answered = msg.clone();
+        msg.extend_from_slice(&opt(&[(CLIENT_SUBNET, SUBNET)]));
+        let mut expected = answered;
+        expected.extend_from_slice(&opt(&[]));
+        assert_eq!(strip_ecs(&msg), Some(expected));
+    }
+
+    #[test]
+    fn leaves_messages_without_a_client_subnet() {
+        assert_eq!(strip_ecs(&query(0, 0)), None);
+        let mut msg = query(0, 1);
+        msg.extend_from_slice(&opt(&[(10, COOKIE)]));
+        assert_eq!(strip_ecs(&msg), None);
+    }
+
+    #[test]
+    fn leaves_messages_that_are_not_dns() {
+        assert_eq!(strip_ecs(b""), None);
+        assert_eq!(strip_ecs(b"GET / HTTP/1.1\r\n\r\n"), None);
+        let mut msg = query(0, 1);
+        msg.extend_from_slice(&opt(&[(CLIENT_SUBNET, SUBNET)]));
+        msg.truncate(msg.len() - 3);
+        assert_eq!(strip_ecs(&msg), None);
+    }
+
+    #[test]
+    fn skips_names() {
+        let msg = query(0, 0);
+        assert_eq!(skip_name(&msg, 12), Some(25));
+        assert_eq!(skip_name(&[0xc0, 12], 0), Some(2));
+        assert_eq!(skip_name(&[3, b'c', b'o'], 0), None);
+    }
+}
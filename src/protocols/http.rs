@@ -0,0 +1,412 @@
+//! Logs the request line and a few headers of HTTP/1.x requests, and the status of responses,
+//! and rewrites the heads of requests for `--rewrite-host`, `--inject-xff` and
+//! `--inject-request-id`.
+//!
+//! Only the head of the first message in each direction is collected, up to the blank line that
+//! ends its headers; the rest of the stream passes through without being looked at.
+
+use std::{
+    fmt, mem,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::{self, FromStr},
+    task::{ready, Context, Poll},
+};
+
+use smol::io::{self, AsyncRead};
+
+use super::{Direction, Inspector};
+
+/// Maximum size of a message head that is collected for parsing.
+const MAX_HEAD: usize = 8192;
+
+/// Inspects the first message of one direction of an HTTP/1.x connection.
+pub struct HttpInspector {
+    direction: Direction,
+    peer_addr: SocketAddr,
+    head: Vec<u8>,
+}
+
+impl HttpInspector {
+    pub fn new(direction: Direction, peer_addr: SocketAddr) -> Self {
+        Self {
+            direction,
+            peer_addr,
+            head: Vec::new(),
+        }
+    }
+
+    /// Checks whether a start line belongs to an HTTP/1.x message.
+    fn is_start_line(&self, line: &[u8]) -> bool {
+        match self.direction {
+            Direction::ClientToServer => is_request_line(line),
+            Direction::ServerToClient => {
+                line.starts_with(b"HTTP/1.0 ") || line.starts_with(b"HTTP/1.1 ")
+            }
+        }
+    }
+
+    /// Parses and logs a complete (or truncated) message head.
+    fn log(&self) {
+        let head = String::from_utf8_lossy(&self.head);
+        let mut lines = head.split("\r\n");
+        let start = lines.next().unwrap_or_default();
+
+        match self.direction {
+            Direction::ClientToServer => {
+                let mut parts = start.split(' ');
+                let method = parts.next().unwrap_or_default();
+                let path = parts.next().unwrap_or_default();
+                let header = |name: &str| {
+                    lines
+                        .clone()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+                        .map(|(_, value)| value.trim())
+                        .unwrap_or("-")
+                };
+                tracing::info!(
+                    "HTTP request from {}: {} {} (host {}, content length {})",
+                    self.peer_addr,
+                    method,
+                    path,
+                    header("Host"),
+                    header("Content-Length")
+                );
+            }
+            Direction::ServerToClient => {
+                let status = start.split(' ').nth(1).unwrap_or_default();
+                tracing::info!("HTTP response to {}: status {}", self.peer_addr, status);
+            }
+        }
+    }
+}
+
+impl Inspector for HttpInspector {
+    fn inspect(&mut self, data: &[u8]) -> bool {
+        // Only search the bytes that may complete a line or the end of the head.
+        let from = self.head.len().saturating_sub(3);
+        let room = MAX_HEAD - self.head.len();
+        self.head.extend_from_slice(&data[..data.len().min(room)]);
+
+        // Check the start line as soon as it is complete, to stop early on other protocols.
+        let Some(line_end) = find(&self.head, b"\r\n") else {
+            return self.head.len() < MAX_HEAD;
+        };
+        if !self.is_start_line(&self.head[..line_end]) {
+            return false;
+        }
+
+        match find(&self.head[from..], b"\r\n\r\n") {
+            Some(end) => {
+                self.head.truncate(from + end);
+                self.log();
+                false
+            }
+            None if self.head.len() == MAX_HEAD => {
+                self.log();
+                false
+            }
+            None => true,
+        }
+    }
+}
+
+/// Finds the first occurrence of a needle in a haystack.
+pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Checks whether a line is the start line of an HTTP/1.x request.
+fn is_request_line(line: &[u8]) -> bool {
+    line.ends_with(b" HTTP/1.0") || line.ends_with(b" HTTP/1.1")
+}
+
+/// A replacement of the `Host` header, given as `<ORIGINAL>=<REPLACEMENT>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostRewrite {
+    from: String,
+    to: String,
+}
+
+impl FromStr for HostRewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(HostRewrite {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => Err(format!("expected <ORIGINAL>=<REPLACEMENT>, got: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for HostRewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.from, self.to)
+    }
+}
+
+/// Rewrites the head of a request for the backend: replaces its `Host` header if its value,
+/// ignoring case, is the original of a rewrite, adds `X-Forwarded-For` and `X-Real-IP` with
+/// the address of the client, unless the request already has them, and adds
+/// `X-Portfwd-Request-Id` with a request id. Returns `None` if nothing changed.
+///
+/// Only the header lines concerned change; the other lines are kept byte for byte.
+pub fn rewrite_head(
+    head: &[u8],
+    rewrites: &[HostRewrite],
+    client_ip: Option<IpAddr>,
+    request_id: Option<&str>,
+) -> Option<Vec<u8>> {
+    let mut rewritten = Vec::with_capacity(head.len());
+    let mut changed = false;
+    let (mut has_xff, mut has_real_ip) = (false, false);
+    for (i, line) in head.split_inclusive(|&b| b == b'\n').enumerate() {
+        let header = str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.split_once(':'))
+            .filter(|_| i > 0)
+            .map(|(name, value)| (name.trim(), value.trim()));
+        match header {
+            Some((name, value)) if name.eq_ignore_ascii_case("Host") && !changed => {
+                let rewrite = rewrites
+                    .iter()
+                    .find(|rewrite| rewrite.from.eq_ignore_ascii_case(value));
+                if let Some(rewrite) = rewrite {
+                    rewritten.extend_from_slice(b"Host: ");
+                    rewritten.extend_from_slice(rewrite.to.as_bytes());
+                    rewritten.extend_from_slice(b"\r\n");
+                    changed = true;
+                    continue;
+                }
+            }
+            Some((name, _)) if name.eq_ignore_ascii_case("X-Forwarded-For") => has_xff = true,
+            Some((name, _)) if name.eq_ignore_ascii_case("X-Real-IP") => has_real_ip = true,
+            _ => {}
+        }
+
+        // Add the address of the client before the blank line that ends the head.
+        if let Some(ip) = client_ip.filter(|_| i > 0 && matches!(line, b"\r\n" | b"\n")) {
+            let ip = ip.to_canonical();
+            if !has_xff {
+                rewritten.extend_from_slice(format!("X-Forwarded-For: {ip}\r\n").as_bytes());
+                changed = true;
+            }
+            if !has_real_ip {
+                rewritten.extend_from_slice(format!("X-Real-IP: {ip}\r\n").as_bytes());
+                changed = true;
+            }
+        }
+        if let Some(id) = request_id.filter(|_| i > 0 && matches!(line, b"\r\n" | b"\n")) {
+            rewritten.extend_from_slice(format!("X-Portfwd-Request-Id: {id}\r\n").as_bytes());
+            changed = true;
+        }
+        rewritten.extend_from_slice(line);
+    }
+    changed.then_some(rewritten)
+}
+
+/// A new random request id, a UUID v4.
+pub fn request_id() -> String {
+    let mut bytes: [u8; 16] = fastrand::u128(..).to_be_bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+enum RewriteState {
+    /// Collecting the head of the first request.
+    Collecting(Vec<u8>),
+    /// Handing out the collected bytes, rewritten if they held a request head.
+    Flushing { data: Vec<u8>, pos: usize },
+    /// Passing the rest of the stream through.
+    Passing,
+}
+
+/// A reader of the bytes of a client that rewrites the head of its first request with
+/// [`rewrite_head`], if the client speaks HTTP/1.x.
+pub struct HeadRewriteReader<'a, R> {
+    inner: R,
+    rewrites: &'a [HostRewrite],
+    /// The address of the client, if it is added to the request.
+    client_ip: Option<IpAddr>,
+    /// Whether the request gets a request id.
+    inject_request_id: bool,
+    conn_id: u64,
+    peer_addr: SocketAddr,
+    state: RewriteState,
+}
+
+impl<'a, R> HeadRewriteReader<'a, R> {
+    /// Wraps a reader, which is passed through untouched if there is nothing to rewrite.
+    pub fn new(
+        inner: R,
+        rewrites: &'a [HostRewrite],
+        inject_xff: bool,
+        inject_request_id: bool,
+        conn_id: u64,
+        peer_addr: SocketAddr,
+    ) -> Self {
+        let state = if rewrites.is_empty() && !inject_xff && !inject_request_id {
+            RewriteState::Passing
+        } else {
+            RewriteState::Collecting(Vec::new())
+        };
+        Self {
+            inner,
+            rewrites,
+            client_ip: inject_xff.then_some(peer_addr.ip()),
+            inject_request_id,
+            conn_id,
+            peer_addr,
+            state,
+        }
+    }
+
+    /// Decides what to do with the bytes collected so far, returning them once they either hold
+    /// a complete head or can't be the start of a request.
+    fn collected(&self, head: &mut Vec<u8>, eof: bool) -> Option<Vec<u8>> {
+        let Some(line_end) = find(head, b"\r\n") else {
+            return (eof || head.len() >= MAX_HEAD).then(|| mem::take(head));
+        };
+        if !is_request_line(&head[..line_end]) {
+            return Some(mem::take(head));
+        }
+        let Some(end) = find(head, b"\r\n\r\n") else {
+            return (eof || head.len() >= MAX_HEAD).then(|| mem::take(head));
+        };
+        let mut data = mem::take(head);
+        let request_id = self.inject_request_id.then(request_id);
+        if let Some(id) = &request_id {
+            tracing::debug!("Request {} of connection {}", id, self.conn_id);
+        }
+        let rewritten = rewrite_head(
+            &data[..end + 4],
+            self.rewrites,
+            self.client_ip,
+            request_id.as_deref(),
+        );
+        if let Some(mut rewritten) = rewritten {
+            tracing::debug!("Rewrote the request head of {}", self.peer_addr);
+            rewritten.extend_from_slice(&data[end + 4..]);
+            data = rewritten;
+        }
+        Some(data)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HeadRewriteReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            match &mut this.state {
+                RewriteState::Passing => return Pin::new(&mut this.inner).poll_read(cx, buf),
+                RewriteState::Flushing { data, pos } => {
+                    let n = (data.len() - *pos).min(buf.len());
+                    buf[..n].copy_from_slice(&data[*pos..*pos + n]);
+                    *pos += n;
+                    if *pos == data.len() {
+                        this.state = RewriteState::Passing;
+                    }
+                    return Poll::Ready(Ok(n));
+                }
+                RewriteState::Collecting(head) => {
+                    let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+                    let mut head = mem::take(head);
+                    head.extend_from_slice(&buf[..n]);
+                    this.state = match this.collected(&mut head, n == 0) {
+                        Some(data) => RewriteState::Flushing { data, pos: 0 },
+                        None => RewriteState::Collecting(head),
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEAD: &[u8] = b"GET / HTTP/1.1\r\nHost: Example.com\r\nAccept: */*\r\n\r\n";
+
+    fn rewrites(rewrites: &[&str]) -> Vec<HostRewrite> {
+        rewrites.iter().map(|r| r.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_host_rewrites() {
+        let rewrite: HostRewrite = "example.com=backend.internal".parse().unwrap();
+        assert_eq!(rewrite.to_string(), "example.com=backend.internal");
+        assert!("example.com".parse::<HostRewrite>().is_err());
+        assert!("=backend.internal".parse::<HostRewrite>().is_err());
+        assert!("example.com=".parse::<HostRewrite>().is_err());
+    }
+
+    #[test]
+    fn rewrites_the_host_ignoring_case() {
+        let rewritten = rewrite_head(HEAD, &rewrites(&["example.com=backend"]), None, None);
+        assert_eq!(
+            rewritten.as_deref(),
+            Some(&b"GET / HTTP/1.1\r\nHost: backend\r\nAccept: */*\r\n\r\n"[..])
+        );
+    }
+
+    #[test]
+    fn leaves_heads_with_nothing_to_rewrite() {
+        assert_eq!(
+            rewrite_head(HEAD, &rewrites(&["other=backend"]), None, None),
+            None
+        );
+        assert_eq!(rewrite_head(HEAD, &[], None, None), None);
+    }
+
+    #[test]
+    fn adds_the_client_address_and_request_id() {
+        let ip = "::ffff:192.0.2.1".parse().unwrap();
+        let rewritten = rewrite_head(HEAD, &[], Some(ip), Some("id")).unwrap();
+        assert_eq!(
+            rewritten,
+            b"GET / HTTP/1.1\r\nHost: Example.com\r\nAccept: */*\r\n\
+            X-Forwarded-For: 192.0.2.1\r\nX-Real-IP: 192.0.2.1\r\n\
+            X-Portfwd-Request-Id: id\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn keeps_the_client_address_headers_of_the_request() {
+        let head = b"GET / HTTP/1.1\r\nx-forwarded-for: 10.0.0.1\r\nX-Real-IP: 10.0.0.1\r\n\r\n";
+        let ip = "192.0.2.1".parse().unwrap();
+        assert_eq!(rewrite_head(head, &[], Some(ip), None), None);
+    }
+
+    #[test]
+    fn generates_uuid_v4_request_ids() {
+        let id = request_id();
+        assert_eq!(id.len(), 36);
+        assert_eq!(id.as_bytes()[14], b'4');
+        assert!(matches!(id.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+        assert_ne!(id, request_id());
+    }
+
+    #[test]
+    fn finds_needles() {
+        assert_eq!(find(b"ab\r\n\r\ncd", b"\r\n\r\n"), Some(2));
+        assert_eq!(find(b"ab\r\n", b"\r\n\r\n"), None);
+    }
+}
@@ -0,0 +1,592 @@
+//! Framing of HTTP/1.1 messages, so that the requests and responses on a persistent connection
+//! can be told apart.
+//!
+//! Messages are passed on as they were received: only their heads are parsed, to find out how
+//! their bodies are delimited, and bodies are copied without being decoded.
+//!
+//! A body whose sender dropped it midway can be finished from another copy of the message, such
+//! as the response to the same request sent again: the content that was already passed on is
+//! skipped, and the rest is written in the framing of the body that was started.
+
+use std::str;
+
+use smol::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Maximum size of a message head, and of a line of a chunked body.
+const MAX_HEAD: u64 = 65536;
+
+/// How the body of a message is delimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyLength {
+    /// The message has no body.
+    Empty,
+    /// The body has a `Content-Length`.
+    Fixed(u64),
+    /// The body is sent in chunks, ending with an empty one.
+    Chunked,
+    /// The body ends when the connection is closed, which only responses may do.
+    UntilClose,
+}
+
+/// The start line and headers of a message, as received.
+#[derive(Debug)]
+pub struct Head {
+    pub raw: Vec<u8>,
+}
+
+impl Head {
+    /// The first line of the message, without its line break.
+    pub fn start_line(&self) -> &str {
+        self.lines().next().unwrap_or_default()
+    }
+
+    fn lines(&self) -> impl Iterator<Item = &str> {
+        str::from_utf8(&self.raw)
+            .unwrap_or_default()
+            .split('\n')
+            .map(|line| line.trim_end_matches('\r'))
+    }
+
+    /// The values of all headers with a name, in order.
+    fn headers<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .filter(move |(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+
+    /// Whether a header with a list of tokens, such as `Connection`, contains a token.
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.headers(name)
+            .flat_map(|value| value.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Whether the message was sent by an HTTP/1.0 peer.
+    fn is_http10(&self) -> bool {
+        let line = self.start_line();
+        line.starts_with("HTTP/1.0 ") || line.ends_with(" HTTP/1.0")
+    }
+
+    /// The value of the first header with a name.
+    pub fn header<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        self.headers(name).next()
+    }
+
+    /// The method of a request.
+    pub fn method(&self) -> &str {
+        self.start_line().split(' ').next().unwrap_or_default()
+    }
+
+    /// Whether a request may be sent again without changing what it does, going by its method.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self.method(),
+            "GET" | "HEAD" | "PUT" | "DELETE" | "OPTIONS" | "TRACE"
+        )
+    }
+
+    /// The status code of a response.
+    pub fn status(&self) -> Option<u16> {
+        self.start_line().split(' ').nth(1)?.parse().ok()
+    }
+
+    /// Whether the connection is closed after this message, which it always is after one with
+    /// both a `Transfer-Encoding` and a `Content-Length`, as a peer that went by the other header
+    /// would read the next message from the wrong place.
+    pub fn closes(&self) -> bool {
+        if self.header("Transfer-Encoding").is_some() && self.header("Content-Length").is_some() {
+            return true;
+        }
+        if self.is_http10() {
+            !self.has_token("Connection", "keep-alive")
+        } else {
+            self.has_token("Connection", "close")
+        }
+    }
+
+    /// How the body is delimited by the headers, if they say so, where `Transfer-Encoding`
+    /// overrides `Content-Length`, and more than one `Content-Length` is an error.
+    fn declared_length(&self) -> io::Result<Option<BodyLength>> {
+        if self.headers("Transfer-Encoding").next().is_some() {
+            // Only a final chunked encoding delimits the body; otherwise it runs until the end.
+            let chunked = self
+                .headers("Transfer-Encoding")
+                .flat_map(|value| value.split(','))
+                .last()
+                .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+            return Ok(Some(if chunked {
+                BodyLength::Chunked
+            } else {
+                BodyLength::UntilClose
+            }));
+        }
+        let mut lengths = self
+            .headers("Content-Length")
+            .flat_map(|value| value.split(','))
+            .map(str::trim);
+        match (lengths.next(), lengths.next()) {
+            (Some(value), None) => match value.bytes().all(|b| b.is_ascii_digit()) {
+                true => value
+                    .parse()
+                    .map(|len| Some(BodyLength::Fixed(len)))
+                    .map_err(|_| invalid(format!("invalid Content-Length: {value}"))),
+                false => Err(invalid(format!("invalid Content-Length: {value}"))),
+            },
+            (Some(_), Some(_)) => Err(invalid("more than one Content-Length".into())),
+            (None, _) => Ok(None),
+        }
+    }
+
+    /// How the body of a request is delimited.
+    pub fn request_body(&self) -> io::Result<BodyLength> {
+        match self.declared_length()? {
+            Some(BodyLength::UntilClose) => Err(invalid("request body without a length".into())),
+            Some(length) => Ok(length),
+            None => Ok(BodyLength::Empty),
+        }
+    }
+
+    /// How the body of a response to a request with a method is delimited.
+    pub fn response_body(&self, method: &str) -> io::Result<BodyLength> {
+        let status = self.status().unwrap_or_default();
+        if method == "HEAD" || (100..200).contains(&status) || status == 204 || status == 304 {
+            return Ok(BodyLength::Empty);
+        }
+        Ok(self.declared_length()?.unwrap_or(BodyLength::UntilClose))
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads the head of the next message, or `None` if the stream ends before it starts.
+pub async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Head>> {
+    let mut raw = Vec::new();
+    loop {
+        let start = raw.len();
+        let limit = MAX_HEAD.saturating_sub(start as u64);
+        let n = reader.take(limit).read_until(b'\n', &mut raw).await?;
+        match n {
+            0 if raw.is_empty() => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            _ if !raw.ends_with(b"\n") => return Err(invalid("message head is too long".into())),
+            // Empty lines before a request are ignored.
+            _ if start == 0 && matches!(&raw[..], b"\r\n" | b"\n") => raw.clear(),
+            _ if matches!(&raw[start..], b"\r\n" | b"\n") => return Ok(Some(Head { raw })),
+            _ => {}
+        }
+    }
+}
+
+/// How far the body of a message got to the writer, so that the rest of it can be taken from
+/// another copy of the message if the reader fails midway.
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// How many bytes of content were written, not counting the chunked framing.
+    content: u64,
+    /// Where a chunked body was left.
+    chunk: Chunk,
+    /// Whether copying failed because the reader failed or ended early.
+    pub dropped: bool,
+}
+
+/// A position in a chunked body.
+#[derive(Clone, Copy, Debug, Default)]
+enum Chunk {
+    /// Before the size line of the next chunk.
+    #[default]
+    Next,
+    /// Within a chunk, with this many bytes of it left.
+    Data(u64),
+    /// Before the line break after a chunk.
+    End,
+    /// Within the trailers after the last chunk.
+    Trailers,
+}
+
+/// Copies a body of some length from a reader to a writer, returning how many bytes were copied.
+pub async fn copy_body<R, W>(reader: &mut R, writer: &mut W, length: BodyLength) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy_body_tracked(reader, writer, length, &mut Progress::default()).await
+}
+
+/// Copies a body like [`copy_body`], keeping track of how far it got in `progress`.
+pub async fn copy_body_tracked<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    length: BodyLength,
+    progress: &mut Progress,
+) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    progress.dropped = false;
+    match length {
+        BodyLength::Empty => Ok(0),
+        BodyLength::Fixed(len) => copy_exactly(reader, writer, len, progress).await,
+        BodyLength::UntilClose => io::copy(reader, writer).await,
+        BodyLength::Chunked => {
+            let mut total = 0;
+            loop {
+                if let Chunk::Data(left) = progress.chunk {
+                    // Copy the chunk, and then the line break after it.
+                    total += copy_exactly(reader, writer, left, progress).await?;
+                    progress.chunk = Chunk::End;
+                    continue;
+                }
+                let line = read_line(reader, progress).await?;
+                writer.write_all(&line).await?;
+                total += line.len() as u64;
+                progress.chunk = match progress.chunk {
+                    // The last chunk is followed by trailers up to an empty line.
+                    Chunk::Next => match chunk_size(&line)? {
+                        0 => Chunk::Trailers,
+                        size => Chunk::Data(size),
+                    },
+                    Chunk::Trailers if is_blank(&line) => return Ok(total),
+                    Chunk::End => Chunk::Next,
+                    chunk => chunk,
+                };
+            }
+        }
+    }
+}
+
+/// Finishes a body that copying stopped at `progress`, from the body of another copy of the
+/// message delimited by `length`, returning how many bytes were written.
+///
+/// The rest of a chunked body is written in chunks of whatever size the other copy is read in,
+/// and without trailers. Fails if the content of the copy is shorter than what was already
+/// written, or than the body that was started.
+pub async fn resume_body<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    length: BodyLength,
+    started: BodyLength,
+    progress: &mut Progress,
+) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    progress.dropped = false;
+    let shorter = || invalid("the other copy of the body is shorter".into());
+    let mut total = 0;
+    let mut content = Content::new(length);
+    let mut skip = progress.content;
+    while let Some(piece) = content.next(reader, progress).await? {
+        // Skip the content that was already written.
+        let skipped = skip.min(piece.len() as u64);
+        skip -= skipped;
+        let piece = &piece[skipped as usize..];
+        if !piece.is_empty() {
+            total += write_content(writer, started, piece, progress).await?;
+        }
+    }
+    if skip > 0 {
+        return Err(shorter());
+    }
+
+    // End the body that was started.
+    let end: &[u8] = match (started, progress.chunk) {
+        (BodyLength::Fixed(len), _) if progress.content < len => return Err(shorter()),
+        (BodyLength::Chunked, Chunk::Data(left)) if left > 0 => return Err(shorter()),
+        (BodyLength::Chunked, Chunk::Next) => b"0\r\n\r\n",
+        (BodyLength::Chunked, Chunk::Data(_) | Chunk::End) => b"\r\n0\r\n\r\n",
+        (BodyLength::Chunked, Chunk::Trailers) => b"\r\n",
+        _ => b"",
+    };
+    writer.write_all(end).await?;
+    Ok(total + end.len() as u64)
+}
+
+/// Writes content to a body that was started, in its framing, returning how many bytes were
+/// written.
+async fn write_content<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    started: BodyLength,
+    mut content: &[u8],
+    progress: &mut Progress,
+) -> io::Result<u64> {
+    let mut total = 0;
+    match started {
+        BodyLength::Fixed(len) if progress.content + content.len() as u64 <= len => {
+            writer.write_all(content).await?;
+            progress.content += content.len() as u64;
+            total += content.len() as u64;
+        }
+        BodyLength::Chunked => {
+            while !content.is_empty() {
+                match progress.chunk {
+                    Chunk::Data(left) if left > 0 => {
+                        let n = left.min(content.len() as u64);
+                        writer.write_all(&content[..n as usize]).await?;
+                        progress.content += n;
+                        progress.chunk = Chunk::Data(left - n);
+                        content = &content[n as usize..];
+                        total += n;
+                    }
+                    Chunk::Data(_) | Chunk::End => {
+                        writer.write_all(b"\r\n").await?;
+                        progress.chunk = Chunk::Next;
+                        total += 2;
+                    }
+                    Chunk::Next => {
+                        let line = format!("{:x}\r\n", content.len());
+                        writer.write_all(line.as_bytes()).await?;
+                        progress.chunk = Chunk::Data(content.len() as u64);
+                        total += line.len() as u64;
+                    }
+                    Chunk::Trailers => {
+                        return Err(invalid("the other copy of the body is longer".into()))
+                    }
+                }
+            }
+        }
+        _ => return Err(invalid("the other copy of the body is longer".into())),
+    }
+    Ok(total)
+}
+
+/// The content of a body, read piece by piece without its chunked framing.
+struct Content {
+    length: BodyLength,
+    /// What is left of the body, or of its current chunk.
+    left: u64,
+    /// Whether a chunk was read, which is followed by a line break.
+    in_chunk: bool,
+    done: bool,
+}
+
+impl Content {
+    fn new(length: BodyLength) -> Self {
+        let left = match length {
+            BodyLength::Fixed(len) => len,
+            _ => 0,
+        };
+        Self {
+            length,
+            left,
+            in_chunk: false,
+            done: false,
+        }
+    }
+
+    /// Reads the next piece of content, or `None` at the end of the body.
+    async fn next<R: AsyncBufRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        progress: &mut Progress,
+    ) -> io::Result<Option<Vec<u8>>> {
+        while !self.done {
+            match self.length {
+                BodyLength::UntilClose => {
+                    let piece = fill_buf(reader, progress, true).await?.to_vec();
+                    reader.consume(piece.len());
+                    if piece.is_empty() {
+                        self.done = true;
+                        break;
+                    }
+                    return Ok(Some(piece));
+                }
+                _ if self.left > 0 => {
+                    let buf = fill_buf(reader, progress, false).await?;
+                    let piece = buf[..self.left.min(buf.len() as u64) as usize].to_vec();
+                    reader.consume(piece.len());
+                    self.left -= piece.len() as u64;
+                    return Ok(Some(piece));
+                }
+                BodyLength::Chunked => {
+                    if self.in_chunk {
+                        read_line(reader, progress).await?;
+                    }
+                    match chunk_size(&read_line(reader, progress).await?)? {
+                        0 => {
+                            while !is_blank(&read_line(reader, progress).await?) {}
+                            self.done = true;
+                        }
+                        size => {
+                            self.left = size;
+                            self.in_chunk = true;
+                        }
+                    }
+                }
+                _ => self.done = true,
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Copies exactly `len` bytes, failing if the reader ends before.
+async fn copy_exactly<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    len: u64,
+    progress: &mut Progress,
+) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut left = len;
+    while left > 0 {
+        let buf = fill_buf(reader, progress, false).await?;
+        let n = left.min(buf.len() as u64) as usize;
+        writer.write_all(&buf[..n]).await?;
+        reader.consume(n);
+        left -= n as u64;
+        progress.content += n as u64;
+        if let Chunk::Data(rest) = &mut progress.chunk {
+            *rest -= n as u64;
+        }
+    }
+    Ok(len)
+}
+
+/// Fills the buffer of a reader, failing if it ends unless `may_end`.
+async fn fill_buf<'a, R: AsyncBufRead + Unpin>(
+    reader: &'a mut R,
+    progress: &mut Progress,
+    may_end: bool,
+) -> io::Result<&'a [u8]> {
+    match reader.fill_buf().await {
+        Ok([]) if !may_end => {
+            progress.dropped = true;
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+        Ok(buf) => Ok(buf),
+        Err(err) => {
+            progress.dropped = true;
+            Err(err)
+        }
+    }
+}
+
+/// Reads a line of a chunked body, including its line break.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    progress: &mut Progress,
+) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    if let Err(err) = reader.take(MAX_HEAD).read_until(b'\n', &mut line).await {
+        progress.dropped = true;
+        return Err(err);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() as u64 == MAX_HEAD {
+            return Err(invalid("line of chunked body is too long".into()));
+        }
+        progress.dropped = true;
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line)
+}
+
+/// The size of a chunk, from the line that starts it.
+fn chunk_size(line: &[u8]) -> io::Result<u64> {
+    str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.trim().split(';').next())
+        .map(str::trim)
+        .filter(|size| size.bytes().all(|b| b.is_ascii_hexdigit()))
+        .and_then(|size| u64::from_str_radix(size, 16).ok())
+        .ok_or_else(|| invalid("invalid chunk size".into()))
+}
+
+fn is_blank(line: &[u8]) -> bool {
+    matches!(line, b"\r\n" | b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(raw: &str) -> Head {
+        smol::block_on(read_head(&mut raw.as_bytes()))
+            .unwrap()
+            .unwrap()
+    }
+
+    fn copy(raw: &str, length: BodyLength) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        smol::block_on(copy_body(&mut raw.as_bytes(), &mut body, length))?;
+        Ok(body)
+    }
+
+    #[test]
+    fn transfer_encoding_overrides_content_length_and_closes() {
+        let request =
+            head("POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert_eq!(request.request_body().unwrap(), BodyLength::Chunked);
+        assert!(request.closes());
+
+        let request = head("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert!(!request.closes());
+    }
+
+    #[test]
+    fn rejects_more_than_one_content_length() {
+        for headers in [
+            "Content-Length: 3\r\nContent-Length: 4\r\n",
+            "Content-Length: 3\r\nContent-Length: 3\r\n",
+            "Content-Length: 3, 3\r\n",
+        ] {
+            let request = head(&format!("POST / HTTP/1.1\r\n{headers}\r\n"));
+            assert_eq!(
+                request.request_body().unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+        let request = head("POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\n");
+        assert!(request.request_body().is_err());
+        let request = head("POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\n");
+        assert_eq!(request.request_body().unwrap(), BodyLength::Fixed(3));
+    }
+
+    #[test]
+    fn only_a_final_chunked_encoding_delimits_the_body() {
+        let request = head("POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n");
+        assert!(request.request_body().is_err());
+        let response = head("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked, gzip\r\n\r\n");
+        assert_eq!(
+            response.response_body("GET").unwrap(),
+            BodyLength::UntilClose
+        );
+        let response = head("HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+        assert_eq!(response.response_body("GET").unwrap(), BodyLength::Chunked);
+    }
+
+    #[test]
+    fn copies_chunked_bodies_as_they_are() {
+        let body = "3;ext=1\r\nabc\r\n0\r\nTrailer: x\r\n\r\n";
+        assert_eq!(copy(body, BodyLength::Chunked).unwrap(), body.as_bytes());
+        assert_eq!(
+            copy(&format!("{body}GET"), BodyLength::Chunked).unwrap(),
+            body.as_bytes()
+        );
+    }
+
+    #[test]
+    fn rejects_bad_chunk_sizes() {
+        for body in [
+            "x\r\nabc\r\n0\r\n\r\n",
+            "\r\n",
+            "-3\r\nabc\r\n0\r\n\r\n",
+            "+3\r\nabc\r\n0\r\n\r\n",
+            "10000000000000000\r\n",
+        ] {
+            assert_eq!(
+                copy(body, BodyLength::Chunked).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! Logs the client ids of MQTT CONNECT packets and the topics of PUBLISH packets.
+//!
+//! Only the fixed header and the start of the variable header of those packets are copied aside
+//! for parsing; every other byte is skipped by counting, and the stream is never modified.
+
+use std::net::SocketAddr;
+
+use super::{Direction, Inspector};
+
+/// Packet type of a CONNECT packet.
+const CONNECT: u8 = 1;
+/// Packet type of a CONNACK packet.
+const CONNACK: u8 = 2;
+/// Packet type of a PUBLISH packet.
+const PUBLISH: u8 = 3;
+
+/// Maximum number of bytes collected from the start of a packet to parse its headers.
+const MAX_HEADER: usize = 512;
+
+/// Inspects one direction of an MQTT connection.
+pub struct MqttInspector {
+    direction: Direction,
+    peer_addr: SocketAddr,
+    /// Whether no packet has been seen yet, which decides if the stream is MQTT at all.
+    first: bool,
+    state: State,
+}
+
+enum State {
+    /// Waiting for the first byte of a fixed header.
+    Header,
+    /// Decoding the variable-length "remaining length" of a packet.
+    Length {
+        packet_type: u8,
+        value: usize,
+        shift: u32,
+    },
+    /// Collecting the start of a packet body for parsing.
+    Collect {
+        packet_type: u8,
+        remaining: usize,
+        buf: Vec<u8>,
+    },
+    /// Skipping over the rest of a packet body.
+    Skip { remaining: usize },
+}
+
+/// Outcome of parsing the collected start of a packet.
+enum Parsed {
+    /// More bytes are needed.
+    Incomplete,
+    /// The packet is not what it should be, which means the stream is not MQTT.
+    Invalid,
+    Done,
+}
+
+impl MqttInspector {
+    pub fn new(direction: Direction, peer_addr: SocketAddr) -> Self {
+        Self {
+            direction,
+            peer_addr,
+            first: true,
+            state: State::Header,
+        }
+    }
+
+    /// Starts on a packet body once its length is known.
+    fn begin_body(&self, packet_type: u8, remaining: usize) -> State {
+        match packet_type {
+            _ if remaining == 0 => State::Header,
+            CONNECT | PUBLISH => State::Collect {
+                packet_type,
+                remaining,
+                buf: Vec::new(),
+            },
+            _ => State::Skip { remaining },
+        }
+    }
+
+    /// Parses and logs the collected start of a packet.
+    fn parse(&self, packet_type: u8, buf: &[u8]) -> Parsed {
+        let mut cursor = Cursor(buf);
+        if packet_type == CONNECT {
+            let Some(name) = cursor.string() else {
+                return Parsed::Incomplete;
+            };
+            if name != b"MQTT" && name != b"MQIsdp" {
+                return Parsed::Invalid;
+            }
+            let Some([level, flags, _, _]) = cursor.array() else {
+                return Parsed::Incomplete;
+            };
+            // MQTT 5 adds properties before the payload.
+            if level >= 5 && cursor.properties().is_none() {
+                return Parsed::Incomplete;
+            }
+            let Some(client_id) = cursor.string() else {
+                return Parsed::Incomplete;
+            };
+            tracing::info!(
+                "MQTT CONNECT from {}: client id {:?}, clean session {}",
+                self.peer_addr,
+                String::from_utf8_lossy(client_id),
+                flags & 0x02 != 0
+            );
+        } else {
+            let Some(topic) = cursor.string() else {
+                return Parsed::Incomplete;
+            };
+            tracing::info!(
+                "MQTT PUBLISH on {} ({}): topic {:?}",
+                self.peer_addr,
+                self.direction,
+                String::from_utf8_lossy(topic)
+            );
+        }
+        Parsed::Done
+    }
+}
+
+impl Inspector for MqttInspector {
+    fn inspect(&mut self, mut data: &[u8]) -> bool {
+        while !data.is_empty() {
+            self.state = match std::mem::replace(&mut self.state, State::Header) {
+                State::Header => {
+                    let byte = data[0];
+                    data = &data[1..];
+                    // A connection must start with CONNECT, answered by CONNACK.
+                    if self.first {
+                        let expected = match self.direction {
+                            Direction::ClientToServer => byte == CONNECT << 4,
+                            Direction::ServerToClient => byte >> 4 == CONNACK,
+                        };
+                        if !expected {
+                            return false;
+                        }
+                        self.first = false;
+                    }
+                    State::Length {
+                        packet_type: byte >> 4,
+                        value: 0,
+                        shift: 0,
+                    }
+                }
+                State::Length {
+                    packet_type,
+                    value,
+                    shift,
+                } => {
+                    let byte = data[0];
+                    data = &data[1..];
+                    let value = value | ((byte & 0x7f) as usize) << shift;
+                    if byte & 0x80 == 0 {
+                        self.begin_body(packet_type, value)
+                    } else if shift >= 21 {
+                        // The remaining length takes at most four bytes.
+                        return false;
+                    } else {
+                        State::Length {
+                            packet_type,
+                            value,
+                            shift: shift + 7,
+                        }
+                    }
+                }
+                State::Collect {
+                    packet_type,
+                    remaining,
+                    mut buf,
+                } => {
+                    let wanted = remaining.min(MAX_HEADER - buf.len());
+                    let taken = wanted.min(data.len());
+                    buf.extend_from_slice(&data[..taken]);
+                    data = &data[taken..];
+                    let remaining = remaining - taken;
+
+                    match self.parse(packet_type, &buf) {
+                        Parsed::Invalid => return false,
+                        Parsed::Incomplete if remaining > 0 && buf.len() < MAX_HEADER => {
+                            State::Collect {
+                                packet_type,
+                                remaining,
+                                buf,
+                            }
+                        }
+                        _ if remaining > 0 => State::Skip { remaining },
+                        _ => State::Header,
+                    }
+                }
+                State::Skip { remaining } => {
+                    let skipped = remaining.min(data.len());
+                    data = &data[skipped..];
+                    match remaining - skipped {
+                        0 => State::Header,
+                        remaining => State::Skip { remaining },
+                    }
+                }
+            };
+        }
+        true
+    }
+}
+
+/// Reads MQTT data types from the collected start of a packet.
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.bytes(N).map(|b| b.try_into().unwrap())
+    }
+
+    /// A string prefixed by its two-byte length.
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let len = u16::from_be_bytes(self.array()?);
+        self.bytes(len as usize)
+    }
+
+    /// MQTT 5 properties, prefixed by their variable-length size.
+    fn properties(&mut self) -> Option<()> {
+        let mut len = 0;
+        for shift in [0, 7, 14, 21] {
+            let [byte] = self.array()?;
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return self.bytes(len).map(|_| ());
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inspector(direction: Direction) -> MqttInspector {
+        MqttInspector::new(direction, "127.0.0.1:1883".parse().unwrap())
+    }
+
+    /// A CONNECT packet of MQTT 3.1.1 with a client id.
+    fn connect(client_id: &[u8]) -> Vec<u8> {
+        let mut body = b"\x00\x04MQTT\x04\x02\x00\x3c".to_vec();
+        body.extend_from_slice(&(client_id.len() as u16).to_be_bytes());
+        body.extend_from_slice(client_id);
+        let mut packet = vec![CONNECT << 4, body.len() as u8];
+        packet.extend_from_slice(&body);
+        packet
+    }
+
+    /// A PUBLISH packet with a topic and a payload of `payload` bytes, whose remaining length
+    /// takes two bytes if it is over 127.
+    fn publish(topic: &[u8], payload: usize) -> Vec<u8> {
+        let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(topic);
+        body.resize(body.len() + payload, b'x');
+        let mut packet = vec![PUBLISH << 4];
+        match body.len() {
+            len if len < 128 => packet.push(len as u8),
+            len => packet.extend_from_slice(&[(len & 0x7f) as u8 | 0x80, (len >> 7) as u8]),
+        }
+        packet.extend_from_slice(&body);
+        packet
+    }
+
+    #[test]
+    fn follows_packets_in_any_chunks() {
+        let mut stream = connect(b"client");
+        stream.extend_from_slice(&publish(b"a/b", 1000));
+        stream.extend_from_slice(&[0xc0, 0]);
+        for size in [1, 3, 100, stream.len()] {
+            let mut inspector = inspector(Direction::ClientToServer);
+            assert!(stream.chunks(size).all(|chunk| inspector.inspect(chunk)));
+            assert!(matches!(inspector.state, State::Header));
+        }
+    }
+
+    #[test]
+    fn parses_connect_packets() {
+        let inspector = inspector(Direction::ClientToServer);
+        let packet = connect(b"client");
+        assert!(matches!(
+            inspector.parse(CONNECT, &packet[2..]),
+            Parsed::Done
+        ));
+        assert!(matches!(
+            inspector.parse(CONNECT, &packet[2..10]),
+            Parsed::Incomplete
+        ));
+        let mut other = packet[2..].to_vec();
+        other[2..6].copy_from_slice(b"HTTP");
+        assert!(matches!(inspector.parse(CONNECT, &other), Parsed::Invalid));
+    }
+
+    #[test]
+    fn parses_connect_packets_of_mqtt_5() {
+        let inspector = inspector(Direction::ClientToServer);
+        let body = b"\x00\x04MQTT\x05\x02\x00\x3c\x03\x21\x00\x0a\x00\x01c";
+        assert!(matches!(inspector.parse(CONNECT, body), Parsed::Done));
+        assert!(matches!(
+            inspector.parse(CONNECT, &body[..12]),
+            Parsed::Incomplete
+        ));
+    }
+
+    #[test]
+    fn gives_up_on_other_protocols() {
+        assert!(!inspector(Direction::ClientToServer).inspect(b"GET / HTTP/1.1\r\n"));
+        assert!(!inspector(Direction::ClientToServer).inspect(&publish(b"t", 0)));
+        assert!(!inspector(Direction::ServerToClient).inspect(b"\x30\x00"));
+        assert!(inspector(Direction::ServerToClient).inspect(b"\x20\x02\x00\x00"));
+    }
+
+    #[test]
+    fn rejects_remaining_lengths_over_four_bytes() {
+        let mut inspector = inspector(Direction::ClientToServer);
+        assert!(!inspector.inspect(&[CONNECT << 4, 0xff, 0xff, 0xff, 0xff, 0x01]));
+    }
+}
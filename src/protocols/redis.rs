@@ -0,0 +1,290 @@
+//! Logs the names of Redis commands sent by clients.
+//!
+//! The RESP2 stream is parsed byte by byte as it passes through, so a command never has to be
+//! buffered as a whole: only its name, the first bulk string of the command array, is copied.
+
+use std::net::SocketAddr;
+
+use super::Inspector;
+
+/// Maximum length of a command name that is logged.
+const MAX_NAME: usize = 32;
+
+/// Inspects the commands sent by a Redis client.
+pub struct RedisInspector {
+    peer_addr: SocketAddr,
+    /// Whether no command has been seen yet, which decides if the stream is RESP at all.
+    first: bool,
+    /// The name of the current command, collected from its first element.
+    name: Vec<u8>,
+    state: State,
+}
+
+enum State {
+    /// Waiting for the first byte of a command.
+    Command,
+    /// Reading the number of elements of a command array.
+    ArrayLen(Number),
+    /// Waiting for the type byte of the next element of a command array.
+    Element { remaining: u64, first: bool },
+    /// Reading the length of a bulk string element.
+    BulkLen {
+        remaining: u64,
+        first: bool,
+        len: Number,
+    },
+    /// Reading the contents of a bulk string element, and the CRLF that ends it.
+    Bulk {
+        remaining: u64,
+        first: bool,
+        left: u64,
+    },
+    /// Skipping a simple element up to its LF.
+    Simple { remaining: u64 },
+    /// Reading the name of an inline command, then skipping up to its LF.
+    Inline { done: bool },
+}
+
+/// An integer of a RESP line, read digit by digit up to its LF.
+#[derive(Default)]
+struct Number {
+    value: u64,
+    negative: bool,
+}
+
+impl Number {
+    /// Feeds a byte of the line, returning the value once its LF is reached.
+    ///
+    /// Null arrays and bulk strings have a negative length, which is reported as `None`.
+    fn feed(&mut self, byte: u8) -> Option<Option<u64>> {
+        match byte {
+            b'\n' if self.negative => return Some(None),
+            b'\n' => return Some(Some(self.value)),
+            b'-' => self.negative = true,
+            b'0'..=b'9' => self.value = self.value.saturating_mul(10) + (byte - b'0') as u64,
+            _ => {}
+        }
+        None
+    }
+}
+
+impl RedisInspector {
+    pub fn new(peer_addr: SocketAddr) -> Self {
+        Self {
+            peer_addr,
+            first: true,
+            name: Vec::new(),
+            state: State::Command,
+        }
+    }
+
+    /// Logs the name of the current command, once it has been read.
+    fn log_command(&mut self) {
+        self.first = false;
+        tracing::debug!(
+            "Redis command {} from {}",
+            String::from_utf8_lossy(&self.name).to_ascii_uppercase(),
+            self.peer_addr
+        );
+        self.name.clear();
+    }
+
+    /// Moves on to the next element of a command array, or to the next command.
+    fn next_element(remaining: u64) -> State {
+        match remaining {
+            0 => State::Command,
+            remaining => State::Element {
+                remaining,
+                first: false,
+            },
+        }
+    }
+
+    /// Consumes as much of a bulk string as is available, collecting it if it is a command name.
+    fn bulk<'a>(&mut self, data: &'a [u8], remaining: u64, first: bool, left: u64) -> &'a [u8] {
+        let len = left.min(data.len() as u64) as usize;
+        let (chunk, rest) = data.split_at(len);
+
+        // The contents are followed by two bytes of CRLF.
+        if first {
+            let contents = len.min(left.saturating_sub(2) as usize);
+            let room = MAX_NAME.saturating_sub(self.name.len());
+            self.name.extend_from_slice(&chunk[..contents.min(room)]);
+        }
+
+        self.state = match left - len as u64 {
+            0 => {
+                if first {
+                    self.log_command();
+                }
+                Self::next_element(remaining)
+            }
+            left => State::Bulk {
+                remaining,
+                first,
+                left,
+            },
+        };
+        rest
+    }
+}
+
+impl Inspector for RedisInspector {
+    fn inspect(&mut self, mut data: &[u8]) -> bool {
+        while !data.is_empty() {
+            // Bulk strings are skipped over in one go, everything else is read byte by byte.
+            if let State::Bulk {
+                remaining,
+                first,
+                left,
+            } = self.state
+            {
+                data = self.bulk(data, remaining, first, left);
+                continue;
+            }
+
+            let byte = data[0];
+            data = &data[1..];
+            self.state = match std::mem::replace(&mut self.state, State::Command) {
+                State::Command => match byte {
+                    b'*' => State::ArrayLen(Number::default()),
+                    // Blank lines between commands are allowed.
+                    b'\r' | b'\n' => State::Command,
+                    b if b.is_ascii_alphabetic() => {
+                        self.name.push(b);
+                        State::Inline { done: false }
+                    }
+                    // Anything else means the client does not speak RESP.
+                    _ if self.first => return false,
+                    _ => State::Inline { done: true },
+                },
+                State::ArrayLen(mut len) => match len.feed(byte) {
+                    Some(None | Some(0)) => State::Command,
+                    Some(Some(remaining)) => State::Element {
+                        remaining,
+                        first: true,
+                    },
+                    None => State::ArrayLen(len),
+                },
+                State::Element { remaining, first } => match byte {
+                    b'$' => State::BulkLen {
+                        remaining: remaining - 1,
+                        first,
+                        len: Number::default(),
+                    },
+                    _ => State::Simple {
+                        remaining: remaining - 1,
+                    },
+                },
+                State::BulkLen {
+                    remaining,
+                    first,
+                    mut len,
+                } => match len.feed(byte) {
+                    Some(None) => Self::next_element(remaining),
+                    Some(Some(len)) => State::Bulk {
+                        remaining,
+                        first,
+                        left: len + 2,
+                    },
+                    None => State::BulkLen {
+                        remaining,
+                        first,
+                        len,
+                    },
+                },
+                State::Bulk { .. } => unreachable!("bulk strings are handled above"),
+                State::Simple { remaining } => match byte {
+                    b'\n' => Self::next_element(remaining),
+                    _ => State::Simple { remaining },
+                },
+                State::Inline { done } => match byte {
+                    b'\n' if !done => {
+                        self.log_command();
+                        State::Command
+                    }
+                    b'\n' => State::Command,
+                    b' ' | b'\r' if !done => {
+                        self.log_command();
+                        State::Inline { done: true }
+                    }
+                    b if !done && self.name.len() < MAX_NAME => {
+                        self.name.push(b);
+                        State::Inline { done }
+                    }
+                    _ => State::Inline { done },
+                },
+            };
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inspector() -> RedisInspector {
+        RedisInspector::new("127.0.0.1:6379".parse().unwrap())
+    }
+
+    /// Feeds a stream in chunks of `size` bytes, returning whether the inspector wants more.
+    fn feed(inspector: &mut RedisInspector, data: &[u8], size: usize) -> bool {
+        data.chunks(size).all(|chunk| inspector.inspect(chunk))
+    }
+
+    const COMMANDS: &[u8] =
+        b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nva\r\nl\r\n*1\r\n$4\r\nPING\r\n";
+
+    #[test]
+    fn follows_command_arrays_in_any_chunks() {
+        for size in [1, 2, 5, COMMANDS.len()] {
+            let mut inspector = inspector();
+            assert!(feed(&mut inspector, COMMANDS, size));
+            assert!(!inspector.first);
+            assert!(matches!(inspector.state, State::Command));
+            assert!(inspector.name.is_empty());
+        }
+    }
+
+    #[test]
+    fn collects_the_command_name() {
+        let mut inspector = inspector();
+        assert!(inspector.inspect(b"*2\r\n$3\r\nGE"));
+        assert_eq!(inspector.name, b"GE");
+        assert!(inspector.inspect(b"T\r\n$1\r\nk\r\n"));
+        assert!(inspector.name.is_empty());
+        assert!(matches!(inspector.state, State::Command));
+    }
+
+    #[test]
+    fn skips_null_and_simple_elements() {
+        let mut inspector = inspector();
+        assert!(inspector.inspect(b"*-1\r\n*3\r\n$4\r\nECHO\r\n$-1\r\n:5\r\n"));
+        assert!(matches!(inspector.state, State::Command));
+    }
+
+    #[test]
+    fn follows_inline_commands() {
+        let mut inspector = inspector();
+        assert!(inspector.inspect(b"PING\r\nGET key\r\n"));
+        assert!(!inspector.first);
+        assert!(matches!(inspector.state, State::Command));
+    }
+
+    #[test]
+    fn gives_up_on_other_protocols() {
+        assert!(!inspector().inspect(b"\x16\x03\x01"));
+        assert!(!inspector().inspect(b"{\"json\": 1}"));
+    }
+
+    #[test]
+    fn truncates_long_command_names() {
+        let mut inspector = inspector();
+        let name = [b'A'; 40];
+        let mut command = format!("*1\r\n${}\r\n", name.len()).into_bytes();
+        command.extend_from_slice(&name[..36]);
+        assert!(inspector.inspect(&command));
+        assert_eq!(inspector.name.len(), MAX_NAME);
+    }
+}
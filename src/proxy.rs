@@ -0,0 +1,92 @@
+//! Proxy modes, where each client asks for its own destination instead of being forwarded to
+//! the backends.
+
+use std::{fmt, net::SocketAddr};
+
+use smol::io::{self, AsyncReadExt};
+
+use crate::{auth::Credentials, http_connect, socks4, socks5, transport::BoxStream};
+
+/// The kind of proxy that portfwd acts as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// SOCKS4a and SOCKS5 on the same port, told apart by their version byte.
+    Socks,
+    /// HTTP CONNECT tunnels.
+    HttpConnect,
+}
+
+impl Mode {
+    /// The port to listen on, unless a backend or `--port` gives another one.
+    pub fn default_port(self) -> u16 {
+        match self {
+            Mode::Socks => 1080,
+            Mode::HttpConnect => 8080,
+        }
+    }
+}
+
+/// The protocol a client made its request in, which its reply is sent in too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handshake {
+    Socks4,
+    Socks5,
+    HttpConnect,
+}
+
+impl fmt::Display for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Handshake::Socks4 => "SOCKS4",
+            Handshake::Socks5 => "SOCKS5",
+            Handshake::HttpConnect => "HTTP CONNECT",
+        })
+    }
+}
+
+/// Reads the request of a client, returning the address to connect to and the client stream to
+/// reply on.
+pub async fn request(
+    mode: Mode,
+    mut stream: BoxStream,
+    auth: Option<&Credentials>,
+    peer_addr: SocketAddr,
+) -> io::Result<(Handshake, SocketAddr, BoxStream)> {
+    match mode {
+        Mode::Socks => {
+            let mut version = [0; 1];
+            stream.read_exact(&mut version).await?;
+            match version[0] {
+                socks4::VERSION => {
+                    let addr = socks4::request(&mut stream, auth).await?;
+                    Ok((Handshake::Socks4, addr, stream))
+                }
+                socks5::VERSION => {
+                    let addr = socks5::request(&mut stream, auth, peer_addr).await?;
+                    Ok((Handshake::Socks5, addr, stream))
+                }
+                version => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported SOCKS version {version}"),
+                )),
+            }
+        }
+        Mode::HttpConnect => {
+            let (addr, stream) = http_connect::request(stream, auth, peer_addr).await?;
+            Ok((Handshake::HttpConnect, addr, stream))
+        }
+    }
+}
+
+/// Tells a client whether its connection was established.
+pub async fn reply(
+    handshake: Handshake,
+    stream: &mut BoxStream,
+    succeeded: bool,
+) -> io::Result<()> {
+    match handshake {
+        Handshake::Socks4 => socks4::reply(stream, succeeded).await,
+        Handshake::Socks5 => socks5::reply(stream, succeeded).await,
+        Handshake::HttpConnect => http_connect::reply(stream, succeeded).await,
+    }
+}
@@ -0,0 +1,142 @@
+//! A token bucket that limits how often something may happen, e.g. accepting a client.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use smol::Timer;
+
+/// How long to wait before checking the bucket again when it is empty.
+const BACKOFF: Duration = Duration::from_millis(1);
+
+/// Allows a number of events per second, with bursts of up to one second's worth.
+#[derive(Debug)]
+pub struct RateLimit {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimit {
+    pub fn new(per_second: u32) -> Self {
+        let rate = f64::from(per_second);
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token if there is one.
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Waits until a token is available and takes it.
+    pub async fn acquire(&mut self) {
+        while !self.try_acquire() {
+            Timer::after(BACKOFF).await;
+        }
+    }
+}
+
+/// A rate limit shared by all clients, e.g. of connections to the backends, with a queue of
+/// limited length for the clients that wait for it.
+#[derive(Debug)]
+pub struct SharedRateLimit {
+    bucket: Mutex<RateLimit>,
+    /// Number of clients waiting for a token.
+    queued: AtomicUsize,
+    queue_depth: usize,
+}
+
+impl SharedRateLimit {
+    pub fn new(per_second: u32, queue_depth: usize) -> Self {
+        Self {
+            bucket: Mutex::new(RateLimit::new(per_second)),
+            queued: AtomicUsize::new(0),
+            queue_depth,
+        }
+    }
+
+    /// Waits until a token is available and takes it, or returns `false` right away if the
+    /// queue is full.
+    pub async fn acquire(&self) -> bool {
+        if self.bucket.lock().unwrap().try_acquire() {
+            return true;
+        }
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.queue_depth {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return false;
+        }
+
+        // Leave the queue even if the client goes away while waiting.
+        let _queued = Queued(&self.queued);
+        while !self.bucket.lock().unwrap().try_acquire() {
+            Timer::after(BACKOFF).await;
+        }
+        true
+    }
+}
+
+/// A place in the queue of a [`SharedRateLimit`], given up when dropped.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Takes tokens until the bucket is empty, returning how many it had.
+    fn drain(bucket: &mut RateLimit) -> usize {
+        std::iter::from_fn(|| bucket.try_acquire().then_some(()))
+            .take(1000)
+            .count()
+    }
+
+    #[test]
+    fn allows_a_burst_of_one_second() {
+        let mut bucket = RateLimit::new(5);
+        assert_eq!(drain(&mut bucket), 5);
+        assert!(!bucket.try_acquire());
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let mut bucket = RateLimit::new(10);
+        drain(&mut bucket);
+        bucket.last_refill -= Duration::from_millis(350);
+        assert_eq!(drain(&mut bucket), 3);
+        // The bucket never holds more than a burst.
+        bucket.last_refill -= Duration::from_secs(5);
+        assert_eq!(drain(&mut bucket), 10);
+    }
+
+    #[test]
+    fn turns_clients_away_when_the_queue_is_full() {
+        smol::block_on(async {
+            let limit = SharedRateLimit::new(1, 0);
+            assert!(limit.acquire().await);
+            assert!(!limit.acquire().await);
+            assert_eq!(limit.queued.load(Ordering::Relaxed), 0);
+        });
+    }
+}
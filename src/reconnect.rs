@@ -0,0 +1,354 @@
+//! Backend streams that reconnect when the backend drops them mid-stream, for
+//! `--reconnect-on-error`.
+//!
+//! Everything sent to the backend since the connection was opened is kept, and replayed on the
+//! new connection before anything else, so that the backend sees the same stream from its
+//! start. Once more was sent than the buffer keeps, the connection is no longer re-established,
+//! since replaying only part of it could start the new connection in the middle of a message.
+//!
+//! The new connection starts over, so the backend answers the replayed bytes again, and the
+//! client gets the answers it already had before the backend dropped the connection once more.
+//! This suits protocols whose clients send everything before the backend answers, or can tell
+//! repeated answers apart.
+
+use std::{
+    mem,
+    net::{SocketAddr, TcpStream},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll, Waker},
+};
+
+use smol::io::{self, AsyncRead, AsyncWrite};
+
+use crate::transport::{self, BoxFuture, BoxStream, ChainedTransport, Stream, Transport};
+
+/// How many times a single connection may be re-established.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+const READ: usize = 0;
+const WRITE: usize = 1;
+
+enum State {
+    Connected(BoxStream),
+    Connecting(BoxFuture<'static, io::Result<BoxStream>>),
+    /// Sending the kept bytes to a new connection.
+    Replaying {
+        stream: BoxStream,
+        pos: usize,
+    },
+    /// Reconnecting failed.
+    Failed,
+}
+
+/// A stream to a backend that is re-established when the backend resets it.
+pub struct ReconnectStream {
+    transport: ChainedTransport,
+    addr: SocketAddr,
+    state: State,
+    /// Everything written to the backend, replayed on a new connection, until it outgrew the
+    /// capacity.
+    replay: Option<Vec<u8>>,
+    capacity: usize,
+    /// The socket of the current connection, for those that reset it when it is killed.
+    socket: Arc<Mutex<Option<TcpStream>>>,
+    attempts: u32,
+    /// The tasks reading and writing, which are woken once a new connection is ready, since
+    /// only one of them drives the reconnection.
+    wakers: [Option<Waker>; 2],
+}
+
+/// Whether an error means that the backend dropped the connection.
+fn is_dropped(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+    )
+}
+
+impl ReconnectStream {
+    /// Wraps a connected stream, keeping up to `capacity` bytes to replay, and the socket of the
+    /// current connection in `socket`.
+    pub fn new(
+        stream: BoxStream,
+        transport: ChainedTransport,
+        addr: SocketAddr,
+        capacity: usize,
+        socket: Arc<Mutex<Option<TcpStream>>>,
+    ) -> Self {
+        Self {
+            transport,
+            addr,
+            state: State::Connected(stream),
+            replay: Some(Vec::new()),
+            capacity,
+            socket,
+            attempts: 0,
+            wakers: [None, None],
+        }
+    }
+
+    /// Starts connecting again after an error, unless it happened too often or too much was
+    /// sent to replay.
+    fn reconnect(&mut self, err: io::Error) -> io::Result<()> {
+        if self.attempts == MAX_ATTEMPTS {
+            return Err(err);
+        }
+        if self.replay.is_none() {
+            tracing::warn!(
+                "Backend {} dropped the connection ({}) after more than {} bytes, which can't be replayed",
+                self.addr,
+                err,
+                self.capacity
+            );
+            return Err(err);
+        }
+        self.attempts += 1;
+        tracing::warn!(
+            "Backend {} dropped the connection ({}), reconnecting",
+            self.addr,
+            err
+        );
+        let transport = self.transport.clone();
+        let addr = self.addr;
+        self.state = State::Connecting(Box::pin(async move { transport.connect(addr).await }));
+        Ok(())
+    }
+
+    /// Keeps the bytes written, until there are too many of them.
+    fn record(&mut self, data: &[u8]) {
+        if let Some(replay) = &mut self.replay {
+            if replay.len() + data.len() > self.capacity {
+                self.replay = None;
+            } else {
+                replay.extend_from_slice(data);
+            }
+        }
+    }
+
+    /// Drives a reconnection, if there is one, until the stream is connected again.
+    fn poll_connected(&mut self, cx: &mut Context<'_>, side: usize) -> Poll<io::Result<()>> {
+        if !self.wakers[side]
+            .as_ref()
+            .is_some_and(|waker| waker.will_wake(cx.waker()))
+        {
+            self.wakers[side] = Some(cx.waker().clone());
+        }
+
+        loop {
+            self.state = match mem::replace(&mut self.state, State::Failed) {
+                State::Connected(stream) => {
+                    self.state = State::Connected(stream);
+                    return Poll::Ready(Ok(()));
+                }
+                State::Connecting(mut connect) => match connect.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        self.state = State::Connecting(connect);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Ok(stream)) => {
+                        *self.socket.lock().unwrap() = transport::try_clone_tcp(&stream);
+                        State::Replaying { stream, pos: 0 }
+                    }
+                    Poll::Ready(Err(err)) => {
+                        tracing::warn!("Failed to reconnect to backend {}: {}", self.addr, err);
+                        return Poll::Ready(Err(err));
+                    }
+                },
+                State::Replaying { mut stream, pos } if pos < self.replayed().len() => {
+                    match Pin::new(&mut stream).poll_write(cx, &self.replayed()[pos..]) {
+                        Poll::Pending => {
+                            self.state = State::Replaying { stream, pos };
+                            return Poll::Pending;
+                        }
+                        Poll::Ready(Ok(0)) => {
+                            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()))
+                        }
+                        Poll::Ready(Ok(n)) => State::Replaying {
+                            stream,
+                            pos: pos + n,
+                        },
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    }
+                }
+                State::Replaying { stream, pos } => {
+                    tracing::warn!(
+                        "Reconnected to backend {}, replayed {} bytes",
+                        self.addr,
+                        pos
+                    );
+                    for waker in self.wakers.iter_mut().filter_map(Option::take) {
+                        waker.wake();
+                    }
+                    State::Connected(stream)
+                }
+                State::Failed => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "reconnecting to the backend failed",
+                    )))
+                }
+            };
+        }
+    }
+
+    /// The bytes to replay, which are all kept while reconnecting.
+    fn replayed(&self) -> &[u8] {
+        self.replay.as_deref().unwrap_or_default()
+    }
+
+    /// The stream once [`Self::poll_connected`] is ready.
+    fn stream(&mut self) -> &mut BoxStream {
+        match &mut self.state {
+            State::Connected(stream) => stream,
+            _ => unreachable!("the stream is connected"),
+        }
+    }
+}
+
+impl AsyncRead for ReconnectStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            ready!(this.poll_connected(cx, READ))?;
+            match ready!(Pin::new(this.stream()).poll_read(cx, buf)) {
+                Err(err) if is_dropped(&err) => this.reconnect(err)?,
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for ReconnectStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            ready!(this.poll_connected(cx, WRITE))?;
+            match ready!(Pin::new(this.stream()).poll_write(cx, buf)) {
+                Ok(n) => {
+                    this.record(&buf[..n]);
+                    return Poll::Ready(Ok(n));
+                }
+                Err(err) if is_dropped(&err) => this.reconnect(err)?,
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_connected(cx, WRITE))?;
+        Pin::new(this.stream()).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_connected(cx, WRITE))?;
+        Pin::new(this.stream()).poll_close(cx)
+    }
+}
+
+/// The socket of the current connection, which a reconnection replaces.
+impl Stream for ReconnectStream {
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        match &self.state {
+            State::Connected(stream) => stream.tcp_socket(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+    use socket2::SockRef;
+
+    use super::*;
+    use crate::transport::TcpTransport;
+
+    /// A backend that resets its first connection once it got `first` bytes, and answers the
+    /// next one with everything it got.
+    fn backend(first: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut vec![0; first]).unwrap();
+            SockRef::from(&stream)
+                .set_linger(Some(std::time::Duration::ZERO))
+                .unwrap();
+            drop(stream);
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = vec![0; first];
+            stream.read_exact(&mut received).unwrap();
+            stream.write_all(&received).unwrap();
+        });
+        addr
+    }
+
+    async fn connect(
+        addr: SocketAddr,
+        capacity: usize,
+    ) -> (ReconnectStream, Arc<Mutex<Option<TcpStream>>>) {
+        let transport = ChainedTransport::new(TcpTransport::default());
+        let stream = transport.connect(addr).await.unwrap();
+        let socket = Arc::new(Mutex::new(transport::try_clone_tcp(&stream)));
+        let stream = ReconnectStream::new(stream, transport, addr, capacity, socket.clone());
+        (stream, socket)
+    }
+
+    #[test]
+    fn replays_everything_since_connecting() {
+        smol::block_on(async {
+            let addr = backend(11);
+            let (mut stream, socket) = connect(addr, 16).await;
+            let first = socket
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.write_all(b" world").await.unwrap();
+            let mut received = [0; 11];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"hello world");
+            // The socket of the new connection replaced the first one.
+            let second = socket
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            assert_ne!(first, second);
+        });
+    }
+
+    #[test]
+    fn refuses_to_replay_more_than_it_kept() {
+        smol::block_on(async {
+            let addr = backend(11);
+            let (mut stream, _) = connect(addr, 8).await;
+            stream.write_all(b"hello").await.unwrap();
+            stream.write_all(b" world").await.unwrap();
+            let err = stream.read(&mut [0; 11]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        });
+    }
+}
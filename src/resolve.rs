@@ -0,0 +1,602 @@
+//! Asynchronous resolution of the host names that proxy clients ask to connect to.
+//!
+//! By default names are resolved by the system resolver. With `--dns-server`, each name is
+//! looked up on all of the servers at once, for both IPv4 and IPv6 addresses, and the first
+//! answer with an address wins. Answers are cached for their TTL, and names that none of the
+//! servers answered fall back to the system resolver.
+//!
+//! In builds with the `hickory` feature, the servers are queried by the resolver of
+//! hickory-resolver, on a thread of its own. Other builds have a minimal client, which only
+//! takes the addresses of a reply that echoes the question, owned by the name asked about or by
+//! the names its CNAME records lead to.
+
+#[cfg(not(feature = "hickory"))]
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, UdpSocket},
+    sync::Mutex,
+    time::Instant,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use smol::io;
+#[cfg(not(feature = "hickory"))]
+use smol::{future, Async, Timer};
+
+#[cfg(not(feature = "hickory"))]
+use crate::protocols::dns::skip_name;
+
+/// How long a server may take to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many names the cache holds before the least recently used one is evicted.
+const CACHE_CAPACITY: usize = 1024;
+
+/// The longest an answer is cached, whatever its TTL.
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+/// The record types that are queried for, A and AAAA.
+#[cfg(not(feature = "hickory"))]
+const QTYPES: [u16; 2] = [1, 28];
+
+/// The type of the CNAME records that lead from an alias to the name that has the addresses.
+#[cfg(not(feature = "hickory"))]
+const CNAME: u16 = 5;
+
+/// The DNS servers and the cache of their answers, set by [`use_servers`].
+static RESOLVER: OnceLock<Resolver> = OnceLock::new();
+
+/// DNS servers to query instead of the system resolver, with the resolver of hickory-resolver.
+#[cfg(feature = "hickory")]
+struct Resolver {
+    runtime: tokio::runtime::Handle,
+    resolver: hickory_resolver::TokioResolver,
+}
+
+/// DNS servers to query instead of the system resolver.
+#[cfg(not(feature = "hickory"))]
+#[derive(Debug)]
+struct Resolver {
+    servers: Vec<SocketAddr>,
+    cache: Mutex<Cache>,
+}
+
+/// A cache of answers, with the least recently used name evicted when it is full.
+#[cfg(not(feature = "hickory"))]
+#[derive(Debug, Default)]
+struct Cache {
+    entries: HashMap<String, CacheEntry>,
+    /// Counts the lookups, stamping each entry with the last one that used it.
+    clock: u64,
+}
+
+#[cfg(not(feature = "hickory"))]
+#[derive(Debug)]
+struct CacheEntry {
+    addr: IpAddr,
+    expires: Instant,
+    used: u64,
+}
+
+#[cfg(not(feature = "hickory"))]
+impl Cache {
+    fn get(&mut self, host: &str) -> Option<IpAddr> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(host)?;
+        if entry.expires <= Instant::now() {
+            self.entries.remove(host);
+            return None;
+        }
+        entry.used = self.clock;
+        Some(entry.addr)
+    }
+
+    fn insert(&mut self, host: &str, addr: IpAddr, ttl: Duration) {
+        if self.entries.len() >= CACHE_CAPACITY && !self.entries.contains_key(host) {
+            let lru = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(host, _)| host.clone());
+            if let Some(lru) = lru {
+                self.entries.remove(&lru);
+            }
+        }
+        let entry = CacheEntry {
+            addr,
+            expires: Instant::now() + ttl.min(MAX_TTL),
+            used: self.clock,
+        };
+        self.entries.insert(host.to_string(), entry);
+    }
+}
+
+/// Resolves names on these DNS servers, on port 53, instead of the system resolver.
+pub fn use_servers(servers: &[IpAddr]) -> io::Result<()> {
+    let resolver = Resolver::new(servers)?;
+    if RESOLVER.set(resolver).is_err() {
+        panic!("the DNS servers are only set once");
+    }
+    Ok(())
+}
+
+/// Resolves a host name to the first of its addresses.
+pub async fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    if let Some(resolver) = RESOLVER.get() {
+        match resolver.lookup(host).await {
+            Some(ip) => return Ok(SocketAddr::new(ip, port)),
+            None => tracing::debug!(
+                "No DNS server resolved {}, falling back to the system resolver",
+                host
+            ),
+        }
+    }
+
+    let addrs = smol::net::resolve((host, port)).await?;
+    tracing::trace!("Resolved {} to {:?}", host, addrs);
+    addrs.into_iter().next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no addresses found for {host}"),
+        )
+    })
+}
+
+#[cfg(feature = "hickory")]
+impl Resolver {
+    /// Starts the thread that the resolver runs on.
+    fn new(servers: &[IpAddr]) -> io::Result<Self> {
+        use hickory_resolver::{
+            config::{
+                ConnectionConfig, LookupIpStrategy, NameServerConfig, ResolveHosts, ResolverConfig,
+            },
+            net::runtime::TokioRuntimeProvider,
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+        let servers = servers
+            .iter()
+            .map(|&ip| {
+                let connections = vec![ConnectionConfig::udp(), ConnectionConfig::tcp()];
+                NameServerConfig::new(ip, true, connections)
+            })
+            .collect::<Vec<_>>();
+        let concurrency = servers.len();
+        let config = ResolverConfig::from_name_servers(servers);
+
+        // The resolver starts its tasks on the runtime it is built in.
+        let _runtime = handle.enter();
+        let mut builder = hickory_resolver::Resolver::builder_with_config(
+            config,
+            TokioRuntimeProvider::default(),
+        );
+        let options = builder.options_mut();
+        options.timeout = QUERY_TIMEOUT;
+        options.attempts = 1;
+        options.num_concurrent_reqs = concurrency;
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        options.cache_size = CACHE_CAPACITY as u64;
+        options.positive_max_ttl = Some(MAX_TTL);
+        options.use_hosts_file = ResolveHosts::Never;
+        let resolver = builder.build().map_err(io::Error::other)?;
+
+        std::thread::Builder::new()
+            .name("dns-resolve".to_string())
+            .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+        Ok(Self {
+            runtime: handle,
+            resolver,
+        })
+    }
+
+    /// Looks up the first address of a name, or `None` if no server answered with one.
+    async fn lookup(&self, host: &str) -> Option<IpAddr> {
+        let resolver = self.resolver.clone();
+        let name = host.to_string();
+        let lookup = self
+            .runtime
+            .spawn(async move { resolver.lookup_ip(name).await })
+            .await
+            .ok()?;
+        match lookup {
+            Ok(lookup) => {
+                let ip = lookup.iter().next()?;
+                tracing::trace!("Resolved {} to {}", host, ip);
+                Some(ip)
+            }
+            Err(err) => {
+                tracing::debug!("DNS servers failed to resolve {}: {}", host, err);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "hickory"))]
+impl Resolver {
+    fn new(servers: &[IpAddr]) -> io::Result<Self> {
+        Ok(Self {
+            servers: servers.iter().map(|&ip| SocketAddr::new(ip, 53)).collect(),
+            cache: Mutex::default(),
+        })
+    }
+
+    /// Looks up the first address of a name, from the cache or else the servers, or `None` if
+    /// no server answered with one.
+    async fn lookup(&self, host: &str) -> Option<IpAddr> {
+        if let Some(ip) = self.cache.lock().unwrap().get(host) {
+            tracing::trace!("Resolved {} to {} from the cache", host, ip);
+            return Some(ip);
+        }
+        let (ip, ttl) = self.query(host).await?;
+        tracing::trace!("Resolved {} to {} for {}s", host, ip, ttl.as_secs());
+        self.cache.lock().unwrap().insert(host, ip, ttl);
+        Some(ip)
+    }
+
+    /// Queries all servers for all record types at once, returning the first address answered
+    /// with its TTL, or `None` if no query got one in time.
+    async fn query(&self, host: &str) -> Option<(IpAddr, Duration)> {
+        let timeout = async {
+            Timer::after(QUERY_TIMEOUT).await;
+            Err(io::ErrorKind::TimedOut.into())
+        };
+        match future::or(self.race(host), timeout).await {
+            Ok(answer) => answer,
+            Err(err) => {
+                tracing::debug!("DNS servers failed to resolve {}: {}", host, err);
+                None
+            }
+        }
+    }
+
+    /// Sends all queries, from a socket for each address family of the servers, and waits for
+    /// the first answer with an address, until all queries are answered.
+    async fn race(&self, host: &str) -> io::Result<Option<(IpAddr, Duration)>> {
+        let bind = |ip: IpAddr| Async::<UdpSocket>::bind((ip, 0));
+        let v4 = match self.servers.iter().any(SocketAddr::is_ipv4) {
+            true => Some(bind(Ipv4Addr::UNSPECIFIED.into())?),
+            false => None,
+        };
+        let v6 = match self.servers.iter().any(SocketAddr::is_ipv6) {
+            true => Some(bind(Ipv6Addr::UNSPECIFIED.into())?),
+            false => None,
+        };
+
+        // The server and record type of each query, by id.
+        let mut pending = HashMap::new();
+        for &server in &self.servers {
+            let socket = if server.is_ipv4() { &v4 } else { &v6 };
+            let socket = socket.as_ref().expect("a socket is bound for each family");
+            for qtype in QTYPES {
+                let id = loop {
+                    let id = fastrand::u16(..);
+                    if !pending.contains_key(&id) {
+                        break id;
+                    }
+                };
+                match socket
+                    .send_to(&encode_query(id, host, qtype)?, server)
+                    .await
+                {
+                    Ok(_) => {
+                        pending.insert(id, (server, qtype));
+                    }
+                    Err(err) => tracing::debug!("Failed to query DNS server {}: {}", server, err),
+                }
+            }
+        }
+
+        let (mut buf4, mut buf6) = ([0; 1500], [0; 1500]);
+        while !pending.is_empty() {
+            let recv4 = async {
+                match &v4 {
+                    Some(socket) => (socket.recv_from(&mut buf4).await, &buf4[..]),
+                    None => future::pending().await,
+                }
+            };
+            let recv6 = async {
+                match &v6 {
+                    Some(socket) => (socket.recv_from(&mut buf6).await, &buf6[..]),
+                    None => future::pending().await,
+                }
+            };
+            let (received, buf) = future::or(recv4, recv6).await;
+            let (n, from) = received?;
+            let msg = &buf[..n];
+
+            // Ignore stray datagrams that answer no pending query.
+            let Some(id) = msg.get(..2).map(|id| u16::from_be_bytes([id[0], id[1]])) else {
+                continue;
+            };
+            let Some(&(server, qtype)) = pending.get(&id) else {
+                continue;
+            };
+            if server != from || skip_question(msg, host, qtype).is_none() {
+                continue;
+            }
+            pending.remove(&id);
+            match decode_answer(msg, host, qtype) {
+                Ok(Some(answer)) => return Ok(Some(answer)),
+                Ok(None) => {}
+                Err(err) => {
+                    tracing::debug!("DNS server {} failed to resolve {}: {}", from, host, err)
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Encodes a recursive query for the records of a type of a name.
+#[cfg(not(feature = "hickory"))]
+fn encode_query(id: u16, host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(host.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, and a single question.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid host name: {host}"),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    // The Internet class.
+    query.extend_from_slice(&[0, 1]);
+    Ok(query)
+}
+
+/// A record of an answer.
+#[cfg(not(feature = "hickory"))]
+struct Record {
+    owner: String,
+    rtype: u16,
+    ttl: u32,
+    /// Where the data of the record is in the message.
+    data: std::ops::Range<usize>,
+}
+
+/// Decodes an answer to a query for the records of a type of a name, returning the first
+/// address of the name in it with its TTL, if there is one.
+///
+/// The answer must echo the question, and only the addresses of the name, or of the names that
+/// its CNAME records lead to, are taken.
+#[cfg(not(feature = "hickory"))]
+fn decode_answer(msg: &[u8], host: &str, qtype: u16) -> io::Result<Option<(IpAddr, Duration)>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid DNS answer");
+    if msg.len() < 12 || msg[2] & 0x80 == 0 {
+        return Err(invalid());
+    }
+    match msg[3] & 0x0f {
+        0 => {}
+        // The name doesn't exist.
+        3 => return Ok(None),
+        rcode => {
+            return Err(io::Error::other(format!(
+                "DNS server answered with error {rcode}"
+            )));
+        }
+    }
+    let mut pos = skip_question(msg, host, qtype).ok_or_else(invalid)?;
+    let answers = u16::from_be_bytes([msg[6], msg[7]]);
+
+    let mut records = Vec::with_capacity(answers.into());
+    for _ in 0..answers {
+        let (owner, end) = read_name(msg, pos).ok_or_else(invalid)?;
+        let record = msg.get(end..end + 10).ok_or_else(invalid)?;
+        let len = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let data = end + 10..end + 10 + len;
+        msg.get(data.clone()).ok_or_else(invalid)?;
+        pos = data.end;
+        records.push(Record {
+            owner,
+            rtype: u16::from_be_bytes([record[0], record[1]]),
+            ttl: u32::from_be_bytes([record[4], record[5], record[6], record[7]]),
+            data,
+        });
+    }
+
+    // Follow the CNAME records from the name to the one with the addresses, each at most once,
+    // keeping the shortest TTL on the way.
+    let (mut name, mut ttl) = (host.trim_end_matches('.').to_string(), u32::MAX);
+    for _ in 0..=records.len() {
+        let mut owned = records
+            .iter()
+            .filter(|record| record.owner.eq_ignore_ascii_case(&name));
+        if let Some((record, ip)) = owned.clone().find_map(|record| {
+            let ip = match (record.rtype, &msg[record.data.clone()]) {
+                (rtype, _) if rtype != qtype => return None,
+                (1, &[a, b, c, d]) => IpAddr::from([a, b, c, d]),
+                (28, data) => IpAddr::from(<[u8; 16]>::try_from(data).ok()?),
+                _ => return None,
+            };
+            Some((record, ip))
+        }) {
+            let ttl = ttl.min(record.ttl);
+            return Ok(Some((ip, Duration::from_secs(ttl.into()))));
+        }
+        let Some(alias) = owned.find(|record| record.rtype == CNAME) else {
+            break;
+        };
+        ttl = ttl.min(alias.ttl);
+        name = read_name(msg, alias.data.start).ok_or_else(invalid)?.0;
+    }
+    Ok(None)
+}
+
+/// The position after the question of a reply, if it has the single question of a query for
+/// the records of a type of a name.
+#[cfg(not(feature = "hickory"))]
+fn skip_question(msg: &[u8], host: &str, qtype: u16) -> Option<usize> {
+    if msg.get(4..6)? != [0, 1] {
+        return None;
+    }
+    let (name, pos) = read_name(msg, 12)?;
+    let [high, low] = qtype.to_be_bytes();
+    let asked = name.eq_ignore_ascii_case(host.trim_end_matches('.'))
+        && msg.get(pos..pos + 4)? == [high, low, 0, 1];
+    asked.then_some(pos + 4)
+}
+
+/// Reads a possibly compressed name at a position of a message, without the final dot,
+/// returning it with the position after it.
+#[cfg(not(feature = "hickory"))]
+fn read_name(msg: &[u8], pos: usize) -> Option<(String, usize)> {
+    let end = skip_name(msg, pos)?;
+    let (mut name, mut pos) = (String::new(), pos);
+    // Pointers may only lead back in the message, so that they can't loop.
+    let mut before = pos;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => return Some((name, end)),
+            len if len & 0xc0 == 0xc0 => {
+                let target = (len & 0x3f) << 8 | *msg.get(pos + 1)? as usize;
+                if target >= before {
+                    return None;
+                }
+                (pos, before) = (target, target);
+            }
+            len if len > 63 => return None,
+            len => {
+                let label = msg.get(pos + 1..pos + 1 + len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                pos += 1 + len;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "hickory")))]
+mod tests {
+    use super::*;
+
+    /// A reply with these flags to a query for the records of a type of `example.com`, with
+    /// these answers.
+    fn reply(flags: [u8; 2], qtype: u16, answers: &[&[u8]]) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, flags[0], flags[1], 0, 1, 0, answers.len() as u8];
+        msg.extend_from_slice(&[0, 0, 0, 0]);
+        msg.extend_from_slice(b"\x07example\x03com\x00");
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&[0, 1]);
+        for answer in answers {
+            msg.extend_from_slice(answer);
+        }
+        msg
+    }
+
+    /// A record of `owner`, of the Internet class, with this type, TTL and data.
+    fn record(owner: &[u8], rtype: u16, ttl: u32, data: &[u8]) -> Vec<u8> {
+        let mut record = owner.to_vec();
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&[0, 1]);
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    /// A pointer to the name in the question.
+    const EXAMPLE: &[u8] = b"\xc0\x0c";
+
+    const OK: [u8; 2] = [0x81, 0x80];
+
+    #[test]
+    fn encodes_queries() {
+        let query = encode_query(0x1234, "example.com.", 28).unwrap();
+        assert_eq!(query, reply([0x01, 0x00], 28, &[]));
+        assert!(encode_query(1, "example..com", 1).is_err());
+        assert!(encode_query(1, &format!("{}.com", "a".repeat(64)), 1).is_err());
+    }
+
+    #[test]
+    fn decodes_addresses_of_the_name() {
+        let a = record(EXAMPLE, 1, 300, &[192, 0, 2, 1]);
+        let msg = reply(OK, 1, &[&a]);
+        let answer = decode_answer(&msg, "example.com", 1).unwrap();
+        assert_eq!(
+            answer,
+            Some(([192, 0, 2, 1].into(), Duration::from_secs(300)))
+        );
+        // Names are the same whatever their case.
+        assert!(decode_answer(&msg, "EXAMPLE.com.", 1).unwrap().is_some());
+
+        let ip: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let aaaa = record(EXAMPLE, 28, 60, &ip.octets());
+        let answer = decode_answer(&reply(OK, 28, &[&aaaa]), "example.com", 28).unwrap();
+        assert_eq!(answer, Some((ip.into(), Duration::from_secs(60))));
+    }
+
+    #[test]
+    fn follows_cnames_with_the_shortest_ttl() {
+        // example.com is an alias of www.example.com, which has the address.
+        let a = record(b"\x03www\xc0\x0c", 1, 300, &[192, 0, 2, 1]);
+        let cname = record(EXAMPLE, CNAME, 30, b"\x03www\xc0\x0c");
+        for answers in [[&cname[..], &a[..]], [&a[..], &cname[..]]] {
+            let answer = decode_answer(&reply(OK, 1, &answers), "example.com", 1).unwrap();
+            assert_eq!(
+                answer,
+                Some(([192, 0, 2, 1].into(), Duration::from_secs(30)))
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_addresses_of_other_names_and_types() {
+        let other = record(b"\x05other\x00", 1, 300, &[192, 0, 2, 2]);
+        let aaaa = record(EXAMPLE, 28, 300, &[0; 16]);
+        let answer = decode_answer(&reply(OK, 1, &[&other, &aaaa]), "example.com", 1);
+        assert_eq!(answer.unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_answers_to_other_questions() {
+        let a = record(EXAMPLE, 1, 300, &[192, 0, 2, 1]);
+        let msg = reply(OK, 1, &[&a]);
+        assert!(skip_question(&msg, "example.com", 1).is_some());
+        assert!(skip_question(&msg, "example.org", 1).is_none());
+        assert!(skip_question(&msg, "example.com", 28).is_none());
+        assert!(decode_answer(&msg, "example.org", 1).is_err());
+        assert!(decode_answer(&msg, "example.com", 28).is_err());
+    }
+
+    #[test]
+    fn decodes_errors_and_bad_answers() {
+        assert_eq!(
+            decode_answer(&reply([0x81, 0x83], 1, &[]), "example.com", 1).unwrap(),
+            None
+        );
+        let servfail = decode_answer(&reply([0x81, 0x82], 1, &[]), "example.com", 1);
+        assert_eq!(servfail.unwrap_err().kind(), io::ErrorKind::Other);
+
+        let a = record(EXAMPLE, 1, 300, &[192, 0, 2, 1]);
+        let msg = reply(OK, 1, &[&a]);
+        // Queries, truncated answers, and pointers that loop.
+        let query = reply([0x01, 0x00], 1, &[]);
+        let looped = reply(OK, 1, &[&record(b"\xc0\x1d", 1, 300, &[192, 0, 2, 1])]);
+        for msg in [&query, &msg[..msg.len() - 1].to_vec(), &looped] {
+            let err = decode_answer(msg, "example.com", 1).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
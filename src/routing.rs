@@ -0,0 +1,251 @@
+//! Routing of clients by their source address, for `--rule`.
+//!
+//! Rules are tried in order of priority, lowest number first, and the first rule whose block of
+//! addresses contains the client wins. Clients that match no rule get the `--fallback` action.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// A block of IP addresses, given as `<IP>/<PREFIX>`, or a single IP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether an address is in the block, where IPv4 clients of dual-stack listeners are
+    /// matched by their IPv4 address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(block), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(block) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(block), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(block) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{e}: {addr}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse() {
+                Ok(prefix) if prefix <= max => prefix,
+                _ => return Err(format!("invalid prefix length: {prefix}")),
+            },
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// A rule that forwards clients from a block of addresses to a destination, given as
+/// `[<PRIORITY>,]<CIDR>=<ADDR>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rule {
+    /// Rules with lower numbers are tried first, 0 if not given.
+    pub priority: u32,
+    pub source: Cidr,
+    pub forward: SocketAddr,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (priority, rule) = match s.split_once(',') {
+            Some((priority, rule)) => {
+                let priority = priority.parse().map_err(|e| format!("{e}: {priority}"))?;
+                (priority, rule)
+            }
+            None => (0, s),
+        };
+        let (source, forward) = rule
+            .split_once('=')
+            .ok_or_else(|| format!("expected [<PRIORITY>,]<CIDR>=<ADDR>, got: {s}"))?;
+        Ok(Rule {
+            priority,
+            source: source.parse()?,
+            forward: forward.parse().map_err(|e| format!("{e}: {forward}"))?,
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}={}", self.priority, self.source, self.forward)
+    }
+}
+
+/// What happens to clients that match no rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fallback {
+    /// Forward them as if there were no rules.
+    Forward,
+    /// Reset TCP connections, and discard UDP datagrams.
+    Reject,
+    /// Close TCP connections without a word, and discard UDP datagrams.
+    Drop,
+}
+
+impl FromStr for Fallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forward" => Ok(Fallback::Forward),
+            "reject" => Ok(Fallback::Reject),
+            "drop" => Ok(Fallback::Drop),
+            _ => Err(format!("expected forward, reject or drop, got: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Fallback::Forward => "forward",
+            Fallback::Reject => "reject",
+            Fallback::Drop => "drop",
+        })
+    }
+}
+
+/// How a client is routed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// To the destination of a rule.
+    Forward(SocketAddr),
+    /// As without rules.
+    Default,
+    /// Nowhere, because the client matched no rule.
+    Refuse(Fallback),
+}
+
+/// Rules in order of priority, with the action for clients that match none.
+#[derive(Debug)]
+pub struct Routing {
+    rules: Vec<(u32, Rule)>,
+    fallback: Fallback,
+}
+
+impl Routing {
+    /// Sorts the rules by priority, keeping the order in which rules of equal priority were
+    /// given.
+    pub fn new(rules: Vec<Rule>, fallback: Fallback) -> Self {
+        let mut rules: Vec<_> = rules
+            .into_iter()
+            .map(|rule| (rule.priority, rule))
+            .collect();
+        rules.sort_by_key(|&(priority, _)| priority);
+        Self { rules, fallback }
+    }
+
+    /// Routes a client by the first rule that matches its address.
+    pub fn route(&self, ip: IpAddr) -> Decision {
+        match self.rules.iter().find(|(_, rule)| rule.source.contains(ip)) {
+            Some((_, rule)) => Decision::Forward(rule.forward),
+            None if self.fallback == Fallback::Forward => Decision::Default,
+            None => Decision::Refuse(self.fallback),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn rule(s: &str) -> Rule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_prefixes() {
+        assert!(cidr("10.1.0.0/16").contains(ip("10.1.255.3")));
+        assert!(!cidr("10.1.0.0/16").contains(ip("10.2.0.1")));
+        assert!(cidr("10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.1.2.3").contains(ip("10.1.2.4")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(cidr("::/0").contains(ip("::1")));
+        // IPv4 clients of dual-stack listeners.
+        assert!(cidr("10.1.0.0/16").contains(ip("::ffff:10.1.0.1")));
+        assert_eq!(cidr("10.1.2.3").to_string(), "10.1.2.3/32");
+    }
+
+    #[test]
+    fn rejects_invalid_blocks_and_rules() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/8".parse::<Rule>().is_err());
+        assert!("x,10.0.0.0/8=10.0.0.1:80".parse::<Rule>().is_err());
+        assert!("10.0.0.0/8=10.0.0.1".parse::<Rule>().is_err());
+        assert!("accept".parse::<Fallback>().is_err());
+        assert_eq!(rule("10.0.0.0/8=10.0.0.1:80").priority, 0);
+        assert_eq!(
+            rule("5,10.0.0.0/8=10.0.0.1:80").to_string(),
+            "5,10.0.0.0/8=10.0.0.1:80"
+        );
+    }
+
+    #[test]
+    fn routes_by_priority_then_order() {
+        let routing = Routing::new(
+            vec![
+                rule("2,10.1.2.0/24=10.0.0.3:80"),
+                rule("1,10.0.0.0/8=10.0.0.1:80"),
+                rule("1,10.1.0.0/16=10.0.0.2:80"),
+                rule("3,192.168.0.0/16=10.0.0.4:80"),
+            ],
+            Fallback::Reject,
+        );
+        // The first rule of the lowest priority wins, however long its prefix.
+        let forward = |s: &str| Decision::Forward(s.parse().unwrap());
+        assert_eq!(routing.route(ip("10.1.2.3")), forward("10.0.0.1:80"));
+        assert_eq!(routing.route(ip("192.168.1.1")), forward("10.0.0.4:80"));
+        assert_eq!(
+            routing.route(ip("172.16.0.1")),
+            Decision::Refuse(Fallback::Reject)
+        );
+        let routing = Routing::new(vec![rule("10.0.0.0/8=10.0.0.1:80")], Fallback::Forward);
+        assert_eq!(routing.route(ip("172.16.0.1")), Decision::Default);
+    }
+}
@@ -0,0 +1,76 @@
+//! Sticky sessions that remember which backend each client was forwarded to, persisted to disk
+//! so that they survive restarts.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use smol::channel::{bounded, Receiver, Sender};
+
+/// A table of source IP → backend assignments, backed by a JSON file.
+#[derive(Debug)]
+pub struct Sessions {
+    path: PathBuf,
+    table: Mutex<HashMap<IpAddr, SocketAddr>>,
+    changed: Sender<()>,
+    changes: Receiver<()>,
+}
+
+impl Sessions {
+    /// Opens a session file, loading the assignments saved by a previous run if it exists.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let table = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+
+        // A single pending notification is enough to trigger the next write.
+        let (changed, changes) = bounded(1);
+        Ok(Self {
+            path,
+            table: Mutex::new(table),
+            changed,
+            changes,
+        })
+    }
+
+    /// Number of clients with a saved backend.
+    pub fn len(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    /// Looks up the backend a client was assigned to.
+    pub fn get(&self, client: IpAddr) -> Option<SocketAddr> {
+        self.table.lock().unwrap().get(&client).copied()
+    }
+
+    /// Assigns a client to a backend, and schedules the session file to be written.
+    pub fn insert(&self, client: IpAddr, backend: SocketAddr) {
+        self.table.lock().unwrap().insert(client, backend);
+        let _ = self.changed.try_send(());
+    }
+
+    /// Writes the session file whenever the assignments change.
+    ///
+    /// The file is written to a temporary file first and then renamed over the old one, so that
+    /// a crash in the middle of a write never leaves a corrupted session file behind.
+    pub async fn persist(&self) -> io::Result<()> {
+        let mut tmp = OsString::from(&self.path);
+        tmp.push(".tmp");
+
+        while self.changes.recv().await.is_ok() {
+            let json = serde_json::to_vec(&*self.table.lock().unwrap())?;
+            smol::fs::write(&tmp, json).await?;
+            smol::fs::rename(&tmp, &self.path).await?;
+            tracing::trace!("Saved sessions to {}", self.path.display());
+        }
+        Ok(())
+    }
+}
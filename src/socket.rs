@@ -0,0 +1,315 @@
+//! Creation of listening and connecting sockets, with the options that the standard library
+//! does not expose.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    str::FromStr,
+};
+
+use smol::{io, Async};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// The default backlog of pending connections, the same as the standard library uses.
+pub const BACKLOG: u32 = 128;
+
+/// Binds a TCP listener.
+///
+/// IPv6 listeners only accept IPv6 clients if `only_v6` is set, so that an IPv4 listener can be
+/// bound to the same port; otherwise they also accept IPv4 clients as IPv4-mapped addresses.
+///
+/// `SO_REUSEADDR` is set unless `reuse_addr` is off, so that a restarted portfwd can bind the
+/// port while connections of the last one are still in `TIME_WAIT`. On Linux it never lets
+/// the listener share the port with one that is still listening; only `SO_REUSEPORT` does,
+/// which is never set, so binding a port in use fails either way.
+///
+/// Up to `backlog` clients wait to be accepted, as far as the kernel allows.
+pub fn tcp_listener(
+    addr: SocketAddr,
+    only_v6: bool,
+    reuse_addr: bool,
+    backlog: u32,
+) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(reuse_addr)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.try_into().unwrap_or(i32::MAX))?;
+    Ok(socket.into())
+}
+
+/// Binds a UDP socket, with the same handling of IPv6 as [`tcp_listener`].
+///
+/// Datagrams are forwarded from this socket too, so they are sent with the TTL given.
+pub fn udp_socket(addr: SocketAddr, only_v6: bool, ttl: Option<u32>) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    if let Some(ttl) = ttl {
+        set_ttl(&socket, addr, ttl)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// A range of ports, given as `<START>-<END>` with both ends included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected <START>-<END>: {s}"))?;
+        let start = start.parse().map_err(|e| format!("{e}: {start}"))?;
+        let end = end.parse().map_err(|e| format!("{e}: {end}"))?;
+        if start == 0 || start > end {
+            return Err(format!("invalid port range: {s}"));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// Options of the sockets that connect or send to the backends.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Outbound {
+    /// Time-to-live of the packets sent.
+    pub ttl: Option<u32>,
+    /// The local address to bind to.
+    pub source_addr: Option<IpAddr>,
+    /// The local ports to bind to, as long as one of them is free.
+    pub source_ports: Option<PortRange>,
+}
+
+impl Outbound {
+    /// Whether sockets are bound to a local address or port before they are used.
+    pub fn binds(&self) -> bool {
+        self.source_addr.is_some() || self.source_ports.is_some()
+    }
+
+    /// Sets the options on a socket that is about to connect or send to `dest`.
+    fn apply(&self, socket: &Socket, dest: SocketAddr) -> io::Result<()> {
+        if let Some(ttl) = self.ttl {
+            set_ttl(socket, dest, ttl)?;
+        }
+        if !self.binds() {
+            return Ok(());
+        }
+        let ip = match self.source_addr {
+            Some(ip) if ip.is_ipv4() != dest.is_ipv4() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("source address {ip} can't reach {dest} of another address family"),
+                ))
+            }
+            Some(ip) => ip,
+            None if dest.is_ipv4() => Ipv4Addr::UNSPECIFIED.into(),
+            None => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        self.bind(socket, ip)
+    }
+
+    /// Binds a socket to a local address, on a free port of the source ports if there is one,
+    /// or any port otherwise.
+    fn bind(&self, socket: &Socket, ip: IpAddr) -> io::Result<()> {
+        let described = |err: io::Error| {
+            let message = match err.kind() {
+                io::ErrorKind::AddrInUse => format!("no free port left on source address {ip}"),
+                io::ErrorKind::AddrNotAvailable => {
+                    format!("source address {ip} is not on this host")
+                }
+                _ => return err,
+            };
+            io::Error::new(err.kind(), message)
+        };
+
+        // Try the ports from a random one on, so that connections don't all probe the same
+        // ports first.
+        if let Some(ports) = self.source_ports {
+            let len = u32::from(ports.end - ports.start) + 1;
+            let offset = fastrand::u32(..len);
+            for i in 0..len {
+                let port = ports.start + ((offset + i) % len) as u16;
+                match socket.bind(&SocketAddr::new(ip, port).into()) {
+                    Ok(()) => return Ok(()),
+                    Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+                    Err(err) => return Err(described(err)),
+                }
+            }
+            tracing::debug!("No free source port in {}, letting the OS pick one", ports);
+        }
+        socket
+            .bind(&SocketAddr::new(ip, 0).into())
+            .map_err(described)
+    }
+}
+
+/// Connects a TCP socket, with the outbound options applied before the first packet is sent.
+pub async fn tcp_connect(addr: SocketAddr, outbound: &Outbound) -> io::Result<Async<TcpStream>> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    outbound.apply(&socket, addr)?;
+
+    // Start connecting without blocking, and wait for the socket to become writable.
+    socket.set_nonblocking(true)?;
+    match socket.connect(&addr.into()) {
+        Ok(()) => {}
+        #[cfg(unix)]
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+        Err(err) => return Err(err),
+    }
+    let stream = Async::new(TcpStream::from(socket))?;
+    stream.writable().await?;
+    match stream.get_ref().take_error()? {
+        Some(err) => Err(err),
+        None => Ok(stream),
+    }
+}
+
+/// Binds a UDP socket to forward datagrams from, for backends of the same address family as
+/// `ip`, if the outbound options bind sockets.
+pub fn udp_outbound_socket(ip: IpAddr, outbound: &Outbound) -> io::Result<UdpSocket> {
+    // Any address of the family will do, since only the address family of the destination
+    // matters here.
+    let dest = SocketAddr::new(ip, 0);
+    let socket = Socket::new(Domain::for_address(dest), Type::DGRAM, Some(Protocol::UDP))?;
+    outbound.apply(&socket, dest)?;
+    Ok(socket.into())
+}
+
+/// Binds a UDP socket to the address of a relay of a previous run, to take over the replies that
+/// come to it.
+pub fn udp_relay_socket(addr: SocketAddr, outbound: &Outbound) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(ttl) = outbound.ttl {
+        set_ttl(&socket, addr, ttl)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Sets the time-to-live of the packets a socket sends, which is the hop limit for IPv6.
+fn set_ttl(socket: &Socket, addr: SocketAddr, ttl: u32) -> io::Result<()> {
+    if addr.is_ipv6() {
+        socket.set_unicast_hops_v6(ttl)
+    } else {
+        socket.set_ttl(ttl)
+    }
+}
+
+/// A request of `IPV6_FLOWLABEL_MGR`, the `struct in6_flowlabel_req` of Linux.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct FlowLabelReq {
+    dst: libc::in6_addr,
+    label: u32,
+    action: u8,
+    share: u8,
+    flags: u16,
+    expires: u16,
+    linger: u16,
+    pad: u32,
+}
+
+/// Lets a UDP socket send datagrams to an IPv6 destination with a flow label, which Linux only
+/// allows for labels that the socket leased.
+///
+/// The label is then sent with the datagrams whose destination carries it as its flow info.
+#[cfg(target_os = "linux")]
+pub fn lease_flow_label(socket: &UdpSocket, dst: Ipv6Addr, label: u32) -> io::Result<()> {
+    use std::{mem::size_of, os::unix::io::AsRawFd};
+
+    // Lease the label, shared with the other sockets of this process to the same label.
+    let req = FlowLabelReq {
+        dst: libc::in6_addr {
+            s6_addr: dst.octets(),
+        },
+        label: label.to_be(),
+        action: 0, // IPV6_FL_A_GET
+        share: 2,  // IPV6_FL_S_PROCESS
+        flags: 1,  // IPV6_FL_F_CREATE
+        expires: 0,
+        linger: 0,
+        pad: 0,
+    };
+    let enable: libc::c_int = 1;
+    // SAFETY: the option values point to a live request and a live `c_int` of the given sizes.
+    unsafe {
+        if libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_FLOWLABEL_MGR,
+            &req as *const FlowLabelReq as *const libc::c_void,
+            size_of::<FlowLabelReq>() as libc::socklen_t,
+        ) == -1
+            || libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_FLOWINFO_SEND,
+                &enable as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            ) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lease_flow_label(_socket: &UdpSocket, _dst: Ipv6Addr, _label: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IPv6 flow labels are unsupported on this platform",
+    ))
+}
+
+/// Makes a UDP socket report the ICMP errors caused by the datagrams it sent, which Linux only
+/// does for unconnected sockets that ask for them.
+#[cfg(target_os = "linux")]
+pub fn set_recv_errors(socket: &UdpSocket) -> io::Result<()> {
+    use std::{mem::size_of, os::unix::io::AsRawFd};
+
+    let (level, name) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_RECVERR),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVERR),
+    };
+    let enable: libc::c_int = 1;
+    // SAFETY: the option value points to a live `c_int` of the given size.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enable as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_recv_errors(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "ICMP error reporting is unsupported on this platform",
+    ))
+}
@@ -0,0 +1,138 @@
+//! The server side of SOCKS4 and SOCKS4a, for clients of the `--socks` proxy that do not speak
+//! SOCKS5.
+//!
+//! SOCKS4a clients that want the proxy to resolve a host name send an invalid IP of the form
+//! `0.0.0.x`, followed by the host name after the user id. SOCKS4 has no passwords, so its
+//! clients are refused when `--auth` is given.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use smol::io::{self, AsyncReadExt, AsyncWriteExt};
+
+use crate::{auth::Credentials, resolve, transport::BoxStream};
+
+pub const VERSION: u8 = 0x04;
+const CONNECT: u8 = 0x01;
+const GRANTED: u8 = 0x5a;
+const REJECTED: u8 = 0x5b;
+
+/// Maximum length of the user id and host name strings.
+const MAX_STRING: usize = 255;
+
+/// Reads the request of a client after its version byte, returning the address to connect to.
+pub async fn request(stream: &mut BoxStream, auth: Option<&Credentials>) -> io::Result<SocketAddr> {
+    let mut header = [0; 7];
+    stream.read_exact(&mut header).await?;
+    let [command, port @ .., a, b, c, d] = header;
+    let port = u16::from_be_bytes(port);
+    let ip = Ipv4Addr::new(a, b, c, d);
+
+    // The user id is not checked.
+    read_string(stream).await?;
+
+    if auth.is_some() {
+        reply(stream, false).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS4 clients cannot authenticate",
+        ));
+    }
+    if command != CONNECT {
+        reply(stream, false).await?;
+        return Err(io::Error::other(format!(
+            "unsupported SOCKS4 command {command}"
+        )));
+    }
+
+    if a == 0 && b == 0 && c == 0 && d != 0 {
+        let host = read_string(stream).await?;
+        match resolve::resolve(&host, port).await {
+            Ok(addr) => Ok(addr),
+            Err(err) => {
+                reply(stream, false).await?;
+                Err(err)
+            }
+        }
+    } else {
+        Ok(SocketAddr::from((ip, port)))
+    }
+}
+
+/// Tells the client whether its connection was established.
+pub async fn reply(stream: &mut BoxStream, granted: bool) -> io::Result<()> {
+    let status = if granted { GRANTED } else { REJECTED };
+    stream.write_all(&[0, status, 0, 0, 0, 0, 0, 0]).await
+}
+
+/// Reads a NUL-terminated string.
+async fn read_string(stream: &mut BoxStream) -> io::Result<String> {
+    let mut string = Vec::new();
+    loop {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).await?;
+        match byte[0] {
+            0 => break,
+            _ if string.len() == MAX_STRING => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "SOCKS4 string too long",
+                ))
+            }
+            byte => string.push(byte),
+        }
+    }
+    String::from_utf8(string).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ScriptedStream;
+
+    /// Runs a request after the version byte, returning its result and what was sent back.
+    fn run(script: &[u8], auth: Option<&Credentials>) -> (io::Result<SocketAddr>, Vec<u8>) {
+        let (mut stream, written) = ScriptedStream::boxed(script);
+        let result = smol::block_on(request(&mut stream, auth));
+        let written = written.lock().unwrap().clone();
+        (result, written)
+    }
+
+    #[test]
+    fn reads_socks4_requests() {
+        let (addr, written) = run(b"\x01\x00\x50\x0a\x00\x00\x01user\x00", None);
+        assert_eq!(addr.unwrap(), "10.0.0.1:80".parse().unwrap());
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn reads_socks4a_host_names() {
+        let (addr, _) = run(b"\x01\x01\xbb\x00\x00\x00\x01\x00127.0.0.1\x00", None);
+        assert_eq!(addr.unwrap(), "127.0.0.1:443".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_other_commands_and_authentication() {
+        // BIND.
+        let (addr, written) = run(b"\x02\x00\x50\x0a\x00\x00\x01\x00", None);
+        assert!(addr.is_err());
+        assert_eq!(written, [0, REJECTED, 0, 0, 0, 0, 0, 0]);
+
+        let auth: Credentials = "user:$2b$04$invalid".parse().unwrap();
+        let (addr, written) = run(b"\x01\x00\x50\x0a\x00\x00\x01\x00", Some(&auth));
+        assert_eq!(addr.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(written, [0, REJECTED, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn fails_on_truncated_requests() {
+        for script in [&b"\x01\x00\x50"[..], b"\x01\x00\x50\x0a\x00\x00\x01user"] {
+            let (addr, _) = run(script, None);
+            assert_eq!(addr.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        }
+        let (addr, _) = run(b"\x01\x00\x50\x00\x00\x00\x01\x00host", None);
+        assert_eq!(addr.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let long = [&b"\x01\x00\x50\x0a\x00\x00\x01"[..], &[b'a'; 300]].concat();
+        let (addr, _) = run(&long, None);
+        assert_eq!(addr.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
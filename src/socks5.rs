@@ -0,0 +1,242 @@
+//! The server side of SOCKS5 (RFC 1928), for the `--socks` proxy.
+//!
+//! Only the CONNECT command is supported, without authentication or with a username and
+//! password (RFC 1929) when `--auth` is given. Domain names are resolved by the proxy.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use smol::io::{self, AsyncReadExt, AsyncWriteExt};
+
+use crate::{auth::Credentials, resolve, transport::BoxStream};
+
+pub const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const USER_PASS_VERSION: u8 = 0x01;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const HOST_UNREACHABLE: u8 = 0x04;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Authenticates a client and reads its request after its version byte, returning the address
+/// to connect to.
+pub async fn request(
+    stream: &mut BoxStream,
+    auth: Option<&Credentials>,
+    peer_addr: SocketAddr,
+) -> io::Result<SocketAddr> {
+    // Pick the only method that is allowed: a username and password if they are required.
+    let mut len = [0; 1];
+    stream.read_exact(&mut len).await?;
+    let mut methods = vec![0; len[0] as usize];
+    stream.read_exact(&mut methods).await?;
+    let method = if auth.is_some() { USER_PASS } else { NO_AUTH };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(io::Error::other(
+            "SOCKS5 client does not support the required authentication method",
+        ));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+    if let Some(credentials) = auth {
+        authenticate(stream, credentials, peer_addr).await?;
+    }
+
+    // Read the request.
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, command, _, atyp] = header;
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid SOCKS5 request",
+        ));
+    }
+    let addr = match atyp {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            SocketAddr::from((Ipv4Addr::from(ip), read_port(stream).await?))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            SocketAddr::from((Ipv6Addr::from(ip), read_port(stream).await?))
+        }
+        ATYP_DOMAIN => {
+            let host = read_string(stream).await?;
+            let port = read_port(stream).await?;
+            let host = String::from_utf8_lossy(&host);
+            match resolve::resolve(&host, port).await {
+                Ok(addr) => addr,
+                Err(err) => {
+                    send_reply(stream, HOST_UNREACHABLE).await?;
+                    return Err(err);
+                }
+            }
+        }
+        _ => {
+            send_reply(stream, ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            return Err(io::Error::other(format!(
+                "unsupported SOCKS5 address type {atyp}"
+            )));
+        }
+    };
+
+    if command != CONNECT {
+        send_reply(stream, COMMAND_NOT_SUPPORTED).await?;
+        return Err(io::Error::other(format!(
+            "unsupported SOCKS5 command {command}"
+        )));
+    }
+    Ok(addr)
+}
+
+/// Runs the username and password sub-negotiation.
+async fn authenticate(
+    stream: &mut BoxStream,
+    credentials: &Credentials,
+    peer_addr: SocketAddr,
+) -> io::Result<()> {
+    let mut version = [0; 1];
+    stream.read_exact(&mut version).await?;
+    if version[0] != USER_PASS_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid SOCKS5 authentication request",
+        ));
+    }
+    let user = read_string(stream).await?;
+    let password = read_string(stream).await?;
+
+    if !credentials.check(&user, &password, peer_addr).await {
+        stream.write_all(&[USER_PASS_VERSION, 1]).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 authentication failed",
+        ));
+    }
+    stream.write_all(&[USER_PASS_VERSION, 0]).await
+}
+
+/// Tells the client whether its connection was established.
+pub async fn reply(stream: &mut BoxStream, succeeded: bool) -> io::Result<()> {
+    send_reply(
+        stream,
+        if succeeded {
+            SUCCEEDED
+        } else {
+            GENERAL_FAILURE
+        },
+    )
+    .await
+}
+
+/// Sends a reply, without a bound address since clients of CONNECT do not need it.
+async fn send_reply(stream: &mut BoxStream, code: u8) -> io::Result<()> {
+    stream
+        .write_all(&[VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+async fn read_port(stream: &mut BoxStream) -> io::Result<u16> {
+    let mut port = [0; 2];
+    stream.read_exact(&mut port).await?;
+    Ok(u16::from_be_bytes(port))
+}
+
+/// Reads a string prefixed by its length.
+async fn read_string(stream: &mut BoxStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 1];
+    stream.read_exact(&mut len).await?;
+    let mut string = vec![0; len[0] as usize];
+    stream.read_exact(&mut string).await?;
+    Ok(string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ScriptedStream;
+
+    const PEER: &str = "127.0.0.1:1080";
+
+    /// Runs a handshake after the version byte, returning its result and what was sent back.
+    fn run(script: &[u8], auth: Option<&Credentials>) -> (io::Result<SocketAddr>, Vec<u8>) {
+        let (mut stream, written) = ScriptedStream::boxed(script);
+        let result = smol::block_on(request(&mut stream, auth, PEER.parse().unwrap()));
+        let written = written.lock().unwrap().clone();
+        (result, written)
+    }
+
+    #[test]
+    fn reads_each_address_type() {
+        let (addr, written) = run(b"\x01\x00\x05\x01\x00\x01\x0a\x00\x00\x01\x00\x50", None);
+        assert_eq!(addr.unwrap(), "10.0.0.1:80".parse().unwrap());
+        assert_eq!(written, [VERSION, NO_AUTH]);
+
+        let mut script = b"\x01\x00\x05\x01\x00\x04".to_vec();
+        script.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        script.extend_from_slice(&443u16.to_be_bytes());
+        let (addr, _) = run(&script, None);
+        assert_eq!(addr.unwrap(), "[2001:db8::1]:443".parse().unwrap());
+
+        let (addr, _) = run(b"\x01\x00\x05\x01\x00\x03\x09127.0.0.1\x00\x35", None);
+        assert_eq!(addr.unwrap(), "127.0.0.1:53".parse().unwrap());
+    }
+
+    #[test]
+    fn authenticates_with_a_password() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let auth: Credentials = format!("user:{hash}").parse().unwrap();
+        let script = b"\x01\x02\x01\x04user\x06secret\x05\x01\x00\x01\x0a\x00\x00\x01\x00\x50";
+        let (addr, written) = run(script, Some(&auth));
+        assert_eq!(addr.unwrap(), "10.0.0.1:80".parse().unwrap());
+        assert_eq!(written, [VERSION, USER_PASS, USER_PASS_VERSION, 0]);
+
+        // Clients that can't authenticate are turned away.
+        let (addr, written) = run(b"\x01\x00", Some(&auth));
+        assert!(addr.is_err());
+        assert_eq!(written, [VERSION, NO_ACCEPTABLE_METHODS]);
+    }
+
+    #[test]
+    fn rejects_other_commands_and_address_types() {
+        // BIND.
+        let (addr, written) = run(b"\x01\x00\x05\x02\x00\x01\x0a\x00\x00\x01\x00\x50", None);
+        assert!(addr.is_err());
+        assert_eq!(written[2..4], [VERSION, COMMAND_NOT_SUPPORTED]);
+
+        let (addr, written) = run(b"\x01\x00\x05\x01\x00\x02", None);
+        assert!(addr.is_err());
+        assert_eq!(written[2..4], [VERSION, ADDRESS_TYPE_NOT_SUPPORTED]);
+
+        let (addr, _) = run(b"\x01\x00\x04\x01\x00\x01", None);
+        assert_eq!(addr.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fails_on_truncated_handshakes() {
+        for script in [
+            &b"\x02\x00"[..],
+            b"\x01\x00\x05\x01",
+            b"\x01\x00\x05\x01\x00\x01\x0a\x00\x00\x01\x00",
+            b"\x01\x00\x05\x01\x00\x04\x20\x01",
+            b"\x01\x00\x05\x01\x00\x03\x0bexample",
+        ] {
+            let (addr, _) = run(script, None);
+            assert_eq!(
+                addr.unwrap_err().kind(),
+                io::ErrorKind::UnexpectedEof,
+                "{script:?}"
+            );
+        }
+    }
+}
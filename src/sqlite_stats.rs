@@ -0,0 +1,267 @@
+//! A SQLite database with a row for each closed TCP connection, for `--sqlite-stats`.
+//!
+//! Rows are queued without waiting, like the lines of the event log, and a background task
+//! inserts them in transactions of up to [`BATCH_SIZE`] rows, or whatever is queued once the
+//! first row of a batch waited for [`BATCH_DELAY`]. Rows that don't fit in the queue are
+//! dropped.
+//!
+//! Whatever is still queued or waiting for its batch when portfwd exits on its own is inserted
+//! before it does, and the database is closed.
+//!
+//! SQLite is compiled into portfwd with `rusqlite`, in builds with the `sqlite` feature.
+
+use std::{
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use smol::{
+    channel::{bounded, Receiver, Sender},
+    future, io, Timer,
+};
+
+/// How many rows may wait to be inserted.
+const QUEUE_SIZE: usize = 4096;
+
+/// How many rows are inserted in one transaction.
+const BATCH_SIZE: usize = 100;
+
+/// How long a row may wait for a batch to fill up.
+const BATCH_DELAY: Duration = Duration::from_secs(1);
+
+#[cfg(feature = "sqlite")]
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS connections (
+    id INTEGER PRIMARY KEY,
+    start_time INTEGER NOT NULL,
+    end_time INTEGER NOT NULL,
+    src_ip TEXT NOT NULL,
+    dst_ip TEXT NOT NULL,
+    proto TEXT NOT NULL,
+    bytes_in INTEGER NOT NULL,
+    bytes_out INTEGER NOT NULL,
+    error_code TEXT
+)";
+
+#[cfg(feature = "sqlite")]
+const INSERT: &str = "INSERT INTO connections
+    (start_time, end_time, src_ip, dst_ip, proto, bytes_in, bytes_out, error_code)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?)";
+
+/// A closed connection.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct Row {
+    pub start_time: SystemTime,
+    pub end_time: SystemTime,
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub proto: &'static str,
+    /// Bytes from the client to the destination.
+    pub bytes_in: u64,
+    /// Bytes from the destination to the client.
+    pub bytes_out: u64,
+    /// The kind of error the connection failed with, if it did.
+    pub error_code: Option<&'static str>,
+}
+
+/// Milliseconds since the epoch, which the times are stored as.
+#[cfg(feature = "sqlite")]
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as i64)
+}
+
+/// The queue of rows to insert into a database.
+pub struct SqliteStats {
+    path: PathBuf,
+    db: Arc<Mutex<Db>>,
+    queued: Sender<Row>,
+    queue: Receiver<Row>,
+    /// The rows of the batch that is filling up.
+    pending: Mutex<Vec<Row>>,
+}
+
+impl SqliteStats {
+    /// Opens a database, creating it and its table if they don't exist.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let db = Db::open(path)?;
+        let (queued, queue) = bounded(QUEUE_SIZE);
+        Ok(Self {
+            path: path.to_path_buf(),
+            db: Arc::new(Mutex::new(db)),
+            queued,
+            queue,
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Queues a row to be inserted, dropping it if the queue is full.
+    pub fn record(&self, row: Row) {
+        if self.queued.try_send(row).is_err() {
+            tracing::debug!("Dropped a connection row: SQLite queue is full");
+        }
+    }
+
+    /// Inserts the queued rows in batches, forever.
+    pub async fn write(&self) -> io::Result<()> {
+        while let Ok(first) = self.queue.recv().await {
+            self.pending.lock().unwrap().push(first);
+            let deadline = Instant::now() + BATCH_DELAY;
+            while self.pending.lock().unwrap().len() < BATCH_SIZE {
+                let next = async { self.queue.recv().await.ok() };
+                let timeout = async {
+                    Timer::at(deadline).await;
+                    None
+                };
+                match future::or(next, timeout).await {
+                    Some(row) => self.pending.lock().unwrap().push(row),
+                    None => break,
+                }
+            }
+
+            let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+            let db = self.db.clone();
+            let len = batch.len();
+            smol::unblock(move || db.lock().unwrap().insert(&batch)).await?;
+            tracing::trace!("Inserted {} rows into {}", len, self.path.display());
+        }
+        Ok(())
+    }
+
+    /// Inserts the rows that are queued or waiting for their batch, before portfwd exits.
+    pub fn flush(&self) {
+        let mut batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        batch.extend(std::iter::from_fn(|| self.queue.try_recv().ok()));
+        if batch.is_empty() {
+            return;
+        }
+        match self.db.lock().unwrap().insert(&batch) {
+            Ok(()) => tracing::debug!(
+                "Inserted the last {} rows into {}",
+                batch.len(),
+                self.path.display()
+            ),
+            Err(err) => tracing::warn!(
+                "Failed to insert the last {} rows into {}: {}",
+                batch.len(),
+                self.path.display(),
+                err
+            ),
+        }
+    }
+}
+
+impl Drop for SqliteStats {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl fmt::Debug for SqliteStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteStats")
+            .field("path", &self.path)
+            .field("queued", &self.queue.len())
+            .finish()
+    }
+}
+
+/// An open database, which is closed once this is dropped.
+#[cfg(feature = "sqlite")]
+struct Db {
+    conn: rusqlite::Connection,
+}
+
+/// A database, which can't be opened without the `sqlite` feature.
+#[cfg(not(feature = "sqlite"))]
+struct Db {
+    never: std::convert::Infallible,
+}
+
+#[cfg(feature = "sqlite")]
+impl Db {
+    fn open(path: &Path) -> io::Result<Self> {
+        let conn = rusqlite::Connection::open(path).map_err(error)?;
+        conn.execute_batch(SCHEMA).map_err(error)?;
+        Ok(Self { conn })
+    }
+
+    /// Inserts rows in a single transaction, which is rolled back if any of them fails.
+    fn insert(&mut self, rows: &[Row]) -> io::Result<()> {
+        let tx = self.conn.transaction().map_err(error)?;
+        {
+            let mut insert = tx.prepare_cached(INSERT).map_err(error)?;
+            for row in rows {
+                insert
+                    .execute(rusqlite::params![
+                        unix_millis(row.start_time),
+                        unix_millis(row.end_time),
+                        row.src_ip.to_string(),
+                        row.dst_ip.to_string(),
+                        row.proto,
+                        row.bytes_in as i64,
+                        row.bytes_out as i64,
+                        row.error_code,
+                    ])
+                    .map_err(error)?;
+            }
+        }
+        tx.commit().map_err(error)
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+impl Db {
+    fn open(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SQLite statistics need portfwd to be built with the `sqlite` feature",
+        ))
+    }
+
+    fn insert(&mut self, _rows: &[Row]) -> io::Result<()> {
+        match self.never {}
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn error(err: rusqlite::Error) -> io::Error {
+    io::Error::other(format!("SQLite: {err}"))
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    #[test]
+    fn inserts_the_queued_rows_when_dropped() {
+        let path = std::env::temp_dir().join("portfwd-test-stats.db");
+        let _ = std::fs::remove_file(&path);
+        let stats = SqliteStats::open(&path).unwrap();
+        stats.record(Row {
+            start_time: UNIX_EPOCH,
+            end_time: UNIX_EPOCH + Duration::from_millis(1500),
+            src_ip: [10, 0, 0, 1].into(),
+            dst_ip: [10, 0, 0, 2].into(),
+            proto: "tcp",
+            bytes_in: 3,
+            bytes_out: 4,
+            error_code: None,
+        });
+        drop(stats);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let row: (i64, String, i64, Option<String>) = conn
+            .query_row(
+                "SELECT end_time, dst_ip, bytes_out, error_code FROM connections",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (1500, "10.0.0.2".to_string(), 4, None));
+    }
+}
@@ -0,0 +1,126 @@
+//! Steering of new TCP clients to the listeners of their ports with an eBPF program on the TC
+//! ingress hook of an interface, for `--ebpf-steer` in builds with the `ebpf` feature.
+//!
+//! The program, `src/bpf/steer.s`, is checked in compiled as `steer.bpf.o`, and is loaded with
+//! aya. It assigns each SYN that arrives on the interface for the port of a listener to that
+//! listener with `bpf_sk_assign`, whichever local address it is for, so that a listener bound to
+//! one address also accepts the clients of the port on the others. The packets after the SYN
+//! are left to the kernel, which finds the connection it started. Listeners are found by their
+//! port and address family, and a dual-stack IPv6 listener also takes the IPv4 clients of its
+//! port if no IPv4 listener has it.
+//!
+//! Loading or attaching the program needs `CAP_BPF` and `CAP_NET_ADMIN`. If either fails, or a
+//! listener can't be added, clients are accepted by the usual lookup of the kernel.
+
+use std::{fmt, net::TcpListener};
+
+use smol::io;
+
+/// The program attached to an interface, and the listeners it steers clients to.
+#[cfg(feature = "ebpf")]
+pub struct Steering {
+    iface: String,
+    sockets: std::sync::Mutex<aya::maps::SockHash<aya::maps::MapData, u32>>,
+    /// The loaded program, which stays attached for as long as it is kept.
+    _ebpf: aya::Ebpf,
+}
+
+/// The steering of clients, which can't be attached without the `ebpf` feature.
+#[cfg(not(feature = "ebpf"))]
+pub struct Steering {
+    iface: String,
+    never: std::convert::Infallible,
+}
+
+/// The key of the listeners of a port and address family in the map of the program.
+#[cfg(feature = "ebpf")]
+fn key(port: u16, ipv6: bool) -> u32 {
+    u32::from(port) << 1 | u32::from(ipv6)
+}
+
+#[cfg(feature = "ebpf")]
+impl Steering {
+    /// Loads the program and attaches it to the ingress of an interface.
+    pub fn attach(iface: &str) -> io::Result<Self> {
+        use aya::programs::{tc, SchedClassifier, TcAttachType};
+
+        let failed = |err: &dyn fmt::Display| io::Error::other(format!("{iface}: {err}"));
+        let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!("bpf/steer.bpf.o"))
+            .map_err(|err| failed(&err))?;
+        // Kernels before 6.6 attach TC programs to a clsact qdisc, which may be there already.
+        let _ = tc::qdisc_add_clsact(iface);
+        let program: &mut SchedClassifier = ebpf
+            .program_mut("steer")
+            .expect("the object has the program")
+            .try_into()
+            .map_err(|err| failed(&err))?;
+        program.load().map_err(|err| failed(&err))?;
+        program
+            .attach(iface, TcAttachType::Ingress)
+            .map_err(|err| failed(&err))?;
+        let sockets = ebpf
+            .take_map("SOCKETS")
+            .expect("the object has the map")
+            .try_into()
+            .map_err(|err| failed(&err))?;
+        Ok(Self {
+            iface: iface.to_string(),
+            sockets: std::sync::Mutex::new(sockets),
+            _ebpf: ebpf,
+        })
+    }
+
+    /// Steers the clients of the port of a listener to it.
+    pub fn add(&self, listener: &TcpListener, only_v6: bool) -> io::Result<()> {
+        use std::os::fd::AsFd;
+
+        // BPF_NOEXIST, which only adds keys that aren't there yet.
+        const NOEXIST: u64 = 1;
+
+        let addr = listener.local_addr()?;
+        let mut sockets = self.sockets.lock().unwrap();
+        sockets
+            .insert(key(addr.port(), addr.is_ipv6()), listener.as_fd(), 0)
+            .map_err(io::Error::other)?;
+        if addr.is_ipv6() && !only_v6 && addr.ip().is_unspecified() {
+            // Only if no IPv4 listener has the port, whichever is added first.
+            let _ = sockets.insert(key(addr.port(), false), listener.as_fd(), NOEXIST);
+        }
+        tracing::debug!("Steering the clients of {} on {}", addr, self.iface);
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "ebpf"))]
+impl Steering {
+    pub fn attach(_iface: &str) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "eBPF steering needs portfwd to be built with the `ebpf` feature",
+        ))
+    }
+
+    pub fn add(&self, _listener: &TcpListener, _only_v6: bool) -> io::Result<()> {
+        match self.never {}
+    }
+}
+
+impl fmt::Debug for Steering {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Steering")
+            .field("iface", &self.iface)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "ebpf"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_listeners_by_port_and_family() {
+        assert_eq!(key(80, false), 160);
+        assert_eq!(key(80, true), 161);
+        assert_eq!(key(u16::MAX, true), 0x1ffff);
+    }
+}
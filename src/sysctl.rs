@@ -0,0 +1,161 @@
+//! The kernel limits on the backlog of TCP listeners, checked at startup against `--backlog`.
+//!
+//! Linux silently caps the backlog of a listener at `net.core.somaxconn`, and the connections
+//! still in their handshake at `net.ipv4.tcp_max_syn_backlog`. With `--auto-sysctl`, the limits
+//! below the backlog are raised to it, which takes `CAP_NET_ADMIN`, and set back when portfwd
+//! exits, unless something else changed them meanwhile, which includes being killed by SIGINT
+//! or SIGTERM. Both limits belong to the network namespace that the listeners are bound in.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(target_os = "linux")]
+use crate::netns;
+
+/// The limits, by their paths under `/proc/sys`.
+#[cfg(target_os = "linux")]
+const LIMITS: [&str; 2] = ["net/core/somaxconn", "net/ipv4/tcp_max_syn_backlog"];
+
+/// The limits that were raised, which are set back once this is dropped.
+#[derive(Debug, Default)]
+pub struct Raised {
+    /// The paths of the limits, with their original values and the values they were raised to.
+    limits: Mutex<Vec<(&'static str, u64, u64)>>,
+}
+
+/// Checks the kernel limits on the backlog of listeners, raising those below `backlog` if
+/// `auto_raise` is set, and warning about them otherwise.
+#[cfg(target_os = "linux")]
+pub fn check(backlog: u32, auto_raise: bool) -> Raised {
+    let backlog = u64::from(backlog);
+    let raised = Raised::default();
+    for path in LIMITS {
+        let name = path.replace('/', ".");
+        let current = match read(path) {
+            Ok(current) => current,
+            Err(err) => {
+                tracing::debug!("Failed to read {}: {}", name, err);
+                continue;
+            }
+        };
+        if current >= backlog {
+            continue;
+        }
+        if !auto_raise {
+            tracing::warn!(
+                "The backlog of {} is capped at {} = {}, raise it with `sysctl` or --auto-sysctl",
+                backlog,
+                name,
+                current
+            );
+            continue;
+        }
+        match write(path, backlog) {
+            Ok(()) => {
+                tracing::warn!("Raised {} from {} to {}", name, current, backlog);
+                raised.limits.lock().unwrap().push((path, current, backlog));
+            }
+            Err(err) => tracing::warn!(
+                "Failed to raise {} from {} to {}: {}",
+                name,
+                current,
+                backlog,
+                err
+            ),
+        }
+    }
+    raised
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check(_backlog: u32, _auto_raise: bool) -> Raised {
+    Raised::default()
+}
+
+impl Raised {
+    /// Sets the limits back before portfwd exits on SIGINT, and on SIGTERM if `sigterm` is set,
+    /// which would otherwise kill it at once, if any limits were raised.
+    #[cfg(unix)]
+    pub fn restore_on_signals(self: &Arc<Self>, sigterm: bool) -> io::Result<()> {
+        use signal_hook::{
+            consts::{SIGINT, SIGTERM},
+            iterator::Signals,
+            low_level,
+        };
+
+        if self.limits.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+        let mut signals = match sigterm {
+            true => Signals::new([SIGINT, SIGTERM])?,
+            false => Signals::new([SIGINT])?,
+        };
+        // Once portfwd exits on its own, the limits were set back already.
+        let raised = Arc::downgrade(self);
+        std::thread::Builder::new()
+            .name("sysctl-restore".to_string())
+            .spawn(move || {
+                if let Some(signal) = signals.forever().next() {
+                    if let Some(raised) = raised.upgrade() {
+                        raised.restore();
+                    }
+                    let _ = low_level::emulate_default_handler(signal);
+                }
+            })?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn restore_on_signals(self: &Arc<Self>, _sigterm: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sets the limits back, unless they were changed since.
+    fn restore(&self) {
+        for (path, original, raised) in self.limits.lock().unwrap().drain(..) {
+            let name = path.replace('/', ".");
+            match read(path) {
+                Ok(current) if current == raised => match write(path, original) {
+                    Ok(()) => tracing::info!("Restored {} to {}", name, original),
+                    Err(err) => {
+                        tracing::warn!("Failed to restore {} to {}: {}", name, original, err)
+                    }
+                },
+                Ok(current) => tracing::debug!("Left {} at {}, as it was changed", name, current),
+                Err(err) => tracing::warn!("Failed to read {}: {}", name, err),
+            }
+        }
+    }
+}
+
+impl Drop for Raised {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read(path: &str) -> io::Result<u64> {
+    let value = netns::within(|| std::fs::read_to_string(format!("/proc/sys/{path}")))?;
+    value
+        .trim()
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(target_os = "linux")]
+fn write(path: &str, value: u64) -> io::Result<()> {
+    netns::within(|| std::fs::write(format!("/proc/sys/{path}"), value.to_string()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read(_path: &str) -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write(_path: &str, _value: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
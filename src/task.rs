@@ -0,0 +1,95 @@
+//! Names for tasks and for the futures that run inside them, so that they can be told apart in
+//! logs, debuggers and profilers.
+//!
+//! smol tasks have no names of their own. Instead, a named future enters a `task` span with its
+//! name whenever it is polled, which shows up in the logs at `-vv`, and stores the name in the
+//! [`CURRENT`] thread-local, which a debugger can print when it stops inside the future.
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use smol::{future, Executor, Task};
+
+/// The executor that tasks are spawned on, run by the executor threads.
+pub static EXECUTOR: Executor<'static> = Executor::new();
+
+/// The executor of the tasks that go before all others, e.g. those of `--priority-cidr`
+/// clients.
+pub static PRIORITY_EXECUTOR: Executor<'static> = Executor::new();
+
+/// How many tasks are run between the yields of [`run_prioritized`].
+const TICKS_PER_YIELD: usize = 200;
+
+thread_local! {
+    /// The name of the future that is being polled on this thread.
+    pub static CURRENT: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// A future with a name.
+pub struct Named<F> {
+    name: Arc<str>,
+    span: tracing::Span,
+    inner: Pin<Box<F>>,
+}
+
+/// Gives a future a name, e.g. a part of a task that runs alongside others.
+pub fn named<F: Future>(name: impl Into<Arc<str>>, future: F) -> Named<F> {
+    let name = name.into();
+    Named {
+        span: tracing::debug_span!("task", name = &*name),
+        name,
+        inner: Box::pin(future),
+    }
+}
+
+/// Spawns a future on [`EXECUTOR`] in a task with a name.
+pub fn spawn_named<F>(name: impl Into<Arc<str>>, future: F) -> Task<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    EXECUTOR.spawn(named(name, future))
+}
+
+/// Spawns a future on [`PRIORITY_EXECUTOR`] in a task with a name.
+pub fn spawn_prioritized<F>(name: impl Into<Arc<str>>, future: F) -> Task<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    PRIORITY_EXECUTOR.spawn(named(name, future))
+}
+
+/// Runs the tasks of both executors until a future completes, taking the next task from
+/// [`PRIORITY_EXECUTOR`] whenever it has one.
+pub async fn run_prioritized<T>(future: impl Future<Output = T>) -> T {
+    let ticks = async {
+        loop {
+            for _ in 0..TICKS_PER_YIELD {
+                future::or(PRIORITY_EXECUTOR.tick(), EXECUTOR.tick()).await;
+            }
+            // Let the future make progress too.
+            future::yield_now().await;
+        }
+    };
+    future::or(future, ticks).await
+}
+
+impl<F: Future> Future for Named<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let _span = this.span.enter();
+        // Named futures may be nested, in which case the outer name is restored afterwards.
+        let outer = CURRENT.with(|current| current.replace(Some(this.name.clone())));
+        let poll = this.inner.as_mut().poll(cx);
+        CURRENT.with(|current| *current.borrow_mut() = outer);
+        poll
+    }
+}
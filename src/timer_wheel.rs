@@ -0,0 +1,243 @@
+//! A hashed timer wheel that tracks the idle timeouts of all connections of a listener.
+//!
+//! Instead of one timer per connection, each connection is put in the slot of the tick at which
+//! it would expire, and a single task advances the wheel one slot per tick. Activity only
+//! updates a timestamp: when the slot of a connection comes up and the connection turns out to
+//! have been active since, it is moved to the slot of its new deadline instead of expiring.
+//!
+//! The wheel has a fixed number of slots, whatever the timeout. Deadlines more than a turn of
+//! the wheel away stay in their slot for as many more turns as they need, so long timeouts
+//! don't cost more memory.
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use smol::{
+    channel::{bounded, Receiver, Sender},
+    io::{self, AsyncRead},
+    Timer,
+};
+
+/// How often the wheel advances, which is also the precision of the timeouts.
+const TICK: Duration = Duration::from_secs(1);
+
+/// The number of slots, and so ticks in a turn of the wheel.
+const SLOTS: usize = 512;
+
+/// A connection in the wheel.
+struct Entry {
+    /// The tick of the last activity of the connection.
+    last_active: Arc<AtomicU64>,
+    /// Dropped to tell the connection that it expired.
+    expire: Sender<()>,
+}
+
+/// Idle timeouts of connections, in slots of one tick each.
+pub struct TimerWheel {
+    slots: Vec<Vec<Entry>>,
+    /// The timeout in ticks.
+    timeout: u64,
+    /// The number of ticks since the wheel was created.
+    now: Arc<AtomicU64>,
+}
+
+impl TimerWheel {
+    pub fn new(timeout: Duration) -> Self {
+        let timeout = timeout.as_secs().div_ceil(TICK.as_secs()).max(1);
+        Self {
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            timeout,
+            now: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Starts tracking a new connection.
+    pub fn insert(&mut self) -> Idle {
+        let now = self.now.load(Ordering::Relaxed);
+        let last_active = Arc::new(AtomicU64::new(now));
+        let (expire, expired) = bounded(1);
+        self.schedule(Entry {
+            last_active: last_active.clone(),
+            expire,
+        });
+        Idle {
+            now: self.now.clone(),
+            last_active,
+            expired,
+        }
+    }
+
+    /// The tick at which an entry expires, unless it becomes active again.
+    ///
+    /// This is one tick later than the timeout, since the last activity may have been right
+    /// before the end of its tick.
+    fn deadline(&self, entry: &Entry) -> u64 {
+        entry
+            .last_active
+            .load(Ordering::Relaxed)
+            .saturating_add(self.timeout)
+            .saturating_add(1)
+    }
+
+    /// Puts an entry in the slot of its deadline, which comes up once every turn until then.
+    fn schedule(&mut self, entry: Entry) {
+        let slot = (self.deadline(&entry) % self.slots.len() as u64) as usize;
+        self.slots[slot].push(entry);
+    }
+
+    /// Moves on to the next tick, expiring the connections that were idle for too long.
+    fn advance(&mut self) {
+        let now = self.now.fetch_add(1, Ordering::Relaxed) + 1;
+        let slot = (now % self.slots.len() as u64) as usize;
+        for entry in std::mem::take(&mut self.slots[slot]) {
+            // Connections that are already closed are forgotten.
+            if entry.expire.is_closed() {
+                continue;
+            }
+            if self.deadline(&entry) <= now {
+                drop(entry);
+            } else {
+                // Active since, or a deadline in a later turn.
+                self.schedule(entry);
+            }
+        }
+    }
+
+    /// Advances a shared wheel every tick.
+    pub async fn run(wheel: Arc<Mutex<TimerWheel>>) {
+        loop {
+            Timer::after(TICK).await;
+            wheel.lock().unwrap().advance();
+        }
+    }
+}
+
+/// The idle timeout of a single connection.
+pub struct Idle {
+    now: Arc<AtomicU64>,
+    last_active: Arc<AtomicU64>,
+    expired: Receiver<()>,
+}
+
+impl Idle {
+    /// Waits until the connection has been idle for longer than the timeout.
+    pub async fn expired(&self) {
+        let _ = self.expired.recv().await;
+    }
+}
+
+/// Fails once a connection has been idle for longer than its timeout, if it has one.
+pub async fn timeout<T, E: From<io::Error>>(idle: Option<&Idle>) -> Result<T, E> {
+    match idle {
+        Some(idle) => idle.expired().await,
+        None => smol::future::pending().await,
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout").into())
+}
+
+/// Something that keeps track of when a connection was last active.
+pub trait Activity {
+    /// Records activity on the connection.
+    fn touch(&self);
+}
+
+impl Activity for Idle {
+    /// Pushes the timeout of the connection back.
+    fn touch(&self) {
+        let now = self.now.load(Ordering::Relaxed);
+        self.last_active.store(now, Ordering::Relaxed);
+    }
+}
+
+/// A reader that counts every chunk it reads as activity on a connection.
+pub struct ActivityReader<'a, R, A> {
+    inner: R,
+    activity: Option<&'a A>,
+}
+
+impl<'a, R, A> ActivityReader<'a, R, A> {
+    pub fn new(inner: R, activity: Option<&'a A>) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<R: AsyncRead + Unpin, A: Activity> AsyncRead for ActivityReader<'_, R, A> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(_)), Some(activity)) = (&poll, self.activity) {
+            activity.touch();
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_expired(idle: &Idle) -> bool {
+        idle.expired.is_closed()
+    }
+
+    #[test]
+    fn expires_idle_connections() {
+        let mut wheel = TimerWheel::new(Duration::from_secs(3));
+        let idle = wheel.insert();
+        for _ in 0..3 {
+            wheel.advance();
+        }
+        assert!(!is_expired(&idle));
+        wheel.advance();
+        assert!(is_expired(&idle));
+    }
+
+    #[test]
+    fn reschedules_active_connections() {
+        let mut wheel = TimerWheel::new(Duration::from_secs(3));
+        let idle = wheel.insert();
+        wheel.advance();
+        wheel.advance();
+        idle.touch();
+        for _ in 0..3 {
+            wheel.advance();
+        }
+        assert!(!is_expired(&idle));
+        wheel.advance();
+        assert!(is_expired(&idle));
+    }
+
+    #[test]
+    fn waits_for_deadlines_in_later_turns() {
+        let timeout = 2 * SLOTS as u64 + 5;
+        let mut wheel = TimerWheel::new(Duration::from_secs(timeout));
+        assert_eq!(wheel.slots.len(), SLOTS);
+        let idle = wheel.insert();
+        for _ in 0..timeout {
+            wheel.advance();
+        }
+        assert!(!is_expired(&idle));
+        wheel.advance();
+        assert!(is_expired(&idle));
+    }
+
+    #[test]
+    fn takes_huge_timeouts() {
+        let mut wheel = TimerWheel::new(Duration::from_secs(u64::MAX));
+        let idle = wheel.insert();
+        for _ in 0..2 * SLOTS {
+            wheel.advance();
+        }
+        assert!(!is_expired(&idle));
+    }
+}
@@ -0,0 +1,343 @@
+//! Pluggable transports that open and accept the byte streams of forwarded TCP connections.
+//!
+//! A transport either talks to the network directly, like [`TcpTransport`], or runs on top of
+//! the streams of an inner transport, like [`TlsTransport`], [`SocksTransport`] and
+//! [`HttpProxyTransport`]. Transports are stacked with [`ChainedTransport`], e.g. to reach a TLS
+//! backend through a SOCKS5 proxy.
+
+use std::{
+    fmt,
+    future::Future,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use smol::{
+    io::{self, AsyncRead, AsyncWrite},
+    Async,
+};
+use socket2::SockRef;
+
+use crate::{
+    netns,
+    socket::{self, Outbound},
+    steer::Steering,
+};
+
+pub mod http_proxy;
+pub mod socks5_client;
+pub mod tls;
+pub mod wireguard;
+
+pub use http_proxy::HttpProxyTransport;
+pub use socks5_client::SocksTransport;
+pub use tls::TlsTransport;
+pub use wireguard::WireGuardTransport;
+
+/// Which side of the forwarded connections a layer applies to, given as `client` or `backend`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    /// The streams accepted from the clients.
+    Client,
+    /// The streams opened to the backends.
+    Backend,
+}
+
+impl FromStr for Side {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(Side::Client),
+            "backend" => Ok(Side::Backend),
+            _ => Err(format!("expected client or backend, got: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Side::Client => "client",
+            Side::Backend => "backend",
+        })
+    }
+}
+
+/// A bidirectional byte stream opened or accepted by a transport.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {
+    /// The TCP socket under the stream, if it runs over one, e.g. to reset the connection.
+    fn tcp_socket(&self) -> Option<&TcpStream>;
+}
+
+impl Stream for BoxStream {
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        (**self).tcp_socket()
+    }
+}
+
+pub type BoxStream = Box<dyn Stream>;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A way of opening streams to other hosts and accepting streams from them.
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Opens a stream to an address.
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxStream>>;
+
+    /// Starts accepting streams on a local address.
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn TransportListener>>>;
+}
+
+/// Streams accepted by a transport.
+pub trait TransportListener: Send + Sync {
+    /// Waits for the next stream, returning it with the address of its peer.
+    fn accept(&self) -> BoxFuture<'_, io::Result<(BoxStream, SocketAddr)>>;
+
+    /// The local address the listener is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+/// Plain TCP, with the socket options given on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct TcpTransport {
+    /// SO_LINGER timeout of TCP sockets.
+    linger: Option<Duration>,
+    /// Whether IPv6 listeners only accept IPv6 clients.
+    only_v6: bool,
+    /// Whether listeners are bound with `SO_REUSEADDR`.
+    reuse_addr: bool,
+    /// How many clients may wait to be accepted by a listener.
+    backlog: u32,
+    /// Options of connected sockets.
+    outbound: Outbound,
+    /// The eBPF program that steers new clients to the listeners, if it is attached.
+    steering: Option<Arc<Steering>>,
+}
+
+impl TcpTransport {
+    pub fn new(
+        linger: Option<Duration>,
+        only_v6: bool,
+        reuse_addr: bool,
+        backlog: u32,
+        outbound: Outbound,
+        steering: Option<Arc<Steering>>,
+    ) -> Self {
+        Self {
+            linger,
+            only_v6,
+            reuse_addr,
+            backlog,
+            outbound,
+            steering,
+        }
+    }
+
+    /// Applies the socket options to both accepted and connected sockets.
+    fn set_options(&self, stream: &TcpStream) -> io::Result<()> {
+        if let Some(linger) = self.linger {
+            SockRef::from(stream).set_linger(Some(linger))?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let stream = socket::tcp_connect(addr, &self.outbound).await?;
+            self.set_options(stream.get_ref())?;
+            Ok(Box::new(TcpConnection(stream)) as BoxStream)
+        })
+    }
+
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn TransportListener>>> {
+        Box::pin(async move {
+            let listener = netns::within(|| {
+                socket::tcp_listener(addr, self.only_v6, self.reuse_addr, self.backlog)
+            })?;
+            if let Some(steering) = &self.steering {
+                if let Err(err) = steering.add(&listener, self.only_v6) {
+                    tracing::warn!("Failed to steer the clients of {}: {}", addr, err);
+                }
+            }
+            let listener = Async::new(listener)?;
+            Ok(Box::new(TcpTransportListener {
+                listener,
+                transport: self.clone(),
+            }) as Box<dyn TransportListener>)
+        })
+    }
+}
+
+struct TcpTransportListener {
+    listener: Async<TcpListener>,
+    transport: TcpTransport,
+}
+
+impl TransportListener for TcpTransportListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<(BoxStream, SocketAddr)>> {
+        Box::pin(async move {
+            let (stream, peer_addr) = self.listener.accept().await?;
+            self.transport.set_options(stream.get_ref())?;
+            Ok((Box::new(TcpConnection(stream)) as BoxStream, peer_addr))
+        })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.get_ref().local_addr()
+    }
+}
+
+/// A TCP stream that shuts down its sending side when it is closed, so that the peer sees the
+/// end of the stream while it can still send data back.
+struct TcpConnection(Async<TcpStream>);
+
+impl AsyncRead for TcpConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TcpConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.0.get_ref().shutdown(Shutdown::Write))
+    }
+}
+
+impl Stream for TcpConnection {
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        Some(self.0.get_ref())
+    }
+}
+
+/// Duplicates the socket of a plain TCP stream, e.g. to reset the connection when it is
+/// dropped.
+pub fn try_clone_tcp(stream: &BoxStream) -> Option<TcpStream> {
+    stream.tcp_socket()?.try_clone().ok()
+}
+
+/// A stack of transports, each one reaching the network through the one below it.
+#[derive(Clone, Debug)]
+pub struct ChainedTransport {
+    top: Arc<dyn Transport>,
+}
+
+impl ChainedTransport {
+    /// Starts a stack with the transport that talks to the network.
+    pub fn new(base: impl Transport + 'static) -> Self {
+        Self {
+            top: Arc::new(base),
+        }
+    }
+
+    /// Puts a transport on top of the stack, built over the transport that was on top so far.
+    pub fn then<T: Transport + 'static>(self, layer: impl FnOnce(Arc<dyn Transport>) -> T) -> Self {
+        Self {
+            top: Arc::new(layer(self.top)),
+        }
+    }
+}
+
+impl Transport for ChainedTransport {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxStream>> {
+        self.top.connect(addr)
+    }
+
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn TransportListener>>> {
+        self.top.listen(addr)
+    }
+}
+
+/// A stream that reads a script of bytes and keeps what is written to it, for the tests of
+/// the protocols that streams speak.
+#[cfg(test)]
+pub struct ScriptedStream {
+    script: io::Cursor<Vec<u8>>,
+    written: Arc<std::sync::Mutex<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl ScriptedStream {
+    /// A stream that reads `script`, with what is written to it.
+    pub fn boxed(script: &[u8]) -> (BoxStream, Arc<std::sync::Mutex<Vec<u8>>>) {
+        let written = Arc::default();
+        let stream = Self {
+            script: io::Cursor::new(script.to_vec()),
+            written: Arc::clone(&written),
+        };
+        (Box::new(stream), written)
+    }
+}
+
+#[cfg(test)]
+impl AsyncRead for ScriptedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.script).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+impl AsyncWrite for ScriptedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+impl Stream for ScriptedStream {
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sides() {
+        for side in [Side::Client, Side::Backend] {
+            assert_eq!(side.to_string().parse(), Ok(side));
+        }
+        assert!("both".parse::<Side>().is_err());
+    }
+}
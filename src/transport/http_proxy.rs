@@ -0,0 +1,182 @@
+//! Connections to the backends through an HTTP CONNECT proxy, the client side of what
+//! `--http-connect` serves.
+
+use std::{fmt, net::SocketAddr, str::FromStr, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_rustls::{pki_types::ServerName, TlsConnector};
+use smol::io::{self, AsyncReadExt, AsyncWriteExt};
+
+use super::{tls, BoxFuture, BoxStream, Transport, TransportListener};
+use crate::resolve;
+
+/// Maximum size of a response head.
+const MAX_HEAD: usize = 8192;
+
+/// An `http://[USER:PASS@]HOST[:PORT]` or `https://` URL of a proxy, on port 8080 by default.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyUrl {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// The `USER:PASS` to log in with, if any.
+    credentials: Option<String>,
+}
+
+impl ProxyUrl {
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl FromStr for ProxyUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tls, rest) = if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = s.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            return Err(format!("expected an http:// or https:// URL, got: {s}"));
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, authority) = match rest.rsplit_once('@') {
+            Some((credentials, authority)) if credentials.contains(':') => {
+                (Some(credentials.to_string()), authority)
+            }
+            Some(_) => return Err(format!("expected <USER>:<PASS> before @, got: {s}")),
+            None => (None, rest),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // The colons of an IPv6 address are not a port.
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|e| format!("{e}: {port}"))?)
+            }
+            _ => (authority, 8080),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.contains('/') {
+            return Err(format!("invalid proxy host: {s}"));
+        }
+        Ok(ProxyUrl {
+            tls,
+            host: host.to_string(),
+            port,
+            credentials,
+        })
+    }
+}
+
+/// Shows the URL without the password.
+impl fmt::Display for ProxyUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        write!(f, "{scheme}://")?;
+        if let Some((user, _)) = self.credentials.as_ref().and_then(|c| c.split_once(':')) {
+            write!(f, "{user}:***@")?;
+        }
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl fmt::Debug for ProxyUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{self}\"")
+    }
+}
+
+/// Opens streams through an HTTP CONNECT proxy, which is reached over an inner transport.
+///
+/// Listening is left to the inner transport.
+#[derive(Debug)]
+pub struct HttpProxyTransport {
+    inner: Arc<dyn Transport>,
+    proxy: ProxyUrl,
+}
+
+impl HttpProxyTransport {
+    pub fn new(inner: Arc<dyn Transport>, proxy: ProxyUrl) -> Self {
+        Self { inner, proxy }
+    }
+}
+
+impl Transport for HttpProxyTransport {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let proxy = resolve::resolve(&self.proxy.host, self.proxy.port).await?;
+            let mut stream = self.inner.connect(proxy).await?;
+            if self.proxy.tls {
+                let server_name = ServerName::try_from(self.proxy.host.clone())
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+                stream = Box::new(
+                    TlsConnector::from(tls::client_config())
+                        .connect(server_name, stream)
+                        .await?,
+                );
+            }
+            handshake(&mut stream, addr, self.proxy.credentials.as_deref()).await?;
+            tracing::trace!("Connected to {} through HTTP proxy {}", addr, self.proxy);
+            Ok(stream)
+        })
+    }
+
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn TransportListener>>> {
+        self.inner.listen(addr)
+    }
+}
+
+/// Asks the proxy on the other end of a stream to connect it to an address.
+async fn handshake(
+    stream: &mut BoxStream,
+    addr: SocketAddr,
+    credentials: Option<&str>,
+) -> io::Result<()> {
+    let mut request = format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n");
+    if let Some(credentials) = credentials {
+        request += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            STANDARD.encode(credentials)
+        );
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    // Read the response a byte at a time, so that nothing sent through the tunnel after it is
+    // taken from the stream.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP proxy response too large",
+            ));
+        }
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some(_) => Err(io::Error::other(format!(
+            "HTTP proxy failed to connect to {}: {}",
+            addr,
+            status.split_once(' ').map_or(status, |(_, reason)| reason)
+        ))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid HTTP proxy response",
+        )),
+    }
+}
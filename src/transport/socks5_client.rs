@@ -0,0 +1,241 @@
+//! Connections to the backends through a SOCKS5 proxy (RFC 1928), without authentication or
+//! with a username and password (RFC 1929).
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use smol::io::{self, AsyncReadExt, AsyncWriteExt};
+
+use super::{BoxFuture, BoxStream, Transport, TransportListener};
+use crate::resolve;
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE: u8 = 0xff;
+const USER_PASS_VERSION: u8 = 0x01;
+const CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// The address of a proxy, given as `<HOST>:<PORT>`, where the host is a name or an IP address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyAddr {
+    host: String,
+    port: u16,
+}
+
+impl ProxyAddr {
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl FromStr for ProxyAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(ProxyAddr {
+                host: addr.ip().to_string(),
+                port: addr.port(),
+            });
+        }
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected <HOST>:<PORT>, got: {s}"))?;
+        if host.is_empty() || host.contains(':') {
+            return Err(format!("invalid proxy host: {s}"));
+        }
+        Ok(ProxyAddr {
+            host: host.to_string(),
+            port: port.parse().map_err(|e| format!("{e}: {port}"))?,
+        })
+    }
+}
+
+impl fmt::Display for ProxyAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// A username and password to log in to a proxy with.
+#[derive(Clone)]
+pub struct Login {
+    pub user: String,
+    pub pass: String,
+}
+
+impl fmt::Debug for Login {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Login")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Opens streams through a SOCKS5 proxy, which is reached over an inner transport.
+///
+/// Listening is left to the inner transport.
+#[derive(Debug)]
+pub struct SocksTransport {
+    inner: Arc<dyn Transport>,
+    proxy: ProxyAddr,
+    login: Option<Login>,
+}
+
+impl SocksTransport {
+    pub fn new(inner: Arc<dyn Transport>, proxy: ProxyAddr, login: Option<Login>) -> Self {
+        Self {
+            inner,
+            proxy,
+            login,
+        }
+    }
+}
+
+impl Transport for SocksTransport {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let proxy = resolve::resolve(&self.proxy.host, self.proxy.port).await?;
+            let mut stream = self.inner.connect(proxy).await?;
+            handshake(&mut stream, addr, self.login.as_ref()).await?;
+            tracing::trace!("Connected to {} through SOCKS5 proxy {}", addr, self.proxy);
+            Ok(stream)
+        })
+    }
+
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn TransportListener>>> {
+        self.inner.listen(addr)
+    }
+}
+
+/// Asks the proxy on the other end of a stream to connect it to an address.
+async fn handshake(
+    stream: &mut BoxStream,
+    addr: SocketAddr,
+    login: Option<&Login>,
+) -> io::Result<()> {
+    // Offer to log in if there are credentials, and no authentication otherwise.
+    let method = if login.is_some() { USER_PASS } else { NO_AUTH };
+    stream.write_all(&[VERSION, 1, method]).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    match (choice, login) {
+        ([VERSION, NO_AUTH], None) => {}
+        ([VERSION, USER_PASS], Some(login)) => authenticate(stream, login).await?,
+        ([VERSION, NO_ACCEPTABLE], None) => {
+            return Err(io::Error::other("SOCKS5 proxy requires authentication"))
+        }
+        ([VERSION, NO_ACCEPTABLE], Some(_)) => {
+            return Err(io::Error::other(
+                "SOCKS5 proxy doesn't accept username and password authentication",
+            ))
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid SOCKS5 method selection",
+            ))
+        }
+    }
+
+    // Request a connection to the address.
+    let mut request = vec![VERSION, CONNECT, 0];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+    request.extend_from_slice(&addr.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    // Read the reply, up to the bound address that ends it.
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid SOCKS5 reply",
+        ));
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::other(format!(
+            "SOCKS5 proxy failed to connect to {}: {}",
+            addr,
+            reply_message(reply[1])
+        )));
+    }
+    let len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid SOCKS5 address type",
+            ))
+        }
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Logs in to the proxy with a username and password.
+async fn authenticate(stream: &mut BoxStream, login: &Login) -> io::Result<()> {
+    let (user, pass) = (login.user.as_bytes(), login.pass.as_bytes());
+    let mut request = vec![USER_PASS_VERSION, user.len() as u8];
+    request.extend_from_slice(user);
+    request.push(pass.len() as u8);
+    request.extend_from_slice(pass);
+    stream.write_all(&request).await?;
+
+    let mut status = [0; 2];
+    stream.read_exact(&mut status).await?;
+    if status[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 proxy rejected the username and password",
+        ));
+    }
+    Ok(())
+}
+
+/// Describes the reply codes of a failed request.
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
@@ -0,0 +1,108 @@
+//! TLS on top of another transport, for backends that only accept encrypted connections.
+
+use std::{
+    fmt,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+};
+
+use futures_rustls::{
+    client::TlsStream,
+    pki_types::ServerName,
+    rustls::{crypto::ring, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use smol::io;
+
+use super::{BoxFuture, BoxStream, Stream, Transport, TransportListener};
+
+/// Wraps the streams opened by an inner transport in TLS.
+///
+/// Backend certificates are verified against the Mozilla root certificates, for the IP address
+/// of the backend unless another server name is given. Listening is left to the inner
+/// transport, so only the connections to the backends are encrypted.
+pub struct TlsTransport {
+    inner: Arc<dyn Transport>,
+    config: Arc<ClientConfig>,
+    /// The name to verify certificates for and send as SNI, instead of the backend IP.
+    server_name: Option<ServerName<'static>>,
+    /// The backend ports to use TLS for, or all of them.
+    ports: Option<Vec<u16>>,
+}
+
+impl TlsTransport {
+    pub fn new(inner: Arc<dyn Transport>) -> Self {
+        Self {
+            inner,
+            config: client_config(),
+            server_name: None,
+            ports: None,
+        }
+    }
+
+    /// Verifies certificates for a name rather than for the backend IP.
+    pub fn with_server_name(mut self, server_name: Option<ServerName<'static>>) -> Self {
+        self.server_name = server_name;
+        self
+    }
+
+    /// Only uses TLS for backends on these ports, leaving the others to the inner transport.
+    pub fn only_ports(mut self, ports: Vec<u16>) -> Self {
+        self.ports = Some(ports);
+        self
+    }
+}
+
+/// A client configuration that verifies servers against the Mozilla root certificates.
+pub fn client_config() -> Arc<ClientConfig> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("the ring provider supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+impl fmt::Debug for TlsTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsTransport")
+            .field("inner", &self.inner)
+            .field("server_name", &self.server_name)
+            .field("ports", &self.ports)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Transport for TlsTransport {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxStream>> {
+        Box::pin(async move {
+            let stream = self.inner.connect(addr).await?;
+            if let Some(ports) = &self.ports {
+                if !ports.contains(&addr.port()) {
+                    return Ok(stream);
+                }
+            }
+            let server_name = match &self.server_name {
+                Some(server_name) => server_name.clone(),
+                None => ServerName::IpAddress(addr.ip().into()),
+            };
+            let stream = TlsConnector::from(self.config.clone())
+                .connect(server_name, stream)
+                .await?;
+            Ok(Box::new(stream) as BoxStream)
+        })
+    }
+
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn TransportListener>>> {
+        self.inner.listen(addr)
+    }
+}
+
+impl<S: Stream> Stream for TlsStream<S> {
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        self.get_ref().0.tcp_socket()
+    }
+}
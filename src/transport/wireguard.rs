@@ -0,0 +1,38 @@
+//! Connections to the backends behind a WireGuard peer, through the tunnel to it.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use smol::io;
+
+use super::{BoxFuture, BoxStream, Transport, TransportListener};
+use crate::wireguard::Tunnel;
+
+/// Connects to the backends in the allowed IPs of a tunnel through it, and to the others with
+/// the inner transport.
+///
+/// Listening is left to the inner transport.
+#[derive(Debug)]
+pub struct WireGuardTransport {
+    inner: Arc<dyn Transport>,
+    tunnel: Arc<Tunnel>,
+}
+
+impl WireGuardTransport {
+    pub fn new(inner: Arc<dyn Transport>, tunnel: Arc<Tunnel>) -> Self {
+        Self { inner, tunnel }
+    }
+}
+
+impl Transport for WireGuardTransport {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxStream>> {
+        if self.tunnel.routes(addr.ip()) {
+            Box::pin(self.tunnel.connect(addr))
+        } else {
+            self.inner.connect(addr)
+        }
+    }
+
+    fn listen(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn TransportListener>>> {
+        self.inner.listen(addr)
+    }
+}
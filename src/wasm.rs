@@ -0,0 +1,267 @@
+//! Filters compiled to WebAssembly, for `--wasm-filter` in builds with the `wasm` feature.
+//!
+//! A filter is a WebAssembly module that exports its `memory`, an `alloc(len: i32) -> i32`
+//! function that returns where in the memory portfwd may write `len` bytes, and either or both
+//! of:
+//!
+//! - `on_client_data(ptr: i32, len: i32) -> (i32, i32)`, called with each chunk read from the
+//!   client before it is passed on to the backend;
+//! - `on_backend_data(ptr: i32, len: i32) -> (i32, i32)`, called with each chunk read from the
+//!   backend before it is passed on to the client.
+//!
+//! Both return where in the memory the bytes to pass on instead are, which portfwd copies before
+//! calling the module again. Returning the chunk itself passes it on unchanged, and an empty
+//! range drops it.
+//! The two results are returned as multiple values, which compilers only do for the
+//! multi-value ABI of WebAssembly, e.g. with `-mmultivalue -Xclang -target-abi -Xclang
+//! experimental-mv` for clang.
+//!
+//! Each TCP connection gets an instance of the module of its own, shared by both directions,
+//! which lives as long as the connection. The module may not import anything, so it can't reach
+//! the files, the network or anything else of the host, and each call may only run for a limited
+//! number of instructions, as it holds up the executor thread it runs on. A module that traps or
+//! runs out of them fails the connection.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use smol::io::{self, AsyncRead};
+
+use crate::protocols::Direction;
+
+/// A compiled filter, ready to be instantiated for each connection.
+#[cfg(feature = "wasm")]
+pub struct WasmFilter {
+    path: PathBuf,
+    pre: wasmtime::InstancePre<()>,
+}
+
+/// A filter, which can't be loaded without the `wasm` feature.
+#[cfg(not(feature = "wasm"))]
+pub struct WasmFilter {
+    path: PathBuf,
+    never: std::convert::Infallible,
+}
+
+/// The instance of a filter for a single connection.
+#[cfg(feature = "wasm")]
+pub struct WasmInstance {
+    path: PathBuf,
+    inner: std::sync::Mutex<Instance>,
+}
+
+#[cfg(not(feature = "wasm"))]
+pub struct WasmInstance {
+    never: std::convert::Infallible,
+}
+
+/// An instance with the exports that portfwd calls.
+#[cfg(feature = "wasm")]
+struct Instance {
+    store: wasmtime::Store<()>,
+    memory: wasmtime::Memory,
+    alloc: wasmtime::TypedFunc<u32, u32>,
+    on_client_data: Option<wasmtime::TypedFunc<(u32, u32), (u32, u32)>>,
+    on_backend_data: Option<wasmtime::TypedFunc<(u32, u32), (u32, u32)>>,
+}
+
+/// How many instructions, roughly, a single call of a filter may run for.
+#[cfg(feature = "wasm")]
+const FUEL: u64 = 100_000_000;
+
+#[cfg(feature = "wasm")]
+impl WasmFilter {
+    /// Compiles a filter, checking that it can be instantiated. Errors name the module.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        use wasmtime::{Config, Engine, Linker, Module};
+
+        let failed = |err| error(path, err);
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(failed)?;
+        let module = Module::from_file(&engine, path).map_err(failed)?;
+        // Nothing of the host is linked, so modules that import anything fail here.
+        let pre = Linker::new(&engine)
+            .instantiate_pre(&module)
+            .map_err(failed)?;
+        let filter = Self {
+            path: path.to_path_buf(),
+            pre,
+        };
+        filter.instantiate()?;
+        Ok(filter)
+    }
+
+    /// Instantiates the filter for a new connection.
+    pub fn instantiate(&self) -> io::Result<WasmInstance> {
+        let failed = |err| error(&self.path, err);
+        let mut store = wasmtime::Store::new(self.pre.module().engine(), ());
+        store.set_fuel(FUEL).map_err(failed)?;
+        let instance = self.pre.instantiate(&mut store).map_err(failed)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| failed(wasmtime::Error::msg("no `memory` is exported")))?;
+        let alloc = instance
+            .get_typed_func(&mut store, "alloc")
+            .map_err(failed)?;
+        let mut hook = |name| match instance.get_func(&mut store, name) {
+            Some(func) => func.typed(&store).map(Some).map_err(failed),
+            None => Ok(None),
+        };
+        let on_client_data = hook("on_client_data")?;
+        let on_backend_data = hook("on_backend_data")?;
+        if on_client_data.is_none() && on_backend_data.is_none() {
+            return Err(failed(wasmtime::Error::msg(
+                "neither `on_client_data` nor `on_backend_data` is exported",
+            )));
+        }
+        Ok(WasmInstance {
+            path: self.path.clone(),
+            inner: std::sync::Mutex::new(Instance {
+                store,
+                memory,
+                alloc,
+                on_client_data,
+                on_backend_data,
+            }),
+        })
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+impl WasmFilter {
+    pub fn load(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "WASM filters need portfwd to be built with the `wasm` feature",
+        ))
+    }
+
+    pub fn instantiate(&self) -> io::Result<WasmInstance> {
+        match self.never {}
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl WasmInstance {
+    /// Passes a chunk of the connection through the filter, returning the bytes to pass on
+    /// instead, if it changed them.
+    fn data(&self, direction: Direction, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        let Instance {
+            store,
+            memory,
+            alloc,
+            on_client_data,
+            on_backend_data,
+        } = &mut *inner;
+        let hook = match direction {
+            Direction::ClientToServer => on_client_data,
+            Direction::ServerToClient => on_backend_data,
+        };
+        let Some(hook) = hook else {
+            return Ok(None);
+        };
+
+        let failed = |err| error(&self.path, err);
+        let len = u32::try_from(data.len()).map_err(|err| failed(err.into()))?;
+        store.set_fuel(FUEL).map_err(failed)?;
+        let ptr = alloc.call(&mut *store, len).map_err(failed)?;
+        memory
+            .write(&mut *store, ptr as usize, data)
+            .map_err(|err| failed(err.into()))?;
+        let (out_ptr, out_len) = hook.call(&mut *store, (ptr, len)).map_err(failed)?;
+        if (out_ptr, out_len) == (ptr, len) {
+            return Ok(None);
+        }
+        let out = memory
+            .data(&*store)
+            .get(out_ptr as usize..)
+            .and_then(|out| out.get(..out_len as usize))
+            .ok_or_else(|| failed(wasmtime::Error::msg("returned bytes out of its memory")))?;
+        Ok(Some(out.to_vec()))
+    }
+}
+
+/// An error of a filter, naming its module, with only the trap of an error while running it.
+#[cfg(feature = "wasm")]
+fn error(path: &Path, err: wasmtime::Error) -> io::Error {
+    match err.downcast_ref::<wasmtime::Trap>() {
+        Some(trap) => io::Error::other(format!("{}: {trap}", path.display())),
+        None => io::Error::other(format!("{}: {err:#}", path.display())),
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+impl WasmInstance {
+    fn data(&self, _direction: Direction, _data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match self.never {}
+    }
+}
+
+impl fmt::Debug for WasmFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmFilter")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// A reader that passes every chunk it reads through an instance of a filter, if there is one.
+pub struct WasmReader<'a, R> {
+    inner: R,
+    instance: Option<&'a WasmInstance>,
+    direction: Direction,
+    /// The replacement of the last chunk not read yet, from `pos` on.
+    replaced: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, R> WasmReader<'a, R> {
+    pub fn new(inner: R, instance: Option<&'a WasmInstance>, direction: Direction) -> Self {
+        Self {
+            inner,
+            instance,
+            direction,
+            replaced: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for WasmReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Some(instance) = this.instance else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        loop {
+            if this.pos < this.replaced.len() {
+                let n = buf.len().min(this.replaced.len() - this.pos);
+                buf[..n].copy_from_slice(&this.replaced[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            if n == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            match instance.data(this.direction, &buf[..n])? {
+                Some(replaced) => {
+                    // An empty replacement drops the chunk, and the next one is read.
+                    this.replaced = replaced;
+                    this.pos = 0;
+                }
+                None => return Poll::Ready(Ok(n)),
+            }
+        }
+    }
+}
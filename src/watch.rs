@@ -0,0 +1,214 @@
+//! The `watch` subcommand, which restarts portfwd whenever its binary or another file changes,
+//! for development.
+//!
+//! The forwarder runs in a child process with the options given before `watch`. Once the file
+//! has changed and then stopped changing, as when a build finished writing the binary, the child
+//! is sent SIGTERM, which drains it for up to [`DRAIN_TIMEOUT`], and a new child is started. A
+//! child that exits on its own is restarted on the next change, not before, so that a broken
+//! build doesn't restart in a loop. When the watcher is told to stop, it stops the child the
+//! same way first.
+//!
+//! In builds with the `notify` feature, the changes are reported by the system, with inotify,
+//! kqueue or the like, watching the directory of the file, as the file may be replaced rather
+//! than written to. Other builds poll the file for changes of its modification time.
+
+#[cfg(feature = "notify")]
+use std::sync::mpsc;
+#[cfg(not(feature = "notify"))]
+use std::time::SystemTime;
+use std::{
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long the file must go without changes for it to have stopped changing, and how often the
+/// child and the stop signals are checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long the old child may take to drain its connections.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The changes of a file, as reported by the system.
+#[cfg(feature = "notify")]
+struct Changes {
+    file: PathBuf,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+/// The changes of a file, polled for.
+#[cfg(not(feature = "notify"))]
+struct Changes {
+    file: PathBuf,
+    /// The modification time of the file, or `None` while it doesn't exist, e.g. in the middle
+    /// of being replaced.
+    modified: Option<SystemTime>,
+}
+
+#[cfg(feature = "notify")]
+impl Changes {
+    fn new(file: &Path) -> io::Result<Self> {
+        use notify::{RecursiveMode, Watcher};
+
+        let file = std::path::absolute(file)?;
+        let dir = file.parent().unwrap_or(&file);
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender).map_err(io::Error::other)?;
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .map_err(|err| io::Error::other(format!("{}: {err}", dir.display())))?;
+        Ok(Self {
+            file,
+            events,
+            _watcher: watcher,
+        })
+    }
+
+    /// Waits for the file to change, returning whether it did within the timeout.
+    fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.events.recv_timeout(timeout) {
+                Ok(Ok(event)) => {
+                    if !event.kind.is_access() && event.paths.contains(&self.file) {
+                        return Ok(true);
+                    }
+                }
+                Ok(Err(err)) => tracing::warn!("Failed to watch {}: {}", self.file.display(), err),
+                Err(mpsc::RecvTimeoutError::Timeout) => return Ok(false),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::other("the file watcher stopped"))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "notify"))]
+impl Changes {
+    fn new(file: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: file.to_path_buf(),
+            modified: modified(file),
+        })
+    }
+
+    /// Waits out the timeout, returning whether the file changed meanwhile.
+    fn wait(&mut self, timeout: Duration) -> io::Result<bool> {
+        thread::sleep(timeout);
+        let modified = modified(&self.file);
+        Ok(std::mem::replace(&mut self.modified, modified) != modified)
+    }
+}
+
+/// The modification time of a file, if it exists.
+#[cfg(not(feature = "notify"))]
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Runs portfwd with `args` in a child process, restarting it whenever `file`, or the binary if
+/// none is given, changes, forever.
+pub fn run(file: Option<PathBuf>, args: Vec<OsString>) -> io::Result<()> {
+    let exe = std::env::current_exe()?;
+    let file = file.unwrap_or_else(|| exe.clone());
+    tracing::info!("Watching {} for changes", file.display());
+    let stopped = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, stopped.clone())?;
+    }
+    let mut changes = Changes::new(&file)?;
+    loop {
+        let mut child = Command::new(&exe)
+            .args(&args)
+            .args(["--drain-on-sigterm", "--drain-timeout"])
+            .arg(DRAIN_TIMEOUT.as_secs().to_string())
+            .spawn()?;
+        tracing::info!("Started portfwd as process {}", child.id());
+
+        // Wait for the file to change, and then to stop changing.
+        let mut exited = false;
+        loop {
+            let changed = changes.wait(POLL_INTERVAL)?;
+            if !exited {
+                if let Some(status) = child.try_wait()? {
+                    tracing::warn!(
+                        "portfwd exited with {}, restarting on the next change",
+                        status
+                    );
+                    exited = true;
+                }
+            }
+            if stopped.load(Ordering::Relaxed) {
+                if !exited {
+                    stop(&mut child)?;
+                }
+                return Ok(());
+            }
+            if changed {
+                break;
+            }
+        }
+        while changes.wait(POLL_INTERVAL)? || !file.exists() {}
+
+        tracing::info!("{} changed, restarting portfwd", file.display());
+        if !exited {
+            stop(&mut child)?;
+        }
+    }
+}
+
+/// Stops a child with SIGTERM, killing it if it is still running after the drain timeout.
+fn stop(child: &mut Child) -> io::Result<()> {
+    #[cfg(unix)]
+    // SAFETY: sending a signal to a process id has no memory effects.
+    if unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } == 0 {
+        // Leave the child a little longer than its drain timeout to exit on its own.
+        let deadline = Instant::now() + DRAIN_TIMEOUT + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            if child.try_wait()?.is_some() {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        tracing::warn!("portfwd didn't exit after SIGTERM, killing it");
+    }
+    child.kill()?;
+    child.wait()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_changes_of_the_file() {
+        let dir = std::env::temp_dir().join(format!("portfwd-test-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("watched");
+        std::fs::write(&file, "old").unwrap();
+        let mut changes = Changes::new(&file).unwrap();
+        let interval = Duration::from_millis(50);
+        assert!(!changes.wait(interval).unwrap());
+
+        // Files next to it aren't watched.
+        std::fs::write(dir.join("other"), "other").unwrap();
+        assert!(!changes.wait(interval).unwrap());
+
+        // The file is replaced, as a build replaces a binary.
+        std::fs::write(dir.join("new"), "new").unwrap();
+        std::fs::rename(dir.join("new"), &file).unwrap();
+        assert!(changes.wait(interval).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+//! Notifications of connection events to an HTTP endpoint, for `--webhook`.
+//!
+//! Events are queued without waiting, and posted one after another by a background task, so a
+//! slow or unreachable endpoint never holds up forwarding. Events that don't fit in the queue
+//! are dropped.
+
+use std::{
+    fmt,
+    net::{SocketAddr, TcpStream},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures_rustls::{pki_types::ServerName, TlsConnector};
+use serde_json::json;
+use smol::{
+    channel::{bounded, Receiver, Sender},
+    future,
+    io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    Async, Timer,
+};
+
+use crate::{resolve, transport::tls};
+
+/// How many events may wait to be posted.
+const QUEUE_SIZE: usize = 1024;
+
+/// How long posting an event may take.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An `http://` or `https://` URL to post events to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebhookUrl {
+    tls: bool,
+    /// The host and port as given, for the `Host` header.
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl WebhookUrl {
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl FromStr for WebhookUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tls, rest) = if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = s.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            return Err(format!("expected an http:// or https:// URL, got: {s}"));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // The colons of an IPv6 address are not a port.
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|e| format!("{e}: {port}"))?)
+            }
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("missing host: {s}"));
+        }
+        Ok(WebhookUrl {
+            tls,
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for WebhookUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        write!(f, "{scheme}://{}{}", self.authority, self.path)
+    }
+}
+
+/// What happened to a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Connect,
+    Disconnect,
+    Error,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EventKind::Connect => "connect",
+            EventKind::Disconnect => "disconnect",
+            EventKind::Error => "error",
+        })
+    }
+}
+
+/// An event of a forwarded connection, with the bytes it transferred so far.
+#[derive(Debug)]
+pub struct Event {
+    pub kind: EventKind,
+    pub conn_id: u64,
+    pub peer_addr: SocketAddr,
+    pub dest_addr: SocketAddr,
+    pub proto: &'static str,
+    /// Bytes from the client to the destination.
+    pub bytes_in: u64,
+    /// Bytes from the destination to the client.
+    pub bytes_out: u64,
+    /// How long the connection has been open.
+    pub duration: Duration,
+    /// Why the connection failed, for errors.
+    pub error: Option<String>,
+}
+
+/// The queue of events to post to a URL.
+pub struct Webhook {
+    url: WebhookUrl,
+    queued: Sender<Vec<u8>>,
+    queue: Receiver<Vec<u8>>,
+}
+
+impl Webhook {
+    pub fn new(url: WebhookUrl) -> Self {
+        let (queued, queue) = bounded(QUEUE_SIZE);
+        Self { url, queued, queue }
+    }
+
+    /// Queues an event to be posted, dropping it if the queue is full.
+    pub fn notify(&self, event: Event) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |since| since.as_secs_f64());
+        let body = json!({
+            "event": event.kind.to_string(),
+            "conn_id": event.conn_id,
+            "timestamp": timestamp,
+            "peer_addr": event.peer_addr.to_string(),
+            "dest_addr": event.dest_addr.to_string(),
+            "proto": event.proto,
+            "bytes_in": event.bytes_in,
+            "bytes_out": event.bytes_out,
+        });
+        if self.queued.try_send(body.to_string().into_bytes()).is_err() {
+            tracing::debug!(
+                "Dropped {} event of connection {}: webhook queue is full",
+                event.kind,
+                event.conn_id
+            );
+        }
+    }
+
+    /// Posts the queued events in order, forever.
+    pub async fn deliver(&self) {
+        while let Ok(body) = self.queue.recv().await {
+            let post = self.post(&body);
+            let timeout = async {
+                Timer::after(TIMEOUT).await;
+                Err(io::ErrorKind::TimedOut.into())
+            };
+            if let Err(err) = future::or(post, timeout).await {
+                tracing::debug!("Failed to post event to {}: {}", self.url, err);
+            }
+        }
+    }
+
+    /// Posts an event, failing unless the endpoint answers with a success status.
+    async fn post(&self, body: &[u8]) -> io::Result<()> {
+        let addr = resolve::resolve(&self.url.host, self.url.port).await?;
+        let stream = Async::<TcpStream>::connect(addr).await?;
+        if self.url.tls {
+            let server_name = ServerName::try_from(self.url.host.clone())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let stream = TlsConnector::from(tls::client_config())
+                .connect(server_name, stream)
+                .await?;
+            self.exchange(stream, body).await
+        } else {
+            self.exchange(stream, body).await
+        }
+    }
+
+    async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+        body: &[u8],
+    ) -> io::Result<()> {
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.url.path,
+            self.url.authority,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        stream.flush().await?;
+
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).await?;
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected response: {}", status.trim_end()),
+            )),
+        }
+    }
+}
+
+impl fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url)
+            .field("queued", &self.queue.len())
+            .finish()
+    }
+}
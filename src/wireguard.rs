@@ -0,0 +1,601 @@
+//! A WireGuard tunnel to the backends, for `--wg-private-key` and the other `--wg-*` options in
+//! builds with the `wireguard` feature.
+//!
+//! The tunnel has a single peer, which is reached over UDP at its endpoint, and boringtun does
+//! the handshakes and the encryption of the packets. TCP connections and UDP datagrams to the
+//! backends in the allowed IPs go through smoltcp, a TCP/IP stack in userspace, which sends them
+//! in IP packets from the address of portfwd in the tunnel. Connections and datagrams to the other
+//! backends are opened and sent as usual, and packets from the peer that don't come from the
+//! allowed IPs are dropped, as WireGuard does.
+//!
+//! The tunnel runs as a task on the executor, which moves the packets between the peer and the
+//! stack, and polls the stack whenever a connection has something to send. No kernel interface
+//! or privileges are needed. Datagrams are sent from the port of the listener, and replies that
+//! come back through the tunnel aren't relayed to the clients, as those that come to the
+//! listener aren't either.
+
+#[cfg(feature = "wireguard")]
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use smol::io;
+
+use crate::{routing::Cidr, transport::BoxStream};
+
+/// A WireGuard key, given as the 32 bytes of its base64 encoding like `wg genkey` prints.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Key([u8; 32]);
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = STANDARD
+            .decode(s)
+            .map_err(|err| format!("invalid key: {err}"))?;
+        let key = key
+            .try_into()
+            .map_err(|key: Vec<u8>| format!("expected 32 bytes, got {}", key.len()))?;
+        Ok(Key(key))
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Private keys must not end up in the logs.
+        f.write_str("Key(..)")
+    }
+}
+
+/// The settings of a tunnel.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "wireguard"), allow(dead_code))]
+pub struct Settings {
+    pub private_key: Key,
+    pub peer_public_key: Key,
+    pub peer_endpoint: SocketAddr,
+    pub allowed_ips: Vec<Cidr>,
+    /// The address of portfwd in the tunnel.
+    pub address: IpAddr,
+}
+
+/// The tunnel, and the TCP/IP stack that its connections run on.
+#[cfg(feature = "wireguard")]
+pub struct Tunnel {
+    endpoint: SocketAddr,
+    allowed_ips: Vec<Cidr>,
+    address: IpAddr,
+    stack: Mutex<Stack>,
+    /// Wakes the task of the tunnel when the stack has something to send.
+    poke: smol::channel::Sender<()>,
+}
+
+/// A tunnel, which can't be set up without the `wireguard` feature.
+#[cfg(not(feature = "wireguard"))]
+pub struct Tunnel {
+    address: IpAddr,
+    never: std::convert::Infallible,
+}
+
+/// The TCP/IP stack of a tunnel.
+#[cfg(feature = "wireguard")]
+struct Stack {
+    iface: smoltcp::iface::Interface,
+    device: Packets,
+    sockets: smoltcp::iface::SocketSet<'static>,
+    /// The UDP sockets that datagrams are sent from, by their port.
+    udp: HashMap<u16, smoltcp::iface::SocketHandle>,
+    /// The sockets of dropped connections, which are removed once they are closed.
+    closing: Vec<smoltcp::iface::SocketHandle>,
+}
+
+/// The IP packets that the stack received from the peer, and those it sends to the peer.
+#[cfg(feature = "wireguard")]
+#[derive(Default)]
+struct Packets {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+/// How many bytes each direction of a connection buffers.
+#[cfg(feature = "wireguard")]
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// How long a connection waits for its data or connection request to be acknowledged.
+#[cfg(feature = "wireguard")]
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the timers of boringtun are updated, for its retries and keepalives.
+#[cfg(feature = "wireguard")]
+const TIMER_TICK: Duration = Duration::from_millis(250);
+
+/// The largest IP packet sent through the tunnel, which leaves room for the headers of WireGuard
+/// and UDP within a common MTU of 1500 bytes, as `wg-quick` does.
+#[cfg(feature = "wireguard")]
+const MTU: usize = 1420;
+
+#[cfg(feature = "wireguard")]
+impl Tunnel {
+    /// Sets up the tunnel and starts its task, which sends the first handshake with the first
+    /// packet.
+    pub fn new(settings: Settings) -> io::Result<Arc<Self>> {
+        use boringtun::{
+            noise::Tunn,
+            x25519::{PublicKey, StaticSecret},
+        };
+        use smoltcp::{
+            iface::{Config, Interface, SocketSet},
+            wire::{HardwareAddress, IpCidr},
+        };
+
+        let Settings {
+            private_key,
+            peer_public_key,
+            peer_endpoint,
+            allowed_ips,
+            address,
+        } = settings;
+        let local: SocketAddr = match peer_endpoint {
+            SocketAddr::V4(_) => ([0; 4], 0).into(),
+            SocketAddr::V6(_) => ([0; 16], 0).into(),
+        };
+        let socket = std::net::UdpSocket::bind(local)?;
+        socket.connect(peer_endpoint)?;
+        let socket = smol::Async::new(socket)?;
+        // Sessions are told apart by 24 bits of the index.
+        let tunn = Tunn::new(
+            StaticSecret::from(private_key.0),
+            PublicKey::from(peer_public_key.0),
+            None,
+            None,
+            fastrand::u32(..1 << 24),
+            None,
+        );
+
+        let mut device = Packets::default();
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = fastrand::u64(..);
+        let mut iface = Interface::new(config, &mut device, smoltcp::time::Instant::now());
+        let prefix = if address.is_ipv4() { 32 } else { 128 };
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(address.into(), prefix));
+        });
+        // Everything goes to the peer, which has no link-layer address to look up.
+        match address {
+            IpAddr::V4(address) => {
+                let _ = iface.routes_mut().add_default_ipv4_route(address);
+            }
+            IpAddr::V6(address) => {
+                let _ = iface.routes_mut().add_default_ipv6_route(address);
+            }
+        }
+
+        let (poke, poked) = smol::channel::bounded(1);
+        let tunnel = Arc::new(Self {
+            endpoint: peer_endpoint,
+            allowed_ips,
+            address,
+            stack: Mutex::new(Stack {
+                iface,
+                device,
+                sockets: SocketSet::new(Vec::new()),
+                udp: HashMap::new(),
+                closing: Vec::new(),
+            }),
+            poke,
+        });
+        crate::task::spawn_named(
+            format!("wireguard-{peer_endpoint}"),
+            run(Arc::downgrade(&tunnel), tunn, socket, poked),
+        )
+        .detach();
+        Ok(tunnel)
+    }
+
+    /// Whether connections and datagrams to an address go through the tunnel.
+    pub fn routes(&self, ip: IpAddr) -> bool {
+        self.allowed_ips.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Opens a TCP connection through the tunnel.
+    pub async fn connect(self: &Arc<Self>, addr: SocketAddr) -> io::Result<BoxStream> {
+        use smoltcp::socket::tcp::{Socket, SocketBuffer, State};
+
+        let handle = {
+            let mut stack = self.stack.lock().unwrap();
+            let mut socket = Socket::new(
+                SocketBuffer::new(vec![0; BUFFER_SIZE]),
+                SocketBuffer::new(vec![0; BUFFER_SIZE]),
+            );
+            socket.set_timeout(Some(TIMEOUT.into()));
+            let port = 49152 + fastrand::u16(..16384);
+            let Stack { iface, .. } = &mut *stack;
+            socket
+                .connect(iface.context(), addr, (self.address, port))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+            stack.sockets.add(socket)
+        };
+        let stream = TunnelStream {
+            tunnel: self.clone(),
+            handle,
+        };
+        self.poke();
+        smol::future::poll_fn(|cx| {
+            let mut stack = self.stack.lock().unwrap();
+            let socket = stack.sockets.get_mut::<Socket>(handle);
+            match socket.state() {
+                State::SynSent | State::SynReceived => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                State::Closed => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("{addr} refused the connection through the tunnel, or didn't answer"),
+                ))),
+                _ => Poll::Ready(Ok(())),
+            }
+        })
+        .await?;
+        tracing::trace!("Connected to {} through the tunnel", addr);
+        Ok(Box::new(stream))
+    }
+
+    /// Sends a UDP datagram through the tunnel from a port.
+    pub fn send_to(&self, payload: &[u8], port: u16, addr: SocketAddr) -> io::Result<()> {
+        use smoltcp::socket::udp::{PacketBuffer, PacketMetadata, Socket};
+
+        let mut stack = self.stack.lock().unwrap();
+        let Stack { sockets, udp, .. } = &mut *stack;
+        let handle = *udp.entry(port).or_insert_with(|| {
+            // Replies aren't read, so they don't need more than a little room.
+            let mut socket = Socket::new(
+                PacketBuffer::new(vec![PacketMetadata::EMPTY; 1], vec![0; 1]),
+                PacketBuffer::new(vec![PacketMetadata::EMPTY; 64], vec![0; BUFFER_SIZE]),
+            );
+            let _ = socket.bind((self.address, port));
+            sockets.add(socket)
+        });
+        sockets
+            .get_mut::<Socket>(handle)
+            .send_slice(payload, addr)
+            .map_err(|err| io::Error::new(io::ErrorKind::WouldBlock, err.to_string()))?;
+        drop(stack);
+        self.poke();
+        Ok(())
+    }
+
+    /// Passes a packet from the peer on to the stack, if it comes from the allowed IPs.
+    fn receive(&self, packet: &[u8], src: IpAddr) {
+        if !self.routes(src) {
+            tracing::debug!("Dropped a packet from {} outside of the allowed IPs", src);
+            return;
+        }
+        self.stack
+            .lock()
+            .unwrap()
+            .device
+            .rx
+            .push_back(packet.to_vec());
+    }
+
+    /// Wakes the task of the tunnel to poll the stack, unless it is about to.
+    fn poke(&self) {
+        let _ = self.poke.try_send(());
+    }
+}
+
+/// Moves the packets between the peer and the stack, for as long as the tunnel is kept.
+#[cfg(feature = "wireguard")]
+async fn run(
+    tunnel: std::sync::Weak<Tunnel>,
+    mut tunn: boringtun::noise::Tunn,
+    socket: smol::Async<std::net::UdpSocket>,
+    poked: smol::channel::Receiver<()>,
+) {
+    use boringtun::noise::{errors::WireGuardError, TunnResult};
+    use smol::{future, Timer};
+
+    let mut datagram = vec![0; 65536];
+    let mut buf = vec![0; 65536 + 32];
+    loop {
+        let Some(tunnel) = tunnel.upgrade() else {
+            return;
+        };
+
+        // Let the stack take in what came from the peer, and encapsulate what it sent.
+        let (sent, delay) = {
+            let mut stack = tunnel.stack.lock().unwrap();
+            let now = smoltcp::time::Instant::now();
+            let Stack {
+                iface,
+                device,
+                sockets,
+                closing,
+                ..
+            } = &mut *stack;
+            iface.poll(now, device, sockets);
+            closing.retain(|&handle| {
+                use smoltcp::socket::tcp::{Socket, State};
+
+                let closed = matches!(
+                    sockets.get::<Socket>(handle).state(),
+                    State::Closed | State::TimeWait
+                );
+                if closed {
+                    sockets.remove(handle);
+                }
+                !closed
+            });
+            let delay = iface.poll_delay(now, sockets).map(Duration::from);
+            (std::mem::take(&mut device.tx), delay)
+        };
+        for packet in sent {
+            match tunn.encapsulate(&packet, &mut buf) {
+                TunnResult::WriteToNetwork(datagram) => {
+                    if let Err(err) = socket.send(datagram).await {
+                        tracing::debug!("Failed to send to {}: {}", tunnel.endpoint, err);
+                    }
+                }
+                TunnResult::Err(err) => {
+                    tracing::debug!("Failed to encapsulate a packet: {:?}", err)
+                }
+                _ => {}
+            }
+        }
+
+        // Wait for a datagram from the peer, a connection with something to send, or a timer.
+        let wait = delay.map_or(TIMER_TICK, |delay| delay.min(TIMER_TICK));
+        let received = future::or(async { Some(socket.recv(&mut datagram).await) }, async {
+            future::or(
+                async {
+                    let _ = poked.recv().await;
+                },
+                async {
+                    Timer::after(wait).await;
+                },
+            )
+            .await;
+            None
+        })
+        .await;
+        match received {
+            Some(Ok(size)) => {
+                let mut result =
+                    tunn.decapsulate(Some(tunnel.endpoint.ip()), &datagram[..size], &mut buf);
+                loop {
+                    match result {
+                        // Handshakes, and the packets that waited for them.
+                        TunnResult::WriteToNetwork(datagram) => {
+                            if let Err(err) = socket.send(datagram).await {
+                                tracing::debug!("Failed to send to {}: {}", tunnel.endpoint, err);
+                            }
+                            result = tunn.decapsulate(None, &[], &mut buf);
+                        }
+                        TunnResult::WriteToTunnelV4(packet, src) => {
+                            tunnel.receive(packet, src.into());
+                            break;
+                        }
+                        TunnResult::WriteToTunnelV6(packet, src) => {
+                            tunnel.receive(packet, src.into());
+                            break;
+                        }
+                        TunnResult::Done => break,
+                        TunnResult::Err(err) => {
+                            tracing::debug!(
+                                "Dropped a datagram from {}: {:?}",
+                                tunnel.endpoint,
+                                err
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+            Some(Err(err)) => {
+                tracing::debug!("Failed to receive from {}: {}", tunnel.endpoint, err)
+            }
+            None => {}
+        }
+        match tunn.update_timers(&mut buf) {
+            TunnResult::WriteToNetwork(datagram) => {
+                if let Err(err) = socket.send(datagram).await {
+                    tracing::debug!("Failed to send to {}: {}", tunnel.endpoint, err);
+                }
+            }
+            // Without traffic for a while, the session expires until the next packet.
+            TunnResult::Err(WireGuardError::ConnectionExpired) => {}
+            TunnResult::Err(err) => tracing::debug!("WireGuard timers failed: {:?}", err),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(feature = "wireguard")]
+impl smoltcp::phy::Device for Packets {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(
+        &mut self,
+        _timestamp: smoltcp::time::Instant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
+        let mut capabilities = smoltcp::phy::DeviceCapabilities::default();
+        capabilities.medium = smoltcp::phy::Medium::Ip;
+        capabilities.max_transmission_unit = MTU;
+        capabilities
+    }
+}
+
+/// A packet received from the peer.
+#[cfg(feature = "wireguard")]
+struct RxToken(Vec<u8>);
+
+#[cfg(feature = "wireguard")]
+impl smoltcp::phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+/// Room for a packet to send to the peer.
+#[cfg(feature = "wireguard")]
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+#[cfg(feature = "wireguard")]
+impl smoltcp::phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+/// A TCP connection through the tunnel.
+#[cfg(feature = "wireguard")]
+struct TunnelStream {
+    tunnel: Arc<Tunnel>,
+    handle: smoltcp::iface::SocketHandle,
+}
+
+#[cfg(feature = "wireguard")]
+impl TunnelStream {
+    /// Runs a function on the socket of the connection, waking the task of the tunnel afterwards
+    /// in case the socket has something to send.
+    fn with_socket<T>(&self, f: impl FnOnce(&mut smoltcp::socket::tcp::Socket<'static>) -> T) -> T {
+        let mut stack = self.tunnel.stack.lock().unwrap();
+        let result = f(stack.sockets.get_mut(self.handle));
+        drop(stack);
+        self.tunnel.poke();
+        result
+    }
+}
+
+#[cfg(feature = "wireguard")]
+impl smol::io::AsyncRead for TunnelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        use smoltcp::socket::tcp::RecvError;
+
+        self.with_socket(|socket| match socket.recv_slice(buf) {
+            Ok(0) if !buf.is_empty() => {
+                socket.register_recv_waker(cx.waker());
+                Poll::Pending
+            }
+            Ok(size) => Poll::Ready(Ok(size)),
+            Err(RecvError::Finished) => Poll::Ready(Ok(0)),
+            Err(RecvError::InvalidState) => Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())),
+        })
+    }
+}
+
+#[cfg(feature = "wireguard")]
+impl smol::io::AsyncWrite for TunnelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.with_socket(|socket| match socket.send_slice(buf) {
+            Ok(0) if !buf.is_empty() => {
+                socket.register_send_waker(cx.waker());
+                Poll::Pending
+            }
+            Ok(size) => Poll::Ready(Ok(size)),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.with_socket(|socket| socket.close());
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "wireguard")]
+impl Drop for TunnelStream {
+    fn drop(&mut self) {
+        self.with_socket(|socket| socket.close());
+        self.tunnel.stack.lock().unwrap().closing.push(self.handle);
+    }
+}
+
+#[cfg(feature = "wireguard")]
+impl crate::transport::Stream for TunnelStream {
+    fn tcp_socket(&self) -> Option<&std::net::TcpStream> {
+        None
+    }
+}
+
+#[cfg(not(feature = "wireguard"))]
+impl Tunnel {
+    pub fn new(_settings: Settings) -> io::Result<std::sync::Arc<Self>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "WireGuard tunnels need portfwd to be built with the `wireguard` feature",
+        ))
+    }
+
+    pub fn routes(&self, _ip: IpAddr) -> bool {
+        match self.never {}
+    }
+
+    pub async fn connect(self: &std::sync::Arc<Self>, _addr: SocketAddr) -> io::Result<BoxStream> {
+        match self.never {}
+    }
+
+    pub fn send_to(&self, _payload: &[u8], _port: u16, _addr: SocketAddr) -> io::Result<()> {
+        match self.never {}
+    }
+}
+
+impl fmt::Debug for Tunnel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tunnel")
+            .field("address", &self.address)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys() {
+        let key: Key = "YNqHbfBQKaGvzefSSLUwKPq6PvMQcMrEFe6vAg7Zqmo="
+            .parse()
+            .unwrap();
+        assert_eq!(&key.0[..3], [0x60, 0xda, 0x87]);
+        assert!("YNqHbfBQKaGvzefSSLUwKPq6PvMQcMrE".parse::<Key>().is_err());
+        assert!("not base64!".parse::<Key>().is_err());
+        assert_eq!(format!("{key:?}"), "Key(..)");
+    }
+}
@@ -0,0 +1,217 @@
+//! Forwarding of the UDP datagrams of known clients from the driver of an interface with an XDP
+//! program, for `--xdp` in builds with the `xdp` feature.
+//!
+//! The program, `src/bpf/forward.s`, is checked in compiled as `forward.bpf.o`, and is loaded with
+//! aya. Each datagram that a client sends to a listener goes through portfwd as before, which then
+//! adds the client to the flows of the program with the backend it was forwarded to. The next
+//! datagrams of the client that arrive on the interface are rewritten by the program as the
+//! listener would send them on, from the address they were for and the port of the listener, and
+//! sent to the next hop of the backend without going up the network stack. Flows are removed
+//! after 30 seconds, so the next datagram of the client is routed again, e.g. to another backend
+//! if its backend went down, and the program forgets the least recently used flows when it has
+//! too many.
+//!
+//! Only IPv4 datagrams that aren't fragmented are forwarded by the program, and only to backends
+//! that the kernel has a next hop for, on the interface or on another one that can send XDP
+//! frames, e.g. a veth whose peer has an XDP program. The kernel only gives next hops if IPv4
+//! forwarding is enabled on the interface, and for listeners on an unspecified address, only from
+//! Linux 6.7. The rest go through portfwd. The datagrams forwarded by the program aren't logged or
+//! counted in the metrics.
+//!
+//! Loading or attaching the program needs `CAP_BPF` and `CAP_NET_ADMIN`. If either fails, or a
+//! flow can't be added, datagrams are forwarded by portfwd alone.
+
+#[cfg(feature = "xdp")]
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use std::{fmt, net::SocketAddr};
+
+use smol::io;
+
+/// How long the program forwards the datagrams of a client before they are routed again.
+#[cfg(feature = "xdp")]
+const LIFETIME: Duration = Duration::from_secs(30);
+
+/// The program attached to an interface, and the flows it forwards.
+#[cfg(feature = "xdp")]
+pub struct Offload {
+    iface: String,
+    flows: Mutex<Flows>,
+    /// The loaded program, which stays attached for as long as it is kept.
+    _ebpf: aya::Ebpf,
+}
+
+/// The forwarding of datagrams, which can't be attached without the `xdp` feature.
+#[cfg(not(feature = "xdp"))]
+pub struct Offload {
+    iface: String,
+    never: std::convert::Infallible,
+}
+
+/// The flows in the map of the program, and when they were added.
+#[cfg(feature = "xdp")]
+struct Flows {
+    map: aya::maps::HashMap<aya::maps::MapData, [u8; 8], [u8; 12]>,
+    added: HashMap<[u8; 8], Instant>,
+}
+
+/// The key of the flow of a client to a port in the map of the program.
+#[cfg(feature = "xdp")]
+fn key(client: SocketAddrV4, port: u16) -> [u8; 8] {
+    let mut key = [0; 8];
+    key[..4].copy_from_slice(&client.ip().octets());
+    key[4..6].copy_from_slice(&client.port().to_be_bytes());
+    key[6..].copy_from_slice(&port.to_be_bytes());
+    key
+}
+
+/// Where the program forwards the datagrams of a flow for a local address, or any if unspecified.
+#[cfg(feature = "xdp")]
+fn value(local: Ipv4Addr, backend: SocketAddrV4) -> [u8; 12] {
+    let mut value = [0; 12];
+    value[..4].copy_from_slice(&local.octets());
+    value[4..8].copy_from_slice(&backend.ip().octets());
+    value[8..10].copy_from_slice(&backend.port().to_be_bytes());
+    value
+}
+
+#[cfg(feature = "xdp")]
+impl Offload {
+    /// Loads the program and attaches it to an interface.
+    pub fn attach(iface: &str) -> io::Result<Self> {
+        use aya::programs::{Xdp, XdpMode};
+
+        let failed = |err: &dyn fmt::Display| io::Error::other(format!("{iface}: {err}"));
+        let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!("bpf/forward.bpf.o"))
+            .map_err(|err| failed(&err))?;
+        let program: &mut Xdp = ebpf
+            .program_mut("forward")
+            .expect("the object has the program")
+            .try_into()
+            .map_err(|err| failed(&err))?;
+        program.load().map_err(|err| failed(&err))?;
+        program
+            .attach(iface, XdpMode::default())
+            .map_err(|err| failed(&err))?;
+        let map = ebpf
+            .take_map("FLOWS")
+            .expect("the object has the map")
+            .try_into()
+            .map_err(|err| failed(&err))?;
+        Ok(Self {
+            iface: iface.to_string(),
+            flows: Mutex::new(Flows {
+                map,
+                added: HashMap::new(),
+            }),
+            _ebpf: ebpf,
+        })
+    }
+
+    /// Forwards the next datagrams of a client to a listener on to the backend, if they are IPv4.
+    pub fn add(
+        &self,
+        client: SocketAddr,
+        local: SocketAddr,
+        backend: SocketAddr,
+    ) -> io::Result<()> {
+        let (IpAddr::V4(client_ip), IpAddr::V4(backend_ip)) =
+            (client.ip().to_canonical(), backend.ip().to_canonical())
+        else {
+            return Ok(());
+        };
+        // Listeners on an unspecified address take the datagrams for any of them.
+        let local_ip = match local.ip().to_canonical() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        let key = key(SocketAddrV4::new(client_ip, client.port()), local.port());
+        let value = value(local_ip, SocketAddrV4::new(backend_ip, backend.port()));
+        let mut flows = self.flows.lock().unwrap();
+        flows.map.insert(key, value, 0).map_err(io::Error::other)?;
+        flows.added.insert(key, Instant::now());
+        tracing::debug!("Forwarding the datagrams of {} on {}", client, self.iface);
+        Ok(())
+    }
+
+    /// Removes the flows that outlived their lifetime.
+    fn expire(&self) {
+        let mut flows = self.flows.lock().unwrap();
+        let Flows { map, added } = &mut *flows;
+        added.retain(|key, added| {
+            if added.elapsed() < LIFETIME {
+                return true;
+            }
+            // The program may have forgotten the flow already.
+            let _ = map.remove(key);
+            false
+        });
+    }
+
+    /// Removes the flows that outlived their lifetime on a thread of its own, for as long as the
+    /// program is kept.
+    pub fn expire_flows(self: &std::sync::Arc<Self>) -> io::Result<()> {
+        let offload = std::sync::Arc::downgrade(self);
+        std::thread::Builder::new()
+            .name("xdp-expire".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(LIFETIME / 2);
+                match offload.upgrade() {
+                    Some(offload) => offload.expire(),
+                    None => return,
+                }
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "xdp"))]
+impl Offload {
+    pub fn attach(_iface: &str) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "XDP forwarding needs portfwd to be built with the `xdp` feature",
+        ))
+    }
+
+    pub fn add(
+        &self,
+        _client: SocketAddr,
+        _local: SocketAddr,
+        _backend: SocketAddr,
+    ) -> io::Result<()> {
+        match self.never {}
+    }
+
+    pub fn expire_flows(self: &std::sync::Arc<Self>) -> io::Result<()> {
+        match self.never {}
+    }
+}
+
+impl fmt::Debug for Offload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Offload")
+            .field("iface", &self.iface)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, feature = "xdp"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_flows_in_network_order() {
+        let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 0x1234);
+        assert_eq!(key(client, 53), [10, 0, 0, 1, 0x12, 0x34, 0, 53]);
+        let backend = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5353);
+        assert_eq!(
+            value(Ipv4Addr::UNSPECIFIED, backend),
+            [0, 0, 0, 0, 10, 0, 0, 2, 0x14, 0xe9, 0, 0]
+        );
+    }
+}